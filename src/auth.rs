@@ -0,0 +1,160 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tiny_http::{Header, Request, Response};
+use yaml_rust2::Yaml;
+use yaml_rust2::yaml::Hash;
+use crate::config::ConfigError;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The credentials an HTTP endpoint asks for, requests without them are answered with 401
+#[derive(Clone, Debug, PartialEq)]
+pub enum HttpAuth {
+    /// `Authorization: Basic ...`
+    Basic { username: String, password: String },
+    /// `Authorization: Bearer <token>`
+    Bearer(String)
+}
+
+pub fn base64(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            out.push(if i <= chunk.len() {BASE64[(n >> (18 - 6 * i) & 63) as usize] as char} else {'='});
+        }
+    }
+    out
+}
+
+/// Compares what a request `sent` with `secret` as MACs, so how long it takes does not tell how much of them matched
+pub fn constant_time_eq(sent: &[u8], secret: &[u8]) -> bool {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(secret);
+    let expected = mac.finalize().into_bytes();
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(sent);
+    mac.verify_slice(&expected).is_ok()
+}
+
+pub fn header<'r>(request: &'r Request, name: &'static str) -> Option<&'r str> {
+    request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str())
+}
+
+impl HttpAuth {
+    /// Builds it from the mapping at `key` of `doc`, either `username` and `password` or `token`. None if there is none
+    pub fn from_yaml(doc: &Yaml, key: &str) -> Result<Option<Self>, ConfigError> {
+        let auth = &doc[key];
+        let invalid = |msg: &str| ConfigError::InvalidValue(key.to_string(), msg.to_string());
+        if auth.is_badvalue() || auth.is_null() {
+            return Ok(None)
+        }
+        if auth.as_hash().is_none() {
+            return Err(invalid(&format!("expected a mapping with `username` and `password` or `token`, got `{:?}`", auth)))
+        }
+        let value = |name: &str| auth[name].as_str().filter(|v| !v.is_empty()).map(|v| v.to_string());
+        match (value("username"), value("password"), value("token")) {
+            (Some(username), Some(password), None) => Ok(Some(HttpAuth::Basic { username, password })),
+            (None, None, Some(token)) => Ok(Some(HttpAuth::Bearer(token))),
+            (_, _, Some(_)) => Err(invalid("`token` can't be combined with `username` and `password`")),
+            (Some(_), None, None) => Err(invalid("`password` is missing")),
+            (None, Some(_), None) => Err(invalid("`username` is missing")),
+            (None, None, None) => Err(invalid("expected `username` and `password` or `token`"))
+        }
+    }
+
+    pub fn to_yaml(&self) -> Yaml {
+        let mut auth = Hash::new();
+        let mut put = |key: &str, value: &str| auth.insert(Yaml::String(key.to_string()), Yaml::String(value.to_string()));
+        match self {
+            HttpAuth::Basic { username, password } => {
+                put("username", username);
+                put("password", password);
+            }
+            HttpAuth::Bearer(token) => {
+                put("token", token);
+            }
+        }
+        Yaml::Hash(auth)
+    }
+
+    /// The `Authorization` header a request has to send
+    fn expected(&self) -> String {
+        match self {
+            HttpAuth::Basic { username, password } => format!("Basic {}", base64(format!("{}:{}", username, password).as_bytes())),
+            HttpAuth::Bearer(token) => format!("Bearer {}", token)
+        }
+    }
+
+    /// Whether `request` sent the credentials. The scheme is matched case-insensitively, the credentials in constant time
+    pub fn allows(&self, request: &Request) -> bool {
+        let Some(sent) = header(request, "Authorization").map(str::trim) else {
+            return false
+        };
+        let expected = self.expected();
+        let (scheme, credentials) = expected.split_once(' ').expect("The expected header has a scheme");
+        match sent.split_once(' ') {
+            Some((sent_scheme, sent_credentials)) if sent_scheme.eq_ignore_ascii_case(scheme) =>
+                constant_time_eq(sent_credentials.trim().as_bytes(), credentials.as_bytes()),
+            _ => false
+        }
+    }
+
+    /// The 401 to answer a request with that `allows` rejected
+    pub fn challenge(&self) -> Response<std::io::Cursor<Vec<u8>>> {
+        let scheme = match self {
+            HttpAuth::Basic { .. } => "Basic realm=\"gdep\"",
+            HttpAuth::Bearer(_) => "Bearer"
+        };
+        Response::from_string("unauthorized").with_status_code(401)
+            .with_header(Header::from_bytes("WWW-Authenticate", scheme).expect("Static header is valid"))
+    }
+}
+
+/// Checks `request` against `auth` if the endpoint has any: the 401 to answer it with if it is rejected
+pub fn check(auth: Option<&HttpAuth>, request: &Request) -> Option<Response<std::io::Cursor<Vec<u8>>>> {
+    auth.filter(|auth| !auth.allows(request)).map(HttpAuth::challenge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust2::YamlLoader;
+
+    #[test]
+    fn base64_pads_to_whole_blocks() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"scraper:s3cret"), "c2NyYXBlcjpzM2NyZXQ=");
+    }
+
+    #[test]
+    fn constant_time_eq_only_accepts_the_secret_itself() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cre", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret!", b"s3cret"));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+    }
+
+    #[test]
+    fn auth_is_basic_or_bearer_but_not_both() {
+        let parse = |yaml: &str| HttpAuth::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0], "metrics_auth");
+        assert_eq!(parse("other: 1").unwrap(), None);
+        assert_eq!(parse("metrics_auth:\n  username: scraper\n  password: s3cret").unwrap(),
+            Some(HttpAuth::Basic { username: "scraper".to_string(), password: "s3cret".to_string() }));
+        assert_eq!(parse("metrics_auth:\n  token: t0ken").unwrap(), Some(HttpAuth::Bearer("t0ken".to_string())));
+        for invalid in ["metrics_auth: s3cret", "metrics_auth:\n  username: scraper", "metrics_auth:\n  password: s3cret",
+            "metrics_auth:\n  username: scraper\n  password: s3cret\n  token: t0ken", "metrics_auth: {}"] {
+            assert!(matches!(parse(invalid), Err(ConfigError::InvalidValue(key, _)) if key == "metrics_auth"), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn expected_headers_carry_the_scheme() {
+        assert_eq!(HttpAuth::Basic { username: "scraper".to_string(), password: "s3cret".to_string() }.expected(), "Basic c2NyYXBlcjpzM2NyZXQ=");
+        assert_eq!(HttpAuth::Bearer("t0ken".to_string()).expected(), "Bearer t0ken");
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+
+/// How often `SystemClock` looks at the stop condition while it sleeps
+const STEP: Duration = Duration::from_millis(100);
+
+/// Where the polling, debounce, backoff, restart delay and script timeout logic takes the time from
+/// and how it waits, so a stand-in can move time forward without anybody sleeping for real
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The date and time, for what is compared to dates: commit ages and deploy windows
+    fn now_utc(&self) -> DateTime<Utc>;
+
+    /// Sleeps for `duration`, waking up early once `stop` returns true. False if it did
+    fn sleep_interruptible(&self, duration: Duration, stop: &mut dyn FnMut() -> bool) -> bool;
+
+    /// How long ago `since` was
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+}
+
+/// The wall clock, sleeping in steps of `STEP` to look at the stop condition in between
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep_interruptible(&self, duration: Duration, stop: &mut dyn FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            if stop() {
+                return false
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true
+            }
+            thread::sleep(left.min(STEP));
+        }
+    }
+}
+
+/// A clock that only moves when it is slept on or `advance`d, for tests. A sleep `stop` doesn't cut short
+/// right away is over at once, having moved the clock forward by all of it. Every sleep is recorded
+pub struct MockClock {
+    start: Instant,
+    start_utc: DateTime<Utc>,
+    elapsed: Mutex<Duration>,
+    sleeps: Mutex<Vec<Duration>>
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::at(Utc::now())
+    }
+
+    /// A clock whose `now_utc` starts at `time`
+    pub fn at(time: DateTime<Utc>) -> Self {
+        Self { start: Instant::now(), start_utc: time, elapsed: Mutex::new(Duration::ZERO), sleeps: Mutex::new(Vec::new()) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    /// The sleeps so far, oldest first
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.sleeps.lock().unwrap().clone()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        self.start_utc + *self.elapsed.lock().unwrap()
+    }
+
+    fn sleep_interruptible(&self, duration: Duration, stop: &mut dyn FnMut() -> bool) -> bool {
+        if stop() {
+            return false
+        }
+        self.sleeps.lock().unwrap().push(duration);
+        self.advance(duration);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_mock_clock_moves_only_when_slept_on_or_advanced() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert!(clock.sleep_interruptible(Duration::from_secs(30), &mut || false));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.elapsed(start), Duration::from_secs(35));
+
+        assert!(!clock.sleep_interruptible(Duration::from_secs(60), &mut || true));
+        assert_eq!(clock.elapsed(start), Duration::from_secs(35));
+        assert_eq!(clock.sleeps(), [Duration::from_secs(30)]);
+    }
+
+    #[test]
+    fn the_mock_clocks_date_moves_with_it() {
+        let start = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z").unwrap().with_timezone(&Utc);
+        let clock = MockClock::at(start);
+        assert_eq!(clock.now_utc(), start);
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now_utc(), DateTime::parse_from_rfc3339("2024-03-01T12:01:30Z").unwrap());
+    }
+
+    #[test]
+    fn the_system_clock_wakes_up_once_stop_says_so() {
+        let started = Instant::now();
+        let mut checks = 0;
+        assert!(!SystemClock.sleep_interruptible(Duration::from_secs(60), &mut || {
+            checks += 1;
+            checks > 2
+        }));
+        assert!(started.elapsed() < Duration::from_secs(5), "slept for {:?}", started.elapsed());
+
+        let started = Instant::now();
+        assert!(SystemClock.sleep_interruptible(Duration::from_millis(150), &mut || false));
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+}
@@ -1,31 +1,318 @@
-use std::{fmt, fs};
+use std::{fmt, fs, io};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use yaml_rust2::{YamlLoader, Yaml};
+use std::sync::OnceLock;
+use yaml_rust2::{YamlLoader, Yaml, YamlEmitter};
+use yaml_rust2::yaml::Hash;
 use crate::{conv_err, conv_err_e};
+use crate::notify::NotifierConfig;
+use crate::remote::SshCommand;
+use crate::schedule::DeploySchedule;
+use crate::tags::TagFilter;
+use crate::auth::HttpAuth;
+use crate::webhook::WebhookConfig;
 
+#[derive(Clone)]
 pub enum RepoLike {
     Remote(String),
     Local(String),
     Remote2(String, String)
 }
 
+/// What the deployment follows
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Track {
+    Branch,
+    Tag
+}
+
+/// What gdep does with the repo
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Keep a checkout up to date and run the script on it
+    Deploy,
+    /// Keep a bare copy of every ref of origin, with no checkout and no script
+    Mirror
+}
+
+/// How an update goes live
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeployMode {
+    /// The script runs in the checkout, which updates change in place
+    InPlace,
+    /// Every commit is checked out into a release directory of its own, `current_link` is switched to it once it came up healthy
+    Symlink
+}
+
+impl fmt::Display for DeployMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeployMode::InPlace => write!(f, "in_place"),
+            DeployMode::Symlink => write!(f, "symlink")
+        }
+    }
+}
+
+/// Which tags a fetch brings along, `git fetch`'s `--tags`, its default and `--no-tags`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FetchTags {
+    /// Every tag of origin
+    All,
+    /// Only tags pointing at fetched commits
+    Auto,
+    /// No tags
+    None
+}
+
+impl fmt::Display for FetchTags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchTags::All => write!(f, "all"),
+            FetchTags::Auto => write!(f, "auto"),
+            FetchTags::None => write!(f, "none")
+        }
+    }
+}
+
+/// How the branch to follow is picked when none is given
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BranchSelector {
+    /// Once at startup: origin's default branch, or the first glob of `include_branches` that matches
+    Default,
+    /// On every cycle: the branch with the newest commit, switched to whenever another one gets ahead
+    Newest
+}
+
+impl fmt::Display for BranchSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BranchSelector::Default => write!(f, "default"),
+            BranchSelector::Newest => write!(f, "newest")
+        }
+    }
+}
+
+/// How a fast-forward update treats local changes to files it touches
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckoutMode {
+    /// Overwrite them
+    Force,
+    /// Refuse the update
+    Safe,
+    /// Merge them into the updated files, leaving conflict markers where they clash
+    Merge
+}
+
+impl fmt::Display for CheckoutMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckoutMode::Force => write!(f, "force"),
+            CheckoutMode::Safe => write!(f, "safe"),
+            CheckoutMode::Merge => write!(f, "merge")
+        }
+    }
+}
+
+/// What the update thread does when the local branch has commits origin doesn't
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnAhead {
+    /// Stop with `UpdateErrorRepoAhead`
+    Error,
+    /// Keep polling without updating until origin moves
+    Ignore,
+    /// Push the local commits to origin
+    Push
+}
+
+impl fmt::Display for OnAhead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnAhead::Error => write!(f, "error"),
+            OnAhead::Ignore => write!(f, "ignore"),
+            OnAhead::Push => write!(f, "push")
+        }
+    }
+}
+
+/// How merge conflicts are handled when a diverged branch is merged.
+/// Setting it at all opts into merging diverged branches instead of stopping
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictStrategy {
+    /// Apply nothing and report the conflict
+    Abort,
+    /// Resolve with the local side
+    Ours,
+    /// Resolve with the remote side
+    Theirs
+}
+
+impl fmt::Display for ConflictStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictStrategy::Abort => write!(f, "abort"),
+            ConflictStrategy::Ours => write!(f, "ours"),
+            ConflictStrategy::Theirs => write!(f, "theirs")
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub name: String,
+    pub mode: Mode,
+    pub deploy_mode: DeployMode,
+    /// Where `deploy_mode: symlink` checks out the releases, a directory named after the time and the commit each
+    pub releases_dir: Option<String>,
+    /// The symlink `deploy_mode: symlink` points at the live release
+    pub current_link: Option<String>,
+    /// How many releases are kept, the oldest ones beyond it are removed. Every one without it
+    pub keep_releases: Option<usize>,
     pub re_run: bool,
     pub restart_after_update: bool,
+    pub watch_paths: Vec<String>,
+    /// Path globs and the script to run when an update changes a file matching them, in the order they are declared
+    pub on_change: Vec<(String, String)>,
     pub exit_on_script_error: bool,
     pub exit_on_gdep_error: bool,
+    /// Run once before the script when gdep has just cloned the repo, not when it opened an existing one
+    pub bootstrap: Option<String>,
+    pub steps: Vec<String>,
     pub script: String,
+    pub script_file: Option<String>,
     pub repo: RepoLike,
-    pub cleanup: Option<String>
+    pub cleanup: Option<String>,
+    pub cleanup_file: Option<String>,
+    pub max_output_rate: Option<u64>,
+    pub shutdown_grace: u64,
+    pub max_restarts: Option<u64>,
+    pub restart_delay: u64,
+    /// Up to this many seconds are added to `restart_delay` at random, so crashing deployments don't all restart at once
+    pub restart_jitter: u64,
+    /// The script is started at most once in this many seconds, a restart asked for sooner waits for it
+    pub min_restart_interval: u64,
+    pub expect_branch: Option<String>,
+    pub error_context_lines: Option<usize>,
+    pub commit: Option<String>,
+    pub deploy_schedule: Option<DeploySchedule>,
+    pub track: Track,
+    pub tag_pattern: Option<String>,
+    pub pre_update: Option<String>,
+    pub post_update: Option<String>,
+    pub require_annotated_tags: bool,
+    pub verify_tag_signatures: bool,
+    pub max_commit_age: Option<u64>,
+    /// An update bringing in more commits than this is refused unless `force` is set, it needs a look first
+    pub max_behind: Option<usize>,
+    pub require_signed: bool,
+    pub allowed_signers: Vec<String>,
+    /// Globs of the author emails whose commits may be deployed, anyone's without them
+    pub allowed_authors: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub ca_bundle: Option<String>,
+    pub proxy: Option<String>,
+    pub ssh_key: Option<String>,
+    pub ssh_key_passphrase: Option<String>,
+    /// Like `$GIT_SSH_COMMAND`, of which the port, key and user are applied
+    pub ssh_command: Option<String>,
+    pub http_username: Option<String>,
+    pub http_token: Option<String>,
+    pub args: Vec<String>,
+    /// One of `process::SHELLS` to run every script with instead of the platform's shell
+    pub shell: Option<String>,
+    /// An interpreter and its arguments to run every script with, taking the script file after them
+    pub runner: Vec<String>,
+    /// The user, and optionally group, every script runs as: `name`, `uid`, `name:group` or `uid:gid`
+    pub run_as: Option<String>,
+    /// Where scripts run, relative to the checkout or absolute. The checkout itself without it
+    pub working_dir: Option<String>,
+    pub script_timeout: Option<u64>,
+    pub force: bool,
+    pub stall_timeout: Option<u64>,
+    pub stall_retries: u64,
+    pub clone_retries: u64,
+    pub conflict_strategy: Option<ConflictStrategy>,
+    pub checkout_mode: CheckoutMode,
+    pub on_ahead: OnAhead,
+    pub merge_message: String,
+    pub refspec: String,
+    pub prune: bool,
+    pub fetch_tags: FetchTags,
+    pub stash_local_changes: bool,
+    pub fail_on_dirty: bool,
+    pub clean_untracked: bool,
+    pub clean_ignored: bool,
+    pub restore_stash: bool,
+    pub committer_name: Option<String>,
+    pub committer_email: Option<String>,
+    pub smoke_test: Option<String>,
+    pub smoke_timeout: u64,
+    /// Runs in a temporary worktree at a fetched commit, which is only applied if it exits with 0
+    pub verify_script: Option<String>,
+    pub verify_timeout: u64,
+    /// A command or an `http(s)://` URL that tells when the started script is ready to serve
+    pub ready_check: Option<String>,
+    pub ready_timeout: u64,
+    /// Start the updated script next to the old one, which only stops once `ready_check` passes
+    pub graceful_restart: bool,
+    pub rollback_on_smoke_fail: bool,
+    pub rollback_on_failure: bool,
+    pub depth: Option<u32>,
+    pub submodules: bool,
+    /// Runs `lfs_command` in the checkout after it was cloned and after every update, as libgit2 leaves LFS pointer files alone
+    pub lfs: bool,
+    pub lfs_command: String,
+    pub max_fetch_retries: u64,
+    /// How often an update that failed on a locked index or ref is tried again
+    pub update_retries: u64,
+    /// Seconds a single fetch may take before it is aborted and retried. libgit2's read timeout is process wide, so this applies to every deployment
+    pub fetch_timeout: Option<u64>,
+    /// Seconds after applying an update during which newer ones wait, to be applied together once it is over
+    pub debounce: Option<u64>,
+    pub log_file: Option<String>,
+    pub log_max_size: u64,
+    pub log_keep: usize,
+    pub branch: Option<String>,
+    /// Globs of the branches inferring the branch picks from, the first one that matches a branch of origin wins
+    pub include_branches: Vec<String>,
+    /// Globs of the branches inferring the branch never picks
+    pub exclude_branches: Vec<String>,
+    pub branch_selector: BranchSelector,
+    pub critical: bool,
+    pub poll_interval: u64,
+    pub webhook: Option<WebhookConfig>,
+    pub health_addr: Option<String>,
+    /// Serves `/metrics` in the Prometheus text format, on the health server if it's the same address
+    pub metrics_addr: Option<String>,
+    /// Credentials `/healthz` asks for, basic (`username` and `password`) or a bearer `token`
+    pub health_auth: Option<HttpAuth>,
+    /// Credentials `/metrics` asks for, like `health_auth`
+    pub metrics_auth: Option<HttpAuth>,
+    pub control_socket: Option<String>,
+    pub notifier: Option<NotifierConfig>,
+    pub stdout_file: Option<String>,
+    pub stderr_file: Option<String>,
+    /// The script's output goes through gdep's logger, each line tagged `[stdout]` or `[stderr]`. Lines going to
+    /// `stdout_file` or `stderr_file` are written there with a timestamp and the tag instead
+    pub prefix_output: bool,
+    pub default_repo_path: Option<String>,
+    pub watch_config: bool,
+    /// Only supervise the script, the checkout is never fetched or updated
+    pub no_update: bool,
+    /// On startup, go back to the last commit that came up healthy if HEAD is past it
+    pub restore_on_start: bool
 }
 
 #[derive(Debug, Clone)]
 pub enum ConfigError {
     ConfigFileNotFound,
-    ScriptFileNotFound,
+    ScriptFileNotFound(String),
     ParsingFailed(String),
-    MissingContent(String)
+    MissingContent(String),
+    /// A key `schema::KEYS` doesn't list, most likely a typo
+    UnknownKey(String),
+    InvalidValue(String, String),
+    UndefinedVariable(String, String),
+    EmitFailed(String)
 }
 impl fmt::Display for ConfigError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -33,14 +320,26 @@ impl fmt::Display for ConfigError {
             ConfigError::ConfigFileNotFound => {
                 write!(f, "Config file not found")
             },
-            ConfigError::ScriptFileNotFound => {
-                write!(f, "Script file not found")
+            ConfigError::ScriptFileNotFound(path) => {
+                write!(f, "Script file not found: {}", path)
             },
             ConfigError::ParsingFailed(err) => {
                 write!(f, "Parsing failed: {}", err)
             },
             ConfigError::MissingContent(c) => {
                 write!(f, "Missing mandatory property: {}", c)
+            },
+            ConfigError::UnknownKey(key) => {
+                write!(f, "Unknown key `{}`, `gdep schema` lists every key", key)
+            },
+            ConfigError::InvalidValue(key, err) => {
+                write!(f, "Invalid value for `{}`: {}", key, err)
+            },
+            ConfigError::UndefinedVariable(key, var) => {
+                write!(f, "`{}` references the environment variable `{}`, which is not set", key, var)
+            },
+            ConfigError::EmitFailed(err) => {
+                write!(f, "Emitting config failed: {}", err)
             }
         }
     }
@@ -56,69 +355,1790 @@ fn resolve_other_path(original: &Path, other: &Path) -> PathBuf {
     resolved_path
 }
 
-fn ld_yaml_docs(path: &String) -> Result<Vec<Yaml>, ConfigError> {
-    let content = conv_err!(fs::read_to_string(path), ConfigError::ConfigFileNotFound)?;
-    conv_err_e!(YamlLoader::load_from_str(&*content), ConfigError::ParsingFailed)
+/// `scheme://...` or scp-like `user@host:path`, which can only be cloned, not opened
+fn is_url(repo: &str) -> bool {
+    repo.contains("://") || repo.split_once(':').is_some_and(|(host, _)| host.contains('@') && !host.contains('/'))
+}
+
+fn as_u64(value: &Yaml) -> Option<u64> {
+    value.as_i64().and_then(|t| u64::try_from(t).ok())
+}
+
+const DURATION_UNITS: [(&str, u64); 5] = [("w", 604800), ("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+
+/// Durations are given in seconds, either plain (`90`) or with a unit (`90s`, `15m`, `12h`, `30d`, `2w`)
+fn as_duration(doc: &Yaml, key: &str) -> Result<Option<u64>, ConfigError> {
+    let invalid = |v: &str| ConfigError::InvalidValue(key.to_string(), format!("`{}` is not a duration like `90s`, `15m`, `12h` or `30d`", v));
+    match &doc[key] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Integer(_) => as_u64(&doc[key]).map(Some).ok_or_else(|| invalid(&format!("{:?}", doc[key]))),
+        Yaml::String(spec) => {
+            let spec = spec.trim();
+            let (number, factor) = DURATION_UNITS.iter()
+                .find_map(|(unit, factor)| spec.strip_suffix(unit).map(|n| (n, *factor)))
+                .unwrap_or((spec, 1));
+            number.trim().parse::<u64>().map(|n| Some(n * factor)).map_err(|_| invalid(spec))
+        }
+        other => Err(invalid(&format!("{:?}", other)))
+    }
+}
+
+/// Formats seconds in the largest unit that fits at least once, e.g. `3d` (rounded down)
+pub fn format_duration(secs: u64) -> String {
+    let (unit, factor) = DURATION_UNITS.iter().find(|(_, factor)| secs >= *factor).unwrap_or(&("s", 1));
+    format!("{}{}", secs / factor, unit)
+}
+
+/// Like `format_duration`, but only uses a unit that divides `secs` exactly, so the value reads back the same
+fn duration_to_yaml(secs: u64) -> Yaml {
+    let (unit, factor) = DURATION_UNITS.iter().find(|(_, factor)| secs > 0 && secs.is_multiple_of(*factor)).unwrap_or(&("s", 1));
+    Yaml::String(format!("{}{}", secs / factor, unit))
+}
+
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 10 << 20;
+pub const DEFAULT_LOG_KEEP: usize = 5;
+pub const DEFAULT_CLONE_RETRIES: u64 = 3;
+pub const DEFAULT_READY_TIMEOUT: u64 = 60;
+pub const DEFAULT_UPDATE_RETRIES: u64 = 3;
+pub const DEFAULT_VERIFY_TIMEOUT: u64 = 600;
+/// `{branch}` is replaced by the merged branch, `{sha}` by the commit merged in
+pub const DEFAULT_MERGE_MESSAGE: &str = "Merge commit";
+/// Run with `lfs` after every checkout
+pub const DEFAULT_LFS_COMMAND: &str = "git lfs pull";
+/// `{branch}` is replaced by the tracked branch
+pub const DEFAULT_REFSPEC: &str = "+refs/heads/{branch}:refs/remotes/origin/{branch}";
+/// Written by `to_effective_yaml` in place of secrets
+const REDACTED: &str = "<redacted>";
+
+/// gdep reads the fetched tip from `origin/<branch>`, so that is where every refspec has to store it
+fn as_refspec(doc: &Yaml) -> Result<String, ConfigError> {
+    let Some(refspec) = doc["refspec"].as_str() else {
+        return Ok(DEFAULT_REFSPEC.to_string())
+    };
+    let invalid = |msg: &str| ConfigError::InvalidValue("refspec".to_string(), format!("`{}` {}", refspec, msg));
+    let Some((src, dst)) = refspec.split_once(':') else {
+        return Err(invalid("has no `:<destination>`"))
+    };
+    if src.trim_start_matches('+').is_empty() {
+        return Err(invalid("has no source"))
+    }
+    if dst != "refs/remotes/origin/{branch}" {
+        return Err(invalid("must fetch into `refs/remotes/origin/{branch}`"))
+    }
+    Ok(refspec.to_string())
+}
+
+const SIZE_UNITS: [(&str, u64); 3] = [("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+
+/// Sizes are given in bytes, either plain (`1048576`) or with a binary unit (`512K`, `10M`, `1G`)
+fn as_size(doc: &Yaml, key: &str) -> Result<Option<u64>, ConfigError> {
+    let invalid = |v: &str| ConfigError::InvalidValue(key.to_string(), format!("`{}` is not a size like `512K`, `10M` or `1G`", v));
+    match &doc[key] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Integer(_) => as_u64(&doc[key]).map(Some).ok_or_else(|| invalid(&format!("{:?}", doc[key]))),
+        Yaml::String(spec) => {
+            let spec = spec.trim();
+            let (number, factor) = SIZE_UNITS.iter()
+                .find_map(|(unit, factor)| spec.strip_suffix(unit).map(|n| (n, *factor)))
+                .unwrap_or((spec, 1));
+            number.trim().parse::<u64>().map(|n| Some(n * factor)).map_err(|_| invalid(spec))
+        }
+        other => Err(invalid(&format!("{:?}", other)))
+    }
+}
+
+fn size_to_yaml(bytes: u64) -> Yaml {
+    match SIZE_UNITS.iter().find(|(_, factor)| bytes > 0 && bytes.is_multiple_of(*factor)) {
+        Some((unit, factor)) => Yaml::String(format!("{}{}", bytes / factor, unit)),
+        None => Yaml::Integer(bytes as i64)
+    }
+}
+
+/// Any scalar as a string, so values like `PORT: 8080` or `--port 8080` don't need quoting
+fn as_scalar_string(value: &Yaml, key: &str) -> Result<String, ConfigError> {
+    match value {
+        Yaml::String(v) | Yaml::Real(v) => Ok(v.clone()),
+        Yaml::Integer(v) => Ok(v.to_string()),
+        Yaml::Boolean(v) => Ok(v.to_string()),
+        other => Err(ConfigError::InvalidValue(key.to_string(), format!("`{:?}` is not a scalar", other)))
+    }
+}
+
+fn as_env_map(doc: &Yaml) -> Result<BTreeMap<String, String>, ConfigError> {
+    let Some(hash) = doc["env"].as_hash() else {
+        return Ok(BTreeMap::new())
+    };
+    hash.iter().map(|(key, value)| {
+        let key = key.as_str().ok_or_else(|| ConfigError::InvalidValue("env".to_string(), format!("`{:?}` is not a variable name", key)))?;
+        Ok((key.to_string(), as_scalar_string(value, &format!("env.{}", key))?))
+    }).collect()
+}
+
+fn as_args(doc: &Yaml) -> Result<Vec<String>, ConfigError> {
+    match &doc["args"] {
+        Yaml::BadValue | Yaml::Null => Ok(Vec::new()),
+        Yaml::Array(args) => args.iter().map(|a| as_scalar_string(a, "args")).collect(),
+        other => Err(ConfigError::InvalidValue("args".to_string(), format!("expected a list, got `{:?}`", other)))
+    }
+}
+
+fn as_shell(doc: &Yaml) -> Result<Option<String>, ConfigError> {
+    let Some(shell) = doc["shell"].as_str() else {
+        return match &doc["shell"] {
+            Yaml::BadValue | Yaml::Null => Ok(None),
+            other => Err(ConfigError::InvalidValue("shell".to_string(), format!("expected a string, got `{:?}`", other)))
+        }
+    };
+    if !crate::process::SHELLS.contains(&shell) {
+        return Err(ConfigError::InvalidValue("shell".to_string(),
+            format!("`{}` is not one of {}, `runner` takes any other interpreter", shell, crate::process::SHELLS.join(", "))))
+    }
+    Ok(Some(shell.to_string()))
+}
+
+/// `python3 -u` or `[python3, -u]`, the program followed by its arguments
+fn as_runner(doc: &Yaml) -> Result<Vec<String>, ConfigError> {
+    let runner = match &doc["runner"] {
+        Yaml::BadValue | Yaml::Null => return Ok(Vec::new()),
+        Yaml::String(runner) => runner.split_whitespace().map(|a| a.to_string()).collect(),
+        Yaml::Array(runner) => runner.iter().map(|a| as_scalar_string(a, "runner")).collect::<Result<Vec<_>, _>>()?,
+        other => return Err(ConfigError::InvalidValue("runner".to_string(), format!("expected a command line or a list, got `{:?}`", other)))
+    };
+    if runner.is_empty() {
+        return Err(ConfigError::InvalidValue("runner".to_string(), "no program given".to_string()))
+    }
+    if !doc["shell"].is_badvalue() {
+        return Err(ConfigError::InvalidValue("runner".to_string(), "can't be combined with `shell`".to_string()))
+    }
+    Ok(runner)
+}
+
+/// Whether the program of `runner`, else `shell`, can be found. Depends on the host, not the config file
+fn check_runner(shell: Option<&String>, runner: &[String]) -> Result<(), ConfigError> {
+    let (key, program) = match (runner.first(), shell) {
+        (Some(program), _) => ("runner", program),
+        (None, Some(shell)) => ("shell", shell),
+        (None, None) => return Ok(())
+    };
+    if !crate::process::exists(program) {
+        return Err(ConfigError::InvalidValue(key.to_string(), format!("`{}` is not installed or not on the PATH", program)))
+    }
+    Ok(())
+}
+
+/// `run_as` as written, a bare uid is read as a number by YAML
+fn as_run_as(doc: &Yaml) -> Option<String> {
+    doc["run_as"].as_str().map(|t| t.to_string()).or_else(|| as_u64(&doc["run_as"]).map(|t| t.to_string()))
+}
+
+/// Whether the scripts can be run as `run_as`: the user exists and gdep is allowed to switch to it
+fn check_run_as(run_as: Option<&str>) -> Result<(), ConfigError> {
+    match run_as.map(crate::process::user) {
+        Some(Err(e)) => Err(ConfigError::InvalidValue("run_as".to_string(), e)),
+        _ => Ok(())
+    }
+}
+
+/// Key fingerprints as gpg prints them, `ABCD 1234 ...` is accepted as well
+fn as_fingerprints(doc: &Yaml) -> Result<Vec<String>, ConfigError> {
+    let invalid = |v: &Yaml| ConfigError::InvalidValue("allowed_signers".to_string(), format!("`{:?}` is not a key fingerprint", v));
+    match &doc["allowed_signers"] {
+        Yaml::BadValue | Yaml::Null => Ok(Vec::new()),
+        Yaml::Array(keys) => keys.iter().map(|k| {
+            let fingerprint: String = k.as_str().ok_or_else(|| invalid(k))?.split_whitespace().collect();
+            if fingerprint.is_empty() || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid(k))
+            }
+            Ok(fingerprint.to_ascii_uppercase())
+        }).collect(),
+        other => Err(ConfigError::InvalidValue("allowed_signers".to_string(), format!("expected a list, got `{:?}`", other)))
+    }
+}
+
+/// Globs like `services/api/**`, matched against the repo-relative paths a commit changes
+fn as_watch_paths(doc: &Yaml) -> Result<Vec<String>, ConfigError> {
+    as_globs(doc, "watch_paths")
+}
+
+/// A list of globs under `key`, empty if it isn't set
+fn as_globs(doc: &Yaml, key: &str) -> Result<Vec<String>, ConfigError> {
+    let invalid = |v: &Yaml, msg: String| ConfigError::InvalidValue(key.to_string(), format!("`{:?}` is not a glob: {}", v, msg));
+    match &doc[key] {
+        Yaml::BadValue | Yaml::Null => Ok(Vec::new()),
+        Yaml::Array(globs) => globs.iter().map(|g| {
+            let glob = g.as_str().ok_or_else(|| invalid(g, "expected a string".to_string()))?;
+            glob::Pattern::new(glob).map_err(|e| invalid(g, e.to_string()))?;
+            Ok(glob.to_string())
+        }).collect(),
+        other => Err(ConfigError::InvalidValue(key.to_string(), format!("expected a list, got `{:?}`", other)))
+    }
+}
+
+/// The `on_change` mapping of path globs (like `watch_paths`) to scripts, in declaration order
+fn as_on_change(doc: &Yaml) -> Result<Vec<(String, String)>, ConfigError> {
+    let invalid = |msg: String| ConfigError::InvalidValue("on_change".to_string(), msg);
+    match &doc["on_change"] {
+        Yaml::BadValue | Yaml::Null => Ok(Vec::new()),
+        Yaml::Hash(rules) => rules.iter().map(|(glob, script)| {
+            let glob = glob.as_str().ok_or_else(|| invalid(format!("`{:?}` is not a path glob: expected a string", glob)))?;
+            glob::Pattern::new(glob).map_err(|e| invalid(format!("`{}` is not a path glob: {}", glob, e)))?;
+            let script = script.as_str().ok_or_else(|| invalid(format!("the script for `{}` is not a string", glob)))?;
+            Ok((glob.to_string(), script.to_string()))
+        }).collect(),
+        other => Err(invalid(format!("expected a mapping of path globs to scripts, got `{:?}`", other)))
+    }
+}
+
+/// The steps and the script: a `script` list is split into the entries before the last and the last one,
+/// a single `script` (or the `file_path` one) has no steps
+fn as_script(doc: &Yaml, from_file: bool) -> Result<(Vec<String>, Option<String>), ConfigError> {
+    if from_file {
+        return Ok((Vec::new(), doc["file_path"].as_str().map(|t| t.to_string())))
+    }
+    let Yaml::Array(items) = &doc["script"] else {
+        return Ok((Vec::new(), doc["script"].as_str().map(|t| t.to_string())))
+    };
+    let invalid = |msg: String| ConfigError::InvalidValue("script".to_string(), msg);
+    let mut scripts = items.iter().map(|s| s.as_str().map(|t| t.to_string()).ok_or_else(|| invalid(format!("`{:?}` is not a script", s))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let script = scripts.pop().ok_or_else(|| invalid("the list is empty".to_string()))?;
+    Ok((scripts, Some(script)))
 }
 
-fn ld_script_file(cfg_path: &String, script_path: &String) -> Result<String, ConfigError> {
-    let path = resolve_other_path(Path::new(cfg_path.as_str()), Path::new(script_path.as_str()));
-    Ok(conv_err!(fs::read_to_string(path), ConfigError::ScriptFileNotFound)?)
+fn ld_yaml_docs(content: &str) -> Result<Vec<Yaml>, ConfigError> {
+    conv_err_e!(YamlLoader::load_from_str(content), ConfigError::ParsingFailed)
+}
+
+/// TOML configs are converted into the same `Yaml` tree, so both formats share one code path from here on
+fn toml_to_yaml(value: toml::Value) -> Yaml {
+    match value {
+        toml::Value::String(s) => Yaml::String(s),
+        toml::Value::Integer(i) => Yaml::Integer(i),
+        toml::Value::Float(f) => Yaml::Real(f.to_string()),
+        toml::Value::Boolean(b) => Yaml::Boolean(b),
+        toml::Value::Datetime(d) => Yaml::String(d.to_string()),
+        toml::Value::Array(a) => Yaml::Array(a.into_iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(t) => Yaml::Hash(t.into_iter().map(|(k, v)| (Yaml::String(k), toml_to_yaml(v))).collect())
+    }
+}
+
+fn ld_toml_doc(content: &str) -> Result<Yaml, ConfigError> {
+    let table = conv_err_e!(content.parse::<toml::Table>(), ConfigError::ParsingFailed)?;
+    Ok(toml_to_yaml(toml::Value::Table(table)))
+}
+
+/// Shell scripts, which do their own `${VAR}` expansion when they run
+const SCRIPT_KEYS: [&str; 8] = ["script", "bootstrap", "cleanup", "pre_update", "post_update", "smoke_test", "verify_script", "lfs_command"];
+
+/// Expands `${VAR}` and `${VAR:-default}` (used if `VAR` is unset or empty) in `value`. `$${` is a literal `${`
+fn expand_vars(value: &str, key: &str) -> Result<String, ConfigError> {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue
+        }
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find('}')
+            .ok_or_else(|| ConfigError::InvalidValue(key.to_string(), format!("unterminated `${{` in `{}`", value)))?;
+        let reference = &rest[start + 2..start + end];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None)
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(ConfigError::InvalidValue(key.to_string(), format!("`${{{}}}` is not a variable reference", reference)))
+        }
+        match (std::env::var(name).ok().filter(|v| !v.is_empty() || default.is_none()), default) {
+            (Some(v), _) => out.push_str(&v),
+            (None, Some(default)) => out.push_str(default),
+            (None, None) => return Err(ConfigError::UndefinedVariable(key.to_string(), name.to_string()))
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Expands environment variables in every string value below `doc`, except for scripts
+fn interpolate(doc: &mut Yaml, key: &str) -> Result<(), ConfigError> {
+    match doc {
+        Yaml::String(value) => *value = expand_vars(value, key)?,
+        Yaml::Array(items) => items.iter_mut().try_for_each(|item| interpolate(item, key))?,
+        Yaml::Hash(hash) => for (k, v) in hash.iter_mut() {
+            let name = k.as_str().map(|k| k.to_string()).unwrap_or_else(|| format!("{:?}", k));
+            if SCRIPT_KEYS.contains(&name.as_str()) {
+                continue
+            }
+            interpolate(v, &if key.is_empty() {name} else {format!("{}.{}", key, name)})?;
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Merges `over` into `base`: mappings key by key, recursively, anything else (lists included) is replaced
+fn merge_docs(base: &mut Yaml, over: Yaml) {
+    match (base, over) {
+        (Yaml::Hash(base), Yaml::Hash(over)) => for (key, value) in over {
+            match base.get_mut(&key) {
+                Some(existing) => merge_docs(existing, value),
+                None => { base.insert(key, value); }
+            }
+        },
+        (base, over) => *base = over
+    }
+}
+
+/// Loads the config document of every file in `paths`, picking the format by file extension, merges them in order
+/// so later files override single keys of earlier ones, and expands the environment variables they reference.
+/// Unknown extensions are tried as YAML first and as TOML if that doesn't yield a mapping.
+fn ld_config_doc(paths: &[String]) -> Result<Yaml, ConfigError> {
+    let mut merged = Yaml::Hash(Hash::new());
+    for path in paths {
+        let doc = ld_config_doc_root(path).map_err(|e| match e {
+            ConfigError::ParsingFailed(msg) if paths.len() > 1 => ConfigError::ParsingFailed(format!("`{}`: {}", path, msg)),
+            e => e
+        })?;
+        merge_docs(&mut merged, doc);
+    }
+    interpolate(&mut merged, "")?;
+    Ok(merged)
+}
+
+/// The document of `path`, which has to be a mapping
+fn ld_config_doc_root(path: &str) -> Result<Yaml, ConfigError> {
+    let doc = ld_config_doc_raw(path)?;
+    let root = match &doc {
+        Yaml::Hash(_) => None,
+        Yaml::Array(_) => Some("a list".to_string()),
+        Yaml::Null => Some("an empty document".to_string()),
+        Yaml::String(s) | Yaml::Real(s) => Some(format!("the value `{}`", s)),
+        Yaml::Integer(i) => Some(format!("the value `{}`", i)),
+        Yaml::Boolean(b) => Some(format!("the value `{}`", b)),
+        other => Some(format!("`{:?}`", other))
+    };
+    if let Some(root) = root {
+        return Err(ConfigError::ParsingFailed(format!("expected a mapping of settings like `name: ...`, the file holds {}", root)))
+    }
+    Ok(doc)
+}
+
+/// The file name of a config path that reads standard input instead. `-` resolves relative paths in the config
+/// against the working directory, `<dir>/-` against `dir`
+pub const STDIN_PATH: &str = "-";
+
+static STDIN: OnceLock<Result<String, String>> = OnceLock::new();
+
+pub fn is_stdin(path: &str) -> bool {
+    Path::new(path).file_name().is_some_and(|name| name == STDIN_PATH)
+}
+
+/// Standard input is read once and kept, since some paths load the config more than once
+fn read_config(path: &str) -> Result<String, ConfigError> {
+    if !is_stdin(path) {
+        return conv_err!(fs::read_to_string(path), ConfigError::ConfigFileNotFound)
+    }
+    STDIN.get_or_init(|| io::read_to_string(io::stdin()).map_err(|e| e.to_string())).clone()
+        .map_err(|e| ConfigError::ParsingFailed(format!("could not read the config from stdin: {}", e)))
+}
+
+fn ld_config_doc_raw(path: &str) -> Result<Yaml, ConfigError> {
+    let content = read_config(path)?;
+    if content.trim().is_empty() {
+        return Err(ConfigError::ParsingFailed(if is_stdin(path) {"stdin is empty"} else {"the file is empty"}.to_string()))
+    }
+    match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("toml") => ld_toml_doc(&content),
+        Some("yaml") | Some("yml") => join_yaml_docs(ld_yaml_docs(&content)?),
+        _ => {
+            match ld_yaml_docs(&content) {
+                Ok(docs) if docs.first().is_some_and(|d| d.as_hash().is_some()) => join_yaml_docs(docs),
+                _ => ld_toml_doc(&content)
+            }
+        }
+    }
+}
+
+/// A single document is the config itself, several `---` separated ones are a deployment each
+fn join_yaml_docs(mut docs: Vec<Yaml>) -> Result<Yaml, ConfigError> {
+    match docs.len() {
+        0 => Err(ConfigError::ParsingFailed("the file contains no YAML document".to_string())),
+        1 => Ok(docs.remove(0)),
+        _ if docs.iter().any(|d| !d["deployments"].is_badvalue()) =>
+            Err(ConfigError::InvalidValue("deployments".to_string(), "cannot be combined with `---` separated deployments".to_string())),
+        _ => Ok(Yaml::Hash(Hash::from_iter([(Yaml::String("deployments".to_string()), Yaml::Array(docs))])))
+    }
+}
+
+fn ld_script_file(cfg_path: &str, script_path: &str) -> Result<String, ConfigError> {
+    let path = resolve_other_path(Path::new(cfg_path), Path::new(script_path));
+    conv_err!(fs::read_to_string(&path), ConfigError::ScriptFileNotFound(path.to_string_lossy().to_string()))
+}
+
+fn emit(doc: &Yaml) -> Result<String, ConfigError> {
+    let mut out = String::new();
+    let mut emitter = YamlEmitter::new(&mut out);
+    emitter.multiline_strings(true);
+    conv_err_e!(emitter.dump(doc), ConfigError::EmitFailed)?;
+    out.push('\n');
+    Ok(out)
+}
+
+/// A file with a `deployments` list of `entry` of each deployment, with the process wide settings next to the list
+fn deployments_doc(deployments: &[Config], entry: fn(&Config) -> Yaml) -> Yaml {
+    let mut doc = Hash::new();
+    let mut entries = Vec::new();
+    for deployment in deployments {
+        let Yaml::Hash(mut entry) = entry(deployment) else { unreachable!() };
+        for key in PROCESS_WIDE_KEYS.map(|k| Yaml::String(k.to_string())) {
+            if let Some(value) = entry.remove(&key) {
+                doc.insert(key, value);
+            }
+        }
+        entries.push(Yaml::Hash(entry));
+    }
+    doc.insert(Yaml::String("deployments".to_string()), Yaml::Array(entries));
+    Yaml::Hash(doc)
+}
+
+/// The values a config can hold: hashes with string keys, arrays and scalars
+pub(crate) fn yaml_to_json(doc: &Yaml) -> serde_json::Value {
+    use serde_json::Value;
+    match doc {
+        Yaml::Hash(hash) => Value::Object(hash.iter().map(|(k, v)| {
+            let key = k.as_str().map(str::to_string).unwrap_or_else(|| yaml_to_json(k).to_string());
+            (key, yaml_to_json(v))
+        }).collect()),
+        Yaml::Array(values) => Value::Array(values.iter().map(yaml_to_json).collect()),
+        Yaml::String(value) => Value::String(value.clone()),
+        Yaml::Integer(value) => Value::from(*value),
+        Yaml::Real(value) => value.parse::<f64>().map_or(Value::Null, Value::from),
+        Yaml::Boolean(value) => Value::Bool(*value),
+        _ => Value::Null
+    }
+}
+
+fn duplicate_name(name: &str) -> ConfigError {
+    ConfigError::InvalidValue("name".to_string(), format!("`{}` is used by more than one deployment", name))
+}
+
+/// The documents of the entries of a `deployments` list, each merged over the shared keys next to it.
+/// None if the file describes a single deployment
+fn deployment_docs(doc: &Yaml) -> Result<Option<Vec<Yaml>>, ConfigError> {
+    let entries = match &doc["deployments"] {
+        Yaml::BadValue => return Ok(None),
+        Yaml::Array(entries) if !entries.is_empty() => entries,
+        other => return Err(ConfigError::InvalidValue("deployments".to_string(), format!("expected a non-empty list, got `{:?}`", other)))
+    };
+    let mut shared = doc.as_hash().cloned().unwrap_or_default();
+    shared.remove(&Yaml::String("deployments".to_string()));
+
+    entries.iter().map(|entry| {
+        let Some(entry) = entry.as_hash() else {
+            return Err(ConfigError::InvalidValue("deployments".to_string(), format!("`{:?}` is not a mapping", entry)))
+        };
+        if let Some(key) = PROCESS_WIDE_KEYS.iter().find(|k| entry.get(&Yaml::String(k.to_string())).is_some()) {
+            return Err(ConfigError::InvalidValue(key.to_string(), "applies to all deployments, set it next to `deployments`".to_string()))
+        }
+        let mut merged = shared.clone();
+        merged.extend(entry.iter().map(|(k, v)| (k.clone(), v.clone())));
+        Ok(Yaml::Hash(merged))
+    }).collect::<Result<_, _>>().map(Some)
+}
+
+/// Every problem with the document of a single deployment. Missing keys, script files and the repo settings are
+/// checked directly, invalid values by running `from_yaml` again without each key it rejected
+fn check_doc(doc: &Yaml, path: &str) -> Vec<ConfigError> {
+    let key = |k: &str| Yaml::String(k.to_string());
+    let mut problems = Vec::new();
+    let mut patched = doc.as_hash().cloned().unwrap_or_default();
+
+    let script_key = if doc["script_use_file"].as_bool() == Some(true) {"file_path"} else {"script"};
+    let no_update = doc["no_update"].as_bool() == Some(true);
+    for mandatory in ["name", script_key, "repo"] {
+        if (mandatory == script_key && doc["mode"].as_str() == Some("mirror")) || (mandatory == "repo" && no_update) {
+            continue
+        }
+        if doc[mandatory].as_str().is_none() && !(mandatory == "script" && doc[mandatory].is_array()) {
+            problems.push(ConfigError::MissingContent(mandatory.to_string()));
+        }
+    }
+    for (flag, file_key) in [("script_use_file", "file_path"), ("cleanup_use_file", "cleanup_file_path")] {
+        if doc[flag].as_bool() != Some(true) {
+            continue
+        }
+        match doc[file_key].as_str() {
+            Some(file) => {
+                let resolved = resolve_other_path(Path::new(path), Path::new(file));
+                if !resolved.is_file() {
+                    problems.push(ConfigError::ScriptFileNotFound(resolved.to_string_lossy().to_string()));
+                }
+            }
+            None if flag == "cleanup_use_file" => problems.push(ConfigError::MissingContent(file_key.to_string())),
+            None => {}
+        }
+        patched.insert(key(flag), Yaml::Boolean(false));
+    }
+    if doc["local_repo"].as_bool() == Some(true) {
+        if let Some(repo) = doc["repo"].as_str().filter(|r| !is_url(r)) {
+            if !Path::new(repo).is_dir() {
+                problems.push(ConfigError::InvalidValue("repo".to_string(), format!("local repo `{}` does not exist", repo)));
+            }
+        }
+    }
+
+    if let (Ok(shell), Ok(runner)) = (as_shell(doc), as_runner(doc)) {
+        problems.extend(check_runner(shell.as_ref(), &runner).err());
+    }
+    problems.extend(check_run_as(as_run_as(doc).as_deref()).err());
+
+    // Everything checked above is patched over, so what is left are the values `from_yaml` can't parse
+    for placeholder in ["name", "script", "repo"] {
+        if placeholder == "repo" && no_update {
+            continue
+        }
+        if doc[placeholder].as_str().is_none() && !(placeholder == "script" && doc[placeholder].is_array()) {
+            patched.insert(key(placeholder), key("-"));
+        }
+    }
+    while let Err(e) = Config::from_yaml(&Yaml::Hash(patched.clone()), path) {
+        let rejected = match &e {
+            ConfigError::InvalidValue(k, _) => k.split('.').next().map(key),
+            ConfigError::UnknownKey(k) => Some(key(k)),
+            _ => None
+        };
+        problems.push(e);
+        match rejected {
+            // Mandatory, so a rejected `script` list or `repo` is patched over instead of reported missing as well
+            Some(k) if k == key("script") || k == key("repo") => { patched.insert(k, key("-")); }
+            Some(k) if patched.remove(&k).is_some() => {}
+            _ => break
+        }
+    }
+    problems
+}
+
+/// The optional settings `gdep init` writes out commented, each with an example value
+const STARTER_OPTIONS: &str = "
+# Follow this branch instead of origin's default one
+# branch: main
+# How long to wait between looking for updates
+# poll_interval: 30s
+# Restart the script when an update was applied
+# restart_update: true
+# Don't start the script again once it exited
+# final: true
+# Environment variables of the script
+# env:
+#   APP_ENV: production
+# Runs after the script was stopped
+# cleanup: echo stopped
+# Where the script runs, relative to the checkout
+# working_dir: backend
+# Serves GET /healthz on this address
+# health_addr: 127.0.0.1:8080
+# Serves GET /metrics in the Prometheus text format on this address
+# metrics_addr: 127.0.0.1:9100
+# Asks for these credentials on /healthz, `token: ...` asks for a bearer token instead
+# health_auth:
+#   username: ops
+#   password: changeme
+";
+
+/// Settings that apply to the whole gdep process, so every deployment has to agree on them
+pub(crate) const PROCESS_WIDE_KEYS: [&str; 11] = ["ca_bundle", "proxy", "ssh_key", "ssh_key_passphrase", "ssh_command", "http_username", "http_token",
+    "fetch_timeout", "log_file", "log_max_size", "log_keep"];
+
+/// Relative paths in configs merged from several files are resolved against the first one
+fn base_path(paths: &[String]) -> &str {
+    paths.first().map_or("", String::as_str)
 }
 
 impl Config {
-    pub fn load_from_file(path: &String) -> Result<Self, ConfigError> {
-        let doc = &ld_yaml_docs(path)?[0];
+    /// Loads the config from `paths`, merged in order (see `ld_config_doc`)
+    pub fn load_from_files(paths: &[String]) -> Result<Self, ConfigError> {
+        Self::from_yaml(&ld_config_doc(paths)?, base_path(paths))
+    }
+
+    /// Loads config files that list several deployments under `deployments`, None if they describe a single one.
+    /// Keys next to the list are shared defaults every entry inherits unless it sets them itself
+    pub fn load_deployments(paths: &[String]) -> Result<Option<Vec<Self>>, ConfigError> {
+        let path = base_path(paths);
+        let Some(docs) = deployment_docs(&ld_config_doc(paths)?)? else {
+            return Ok(None)
+        };
+        let mut deployments: Vec<Self> = Vec::new();
+        for doc in docs {
+            let config = Self::from_yaml(&doc, path)?;
+            if deployments.iter().any(|d| d.name == config.name) {
+                return Err(duplicate_name(&config.name))
+            }
+            deployments.push(config);
+        }
+        Ok(Some(deployments))
+    }
+
+    /// Like loading the file, but collects every problem instead of stopping at the first one.
+    /// Problems of a `deployments` entry are prefixed with its name
+    pub fn check_files(paths: &[String]) -> Result<Vec<Self>, Vec<String>> {
+        let path = base_path(paths);
+        let doc = ld_config_doc(paths).map_err(|e| vec![e.to_string()])?;
+        let Some(docs) = deployment_docs(&doc).map_err(|e| vec![e.to_string()])? else {
+            let problems = check_doc(&doc, path);
+            if !problems.is_empty() {
+                return Err(problems.iter().map(|p| p.to_string()).collect())
+            }
+            return Self::from_yaml(&doc, path).map(|c| vec![c]).map_err(|e| vec![e.to_string()])
+        };
+
+        let mut problems = Vec::new();
+        let mut names: Vec<&str> = Vec::new();
+        for (i, doc) in docs.iter().enumerate() {
+            let label = doc["name"].as_str().map(|n| format!("`{}`", n)).unwrap_or_else(|| format!("#{}", i + 1));
+            if let Some(name) = doc["name"].as_str() {
+                if names.contains(&name) {
+                    problems.push(format!("Deployment {}: {}", label, duplicate_name(name)));
+                }
+                names.push(name);
+            }
+            problems.extend(check_doc(doc, path).iter().map(|p| format!("Deployment {}: {}", label, p)));
+        }
+        if !problems.is_empty() {
+            return Err(problems)
+        }
+        docs.iter().map(|d| Self::from_yaml(d, path)).collect::<Result<_, _>>().map_err(|e| vec![e.to_string()])
+    }
+
+    /// Builds the config from a parsed document. `path` is used to resolve script files
+    pub fn from_yaml(doc: &Yaml, path: &str) -> Result<Self, ConfigError> {
+        if let Some(unknown) = doc.as_hash().and_then(|hash| hash.keys().find(|k| !k.as_str().is_some_and(crate::schema::is_key))) {
+            return Err(ConfigError::UnknownKey(unknown.as_str().map(str::to_string).unwrap_or_else(|| format!("{:?}", unknown))))
+        }
         let name = &doc["name"].as_str();
         let run_is_final = doc["final"].as_bool().is_some_and(|t| {t});
         let inst_file1 = doc["script_use_file"].as_bool().is_some_and(|t| {t});
         let inst_file2 = doc["cleanup_use_file"].as_bool().is_some_and(|t| {t});
         let restart_after_update = doc["restart_update"].as_bool().is_some_and(|t| {t});
+        let watch_paths = as_watch_paths(doc)?;
+        let on_change = as_on_change(doc)?;
         let exit_on_gdep_error = !doc["gdep_err_ignore"].as_bool().is_some_and(|t| {t});
         let exit_on_script_error = !doc["script_err_ignore"].as_bool().is_some_and(|t| {t});
-        let script = &doc[if inst_file1 {"file_path"} else {"script"}].as_str();
+        let bootstrap = doc["bootstrap"].as_str().map(|t| t.to_string());
+        let (steps, script) = as_script(doc, inst_file1)?;
         let cleanup = &doc[if inst_file2 {"cleanup_file_path"} else {"cleanup"}].as_str();
         let local_repo = doc["local_repo"].as_bool().is_some_and(|t| {t});
         let repo = &doc["repo"].as_str();
         let into_path = &doc["into_path"].as_str();
+        let max_output_rate = as_u64(&doc["max_output_rate"]);
+        let shutdown_grace = as_u64(&doc["shutdown_grace"]).unwrap_or(5);
+        let max_restarts = as_u64(&doc["max_restarts"]);
+        let restart_delay = as_duration(doc, "restart_delay")?.unwrap_or(0);
+        let restart_jitter = as_duration(doc, "restart_jitter")?.unwrap_or(0);
+        let min_restart_interval = as_duration(doc, "min_restart_interval")?.unwrap_or(0);
+        let expect_branch = doc["expect_branch"].as_str().map(|t| t.to_string());
+        let error_context_lines = as_u64(&doc["error_context_lines"]).map(|t| t as usize);
+        let commit = doc["commit"].as_str().map(|t| t.to_string());
+        let deploy_schedule = DeploySchedule::from_yaml(doc)?;
+        let mode = match doc["mode"].as_str() {
+            None | Some("deploy") => Mode::Deploy,
+            Some("mirror") => Mode::Mirror,
+            Some(other) => return Err(ConfigError::InvalidValue("mode".to_string(), format!("expected `deploy` or `mirror`, got `{}`", other)))
+        };
+        let track = match doc["track"].as_str() {
+            None | Some("branch") => Track::Branch,
+            Some("tag") => Track::Tag,
+            Some(other) => return Err(ConfigError::InvalidValue("track".to_string(), format!("expected `branch` or `tag`, got `{}`", other)))
+        };
+        let tag_pattern = doc["tag_pattern"].as_str().map(|t| t.to_string());
+        let pre_update = doc["pre_update"].as_str().map(|t| t.to_string());
+        let post_update = doc["post_update"].as_str().map(|t| t.to_string());
+        let require_annotated_tags = doc["require_annotated_tags"].as_bool().is_some_and(|t| {t});
+        let verify_tag_signatures = doc["verify_tag_signatures"].as_bool().is_some_and(|t| {t});
+        let max_commit_age = as_duration(doc, "max_commit_age")?;
+        let max_behind = as_u64(&doc["max_behind"]).map(|t| t as usize);
+        let require_signed = doc["require_signed"].as_bool().is_some_and(|t| {t});
+        let allowed_signers = as_fingerprints(doc)?;
+        let allowed_authors = as_globs(doc, "allowed_authors")?;
+        let env = as_env_map(doc)?;
+        let args = as_args(doc)?;
+        let shell = as_shell(doc)?;
+        let runner = as_runner(doc)?;
+        let run_as = as_run_as(doc);
+        let working_dir = doc["working_dir"].as_str().map(|t| t.to_string());
+        let script_timeout = as_duration(doc, "script_timeout")?;
+        let force = doc["force"].as_bool().is_some_and(|t| {t});
+        let stall_timeout = as_duration(doc, "stall_timeout")?;
+        let stall_retries = as_u64(&doc["stall_retries"]).unwrap_or(3);
+        let clone_retries = as_u64(&doc["clone_retries"]).unwrap_or(DEFAULT_CLONE_RETRIES);
+        let smoke_test = doc["smoke_test"].as_str().map(|t| t.to_string());
+        let smoke_timeout = as_duration(doc, "smoke_timeout")?.unwrap_or(30);
+        let verify_script = doc["verify_script"].as_str().map(|t| t.to_string());
+        let verify_timeout = as_duration(doc, "verify_timeout")?.unwrap_or(DEFAULT_VERIFY_TIMEOUT);
+        let rollback_on_smoke_fail = doc["rollback_on_smoke_fail"].as_bool().is_some_and(|t| {t});
+        let ready_check = doc["ready_check"].as_str().map(|t| t.to_string());
+        let ready_timeout = as_duration(doc, "ready_timeout")?.unwrap_or(DEFAULT_READY_TIMEOUT);
+        let graceful_restart = doc["graceful_restart"].as_bool().is_some_and(|t| {t});
+        if graceful_restart && ready_check.is_none() {
+            return Err(ConfigError::InvalidValue("graceful_restart".to_string(), "needs a `ready_check` to tell when the new script is ready".to_string()))
+        }
+        let rollback_on_failure = doc["rollback_on_failure"].as_bool().is_some_and(|t| {t});
+        let depth = match as_u64(&doc["depth"]) {
+            Some(depth) if depth > 0 && depth <= i32::MAX as u64 => Some(depth as u32),
+            Some(depth) => return Err(ConfigError::InvalidValue("depth".to_string(), format!("`{}` is not a positive commit count", depth))),
+            None => None
+        };
+        let submodules = doc["submodules"].as_bool().is_some_and(|t| {t});
+        let lfs = doc["lfs"].as_bool().is_some_and(|t| {t});
+        let lfs_command = doc["lfs_command"].as_str().map(|t| t.to_string());
+        let deploy_mode = match doc["deploy_mode"].as_str() {
+            None | Some("in_place") => DeployMode::InPlace,
+            Some("symlink") => DeployMode::Symlink,
+            Some(other) => return Err(ConfigError::InvalidValue("deploy_mode".to_string(), format!("expected `in_place` or `symlink`, got `{}`", other)))
+        };
+        let releases_dir = doc["releases_dir"].as_str()
+            .map(|t| resolve_other_path(Path::new(path), Path::new(t)).to_string_lossy().to_string());
+        let current_link = doc["current_link"].as_str()
+            .map(|t| resolve_other_path(Path::new(path), Path::new(t)).to_string_lossy().to_string());
+        let keep_releases = as_u64(&doc["keep_releases"]).map(|t| t as usize);
+        let max_fetch_retries = as_u64(&doc["max_fetch_retries"]).unwrap_or(5);
+        let update_retries = as_u64(&doc["update_retries"]).unwrap_or(DEFAULT_UPDATE_RETRIES);
+        let fetch_timeout = as_duration(doc, "fetch_timeout")?;
+        let debounce = as_duration(doc, "debounce")?.filter(|d| *d > 0);
+        let log_file = doc["log_file"].as_str()
+            .map(|t| resolve_other_path(Path::new(path), Path::new(t)).to_string_lossy().to_string());
+        let log_max_size = as_size(doc, "log_max_size")?.unwrap_or(DEFAULT_LOG_MAX_SIZE);
+        let branch = doc["branch"].as_str().map(|t| t.to_string());
+        let include_branches = as_globs(doc, "include_branches")?;
+        let exclude_branches = as_globs(doc, "exclude_branches")?;
+        let branch_selector = match doc["branch_selector"].as_str() {
+            None | Some("default") => BranchSelector::Default,
+            Some("newest") => BranchSelector::Newest,
+            Some(other) => return Err(ConfigError::InvalidValue("branch_selector".to_string(), format!("expected `default` or `newest`, got `{}`", other)))
+        };
+        if branch_selector == BranchSelector::Newest {
+            let fixed = [("branch", branch.is_some()), ("commit", commit.is_some()), ("track", track == Track::Tag), ("mode", mode == Mode::Mirror)];
+            if let Some((key, _)) = fixed.into_iter().find(|(_, set)| *set) {
+                return Err(ConfigError::InvalidValue("branch_selector".to_string(), format!("`newest` picks the branch itself, it cannot be combined with `{}`", key)))
+            }
+        }
+        let critical = doc["critical"].as_bool().is_some_and(|t| {t});
+        let poll_interval = as_duration(doc, "poll_interval")?.unwrap_or(0);
+        let webhook = WebhookConfig::from_yaml(doc)?;
+        let health_addr = doc["health_addr"].as_str().map(|t| t.to_string());
+        let metrics_addr = doc["metrics_addr"].as_str().map(|t| t.to_string());
+        let health_auth = HttpAuth::from_yaml(doc, "health_auth")?;
+        let metrics_auth = HttpAuth::from_yaml(doc, "metrics_auth")?;
+        for (key, auth, addr) in [("health_auth", &health_auth, "health_addr"), ("metrics_auth", &metrics_auth, "metrics_addr")] {
+            if auth.is_some() && doc[addr].as_str().is_none() {
+                return Err(ConfigError::InvalidValue(key.to_string(), format!("only applies to a server, but `{}` is not set", addr)))
+            }
+        }
+        let control_socket = doc["control_socket"].as_str()
+            .map(|t| resolve_other_path(Path::new(path), Path::new(t)).to_string_lossy().to_string());
+        let notifier = NotifierConfig::from_yaml(doc)?;
+        let watch_config = doc["watch_config"].as_bool().is_some_and(|t| {t});
+        let default_repo_path = doc["default_repo_path"].as_str().map(|t| t.to_string());
+        let stdout_file = doc["stdout_file"].as_str()
+            .map(|t| resolve_other_path(Path::new(path), Path::new(t)).to_string_lossy().to_string());
+        let stderr_file = doc["stderr_file"].as_str()
+            .map(|t| resolve_other_path(Path::new(path), Path::new(t)).to_string_lossy().to_string());
+        let prefix_output = doc["prefix_output"].as_bool().is_some_and(|t| {t});
+        let log_keep = as_u64(&doc["log_keep"]).map(|t| t as usize).unwrap_or(DEFAULT_LOG_KEEP);
+        let conflict_strategy = match doc["conflict_strategy"].as_str() {
+            None => None,
+            Some("abort") => Some(ConflictStrategy::Abort),
+            Some("ours") => Some(ConflictStrategy::Ours),
+            Some("theirs") => Some(ConflictStrategy::Theirs),
+            Some(other) => return Err(ConfigError::InvalidValue("conflict_strategy".to_string(), format!("expected `abort`, `ours` or `theirs`, got `{}`", other)))
+        };
+        let checkout_mode = match doc["checkout_mode"].as_str() {
+            None | Some("force") => CheckoutMode::Force,
+            Some("safe") => CheckoutMode::Safe,
+            Some("merge") => CheckoutMode::Merge,
+            Some(other) => return Err(ConfigError::InvalidValue("checkout_mode".to_string(), format!("expected `force`, `safe` or `merge`, got `{}`", other)))
+        };
+        let on_ahead = match doc["on_ahead"].as_str() {
+            None | Some("error") => OnAhead::Error,
+            Some("ignore") => OnAhead::Ignore,
+            Some("push") => OnAhead::Push,
+            Some(other) => return Err(ConfigError::InvalidValue("on_ahead".to_string(), format!("expected `error`, `ignore` or `push`, got `{}`", other)))
+        };
+        let proxy = doc["proxy"].as_str().map(|t| t.to_string());
+        if let Some(proxy) = &proxy {
+            crate::proxy::validate(proxy)?;
+        }
+        let stash_local_changes = doc["stash_local_changes"].as_bool().is_some_and(|t| {t});
+        let fail_on_dirty = doc["fail_on_dirty"].as_bool().is_some_and(|t| {t});
+        let clean_untracked = doc["clean_untracked"].as_bool().is_some_and(|t| {t});
+        let clean_ignored = doc["clean_ignored"].as_bool().is_some_and(|t| {t});
+        let restore_stash = doc["restore_stash"].as_bool().unwrap_or(true);
+        let merge_message = doc["merge_message"].as_str().unwrap_or(DEFAULT_MERGE_MESSAGE).to_string();
+        let refspec = as_refspec(doc)?;
+        let prune = doc["prune"].as_bool().is_some_and(|t| {t});
+        let fetch_tags = match doc["fetch_tags"].as_str() {
+            None | Some("all") => FetchTags::All,
+            Some("auto") => FetchTags::Auto,
+            Some("none") => FetchTags::None,
+            Some(other) => return Err(ConfigError::InvalidValue("fetch_tags".to_string(), format!("expected `all`, `auto` or `none`, got `{}`", other)))
+        };
+        if track == Track::Tag && fetch_tags != FetchTags::All {
+            return Err(ConfigError::InvalidValue("fetch_tags".to_string(), "`track: tag` needs every tag of origin, use `all`".to_string()))
+        }
+        let committer_name = doc["committer_name"].as_str().map(|t| t.to_string());
+        let committer_email = doc["committer_email"].as_str().map(|t| t.to_string());
+        let ca_bundle = doc["ca_bundle"].as_str()
+            .map(|t| resolve_other_path(Path::new(path), Path::new(t)).to_string_lossy().to_string());
+        let ssh_key = doc["ssh_key"].as_str()
+            .map(|t| resolve_other_path(Path::new(path), Path::new(t)).to_string_lossy().to_string());
+        let ssh_key_passphrase = doc["ssh_key_passphrase"].as_str().map(|t| t.to_string());
+        let ssh_command = doc["ssh_command"].as_str().map(|t| t.to_string());
+        let ssh = match &ssh_command {
+            Some(command) => Some(SshCommand::parse(command).map_err(|e| ConfigError::InvalidValue("ssh_command".to_string(), e))?),
+            None => None
+        };
+        if ssh.as_ref().is_some_and(|s| s.identity.is_some()) && ssh_key.is_some() {
+            return Err(ConfigError::InvalidValue("ssh_command".to_string(), "sets a key with `-i`, but so does `ssh_key`".to_string()))
+        }
+        let http_username = doc["http_username"].as_str().map(|t| t.to_string());
+        let http_token = doc["http_token"].as_str().map(|t| t.to_string());
+        if let Err(e) = TagFilter::parse(tag_pattern.as_deref()) {
+            return Err(ConfigError::InvalidValue("tag_pattern".to_string(), e))
+        }
         
         if name.is_none() {
             return Err(ConfigError::MissingContent("name".to_string()))
         }
 
-        if script.is_none() {
+        if mode == Mode::Mirror {
+            if commit.is_some() || track == Track::Tag {
+                return Err(ConfigError::InvalidValue("mode".to_string(), "a mirror follows every ref, `commit` and `track: tag` don't apply".to_string()))
+            }
+        } else if script.is_none() {
             return Err(ConfigError::MissingContent("script".to_string()))
         }
         
+        let no_update = doc["no_update"].as_bool().is_some_and(|t| {t});
+        if no_update {
+            let fixed = [("mode", mode == Mode::Mirror), ("commit", commit.is_some()), ("track", track == Track::Tag),
+                ("branch_selector", branch_selector == BranchSelector::Newest)];
+            if let Some((key, _)) = fixed.into_iter().find(|(_, set)| *set) {
+                // Reported on the other key, so `--check` doesn't go on to miss `repo` once `no_update` is dropped
+                return Err(ConfigError::InvalidValue(key.to_string(), "cannot be combined with `no_update`, which leaves the checkout alone".to_string()))
+            }
+        }
+        let restore_on_start = doc["restore_on_start"].as_bool().is_some_and(|t| {t});
+        if restore_on_start {
+            let fixed = [("mode", mode == Mode::Mirror), ("commit", commit.is_some()), ("track", track == Track::Tag), ("no_update", no_update)];
+            if let Some((key, _)) = fixed.into_iter().find(|(_, set)| *set) {
+                return Err(ConfigError::InvalidValue("restore_on_start".to_string(), format!("only applies when following a branch, it cannot be combined with `{}`", key)))
+            }
+        }
+        if lfs_command.is_some() && !lfs {
+            return Err(ConfigError::InvalidValue("lfs_command".to_string(), "only applies with `lfs`, which is not set".to_string()))
+        }
+        if lfs && mode == Mode::Mirror {
+            return Err(ConfigError::InvalidValue("lfs".to_string(), "a mirror has no working tree to fetch LFS files into".to_string()))
+        }
+        let lfs_command = lfs_command.unwrap_or_else(|| DEFAULT_LFS_COMMAND.to_string());
+        if deploy_mode == DeployMode::Symlink {
+            let fixed = [("mode", mode == Mode::Mirror), ("no_update", no_update)];
+            if let Some((key, _)) = fixed.into_iter().find(|(_, set)| *set) {
+                return Err(ConfigError::InvalidValue("deploy_mode".to_string(), format!("`symlink` checks out every update, it cannot be combined with `{}`", key)))
+            }
+            for (key, set) in [("releases_dir", releases_dir.is_some()), ("current_link", current_link.is_some())] {
+                if !set {
+                    return Err(ConfigError::InvalidValue("deploy_mode".to_string(), format!("`symlink` needs `{}`", key)))
+                }
+            }
+            if keep_releases == Some(0) {
+                return Err(ConfigError::InvalidValue("keep_releases".to_string(), "the live release is always kept, use at least 1".to_string()))
+            }
+        } else if let Some(key) = [("releases_dir", releases_dir.is_some()), ("current_link", current_link.is_some()), ("keep_releases", keep_releases.is_some())]
+            .into_iter().find_map(|(key, set)| set.then_some(key)) {
+            return Err(ConfigError::InvalidValue(key.to_string(), "only applies with `deploy_mode: symlink`".to_string()))
+        }
+        // Without updates, the script can simply run in the working directory
+        let (repo, local_repo) = match repo {
+            None if no_update => (Some("."), true),
+            repo => (*repo, local_repo)
+        };
         if repo.is_none() {
             return Err(ConfigError::MissingContent("repo".to_string()))
         }
+        if local_repo {
+            if into_path.is_some() {
+                return Err(ConfigError::InvalidValue("into_path".to_string(), "only applies to remote repos, but `local_repo` is set".to_string()))
+            }
+            if is_url(repo.unwrap()) {
+                return Err(ConfigError::InvalidValue("repo".to_string(), format!("`{}` is a URL, but `local_repo` is set", repo.unwrap())))
+            }
+        }
+        // Relative to the config file, like script files
+        let into_path = into_path.map(|t| resolve_other_path(Path::new(path), Path::new(t)).to_string_lossy().to_string());
         
         let repo = if local_repo {RepoLike::Local(repo.unwrap().to_string())} 
                             else {
-                                if into_path.is_none() {
-                                    RepoLike::Remote(repo.unwrap().to_string())
-                                } else {
-                                    RepoLike::Remote2(repo.unwrap().to_string(), into_path.unwrap().to_string())
+                                match into_path {
+                                    None => RepoLike::Remote(repo.unwrap().to_string()),
+                                    Some(into_path) => RepoLike::Remote2(repo.unwrap().to_string(), into_path)
                                 }
                             };
+        if let (Some(_), RepoLike::Remote(url) | RepoLike::Remote2(url, _)) = (ssh.as_ref().and_then(|s| s.port), &repo) {
+            if is_url(url) && !url.contains("://") {
+                return Err(ConfigError::InvalidValue("ssh_command".to_string(), format!("sets a port, but `{}` has no room for one, write it as `ssh://user@host/path`", url)))
+            }
+        }
         
-        let script = script.unwrap().to_string();
+        // A mirror runs no script
+        let script = script.unwrap_or_default();
 
-        let installation = if inst_file1 {ld_script_file(&path, &script)?} else {script};
+        let script_file = if inst_file1 {Some(script.clone())} else {None};
+        let installation = if inst_file1 {ld_script_file(path, &script)?} else {script};
+        let cleanup_file = if inst_file2 {cleanup.map(|t| t.to_string())} else {None};
         let cleanup = if cleanup.is_some() {
-            Some(if inst_file2 {ld_script_file(&path, &cleanup.unwrap().to_string())?} else {cleanup.unwrap().to_string()})
+            Some(if inst_file2 {ld_script_file(path, cleanup.unwrap())?} else {cleanup.unwrap().to_string()})
         } else {None};
 
         Ok(Self {
             name: name.unwrap().to_string(),
+            mode,
+            deploy_mode,
+            releases_dir,
+            current_link,
+            keep_releases,
             re_run: !run_is_final,
             restart_after_update,
+            watch_paths,
+            on_change,
             exit_on_script_error,
             exit_on_gdep_error,
+            bootstrap,
+            steps,
             script: installation,
+            script_file,
             cleanup,
-            repo
+            cleanup_file,
+            repo,
+            max_output_rate,
+            shutdown_grace,
+            max_restarts,
+            restart_delay,
+            restart_jitter,
+            min_restart_interval,
+            expect_branch,
+            error_context_lines,
+            commit,
+            deploy_schedule,
+            track,
+            tag_pattern,
+            pre_update,
+            post_update,
+            require_annotated_tags,
+            verify_tag_signatures,
+            max_commit_age,
+            max_behind,
+            require_signed,
+            allowed_signers,
+            allowed_authors,
+            env,
+            ca_bundle,
+            ssh_key,
+            ssh_key_passphrase,
+            ssh_command,
+            http_username,
+            http_token,
+            proxy,
+            args,
+            shell,
+            runner,
+            run_as,
+            working_dir,
+            script_timeout,
+            force,
+            stall_timeout,
+            stall_retries,
+            clone_retries,
+            conflict_strategy,
+            checkout_mode,
+            on_ahead,
+            merge_message,
+            refspec,
+            prune,
+            fetch_tags,
+            stash_local_changes,
+            fail_on_dirty,
+            clean_untracked,
+            clean_ignored,
+            restore_stash,
+            committer_name,
+            committer_email,
+            smoke_test,
+            smoke_timeout,
+            verify_script,
+            verify_timeout,
+            rollback_on_smoke_fail,
+            ready_check,
+            ready_timeout,
+            graceful_restart,
+            rollback_on_failure,
+            depth,
+            submodules,
+            lfs,
+            lfs_command,
+            max_fetch_retries,
+            update_retries,
+            fetch_timeout,
+            debounce,
+            log_file,
+            log_max_size,
+            log_keep,
+            branch,
+            include_branches,
+            exclude_branches,
+            branch_selector,
+            critical,
+            poll_interval,
+            webhook,
+            health_addr,
+            metrics_addr,
+            health_auth,
+            metrics_auth,
+            control_socket,
+            notifier,
+            stdout_file,
+            stderr_file,
+            prefix_output,
+            default_repo_path,
+            watch_config,
+            no_update,
+            restore_on_start
         })
     }
-}
\ No newline at end of file
+
+    /// Fails if the `shell` or `runner` is not installed or the scripts can't run as `run_as`,
+    /// which `from_yaml` leaves alone as it depends on the host
+    pub fn check_runner(&self) -> Result<(), ConfigError> {
+        check_runner(self.shell.as_ref(), &self.runner)?;
+        check_run_as(self.run_as.as_deref())
+    }
+
+    /// Builds the canonical YAML representation of this config, using the
+    /// current key names. Scripts loaded from files are written back as file references.
+    pub fn to_yaml(&self) -> Yaml {
+        let mut doc = Hash::new();
+        let mut put = |key: &str, value: Yaml| { doc.insert(Yaml::String(key.to_string()), value); };
+
+        put("name", Yaml::String(self.name.clone()));
+        if self.mode == Mode::Mirror {
+            put("mode", Yaml::String("mirror".to_string()));
+        }
+        if self.deploy_mode == DeployMode::Symlink {
+            put("deploy_mode", Yaml::String(self.deploy_mode.to_string()));
+            for (key, path) in [("releases_dir", &self.releases_dir), ("current_link", &self.current_link)] {
+                if let Some(path) = path {
+                    put(key, Yaml::String(path.clone()));
+                }
+            }
+            if let Some(keep) = self.keep_releases {
+                put("keep_releases", Yaml::Integer(keep as i64));
+            }
+        }
+        if let Some(path) = &self.default_repo_path {
+            put("default_repo_path", Yaml::String(path.clone()));
+        }
+        match &self.repo {
+            RepoLike::Remote(r) => {
+                put("repo", Yaml::String(r.clone()));
+            }
+            RepoLike::Local(l) => {
+                put("repo", Yaml::String(l.clone()));
+                put("local_repo", Yaml::Boolean(true));
+            }
+            RepoLike::Remote2(r, d) => {
+                put("repo", Yaml::String(r.clone()));
+                put("into_path", Yaml::String(d.clone()));
+            }
+        }
+
+        match &self.script_file {
+            Some(file) => {
+                put("script_use_file", Yaml::Boolean(true));
+                put("file_path", Yaml::String(file.clone()));
+            }
+            None if self.mode == Mode::Mirror && self.script.is_empty() => {}
+            None if self.steps.is_empty() => put("script", Yaml::String(self.script.clone())),
+            None => put("script", Yaml::Array(self.steps.iter().chain([&self.script]).map(|s| Yaml::String(s.clone())).collect()))
+        }
+        if let Some(bootstrap) = &self.bootstrap {
+            put("bootstrap", Yaml::String(bootstrap.clone()));
+        }
+        match (&self.cleanup_file, &self.cleanup) {
+            (Some(file), _) => {
+                put("cleanup_use_file", Yaml::Boolean(true));
+                put("cleanup_file_path", Yaml::String(file.clone()));
+            }
+            (None, Some(cleanup)) => put("cleanup", Yaml::String(cleanup.clone())),
+            (None, None) => {}
+        }
+
+        if let Some(branch) = &self.branch {
+            put("branch", Yaml::String(branch.clone()));
+        }
+        for (key, globs) in [("include_branches", &self.include_branches), ("exclude_branches", &self.exclude_branches)] {
+            if !globs.is_empty() {
+                put(key, Yaml::Array(globs.iter().map(|g| Yaml::String(g.clone())).collect()));
+            }
+        }
+        if self.branch_selector != BranchSelector::Default {
+            put("branch_selector", Yaml::String(self.branch_selector.to_string()));
+        }
+        put("final", Yaml::Boolean(!self.re_run));
+        put("restart_update", Yaml::Boolean(self.restart_after_update));
+        if !self.watch_paths.is_empty() {
+            put("watch_paths", Yaml::Array(self.watch_paths.iter().map(|g| Yaml::String(g.clone())).collect()));
+        }
+        if !self.on_change.is_empty() {
+            put("on_change", Yaml::Hash(self.on_change.iter().map(|(g, s)| (Yaml::String(g.clone()), Yaml::String(s.clone()))).collect()));
+        }
+        put("gdep_err_ignore", Yaml::Boolean(!self.exit_on_gdep_error));
+        put("script_err_ignore", Yaml::Boolean(!self.exit_on_script_error));
+        if let Some(rate) = self.max_output_rate {
+            put("max_output_rate", Yaml::Integer(rate as i64));
+        }
+        put("shutdown_grace", Yaml::Integer(self.shutdown_grace as i64));
+        if let Some(max) = self.max_restarts {
+            put("max_restarts", Yaml::Integer(max as i64));
+        }
+        if self.restart_delay > 0 {
+            put("restart_delay", duration_to_yaml(self.restart_delay));
+        }
+        if self.restart_jitter > 0 {
+            put("restart_jitter", duration_to_yaml(self.restart_jitter));
+        }
+        if self.min_restart_interval > 0 {
+            put("min_restart_interval", duration_to_yaml(self.min_restart_interval));
+        }
+        if let Some(branch) = &self.expect_branch {
+            put("expect_branch", Yaml::String(branch.clone()));
+        }
+        if let Some(lines) = self.error_context_lines {
+            put("error_context_lines", Yaml::Integer(lines as i64));
+        }
+        if let Some(commit) = &self.commit {
+            put("commit", Yaml::String(commit.clone()));
+        }
+        if let Some(schedule) = &self.deploy_schedule {
+            schedule.to_yaml(&mut put);
+        }
+        if self.track == Track::Tag {
+            put("track", Yaml::String("tag".to_string()));
+        }
+        if let Some(pattern) = &self.tag_pattern {
+            put("tag_pattern", Yaml::String(pattern.clone()));
+        }
+        if let Some(hook) = &self.pre_update {
+            put("pre_update", Yaml::String(hook.clone()));
+        }
+        if let Some(hook) = &self.post_update {
+            put("post_update", Yaml::String(hook.clone()));
+        }
+        if self.require_annotated_tags {
+            put("require_annotated_tags", Yaml::Boolean(true));
+        }
+        if self.verify_tag_signatures {
+            put("verify_tag_signatures", Yaml::Boolean(true));
+        }
+        if let Some(age) = self.max_commit_age {
+            put("max_commit_age", duration_to_yaml(age));
+        }
+        if let Some(max) = self.max_behind {
+            put("max_behind", Yaml::Integer(max as i64));
+        }
+        if self.require_signed {
+            put("require_signed", Yaml::Boolean(true));
+        }
+        if !self.allowed_signers.is_empty() {
+            put("allowed_signers", Yaml::Array(self.allowed_signers.iter().map(|f| Yaml::String(f.clone())).collect()));
+        }
+        if !self.allowed_authors.is_empty() {
+            put("allowed_authors", Yaml::Array(self.allowed_authors.iter().map(|a| Yaml::String(a.clone())).collect()));
+        }
+        if !self.env.is_empty() {
+            put("env", Yaml::Hash(self.env.iter().map(|(k, v)| (Yaml::String(k.clone()), Yaml::String(v.clone()))).collect()));
+        }
+        if let Some(bundle) = &self.ca_bundle {
+            put("ca_bundle", Yaml::String(bundle.clone()));
+        }
+        if let Some(key) = &self.ssh_key {
+            put("ssh_key", Yaml::String(key.clone()));
+        }
+        if let Some(command) = &self.ssh_command {
+            put("ssh_command", Yaml::String(command.clone()));
+        }
+        if let Some(passphrase) = &self.ssh_key_passphrase {
+            put("ssh_key_passphrase", Yaml::String(passphrase.clone()));
+        }
+        if let Some(username) = &self.http_username {
+            put("http_username", Yaml::String(username.clone()));
+        }
+        if let Some(token) = &self.http_token {
+            put("http_token", Yaml::String(token.clone()));
+        }
+        if let Some(proxy) = &self.proxy {
+            put("proxy", Yaml::String(proxy.clone()));
+        }
+        if !self.args.is_empty() {
+            put("args", Yaml::Array(self.args.iter().map(|a| Yaml::String(a.clone())).collect()));
+        }
+        if let Some(shell) = &self.shell {
+            put("shell", Yaml::String(shell.clone()));
+        }
+        if !self.runner.is_empty() {
+            put("runner", Yaml::Array(self.runner.iter().map(|a| Yaml::String(a.clone())).collect()));
+        }
+        if let Some(user) = &self.run_as {
+            put("run_as", Yaml::String(user.clone()));
+        }
+        if let Some(dir) = &self.working_dir {
+            put("working_dir", Yaml::String(dir.clone()));
+        }
+        if let Some(timeout) = self.script_timeout {
+            put("script_timeout", duration_to_yaml(timeout));
+        }
+        if self.force {
+            put("force", Yaml::Boolean(true));
+        }
+        if let Some(timeout) = self.stall_timeout {
+            put("stall_timeout", duration_to_yaml(timeout));
+            put("stall_retries", Yaml::Integer(self.stall_retries as i64));
+        }
+        put("clone_retries", Yaml::Integer(self.clone_retries as i64));
+        if let Some(strategy) = self.conflict_strategy {
+            put("conflict_strategy", Yaml::String(strategy.to_string()));
+        }
+        if self.checkout_mode != CheckoutMode::Force {
+            put("checkout_mode", Yaml::String(self.checkout_mode.to_string()));
+        }
+        if self.on_ahead != OnAhead::Error {
+            put("on_ahead", Yaml::String(self.on_ahead.to_string()));
+        }
+        if self.stash_local_changes {
+            put("stash_local_changes", Yaml::Boolean(true));
+            put("restore_stash", Yaml::Boolean(self.restore_stash));
+        }
+        if self.fail_on_dirty {
+            put("fail_on_dirty", Yaml::Boolean(true));
+        }
+        if self.clean_untracked {
+            put("clean_untracked", Yaml::Boolean(true));
+        }
+        if self.clean_ignored {
+            put("clean_ignored", Yaml::Boolean(true));
+        }
+        if self.merge_message != DEFAULT_MERGE_MESSAGE {
+            put("merge_message", Yaml::String(self.merge_message.clone()));
+        }
+        if self.refspec != DEFAULT_REFSPEC {
+            put("refspec", Yaml::String(self.refspec.clone()));
+        }
+        if self.prune {
+            put("prune", Yaml::Boolean(true));
+        }
+        if self.fetch_tags != FetchTags::All {
+            put("fetch_tags", Yaml::String(self.fetch_tags.to_string()));
+        }
+        if let Some(name) = &self.committer_name {
+            put("committer_name", Yaml::String(name.clone()));
+        }
+        if let Some(email) = &self.committer_email {
+            put("committer_email", Yaml::String(email.clone()));
+        }
+        if let Some(depth) = self.depth {
+            put("depth", Yaml::Integer(depth as i64));
+        }
+        if self.submodules {
+            put("submodules", Yaml::Boolean(true));
+        }
+        if self.lfs {
+            put("lfs", Yaml::Boolean(true));
+            if self.lfs_command != DEFAULT_LFS_COMMAND {
+                put("lfs_command", Yaml::String(self.lfs_command.clone()));
+            }
+        }
+        put("max_fetch_retries", Yaml::Integer(self.max_fetch_retries as i64));
+        put("update_retries", Yaml::Integer(self.update_retries as i64));
+        if let Some(timeout) = self.fetch_timeout {
+            put("fetch_timeout", duration_to_yaml(timeout));
+        }
+        if let Some(debounce) = self.debounce {
+            put("debounce", duration_to_yaml(debounce));
+        }
+        if let Some(smoke_test) = &self.smoke_test {
+            put("smoke_test", Yaml::String(smoke_test.clone()));
+            put("smoke_timeout", duration_to_yaml(self.smoke_timeout));
+            put("rollback_on_smoke_fail", Yaml::Boolean(self.rollback_on_smoke_fail));
+        }
+        if let Some(verify) = &self.verify_script {
+            put("verify_script", Yaml::String(verify.clone()));
+            put("verify_timeout", duration_to_yaml(self.verify_timeout));
+        }
+        if let Some(check) = &self.ready_check {
+            put("ready_check", Yaml::String(check.clone()));
+            put("ready_timeout", duration_to_yaml(self.ready_timeout));
+            if self.graceful_restart {
+                put("graceful_restart", Yaml::Boolean(true));
+            }
+        }
+        if self.rollback_on_failure {
+            put("rollback_on_failure", Yaml::Boolean(true));
+        }
+        if self.watch_config {
+            put("watch_config", Yaml::Boolean(true));
+        }
+        if self.no_update {
+            put("no_update", Yaml::Boolean(true));
+        }
+        if self.restore_on_start {
+            put("restore_on_start", Yaml::Boolean(true));
+        }
+        if self.critical {
+            put("critical", Yaml::Boolean(true));
+        }
+        if self.poll_interval > 0 {
+            put("poll_interval", duration_to_yaml(self.poll_interval));
+        }
+        if let Some(webhook) = &self.webhook {
+            put("webhook", webhook.to_yaml());
+        }
+        if let Some(addr) = &self.health_addr {
+            put("health_addr", Yaml::String(addr.clone()));
+        }
+        if let Some(addr) = &self.metrics_addr {
+            put("metrics_addr", Yaml::String(addr.clone()));
+        }
+        if let Some(auth) = &self.health_auth {
+            put("health_auth", auth.to_yaml());
+        }
+        if let Some(auth) = &self.metrics_auth {
+            put("metrics_auth", auth.to_yaml());
+        }
+        if let Some(socket) = &self.control_socket {
+            put("control_socket", Yaml::String(socket.clone()));
+        }
+        if let Some(notifier) = &self.notifier {
+            put("notifier", notifier.to_yaml());
+        }
+        if let Some(file) = &self.stdout_file {
+            put("stdout_file", Yaml::String(file.clone()));
+        }
+        if let Some(file) = &self.stderr_file {
+            put("stderr_file", Yaml::String(file.clone()));
+        }
+        if self.prefix_output {
+            put("prefix_output", Yaml::Boolean(true));
+        }
+        if let Some(file) = &self.log_file {
+            put("log_file", Yaml::String(file.clone()));
+            put("log_max_size", size_to_yaml(self.log_max_size));
+            put("log_keep", Yaml::Integer(self.log_keep as i64));
+        }
+
+        Yaml::Hash(doc)
+    }
+
+    /// A new config file with the mandatory settings and the common optional ones commented out.
+    /// It is loaded back before it is returned, so it is known to be valid
+    pub fn starter_yaml(name: &str, repo: &str, script: &str) -> Result<String, ConfigError> {
+        let doc = Yaml::Hash([("name", name), ("repo", repo), ("script", script)].into_iter()
+            .map(|(k, v)| (Yaml::String(k.to_string()), Yaml::String(v.to_string()))).collect());
+        let yaml = format!("{}{}", emit(&doc)?, STARTER_OPTIONS);
+        Self::from_yaml(&join_yaml_docs(ld_yaml_docs(&yaml)?)?, "gdep.yaml")?;
+        Ok(yaml)
+    }
+
+    pub fn to_yaml_string(&self) -> Result<String, ConfigError> {
+        emit(&self.to_yaml())
+    }
+
+    /// Like `to_yaml_string`, for a file with a `deployments` list. Every entry is written out in full,
+    /// except for the process wide settings, which move next to the list
+    pub fn deployments_to_yaml_string(deployments: &[Self]) -> Result<String, ConfigError> {
+        emit(&deployments_doc(deployments, Self::to_yaml))
+    }
+
+    /// Like `to_yaml`, but as gdep runs it: every setting that has a default is written out,
+    /// and scripts loaded from files are inlined, with their file paths kept next to them.
+    /// Secrets are redacted
+    pub fn to_effective_yaml(&self) -> Yaml {
+        let Yaml::Hash(mut doc) = self.to_yaml() else { unreachable!() };
+        let key = |k: &str| Yaml::String(k.to_string());
+        let strings = |values: &[String]| Yaml::Array(values.iter().map(|v| Yaml::String(v.clone())).collect());
+
+        if self.script_file.is_some() {
+            doc.insert(key("script_use_file"), Yaml::Boolean(false));
+            doc.insert(key("script"), Yaml::String(self.script.clone()));
+        }
+        if let (Some(_), Some(cleanup)) = (&self.cleanup_file, &self.cleanup) {
+            doc.insert(key("cleanup_use_file"), Yaml::Boolean(false));
+            doc.insert(key("cleanup"), Yaml::String(cleanup.clone()));
+        }
+        for secret in ["ssh_key_passphrase", "http_token"] {
+            if let Some(value) = doc.get_mut(&key(secret)) {
+                *value = key(REDACTED);
+            }
+        }
+        let redact_auth = |auth: Option<&mut Yaml>| if let Some(Yaml::Hash(auth)) = auth {
+            for secret in ["password", "token"] {
+                if let Some(value) = auth.get_mut(&key(secret)) {
+                    *value = key(REDACTED);
+                }
+            }
+        };
+        if let Some(Yaml::Hash(webhook)) = doc.get_mut(&key("webhook")) {
+            webhook.insert(key("secret"), key(REDACTED));
+            redact_auth(webhook.get_mut(&key("auth")));
+        }
+        redact_auth(doc.get_mut(&key("health_auth")));
+        redact_auth(doc.get_mut(&key("metrics_auth")));
+
+        // Whatever `to_yaml` left out has its default value
+        let defaults = [
+            ("mode", key("deploy")),
+            ("deploy_mode", key(&self.deploy_mode.to_string())),
+            ("local_repo", Yaml::Boolean(false)),
+            ("include_branches", strings(&self.include_branches)),
+            ("exclude_branches", strings(&self.exclude_branches)),
+            ("branch_selector", key(&self.branch_selector.to_string())),
+            ("watch_paths", strings(&self.watch_paths)),
+            ("on_change", Yaml::Hash(Hash::new())),
+            ("restart_delay", duration_to_yaml(self.restart_delay)),
+            ("restart_jitter", duration_to_yaml(self.restart_jitter)),
+            ("min_restart_interval", duration_to_yaml(self.min_restart_interval)),
+            ("track", key("branch")),
+            ("require_annotated_tags", Yaml::Boolean(self.require_annotated_tags)),
+            ("verify_tag_signatures", Yaml::Boolean(self.verify_tag_signatures)),
+            ("require_signed", Yaml::Boolean(self.require_signed)),
+            ("allowed_signers", strings(&self.allowed_signers)),
+            ("allowed_authors", strings(&self.allowed_authors)),
+            ("env", Yaml::Hash(Hash::new())),
+            ("args", strings(&self.args)),
+            ("force", Yaml::Boolean(self.force)),
+            ("stall_retries", Yaml::Integer(self.stall_retries as i64)),
+            ("checkout_mode", key(&self.checkout_mode.to_string())),
+            ("on_ahead", key(&self.on_ahead.to_string())),
+            ("stash_local_changes", Yaml::Boolean(self.stash_local_changes)),
+            ("restore_stash", Yaml::Boolean(self.restore_stash)),
+            ("fail_on_dirty", Yaml::Boolean(self.fail_on_dirty)),
+            ("clean_untracked", Yaml::Boolean(self.clean_untracked)),
+            ("clean_ignored", Yaml::Boolean(self.clean_ignored)),
+            ("merge_message", key(&self.merge_message)),
+            ("refspec", key(&self.refspec)),
+            ("prune", Yaml::Boolean(self.prune)),
+            ("fetch_tags", key(&self.fetch_tags.to_string())),
+            ("submodules", Yaml::Boolean(self.submodules)),
+            ("lfs", Yaml::Boolean(self.lfs)),
+            ("smoke_timeout", duration_to_yaml(self.smoke_timeout)),
+            ("rollback_on_smoke_fail", Yaml::Boolean(self.rollback_on_smoke_fail)),
+            ("verify_timeout", duration_to_yaml(self.verify_timeout)),
+            ("ready_timeout", duration_to_yaml(self.ready_timeout)),
+            ("graceful_restart", Yaml::Boolean(self.graceful_restart)),
+            ("rollback_on_failure", Yaml::Boolean(self.rollback_on_failure)),
+            ("watch_config", Yaml::Boolean(self.watch_config)),
+            ("prefix_output", Yaml::Boolean(self.prefix_output)),
+            ("no_update", Yaml::Boolean(self.no_update)),
+            ("restore_on_start", Yaml::Boolean(self.restore_on_start)),
+            ("critical", Yaml::Boolean(self.critical)),
+            ("poll_interval", duration_to_yaml(self.poll_interval)),
+            ("log_max_size", size_to_yaml(self.log_max_size)),
+            ("log_keep", Yaml::Integer(self.log_keep as i64))
+        ];
+        for (name, value) in defaults {
+            if !doc.contains_key(&key(name)) {
+                doc.insert(key(name), value);
+            }
+        }
+        Yaml::Hash(doc)
+    }
+
+    /// `--print-config`: `to_effective_yaml` of every deployment, as YAML or JSON
+    pub fn effective_string(deployments: &[Self], json: bool) -> Result<String, ConfigError> {
+        let doc = match deployments {
+            [config] => config.to_effective_yaml(),
+            _ => deployments_doc(deployments, Self::to_effective_yaml)
+        };
+        if json {
+            Ok(format!("{}\n", yaml_to_json(&doc)))
+        } else {
+            emit(&doc)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `files` into a temporary directory, returning it with the paths of the files
+    fn write_files(files: &[(&str, &str)]) -> (tempfile::TempDir, Vec<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = files.iter().map(|(name, content)| {
+            let path = dir.path().join(name);
+            fs::write(&path, content).unwrap();
+            path.to_string_lossy().to_string()
+        }).collect();
+        (dir, paths)
+    }
+
+    #[test]
+    fn toml_and_yaml_configs_load_the_same() {
+        let (_dir, paths) = write_files(&[
+            ("gdep.yaml", "name: app\nrepo: https://example.com/app.git\ninto_path: checkout\nbranch: main\n\
+                script: |\n  ./build.sh\n  ./run.sh\npoll_interval: 15m\nmax_restarts: 3\nrestart_update: true\n\
+                args: [--port, \"8080\"]\nenv:\n  MODE: production\nwatch_paths: [src/**, Cargo.toml]\n"),
+            ("gdep.toml", "name = \"app\"\nrepo = \"https://example.com/app.git\"\ninto_path = \"checkout\"\nbranch = \"main\"\n\
+                script = \"\"\"\n./build.sh\n./run.sh\n\"\"\"\npoll_interval = \"15m\"\nmax_restarts = 3\nrestart_update = true\n\
+                args = [\"--port\", \"8080\"]\nwatch_paths = [\"src/**\", \"Cargo.toml\"]\n[env]\nMODE = \"production\"\n")
+        ]);
+        let yaml = Config::load_from_files(&paths[..1]).unwrap();
+        let toml = Config::load_from_files(&paths[1..]).unwrap();
+        assert_eq!(toml.to_yaml_string().unwrap(), yaml.to_yaml_string().unwrap());
+        assert_eq!(toml.poll_interval, 900);
+        assert_eq!(toml.args, ["--port", "8080"]);
+    }
+
+    #[test]
+    fn check_accepts_a_valid_config() {
+        let (_dir, paths) = write_files(&[("gdep.yml", "name: app\nrepo: https://example.com/app.git\nscript_use_file: true\nfile_path: run.sh\n"), ("run.sh", "./app\n")]);
+        let deployments = Config::check_files(&paths[..1]).unwrap();
+        assert_eq!(deployments.len(), 1);
+        assert_eq!(deployments[0].script, "./app\n");
+    }
+
+    #[test]
+    fn check_reports_every_problem() {
+        let (dir, paths) = write_files(&[("gdep.yml", "repo: https://example.com/app.git\nscript_use_file: true\nfile_path: missing.sh\n\
+            cleanup_use_file: true\npoll_interval: often\n")]);
+        let Err(problems) = Config::check_files(&paths) else {
+            panic!("the config passed the check")
+        };
+        assert_eq!(problems, [
+            "Missing mandatory property: name".to_string(),
+            format!("Script file not found: {}", dir.path().join("missing.sh").to_string_lossy()),
+            "Missing mandatory property: cleanup_file_path".to_string(),
+            "Invalid value for `poll_interval`: `often` is not a duration like `90s`, `15m`, `12h` or `30d`".to_string()
+        ]);
+    }
+
+    #[test]
+    fn a_key_schema_does_not_list_is_rejected() {
+        let (_dir, paths) = write_files(&[("gdep.yml", "name: app\nrepo: https://example.com/app.git\nscript: ./app\npol_interval: 30s\n\
+            restart_delay: often\n")]);
+        let Err(e) = Config::load_from_files(&paths) else {
+            panic!("a misspelt key loaded")
+        };
+        assert_eq!(e.to_string(), "Unknown key `pol_interval`, `gdep schema` lists every key");
+
+        let Err(problems) = Config::check_files(&paths) else {
+            panic!("the config passed the check")
+        };
+        assert_eq!(problems, [
+            "Unknown key `pol_interval`, `gdep schema` lists every key".to_string(),
+            "Invalid value for `restart_delay`: `often` is not a duration like `90s`, `15m`, `12h` or `30d`".to_string()
+        ]);
+    }
+
+    #[test]
+    fn an_empty_file_is_an_error() {
+        let (_dir, paths) = write_files(&[("empty.yml", ""), ("comments.yml", "# name: app\n")]);
+        for (path, expected) in paths.into_iter().zip(["the file is empty", "the file contains no YAML document"]) {
+            let Err(e) = Config::load_from_files(&[path]) else {
+                panic!("an empty file loaded")
+            };
+            assert_eq!(e.to_string(), format!("Parsing failed: {}", expected));
+        }
+    }
+
+    #[test]
+    fn every_document_is_a_deployment() {
+        let (_dir, paths) = write_files(&[("gdep.yml", "name: web\nrepo: https://example.com/web.git\nscript: ./web\n---\n\
+            name: worker\nrepo: https://example.com/worker.git\nscript: ./worker\n")]);
+        let deployments = Config::load_deployments(&paths).unwrap().unwrap();
+        let names: Vec<_> = deployments.iter().map(|d| (d.name.as_str(), d.script.as_str())).collect();
+        assert_eq!(names, [("web", "./web"), ("worker", "./worker")]);
+
+        let (_dir, paths) = write_files(&[("gdep.yml", "name: web\nrepo: x\nscript: ./web\n---\ndeployments: []\n")]);
+        let Err(e) = Config::load_deployments(&paths) else {
+            panic!("`---` and `deployments` were combined")
+        };
+        assert_eq!(e.to_string(), "Invalid value for `deployments`: cannot be combined with `---` separated deployments");
+    }
+
+    #[test]
+    fn variables_are_expanded_with_defaults() {
+        std::env::set_var("GDEP_TEST_USER", "deploy");
+        std::env::set_var("GDEP_TEST_EMPTY", "");
+        std::env::remove_var("GDEP_TEST_UNSET");
+        let expand = |value: &str| expand_vars(value, "repo").map_err(|e| e.to_string());
+
+        assert_eq!(expand("https://${GDEP_TEST_USER}@example.com/${GDEP_TEST_USER}.git"), Ok("https://deploy@example.com/deploy.git".to_string()));
+        assert_eq!(expand("${GDEP_TEST_UNSET:-/srv}/app"), Ok("/srv/app".to_string()));
+        assert_eq!(expand("${GDEP_TEST_EMPTY:-fallback}"), Ok("fallback".to_string()));
+        assert_eq!(expand("${GDEP_TEST_EMPTY}"), Ok("".to_string()));
+        assert_eq!(expand("${GDEP_TEST_USER:-unused}"), Ok("deploy".to_string()));
+        assert_eq!(expand("$${GDEP_TEST_USER}"), Ok("${GDEP_TEST_USER}".to_string()));
+
+        assert_eq!(expand("${GDEP_TEST_UNSET}"), Err("`repo` references the environment variable `GDEP_TEST_UNSET`, which is not set".to_string()));
+        assert_eq!(expand("${GDEP_TEST_USER"), Err("Invalid value for `repo`: unterminated `${` in `${GDEP_TEST_USER`".to_string()));
+        assert_eq!(expand("${not a name}"), Err("Invalid value for `repo`: `${not a name}` is not a variable reference".to_string()));
+    }
+
+    #[test]
+    fn scripts_are_not_expanded() {
+        std::env::set_var("GDEP_TEST_ROOT", "/srv");
+        let (_dir, paths) = write_files(&[("gdep.yml", "name: app\nrepo: https://example.com/app.git\ninto_path: ${GDEP_TEST_ROOT}/app\nscript: echo ${HOME}\n")]);
+        let config = Config::load_from_files(&paths).unwrap();
+        assert_eq!(config.script, "echo ${HOME}");
+        assert!(matches!(&config.repo, RepoLike::Remote2(_, into) if into == "/srv/app"));
+    }
+
+    #[test]
+    fn script_and_cleanup_are_inline_or_from_a_file_independently() {
+        let base = "name: app\nrepo: https://example.com/app.git\n";
+        let inline_script = "script: ./inline-run\n";
+        let file_script = "script_use_file: true\nfile_path: run.sh\n";
+        let inline_cleanup = "cleanup: ./inline-stop\n";
+        let file_cleanup = "cleanup_use_file: true\ncleanup_file_path: stop.sh\n";
+        for (script, cleanup, expected) in [
+            (inline_script, inline_cleanup, ("./inline-run", "./inline-stop")),
+            (inline_script, file_cleanup, ("./inline-run", "./file-stop\n")),
+            (file_script, inline_cleanup, ("./file-run\n", "./inline-stop")),
+            (file_script, file_cleanup, ("./file-run\n", "./file-stop\n"))
+        ] {
+            let (_dir, paths) = write_files(&[("gdep.yml", &format!("{}{}{}", base, script, cleanup)), ("run.sh", "./file-run\n"), ("stop.sh", "./file-stop\n")]);
+            let config = Config::load_from_files(&paths[..1]).unwrap();
+            assert_eq!((config.script.as_str(), config.cleanup.as_deref().unwrap()), expected, "{}{}", script, cleanup);
+        }
+    }
+
+    #[test]
+    fn a_migrated_legacy_config_loads_back_the_same() {
+        let legacy = "name: app\nrepo: https://example.com/app.git\nscript_use_file: true\nfile_path: run.sh\nfinal: true\ngdep_err_ignore: true\n\
+            script_err_ignore: true\npoll_interval: 2m\n";
+        let (dir, paths) = write_files(&[("gdep.yml", legacy), ("run.sh", "./serve\n")]);
+        let original = Config::load_from_files(&paths[..1]).unwrap();
+        let migrated_path = dir.path().join("migrated.yml").to_string_lossy().to_string();
+        fs::write(&migrated_path, original.to_yaml_string().unwrap()).unwrap();
+        let migrated = Config::load_from_files(&[migrated_path]).unwrap();
+
+        assert_eq!(migrated.to_yaml(), original.to_yaml());
+        assert_eq!(migrated.to_effective_yaml(), original.to_effective_yaml());
+        assert!(!migrated.re_run && !migrated.exit_on_gdep_error && !migrated.exit_on_script_error);
+        assert_eq!((migrated.script.as_str(), migrated.script_file.as_deref()), ("./serve\n", Some("run.sh")));
+        assert_eq!(migrated.poll_interval, 120);
+    }
+
+    #[test]
+    fn a_refspec_has_to_fetch_into_the_tracking_branch() {
+        let refspec = |value: &str| as_refspec(&YamlLoader::load_from_str(&format!("refspec: \"{}\"", value)).unwrap()[0]).map_err(|e| e.to_string());
+        assert_eq!(as_refspec(&YamlLoader::load_from_str("name: app").unwrap()[0]).unwrap(), DEFAULT_REFSPEC);
+        assert_eq!(refspec("+refs/pull/1/head:refs/remotes/origin/{branch}"), Ok("+refs/pull/1/head:refs/remotes/origin/{branch}".to_string()));
+        assert_eq!(refspec("refs/heads/main"), Err("Invalid value for `refspec`: `refs/heads/main` has no `:<destination>`".to_string()));
+        assert_eq!(refspec("+:refs/remotes/origin/{branch}"), Err("Invalid value for `refspec`: `+:refs/remotes/origin/{branch}` has no source".to_string()));
+        assert_eq!(refspec("refs/heads/main:refs/heads/main"), Err("Invalid value for `refspec`: `refs/heads/main:refs/heads/main` must fetch into `refs/remotes/origin/{branch}`".to_string()));
+    }
+
+    #[test]
+    fn a_script_list_is_steps_then_the_script() {
+        let script = |yaml: &str| as_script(&YamlLoader::load_from_str(yaml).unwrap()[0], false).map_err(|e| e.to_string());
+        assert_eq!(script("script: [./build, ./migrate, ./serve]"), Ok((vec!["./build".to_string(), "./migrate".to_string()], Some("./serve".to_string()))));
+        assert_eq!(script("script: ./serve"), Ok((Vec::new(), Some("./serve".to_string()))));
+        assert_eq!(script("script: []"), Err("Invalid value for `script`: the list is empty".to_string()));
+        assert_eq!(script("script: [./build, 3]"), Err("Invalid value for `script`: `Integer(3)` is not a script".to_string()));
+    }
+
+    #[test]
+    fn the_root_has_to_be_a_mapping() {
+        let (_dir, paths) = write_files(&[("blank.yml", "  \n\n"), ("scalar.yml", "just a string\n"), ("list.yml", "- name: app\n- repo: x\n"), ("null.yml", "~\n")]);
+        let expected = ["the file is empty", "expected a mapping of settings like `name: ...`, the file holds the value `just a string`",
+            "expected a mapping of settings like `name: ...`, the file holds a list", "expected a mapping of settings like `name: ...`, the file holds an empty document"];
+        for (path, expected) in paths.into_iter().zip(expected) {
+            let Err(e) = Config::load_from_files(std::slice::from_ref(&path)) else {
+                panic!("`{}` loaded", path)
+            };
+            assert_eq!(e.to_string(), format!("Parsing failed: {}", expected), "{}", path);
+        }
+    }
+
+    #[test]
+    fn contradictory_repo_settings_are_rejected() {
+        let load = |yaml: &str| {
+            let (_dir, paths) = write_files(&[("gdep.yml", &format!("name: app\nscript: ./run\n{}", yaml))]);
+            Config::load_from_files(&paths).map(|_| ()).map_err(|e| e.to_string())
+        };
+        assert_eq!(load("local_repo: true\nrepo: /srv/app\ninto_path: checkout\n"),
+            Err("Invalid value for `into_path`: only applies to remote repos, but `local_repo` is set".to_string()));
+        assert_eq!(load("local_repo: true\nrepo: https://example.com/app.git\n"),
+            Err("Invalid value for `repo`: `https://example.com/app.git` is a URL, but `local_repo` is set".to_string()));
+        assert_eq!(load("local_repo: true\nrepo: git@example.com:app.git\n"),
+            Err("Invalid value for `repo`: `git@example.com:app.git` is a URL, but `local_repo` is set".to_string()));
+        assert_eq!(load("into_path: checkout\n"), Err("Missing mandatory property: repo".to_string()));
+        assert_eq!(load("local_repo: true\nrepo: /srv/app\n"), Ok(()));
+    }
+
+    #[test]
+    fn into_path_is_relative_to_the_config_file() {
+        let (dir, paths) = write_files(&[("gdep.yml", "name: app\nrepo: https://example.com/app.git\ninto_path: checkout\nscript: ./run\n")]);
+        let config = Config::load_from_files(&paths).unwrap();
+        assert!(matches!(&config.repo, RepoLike::Remote2(_, into) if Path::new(into) == dir.path().join("checkout")));
+
+        let (_dir, paths) = write_files(&[("gdep.yml", "name: app\nrepo: https://example.com/app.git\ninto_path: /srv/app\nscript: ./run\n")]);
+        let config = Config::load_from_files(&paths).unwrap();
+        assert!(matches!(&config.repo, RepoLike::Remote2(_, into) if into == "/srv/app"));
+    }
+
+    #[test]
+    fn the_starter_config_loads_back_with_or_without_the_optional_settings() {
+        let yaml = Config::starter_yaml("app", "https://example.com/app.git", "./run.sh --port 8080: \"quoted\"").unwrap();
+        let uncommented: String = yaml.lines().map(|line| match line.strip_prefix("# ") {
+            Some(option) if option.starts_with("  ") || option.split_once(':').is_some_and(|(k, _)| k.chars().all(|c| c.is_ascii_lowercase() || c == '_')) => option,
+            _ => line
+        }).map(|line| format!("{}\n", line)).collect();
+        let (_dir, paths) = write_files(&[("gdep.yaml", &yaml), ("full.yaml", &uncommented)]);
+
+        let config = Config::load_from_files(&paths[..1]).unwrap();
+        assert_eq!(config.name, "app");
+        assert!(matches!(&config.repo, RepoLike::Remote(url) if url == "https://example.com/app.git"));
+        assert_eq!(config.script, "./run.sh --port 8080: \"quoted\"");
+
+        let full = Config::load_from_files(&paths[1..]).unwrap();
+        assert_eq!(full.branch.as_deref(), Some("main"));
+        assert_eq!(full.poll_interval, 30);
+        assert_eq!(full.working_dir.as_deref(), Some("backend"));
+        assert_eq!(full.env["APP_ENV"], "production");
+        assert!(full.health_auth.is_some());
+    }
+
+    #[test]
+    fn later_files_override_single_keys_of_earlier_ones() {
+        let (_dir, paths) = write_files(&[
+            ("base.yaml", "name: app\nrepo: https://example.com/app.git\nscript: ./run.sh\npoll_interval: 1m\n\
+                env:\n  MODE: production\n  REGION: eu\nwatch_paths: [src/**, Cargo.toml]\n"),
+            ("host.toml", "poll_interval = \"10s\"\nwatch_paths = [\"app/**\"]\n[env]\nREGION = \"us\"\n"),
+            ("last.yml", "script: ./host-run.sh\n")
+        ]);
+        let config = Config::load_from_files(&paths).unwrap();
+        assert_eq!(config.name, "app");
+        assert_eq!(config.script, "./host-run.sh");
+        assert_eq!(config.poll_interval, 10);
+        // Mappings are merged key by key, lists are replaced as a whole
+        assert_eq!(config.env, BTreeMap::from([("MODE".to_string(), "production".to_string()), ("REGION".to_string(), "us".to_string())]));
+        assert_eq!(config.watch_paths, ["app/**"]);
+
+        let reversed: Vec<String> = paths[..2].iter().rev().cloned().collect();
+        assert_eq!(Config::load_from_files(&reversed).unwrap().poll_interval, 60);
+    }
+}
@@ -0,0 +1,173 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use log::info;
+use crate::health;
+
+/// What the webhook and the control socket ask of a running deployment
+#[derive(Default)]
+pub struct Triggers {
+    /// Look for updates now, picked up by the update thread before its next wait
+    pub update: Arc<AtomicBool>,
+    /// Restart the script, picked up by `execute`
+    pub restart: AtomicBool,
+    /// Switch to this branch and restart the script on it, picked up by `execute`
+    pub checkout: Mutex<Option<String>>,
+    /// Read the config files again and apply them, picked up by `execute`
+    pub reload: AtomicBool
+}
+
+/// Everything a command may need to answer
+struct Context {
+    path: String,
+    repo_path: String,
+    triggers: Arc<Triggers>,
+    status: Arc<Mutex<health::Status>>,
+    shutdown: Arc<Mutex<bool>>
+}
+
+impl Context {
+    fn status(&self) -> String {
+        let status = self.status.lock().unwrap().clone();
+        let relation = git2::Repository::open(&self.repo_path)
+            .and_then(|repo| crate::fetched_relation(&repo, &status.branch))
+            .map(|r| format!("{:?}", r).replace(' ', ""))
+            .unwrap_or_else(|_| "unknown".to_string());
+        format!("ok branch={} head={} relation={} script_running={} updates_running={}",
+            status.branch, status.head.as_deref().unwrap_or("none"), relation, status.script_running, status.updates_running)
+    }
+
+    /// `checkout <branch>` is only passed on if origin has that branch
+    fn checkout(&self, branch: &str) -> String {
+        if !git2::Reference::is_valid_name(&format!("refs/heads/{}", branch)) {
+            return format!("error `{}` is not a valid branch name", branch)
+        }
+        match crate::remote_has_branch(&self.repo_path, branch) {
+            Ok(true) => {
+                *self.triggers.checkout.lock().unwrap() = Some(branch.to_string());
+                format!("ok switching to branch `{}`", branch)
+            }
+            Ok(false) => format!("error origin has no branch `{}`", branch),
+            Err(e) => format!("error could not ask origin for its branches: {}", e.message())
+        }
+    }
+
+    /// The one line `command` is answered with, `ok ...` or `error ...`
+    fn answer(&self, command: &str) -> String {
+        if let Some(branch) = command.strip_prefix("checkout ") {
+            return self.checkout(branch.trim())
+        }
+        match command {
+            "status" => self.status(),
+            "update" => {
+                self.triggers.update.store(true, Ordering::SeqCst);
+                "ok looking for updates".to_string()
+            }
+            "restart" => {
+                self.triggers.restart.store(true, Ordering::SeqCst);
+                "ok restarting the script".to_string()
+            }
+            "reload" => {
+                self.triggers.reload.store(true, Ordering::SeqCst);
+                "ok reloading the config".to_string()
+            }
+            "stop" => {
+                info!("Stop requested on the control socket `{}`", self.path);
+                *self.shutdown.lock().unwrap() = true;
+                "ok stopping".to_string()
+            }
+            other => format!("error unknown command `{}`, expected status, update, restart, reload, checkout <branch> or stop", other)
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::fs;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use log::{debug, info, warn};
+    use crate::signals;
+    use super::Context;
+
+    /// How often the listener looks at the stop flag while nobody connects
+    const POLL_STOP: Duration = Duration::from_millis(100);
+    /// A client that sends nothing for this long is disconnected, so it can't hold up the others
+    const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A socket file left behind by a gdep that is gone is replaced, one somebody still listens on is not
+    fn bind(path: &str) -> io::Result<UnixListener> {
+        if Path::new(path).exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "something is already listening on it"))
+            }
+            fs::remove_file(path)?;
+        }
+        UnixListener::bind(path)
+    }
+
+    /// Answers every line the client sends until it hangs up, stops sending or asks to `stop`
+    fn handle(stream: UnixStream, context: &Context) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let command = line.trim();
+            if command.is_empty() {
+                continue
+            }
+            writeln!(writer, "{}", context.answer(command))?;
+            if command == "stop" {
+                break
+            }
+        }
+        Ok(())
+    }
+
+    pub fn serve(context: Context, stop: Arc<Mutex<bool>>) -> io::Result<thread::JoinHandle<()>> {
+        let listener = bind(&context.path)?;
+        listener.set_nonblocking(true)?;
+        info!("Listening for commands on `{}`", context.path);
+
+        crate::deployment_thread().spawn(move || {
+            while !*stop.lock().unwrap() && signals::received().is_none() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = handle(stream, &context) {
+                            debug!("Failed to answer on the control socket: {}", e);
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_STOP),
+                    Err(e) => {
+                        warn!("Control socket failed to accept a connection: {}", e);
+                        thread::sleep(POLL_STOP);
+                    }
+                }
+            }
+            let _ = fs::remove_file(&context.path);
+        })
+    }
+}
+
+/// Listens on the Unix socket `path` from a new thread until `stop` is set or gdep is interrupted.
+/// Clients send one command per line and get one line back: `status`, `update` (sets `triggers.update`),
+/// `restart` (sets `triggers.restart`), `reload` (sets `triggers.reload`), `checkout <branch>` (sets `triggers.checkout`)
+/// or `stop` (sets `shutdown`, which stops every deployment)
+pub fn serve(path: &str, repo_path: &str, triggers: Arc<Triggers>, status: Arc<Mutex<health::Status>>, shutdown: Arc<Mutex<bool>>, stop: Arc<Mutex<bool>>) -> io::Result<thread::JoinHandle<()>> {
+    let context = Context { path: path.to_string(), repo_path: repo_path.to_string(), triggers, status, shutdown };
+    #[cfg(unix)]
+    {
+        unix::serve(context, stop)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (context, stop);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "control sockets need Unix domain sockets"))
+    }
+}
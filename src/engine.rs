@@ -0,0 +1,75 @@
+use std::sync::{Arc, Mutex};
+use git2::Repository;
+use crate::clock::{Clock, SystemClock};
+use crate::config::Config;
+use crate::errors::GdepError;
+use crate::summary::Summary;
+use crate::{state, UpdateRelationState};
+
+/// A deployment driven from code instead of the command line. It goes through the same steps as `gdep`:
+/// `new` clones and checks out, `check` and `apply_update` are one update cycle split in two, and `run` is the whole loop
+pub struct Engine {
+    config: Config,
+    repo: Repository,
+    repo_path: String,
+    branch: String,
+    clock: Arc<dyn Clock>,
+    _lock: state::Lock
+}
+
+impl Engine {
+    /// Opens the repo of `config`, cloning it first if needed, and checks out what the config asks for.
+    /// A fresh clone runs the `bootstrap` script on the checkout.
+    /// `repo_path` is where a `repo` without `into_path` is cloned to.
+    /// The config's `proxy`, credentials, `fetch_timeout` and `ca_bundle` are process wide, so they apply to every engine from here on.
+    /// The repo stays locked against other gdep instances until the engine is dropped
+    pub fn new(config: Config, repo_path: &str) -> Result<Self, GdepError> {
+        crate::apply_network_settings(&config)?;
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let (repo, cloned) = crate::get_repo_config(&config, &repo_path.to_string(), None, &*clock)?;
+        let lock = crate::lock_repo(&repo)?;
+        let repo_path = crate::repo_root(&repo);
+        let branch = crate::prepare(&repo, &config, None, &*clock)?;
+        if cloned {
+            crate::bootstrap(&config, &repo_path, &branch, &clock)?;
+        }
+        Ok(Self { config, repo, repo_path, branch, clock, _lock: lock })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn repository(&self) -> &Repository {
+        &self.repo
+    }
+
+    /// The working tree of the repo
+    pub fn path(&self) -> &str {
+        &self.repo_path
+    }
+
+    /// The configured branch, else the inferred default one
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// Fetches the branch and tells how the checkout relates to it. The working tree is left alone
+    pub fn check(&self) -> Result<UpdateRelationState, GdepError> {
+        crate::repo_update_cycle(&self.repo, &self.branch, &self.config, &*self.clock).map_err(|e| crate::tracking_error(e, &self.branch))
+    }
+
+    /// Fetches the branch and brings the checkout to it like the update thread does: a fast-forward,
+    /// or a reset or merge as `force` and `conflict_strategy` say, with the configured hooks around it
+    pub fn apply_update(&self) -> Result<(), GdepError> {
+        let tip = crate::fetch_branch(&self.repo, &self.branch, &self.config, &*self.clock).map_err(|e| crate::tracking_error(e, &self.branch))?;
+        crate::update_repo(&self.repo, &self.branch, tip.id(), &self.config)
+    }
+
+    /// Runs the script and keeps it updated, like `gdep` without `--once`, until the script stops for good,
+    /// gdep is interrupted or `shutdown` is set. The summary tells why it stopped
+    pub fn run(self, shutdown: Arc<Mutex<bool>>) -> Summary {
+        let Self { config, repo_path, branch, clock, _lock, .. } = self;
+        crate::deploy(config, repo_path, branch, shutdown, None, clock)
+    }
+}
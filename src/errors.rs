@@ -1,6 +1,7 @@
 use std::fmt;
+use std::process::ExitStatus;
 use git2::{Error, ErrorCode};
-use crate::config::ConfigError;
+use crate::config::{format_duration, ConfigError};
 
 #[derive(Debug, Clone)]
 pub enum GdepError {
@@ -8,12 +9,68 @@ pub enum GdepError {
     RemoteRepoNotFound(String),
     ConfigLoadError(ConfigError),
     BranchInferFailed,
+    UnexpectedBranch(String, String),
+    DetachedHead(String, String),
+    TrackedBranchGone(String),
+    CloneBranchNotFound(String, String),
+    /// The branch to follow and the branches origin has instead
+    BranchNotFound(String, Vec<String>),
+    FetchTimeout(String),
+    CommitNotFound(String),
+    NoMatchingTag(String),
+    StateDirFailed(String),
+    Interrupted(i32),
+    ScriptFailed(ExitStatus, Vec<String>),
+    ScriptTimeout(u64, Vec<String>),
+    NotReady(String),
     GitError(String, ErrorCode),
 
     UpdateErrorRepoAhead(usize),
     UpdateErrorAheadBehind(usize, usize),
+    PushFailed(String, String),
     
-    UpdateFailed(String, ErrorCode)
+    UpdateFailed(String, ErrorCode),
+    HookFailed(String, String),
+    TagSignatureInvalid(String, String),
+    SignatureVerificationFailed(String, String),
+    UnauthorizedAuthor(String, String),
+    CommitTooOld(String, u64, u64),
+    /// How many commits the update would bring in and `max_behind`
+    TooFarBehind(usize, usize),
+    CaBundleInvalid(String, String),
+    ProxyAuthFailed(String, String),
+    RepoOpenFailedInUpdateThread(String, String),
+    MergeConflict(Vec<String>),
+    LocalChangesConflict(Vec<String>),
+    WorkingTreeDirty(Vec<String>),
+    StashRestoreFailed(String, String),
+    CleanFailed(String, String),
+    RollbackFailed(String, String),
+    SubmoduleUpdateFailed(String, String),
+    /// The `lfs_command` and why it failed
+    LfsFetchFailed(String, String),
+    /// The release directory and why it could not be checked out or switched to
+    ReleaseFailed(String, String),
+    RemoteOverrideFailed(String, String),
+    LogFileFailed(String, String),
+    DeploymentFailed(String, Box<GdepError>),
+    WebhookFailed(String, String),
+    HealthEndpointFailed(String, String),
+    MetricsEndpointFailed(String, String),
+    ControlSocketFailed(String, String),
+    AlreadyRunning(String, Option<u32>),
+    ConfigCheckFailed(usize),
+    ConfigExists(String)
+}
+
+fn write_output(f: &mut fmt::Formatter<'_>, output: &[String]) -> fmt::Result {
+    if !output.is_empty() {
+        write!(f, ", last output:")?;
+        for line in output {
+            write!(f, "\n  | {}", line)?;
+        }
+    }
+    Ok(())
 }
 
 impl fmt::Display for GdepError {
@@ -23,14 +80,157 @@ impl fmt::Display for GdepError {
             GdepError::RemoteRepoNotFound(url) => write!(f, "Remote repository not found: {}", url),
             GdepError::ConfigLoadError(err) => write!(f, "Failed to load configuration: {}", err),
             GdepError::BranchInferFailed => write!(f, "Failed to infer branch"),
+            GdepError::TrackedBranchGone(branch) => write!(f, "Branch `{}` was deleted upstream", branch),
+            GdepError::CloneBranchNotFound(url, branch) => write!(f, "Cannot clone branch `{}`, `{}` has no such branch", branch, url),
+            GdepError::BranchNotFound(branch, available) if available.is_empty() => write!(f, "Origin has no branch `{}`, it has no branches at all", branch),
+            GdepError::BranchNotFound(branch, available) => write!(f, "Origin has no branch `{}`, available are: {}", branch, available.join(", ")),
+            GdepError::FetchTimeout(reason) => write!(f, "Fetch timed out: {}", reason),
+            GdepError::UnexpectedBranch(expected, actual) => write!(f, "Expected to deploy branch `{}`, but resolved `{}`", expected, actual),
+            GdepError::DetachedHead(sha, branch) => write!(f, "HEAD is detached at {} and there is no branch `{}` to re-attach it to", sha, branch),
+            GdepError::CommitNotFound(sha) => write!(f, "Commit not found, even after fetching: {}", sha),
+            GdepError::NoMatchingTag(pattern) => write!(f, "No tag matches `{}`", pattern),
+            GdepError::StateDirFailed(err) => write!(f, "Could not prepare state directory: {}", err),
+            GdepError::Interrupted(signal) => write!(f, "Interrupted by signal {}", signal),
+            GdepError::ScriptFailed(status, output) => {
+                write!(f, "Script failed ({})", status)?;
+                write_output(f, output)
+            },
+            GdepError::ScriptTimeout(secs, output) => {
+                write!(f, "Script timed out after {}", format_duration(*secs))?;
+                write_output(f, output)
+            },
+            GdepError::NotReady(reason) => write!(f, "Script did not become ready: {}", reason),
             GdepError::GitError(msg, code) => write!(f, "Git error ({:?}): {}", code, msg),
             GdepError::UpdateErrorRepoAhead(ahead) => write!(f, "Update failed: local repo is {} commits ahead", ahead),
             GdepError::UpdateErrorAheadBehind(ahead, behind) => write!(f, "Update failed: local repo is {} ahead, {} behind", ahead, behind),
+            GdepError::PushFailed(branch, reason) => write!(f, "Pushing the local commits of `{}` to origin failed: {}", branch, reason),
             GdepError::UpdateFailed(msg, code) => write!(f, "Update failed ({:?}): {}", code, msg),
+            GdepError::HookFailed(hook, reason) => write!(f, "The {} hook failed: {}", hook, reason),
+            GdepError::TagSignatureInvalid(tag, reason) => write!(f, "Signature of tag `{}` could not be verified: {}", tag, reason),
+            GdepError::SignatureVerificationFailed(sha, reason) => write!(f, "Refusing to deploy commit {}: its signature could not be verified: {}", sha, reason),
+            GdepError::UnauthorizedAuthor(sha, email) => write!(f, "Refusing to deploy commit {}: its author `{}` is not in allowed_authors", sha, email),
+            GdepError::SubmoduleUpdateFailed(name, reason) => write!(f, "Updating submodule `{}` failed: {}", name, reason),
+            GdepError::LfsFetchFailed(command, reason) => write!(f, "Fetching LFS files with `{}` failed: {}", command, reason),
+            GdepError::ReleaseFailed(path, reason) => write!(f, "Could not deploy the release `{}`: {}", path, reason),
+            GdepError::RemoteOverrideFailed(url, reason) => write!(f, "Cannot fetch from `{}` instead of origin: {}", url, reason),
+            GdepError::RollbackFailed(sha, reason) => write!(f, "Rolling back to {} failed: {}", sha, reason),
+            GdepError::CleanFailed(path, reason) => write!(f, "Could not remove untracked `{}`: {}", path, reason),
+            GdepError::StashRestoreFailed(stash, reason) => write!(f, "Local changes are kept in stash {}, they could not be restored: {}", stash, reason),
+            GdepError::MergeConflict(paths) => write!(f, "Merge aborted, conflicts in: {}", paths.join(", ")),
+            GdepError::LocalChangesConflict(paths) => write!(f, "Update refused, it would overwrite local changes to: {}", paths.join(", ")),
+            GdepError::WorkingTreeDirty(paths) => write!(f, "The checkout has uncommitted changes to: {}", paths.join(", ")),
+            GdepError::RepoOpenFailedInUpdateThread(path, reason) => write!(f, "Update thread could not open the repo at `{}`, no updates will be applied: {}", path, reason),
+            GdepError::ProxyAuthFailed(proxy, reason) => write!(f, "The proxy `{}` rejected the credentials: {}", proxy, reason),
+            GdepError::CaBundleInvalid(path, reason) => write!(f, "Cannot use CA bundle `{}`: {}", path, reason),
+            GdepError::ConfigCheckFailed(count) => write!(f, "The config has {} problem(s)", count),
+            GdepError::ConfigExists(path) => write!(f, "`{}` already exists, pass --force to overwrite it", path),
+            GdepError::AlreadyRunning(path, Some(pid)) => write!(f, "Another gdep (PID {}) is already running on `{}`", pid, path),
+            GdepError::AlreadyRunning(path, None) => write!(f, "Another gdep is already running on `{}`", path),
+            GdepError::HealthEndpointFailed(addr, reason) => write!(f, "Cannot serve health checks on `{}`: {}", addr, reason),
+            GdepError::MetricsEndpointFailed(addr, reason) => write!(f, "Cannot serve metrics on `{}`: {}", addr, reason),
+            GdepError::ControlSocketFailed(path, reason) => write!(f, "Cannot listen on the control socket `{}`: {}", path, reason),
+            GdepError::WebhookFailed(bind, reason) => write!(f, "Cannot listen for webhooks on `{}`: {}", bind, reason),
+            GdepError::DeploymentFailed(name, err) => write!(f, "Deployment `{}` failed: {}", name, err),
+            GdepError::LogFileFailed(path, reason) => write!(f, "Cannot write log file `{}`: {}", path, reason),
+            GdepError::TooFarBehind(behind, max) => write!(f, "Refusing to update: the checkout is {} commit(s) behind origin, more than max_behind ({}), update by hand or set force", behind, max),
+            GdepError::CommitTooOld(sha, age, max) => write!(f, "Refusing to deploy commit {}: it is {} old, the limit is {}", sha, format_duration(*age), format_duration(*max)),
         }
     }
 }
 
+impl GdepError {
+    /// The name of the variant, e.g. `ScriptFailed`, for machine readable output
+    pub fn variant(&self) -> String {
+        let debug = format!("{:?}", self);
+        debug.split(['(', ' ']).next().unwrap_or_default().to_string()
+    }
+
+    /// What gdep exits with when it stops because of this error. The script's own code if it failed,
+    /// 128 + the signal if gdep or the script was killed, and otherwise a fixed code from `GDEP_EXIT_CODES` by category:
+    /// 160-179 the config, 180-199 the repo and fetching, 200-219 applying an update, 220-239 a deployment
+    /// that was refused or failed, 240-254 what gdep itself needs to run. A script exiting with a code from that range
+    /// is reported as 1, so a code from it always comes from gdep. The codes don't change between versions
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GdepError::Interrupted(signal) => outside_gdep_codes(128 + signal),
+            GdepError::ScriptFailed(status, _) => outside_gdep_codes(status_code(status)),
+            GdepError::DeploymentFailed(_, err) => err.exit_code(),
+
+            GdepError::ConfigLoadError(_) => 160,
+            GdepError::ConfigCheckFailed(_) => 161,
+            GdepError::ConfigExists(_) => 162,
+            GdepError::CaBundleInvalid(..) => 163,
+            GdepError::UnexpectedBranch(..) => 164,
+
+            GdepError::GitError(..) => 180,
+            GdepError::LocalRepoNotFound(_) => 181,
+            GdepError::RemoteRepoNotFound(_) => 182,
+            GdepError::BranchInferFailed => 183,
+            GdepError::CloneBranchNotFound(..) => 184,
+            GdepError::TrackedBranchGone(_) => 185,
+            GdepError::CommitNotFound(_) => 186,
+            GdepError::NoMatchingTag(_) => 187,
+            GdepError::FetchTimeout(_) => 188,
+            GdepError::ProxyAuthFailed(..) => 189,
+            GdepError::BranchNotFound(..) => 190,
+
+            GdepError::UpdateFailed(..) => 200,
+            GdepError::UpdateErrorRepoAhead(_) => 201,
+            GdepError::UpdateErrorAheadBehind(..) => 202,
+            GdepError::MergeConflict(_) => 203,
+            GdepError::LocalChangesConflict(_) => 204,
+            GdepError::WorkingTreeDirty(_) => 205,
+            GdepError::DetachedHead(..) => 206,
+            GdepError::StashRestoreFailed(..) => 207,
+            GdepError::CleanFailed(..) => 208,
+            GdepError::PushFailed(..) => 209,
+
+            GdepError::SignatureVerificationFailed(..) => 220,
+            GdepError::TagSignatureInvalid(..) => 221,
+            GdepError::CommitTooOld(..) => 222,
+            GdepError::TooFarBehind(..) => 223,
+            GdepError::UnauthorizedAuthor(..) => 224,
+            GdepError::HookFailed(..) => 225,
+            GdepError::RollbackFailed(..) => 226,
+            GdepError::SubmoduleUpdateFailed(..) => 227,
+            GdepError::LfsFetchFailed(..) => 228,
+            GdepError::ReleaseFailed(..) => 229,
+            GdepError::RemoteOverrideFailed(..) => 230,
+            GdepError::NotReady(_) => 231,
+            GdepError::ScriptTimeout(..) => 232,
+
+            GdepError::StateDirFailed(_) => 240,
+            GdepError::LogFileFailed(..) => 241,
+            GdepError::WebhookFailed(..) => 242,
+            GdepError::HealthEndpointFailed(..) => 243,
+            GdepError::MetricsEndpointFailed(..) => 244,
+            GdepError::ControlSocketFailed(..) => 245,
+            GdepError::AlreadyRunning(..) => 246,
+            GdepError::RepoOpenFailedInUpdateThread(..) => 247
+        }
+    }
+}
+
+/// The exit codes of gdep's own errors, see `GdepError::exit_code`. Above 128 + the standard signals, and below 255,
+/// which a shell reports for an exit code out of range
+pub const GDEP_EXIT_CODES: std::ops::RangeInclusive<i32> = 160..=254;
+
+/// `code` of the script, or 1 if gdep's own errors use it
+fn outside_gdep_codes(code: i32) -> i32 {
+    if GDEP_EXIT_CODES.contains(&code) {1} else {code}
+}
+
+fn status_code(status: &ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal
+        }
+    }
+    status.code().filter(|c| *c != 0).unwrap_or(1)
+}
+
 impl std::error::Error for GdepError {}
 
 impl From<ConfigError> for GdepError {
@@ -41,6 +241,45 @@ impl From<ConfigError> for GdepError {
 
 impl From<Error> for GdepError {
     fn from(value: Error) -> Self {
+        if crate::proxy::auth_failed(&value) {
+            return GdepError::ProxyAuthFailed(crate::proxy::describe(), value.message().to_string())
+        }
+        if value.code() == ErrorCode::Timeout {
+            return GdepError::FetchTimeout(value.message().to_string())
+        }
         GdepError::GitError(value.message().to_string(), value.code())
     }
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use git2::ErrorClass;
+
+    fn script_failed(raw: i32) -> GdepError {
+        GdepError::ScriptFailed(ExitStatus::from_raw(raw), Vec::new())
+    }
+
+    #[test]
+    fn each_category_has_its_range() {
+        assert_eq!(GdepError::ConfigLoadError(ConfigError::MissingContent("name".to_string())).exit_code(), 160);
+        assert_eq!(GdepError::BranchNotFound("main".to_string(), Vec::new()).exit_code(), 190);
+        assert_eq!(GdepError::UpdateErrorRepoAhead(1).exit_code(), 201);
+        assert_eq!(GdepError::UnauthorizedAuthor("x".to_string(), "y".to_string()).exit_code(), 224);
+        assert_eq!(GdepError::AlreadyRunning("work".to_string(), None).exit_code(), 246);
+        // git errors become the variant they stand for
+        assert_eq!(GdepError::from(Error::new(ErrorCode::Timeout, ErrorClass::Net, "timed out")).exit_code(), 188);
+        assert_eq!(GdepError::from(Error::new(ErrorCode::NotFound, ErrorClass::Reference, "no ref")).exit_code(), 180);
+    }
+
+    #[test]
+    fn the_script_and_signals_keep_their_codes() {
+        assert_eq!(script_failed(3 << 8).exit_code(), 3);
+        assert_eq!(script_failed(9).exit_code(), 137);
+        // A script can't pass for one of gdep's errors
+        assert_eq!(script_failed(190 << 8).exit_code(), 1);
+        assert_eq!(GdepError::Interrupted(15).exit_code(), 143);
+        assert_eq!(GdepError::DeploymentFailed("api".to_string(), Box::new(script_failed(4 << 8))).exit_code(), 4);
+    }
+}
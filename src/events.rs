@@ -0,0 +1,104 @@
+use std::io::{self, Write};
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use crate::errors::GdepError;
+use crate::summary::Summary;
+
+/// Set by `--output json`
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// What `--output json` reports, one JSON object per line on stdout
+pub enum Event<'a> {
+    /// The update thread starts looking for updates
+    FetchStarted { branch: &'a str },
+    /// The fetch found nothing new
+    UpToDate { branch: &'a str },
+    /// `commit` describes `current`, if it could be read
+    UpdateApplied { previous: Option<git2::Oid>, current: git2::Oid, commit: Option<&'a CommitInfo> },
+    /// The script ended on its own (it was not stopped by gdep)
+    ScriptExited { status: ExitStatus },
+    /// `fatal` if gdep exits because of it
+    GdepError { err: &'a GdepError, fatal: bool },
+    /// The deployment stopped for good
+    Stopped { summary: &'a Summary }
+}
+
+/// What the log and the `update_applied` event say about a deployed commit
+pub struct CommitInfo {
+    /// The first line of the message
+    pub summary: String,
+    /// `Name <email>`
+    pub author: String
+}
+
+impl CommitInfo {
+    pub fn of(commit: &git2::Commit) -> Self {
+        let author = commit.author();
+        Self {
+            summary: commit.summary().unwrap_or_default().to_string(),
+            author: format!("{} <{}>", author.name().unwrap_or("?"), author.email().unwrap_or("?"))
+        }
+    }
+
+    /// Of commit `id` in the repo at `repo_path`, None if it can't be read
+    pub fn find(repo_path: &str, id: git2::Oid) -> Option<Self> {
+        let repo = git2::Repository::open(repo_path).ok()?;
+        let commit = repo.find_commit(id).ok()?;
+        Some(Self::of(&commit))
+    }
+}
+
+pub fn enable() {
+    JSON.store(true, Ordering::Relaxed);
+}
+
+fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+impl Event<'_> {
+    fn to_json(&self, deployment: Option<&str>) -> Value {
+        let (name, fields) = match self {
+            Event::FetchStarted { branch } => ("fetch_started", json!({"branch": branch})),
+            Event::UpToDate { branch } => ("up_to_date", json!({"branch": branch})),
+            Event::UpdateApplied { previous, current, commit } => ("update_applied", json!({
+                "previous": previous.map(|p| p.to_string()),
+                "current": current.to_string(),
+                "summary": commit.map(|c| c.summary.as_str()),
+                "author": commit.map(|c| c.author.as_str())
+            })),
+            Event::ScriptExited { status } => ("script_exited", json!({"success": status.success(), "code": status.code(), "signal": exit_signal(status)})),
+            Event::GdepError { err, fatal } => ("gdep_error", json!({"error": err.variant(), "message": err.to_string(), "fatal": fatal})),
+            Event::Stopped { summary } => ("stopped", Value::Object(summary.to_json()))
+        };
+        let mut event = Map::new();
+        event.insert("event".to_string(), json!(name));
+        event.insert("time".to_string(), json!(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)));
+        event.insert("deployment".to_string(), json!(deployment));
+        if let Value::Object(fields) = fields {
+            event.extend(fields);
+        }
+        Value::Object(event)
+    }
+}
+
+/// Prints `event` of `deployment` if `--output json` is on. Does nothing otherwise
+pub fn emit(deployment: Option<&str>, event: Event) {
+    if !JSON.load(Ordering::Relaxed) {
+        return
+    }
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{}", event.to_json(deployment));
+    let _ = stdout.flush();
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tiny_http::{Header, Response, Server};
+use crate::auth::{self, HttpAuth};
+use crate::{history, metrics, signals};
+
+/// How often the server looks at the stop flag while no request comes in
+const POLL_STOP: Duration = Duration::from_millis(100);
+
+/// What `execute` knows about a deployment, reported by the health endpoint
+#[derive(Clone, Debug, Default)]
+pub struct Status {
+    pub branch: String,
+    pub head: Option<String>,
+    pub script_running: bool,
+    pub updates_running: bool,
+    /// `mode: mirror` runs no script, so there is none to be running
+    pub mirror: bool,
+    pub last_fetch: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    /// The latest entry of the deploy history
+    pub last_deploy: Option<history::Entry>,
+    /// How many updates were applied since the deployment started
+    pub updates: u64,
+    /// How many fetches failed and were retried
+    pub fetch_failures: u64,
+    /// How often the script was started again
+    pub restarts: u64,
+    /// How often the script exited with each exit code
+    pub exit_codes: BTreeMap<i32, u64>,
+    /// How many commits the checkout was behind origin at the last fetch, None unless a branch is tracked
+    pub behind: Option<usize>,
+    /// When the script was last started, restarts sooner than `min_restart_interval` after it wait
+    pub last_start: Option<Instant>
+}
+
+impl Status {
+    pub fn new(branch: &str, head: Option<String>) -> Self {
+        Self { branch: branch.to_string(), head, ..Self::default() }
+    }
+
+    /// Healthy while the script runs (if there is one) and the update thread is still looking for updates
+    pub fn healthy(&self) -> bool {
+        (self.script_running || self.mirror) && self.updates_running
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "healthy": self.healthy(),
+            "branch": self.branch,
+            "head": self.head,
+            "script_running": self.script_running,
+            "updates_running": self.updates_running,
+            "last_fetch": self.last_fetch.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            "last_error": self.last_error,
+            "last_deploy": self.last_deploy.as_ref().map(history::Entry::to_json)
+        })
+    }
+}
+
+/// What a server started by `serve` answers
+#[derive(Clone)]
+pub struct Endpoints {
+    pub healthz: bool,
+    pub metrics: bool,
+    /// Credentials `/healthz` asks for, `health_auth`
+    pub healthz_auth: Option<HttpAuth>,
+    /// Credentials `/metrics` asks for, `metrics_auth`
+    pub metrics_auth: Option<HttpAuth>
+}
+
+/// Serves `GET /healthz` and/or `GET /metrics` from a new thread until `stop` is set or gdep is interrupted.
+/// `/healthz` answers 200 while the deployment is healthy, 503 otherwise, with the status as JSON either way,
+/// `/metrics` answers with the status in the Prometheus text format. An endpoint with credentials answers 401 without them
+pub fn serve(addr: &str, status: Arc<Mutex<Status>>, stop: Arc<Mutex<bool>>, endpoints: Endpoints) -> io::Result<thread::JoinHandle<()>> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+    if endpoints.healthz {
+        info!("Serving health checks on {}/healthz", addr);
+    }
+    if endpoints.metrics {
+        info!("Serving metrics on {}/metrics", addr);
+    }
+    let content_type = Header::from_bytes("Content-Type", "application/json").expect("Static header is valid");
+    let metrics_type = Header::from_bytes("Content-Type", metrics::CONTENT_TYPE).expect("Static header is valid");
+
+    crate::deployment_thread().spawn(move || {
+        while !*stop.lock().unwrap() && signals::received().is_none() {
+            let request = match server.recv_timeout(POLL_STOP) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Health endpoint failed to receive a request: {}", e);
+                    continue
+                }
+            };
+            let path = request.url().split('?').next().unwrap_or_default().to_string();
+            let rejected = match path.as_str() {
+                "/healthz" if endpoints.healthz => auth::check(endpoints.healthz_auth.as_ref(), &request),
+                "/metrics" if endpoints.metrics => auth::check(endpoints.metrics_auth.as_ref(), &request),
+                _ => None
+            };
+            if let Some(response) = rejected {
+                warn!("Rejected a request for {} from {}: bad credentials", path, request.remote_addr().map(|a| a.to_string()).unwrap_or_default());
+                if let Err(e) = request.respond(response) {
+                    debug!("Failed to answer health check: {}", e);
+                }
+                continue
+            }
+            let response = match path.as_str() {
+                "/healthz" if endpoints.healthz => {
+                    let status = status.lock().unwrap().clone();
+                    Response::from_string(status.to_json().to_string())
+                        .with_status_code(if status.healthy() {200} else {503})
+                        .with_header(content_type.clone())
+                }
+                "/metrics" if endpoints.metrics => {
+                    let status = status.lock().unwrap().clone();
+                    Response::from_string(metrics::render(&status)).with_header(metrics_type.clone())
+                }
+                _ => Response::from_string("not found").with_status_code(404)
+            };
+            if let Err(e) = request.respond(response) {
+                debug!("Failed to answer health check: {}", e);
+            }
+        }
+    })
+}
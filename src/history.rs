@@ -0,0 +1,172 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use crate::state::STATE_DIR;
+
+/// One JSON object per line, oldest first
+const HISTORY_FILE: &str = "history.jsonl";
+/// How many entries the history keeps, the oldest go once there are more
+pub const MAX_ENTRIES: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// The checkout moved to a new commit
+    Update,
+    /// The checkout moved back to `current` after `previous` failed
+    Rollback,
+    /// A run of the script (or one of its steps) ended
+    Script
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Update => "update",
+            Kind::Rollback => "rollback",
+            Kind::Script => "script"
+        }
+    }
+}
+
+/// A line of `<repo>/.gdep/history.jsonl`
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub kind: Kind,
+    pub time: DateTime<Utc>,
+    pub branch: String,
+    /// The commit checked out before, for updates and rollbacks
+    pub previous: Option<String>,
+    /// The commit checked out after, or the one the script ran at
+    pub current: Option<String>,
+    /// What the script exited with, None if it was killed by a signal or this isn't a script run
+    pub exit_code: Option<i32>,
+    pub error: Option<String>
+}
+
+impl Entry {
+    pub fn update(kind: Kind, branch: &str, previous: Option<git2::Oid>, current: git2::Oid) -> Self {
+        Self { kind, time: Utc::now(), branch: branch.to_string(), previous: previous.map(|p| p.to_string()), current: Some(current.to_string()), exit_code: None, error: None }
+    }
+
+    /// The script running at `head` ended with `status`, `error` tells why it counts as failed
+    pub fn script(branch: &str, head: Option<String>, status: Option<ExitStatus>, error: Option<String>) -> Self {
+        Self { kind: Kind::Script, time: Utc::now(), branch: branch.to_string(), previous: None, current: head, exit_code: status.and_then(|s| s.code()), error }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "kind": self.kind.as_str(),
+            "time": self.time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "branch": self.branch,
+            "previous": self.previous,
+            "current": self.current,
+            "exit_code": self.exit_code,
+            "error": self.error
+        })
+    }
+
+    /// None for lines that aren't entries
+    fn from_json(line: &str) -> Option<Self> {
+        let doc: Value = serde_json::from_str(line).ok()?;
+        let kind = [Kind::Update, Kind::Rollback, Kind::Script].into_iter().find(|k| doc["kind"].as_str() == Some(k.as_str()))?;
+        let string = |key: &str| doc[key].as_str().map(|t| t.to_string());
+        Some(Self {
+            kind,
+            time: DateTime::parse_from_rfc3339(doc["time"].as_str()?).ok()?.with_timezone(&Utc),
+            branch: string("branch")?,
+            previous: string("previous"),
+            current: string("current"),
+            exit_code: doc["exit_code"].as_i64().and_then(|c| i32::try_from(c).ok()),
+            error: string("error")
+        })
+    }
+
+    /// `2026-10-14T06:00:00Z main update 1a2b3c4 -> 5d6e7f8`, for `gdep history`
+    pub fn summary(&self) -> String {
+        let short = |sha: &Option<String>| sha.as_deref().map(|s| s.chars().take(7).collect()).unwrap_or_else(|| "none".to_string());
+        let what = match self.kind {
+            Kind::Update | Kind::Rollback => format!("{} {} -> {}", self.kind.as_str(), short(&self.previous), short(&self.current)),
+            Kind::Script => match self.exit_code {
+                Some(code) => format!("script at {} exited with {}", short(&self.current), code),
+                None => format!("script at {} was killed", short(&self.current))
+            }
+        };
+        let mut summary = format!("{} {} {}", self.time.to_rfc3339_opts(SecondsFormat::Secs, true), self.branch, what);
+        if let Some(error) = &self.error {
+            summary.push_str(&format!(": {}", error));
+        }
+        summary
+    }
+}
+
+fn path(repo_path: &str) -> PathBuf {
+    Path::new(repo_path).join(STATE_DIR).join(HISTORY_FILE)
+}
+
+/// Appends `entry` to the history of the repo at `repo_path`. Once it holds `MAX_ENTRIES`, it is written anew without the oldest,
+/// to a temporary file first so a crash never loses the whole history
+pub fn record(repo_path: &str, entry: &Entry) -> io::Result<()> {
+    let path = path(repo_path);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let line = entry.to_json().to_string();
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e)
+    };
+    let lines: Vec<&str> = content.lines().collect();
+    if lines.len() < MAX_ENTRIES {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        return writeln!(file, "{}", line)
+    }
+    let mut kept: Vec<&str> = lines[lines.len() + 1 - MAX_ENTRIES..].to_vec();
+    kept.push(&line);
+    let temporary = path.with_extension("jsonl.tmp");
+    fs::write(&temporary, format!("{}\n", kept.join("\n")))?;
+    fs::rename(temporary, path)
+}
+
+/// The last `count` entries, oldest first. None at all if there is no history yet
+pub fn last(repo_path: &str, count: usize) -> io::Result<Vec<Entry>> {
+    let content = match fs::read_to_string(path(repo_path)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+    let entries: Vec<Entry> = content.lines().filter_map(Entry::from_json).collect();
+    Ok(entries[entries.len().saturating_sub(count)..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_oldest_entries_go_past_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().to_string_lossy().to_string();
+        assert!(last(&repo, 5).unwrap().is_empty());
+        for i in 0..MAX_ENTRIES + 3 {
+            record(&repo, &Entry::script("main", Some(format!("{:040}", i)), None, None)).unwrap();
+        }
+        let entries = last(&repo, MAX_ENTRIES + 10).unwrap();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[0].current, Some(format!("{:040}", 3)));
+        assert_eq!(entries[MAX_ENTRIES - 1].current, Some(format!("{:040}", MAX_ENTRIES + 2)));
+        assert_eq!(last(&repo, 2).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn entries_survive_the_round_trip() {
+        let (previous, current) = (git2::Oid::from_str("1234567890abcdef").unwrap(), git2::Oid::from_str("abcdef1234567890").unwrap());
+        let mut update = Entry::update(Kind::Rollback, "main", Some(previous), current);
+        update.error = Some("the smoke test failed".to_string());
+        let back = Entry::from_json(&update.to_json().to_string()).unwrap();
+        assert_eq!(back.summary(), update.summary());
+        assert!(update.summary().ends_with(" main rollback 1234567 -> abcdef1: the smoke test failed"), "{}", update.summary());
+        assert!(Entry::from_json("{\"kind\": \"unknown\"}").is_none());
+    }
+}
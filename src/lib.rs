@@ -0,0 +1,2981 @@
+//! The update engine behind the `gdep` binary: cloning, fetching and updating a deployment's repo and supervising
+//! its script. [`Engine`] drives one deployment, the free functions are its single steps
+
+mod auth;
+pub mod clock;
+pub mod config;
+mod control;
+mod engine;
+pub mod errors;
+pub mod events;
+pub mod health;
+pub mod history;
+pub mod logfile;
+mod metrics;
+mod netrc;
+pub mod notify;
+pub mod output;
+mod process;
+mod proxy;
+mod release;
+pub mod reload;
+mod remote;
+mod retry;
+mod schedule;
+pub mod schema;
+pub mod signals;
+pub mod state;
+pub mod summary;
+pub mod systemd;
+mod tags;
+mod webhook;
+
+use std::{env, fs};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use git2::{Error, Repository, BranchType, AnnotatedCommit, AutotagOption, Signature};
+use std::string::ToString;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::cell::Cell;
+use std::thread;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::{debug, error, info, warn};
+use run_script::ScriptOptions;
+use run_script::types::IoOptions;
+use crate::clock::Clock;
+use crate::config::{format_duration, DEFAULT_CLONE_RETRIES, DEFAULT_REFSPEC, ConfigError, CheckoutMode, BranchSelector, ConflictStrategy, DeployMode, FetchTags, Mode, OnAhead, Track};
+use crate::errors::GdepError;
+use crate::events::{CommitInfo, Event};
+use crate::notify::Notification;
+use crate::output::{Forwarding, OutputTail, RateLimiter};
+use crate::process::ScriptChild;
+use crate::summary::{Reason, Summary};
+use crate::retry::Backoff;
+use crate::tags::TagFilter;
+use crate::errors::GdepError::{UpdateErrorAheadBehind, UpdateErrorRepoAhead, UpdateFailed};
+
+pub use crate::config::{Config, RepoLike};
+pub use crate::engine::Engine;
+
+pub const NAME: &str = env!("CARGO_PKG_NAME");
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
+pub static DEFAULT_REPO_PATH: &str = "gdep_used_repo";
+/// Bits of `IndexEntry::flags` holding the conflict stage
+const INDEX_STAGE_MASK: u16 = 0x3000;
+/// How often `execute` checks on the script and for shutdown signals
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(100);
+/// Pause between tries of `ready_check`
+const READY_INTERVAL: Duration = Duration::from_secs(1);
+/// Pause between attempts of a clone that failed because of the network
+const CLONE_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// What `mode: mirror` fetches: every ref, as origin has it
+const MIRROR_REFSPEC: &str = "+refs/*:refs/*";
+/// What `branch_selector: newest` fetches to compare the branches of origin
+const ALL_BRANCHES_REFSPEC: &str = "+refs/heads/*:refs/remotes/origin/*";
+/// The branches inferred without `include_branches`, in order of preference
+const DEFAULT_BRANCHES: [&str; 2] = ["main", "master"];
+
+#[macro_export]
+macro_rules! conv_err {
+    ($pre:expr, $err: expr) => {
+        $pre.or_else(|_| { Err($err) })
+    };
+}
+
+#[macro_export]
+macro_rules! conv_err_e {
+    ($pre:expr, $err: expr) => {
+        $pre.or_else(|e| { Err($err(e.to_string())) })
+    };
+}
+
+/// Messages sent from the update thread to `execute`
+#[derive(Debug)]
+pub enum UpdateSignal {
+    /// Sent before every update cycle
+    Alive,
+    /// Sent after every successful fetch, with how many commits the local branch is behind the fetched one when tracking a branch
+    Fetched { behind: Option<usize> },
+    /// Sent when a fetch failed and is retried
+    FetchFailed,
+    /// With `branch_selector: newest`, another branch got the newest commit and should be switched to
+    BranchSelected(String),
+    /// The local repo was fast-forwarded / merged to the remote state, moving HEAD from `previous` to `current`
+    Updated { previous: Option<git2::Oid>, current: git2::Oid },
+    /// The update thread stopped, optionally because of an error
+    Stopped(Option<GdepError>)
+}
+
+pub fn head_id(repo: &Repository) -> Option<git2::Oid> {
+    repo.head().ok()?.peel_to_commit().ok().map(|c| c.id())
+}
+
+fn send_updated(repo: &Repository, sender: &mpsc::Sender<UpdateSignal>, previous: Option<git2::Oid>) -> Result<(), GdepError> {
+    let commit = repo.head()?.peel_to_commit()?;
+    let current = commit.id();
+    let info = CommitInfo::of(&commit);
+    info!("Updated to {:.7} \"{}\" by {}", current.to_string(), info.summary, info.author);
+    sender.send(UpdateSignal::Updated { previous, current }).expect("Failed to send update signal to main thread");
+    Ok(())
+}
+
+/// Holds commits back that failed their smoke test or verification or broke the script, printing a note the first time `target` is skipped
+fn held_back(state_dir: &Path, target: git2::Oid, last_skipped: &mut Option<git2::Oid>) -> bool {
+    if !state::is_quarantined(state_dir, &target.to_string()) {
+        return false
+    }
+    if *last_skipped != Some(target) {
+        warn!("Commit {} was rolled back or failed verification before, waiting for a newer one", target);
+        *last_skipped = Some(target);
+    }
+    true
+}
+
+/// Paces an update loop: every cycle but the first waits `poll_interval` before fetching.
+/// The wait ends early when a webhook sets `trigger` or gdep stops. With `once` there is only the first cycle
+struct Poller {
+    interval: Duration,
+    trigger: Arc<AtomicBool>,
+    stop_flag: Arc<Mutex<bool>>,
+    /// What the waits are measured on
+    clock: Arc<dyn Clock>,
+    first: bool,
+    once: bool,
+    /// Set by `wake_within`, shortens the next wait only
+    next_wait: Option<Duration>
+}
+
+impl Poller {
+    fn new(config: &Config, trigger: Arc<AtomicBool>, stop_flag: Arc<Mutex<bool>>, clock: Arc<dyn Clock>, once: bool) -> Self {
+        Self { interval: Duration::from_secs(config.poll_interval), trigger, stop_flag, clock, first: true, once, next_wait: None }
+    }
+
+    /// Waits for the next cycle. False once gdep stops
+    fn next_cycle(&mut self) -> bool {
+        if *self.stop_flag.lock().unwrap() {
+            return false
+        }
+        if std::mem::take(&mut self.first) {
+            return true
+        }
+        if self.once {
+            return false
+        }
+        let wait = self.next_wait.take().map_or(self.interval, |wait| wait.min(self.interval));
+        self.clock.sleep_interruptible(wait, &mut || self.trigger.swap(false, Ordering::SeqCst) || *self.stop_flag.lock().unwrap());
+        !*self.stop_flag.lock().unwrap()
+    }
+
+    /// The current cycle failed and is tried again, which doesn't count as a new one with `once`
+    fn repeat(&mut self) {
+        if self.once {
+            self.first = true;
+        }
+    }
+
+    /// Starts the next cycle after `wait` at the latest
+    fn wake_within(&mut self, wait: Duration) {
+        self.next_wait = Some(wait);
+    }
+}
+
+/// Holds back updates for `debounce` after one was applied, so a burst of pushes is applied as one.
+/// The newest state is applied when the window ends, the poller wakes up for that
+struct Debounce<'a> {
+    window: Option<Duration>,
+    applied: Option<Instant>,
+    waiting: bool,
+    clock: &'a dyn Clock
+}
+
+impl<'a> Debounce<'a> {
+    fn new(config: &Config, clock: &'a dyn Clock) -> Self {
+        Self { window: config.debounce.map(Duration::from_secs), applied: None, waiting: false, clock }
+    }
+
+    /// Whether the update found in this cycle has to wait
+    fn holds(&mut self, poller: &mut Poller) -> bool {
+        let left = self.window.zip(self.applied).map_or(Duration::ZERO, |(window, at)| window.saturating_sub(self.clock.elapsed(at)));
+        if left.is_zero() {
+            self.waiting = false;
+            return false
+        }
+        if !self.waiting {
+            info!("Update available, applying it once the debounce window ends in {}", format_duration(left.as_secs_f64().ceil() as u64));
+            self.waiting = true;
+        }
+        poller.wake_within(left);
+        true
+    }
+
+    fn applied(&mut self) {
+        self.applied = Some(self.clock.now());
+    }
+}
+
+/// Checks out the pinned commit unless HEAD already is there, then idles until gdep stops:
+/// a pinned deployment never changes, so there is nothing to poll for
+fn hold_pinned_commit(repo: &Repository, sha: &str, config: &Config, poller: &mut Poller, sender: &mpsc::Sender<UpdateSignal>) -> Result<(), GdepError> {
+    sender.send(UpdateSignal::Alive).expect("Failed to send alive signal to main thread");
+
+    let head = head_id(repo);
+    let pinned = repo.revparse_single(sha).ok().and_then(|o| o.peel_to_commit().ok()).map(|c| c.id());
+    if head.is_none() || head != pinned {
+        deploy_commit(repo, sha, config.depth)?;
+        send_updated(repo, sender, head)?;
+    }
+    debug!("Pinned commit `{}` is deployed, not polling for updates", sha);
+
+    // Nothing to poll for, this only waits until gdep stops (or returns right away with `once`)
+    poller.next_cycle();
+    while poller.next_cycle() {}
+    Ok(())
+}
+
+/// Has scripts run by the configured `shell` or `runner` instead of the platform's shell
+fn set_runner(options: &mut ScriptOptions, config: &Config) {
+    if let Some((program, args)) = config.runner.split_first() {
+        options.runner = Some(program.clone());
+        options.runner_args = Some(args.to_vec());
+    } else if let Some(shell) = &config.shell {
+        options.runner = Some(shell.clone());
+    }
+}
+
+fn run_hook(name: &str, script: &Option<String>, repo: &Repository, config: &Config, env: HashMap<String, String>) -> Result<(), GdepError> {
+    let Some(script) = script else {
+        return Ok(())
+    };
+    info!("Running {} hook...", name);
+
+    let mut options = ScriptOptions::new();
+    options.working_directory = repo.workdir().map(|p| p.to_path_buf());
+    options.output_redirection = IoOptions::Inherit;
+    options.env_vars = Some(env);
+    set_runner(&mut options, config);
+
+    let status = process::spawn(script, &[], &options, config.run_as.as_deref()).and_then(|mut child| child.wait())
+        .map_err(|e| GdepError::HookFailed(name.to_string(), e.to_string()))?;
+    if !status.success() {
+        return Err(GdepError::HookFailed(name.to_string(), status.to_string()))
+    }
+    Ok(())
+}
+
+/// Brings every submodule (recursively) to the commit the superproject records, initializing new ones.
+/// They are fetched with the same credentials as the parent repo, looked up for the host each submodule lives on
+pub fn update_submodules(repo: &Repository) -> Result<(), GdepError> {
+    for mut submodule in repo.submodules()? {
+        let name = submodule.name().unwrap_or("?").to_string();
+        let url = submodule.url().unwrap_or("?").to_string();
+        let failed = |e: Error| GdepError::SubmoduleUpdateFailed(name.clone(), format!("{} (from {})", e.message(), url));
+
+        let mut options = git2::SubmoduleUpdateOptions::new();
+        options.fetch(remote::fetch_options(None));
+        submodule.update(true, Some(&mut options)).map_err(failed)?;
+
+        update_submodules(&submodule.open().map_err(failed)?)?;
+    }
+    Ok(())
+}
+
+/// With `lfs`: runs `lfs_command` in the checkout, so the LFS pointer files libgit2 checked out are replaced by the files.
+/// It authenticates with the credentials gdep fetches with, see `remote::git_env`
+fn fetch_lfs(repo: &Repository, config: &Config) -> Result<(), GdepError> {
+    if !config.lfs {
+        return Ok(())
+    }
+    info!("Fetching LFS files...");
+    let failed = |reason: String| GdepError::LfsFetchFailed(config.lfs_command.clone(), reason);
+    let url = repo.find_remote("origin").ok().and_then(|r| r.url().map(str::to_string)).unwrap_or_default();
+
+    let mut options = ScriptOptions::new();
+    options.working_directory = repo.workdir().map(|p| p.to_path_buf());
+    options.output_redirection = IoOptions::Inherit;
+    options.env_vars = Some(remote::git_env(&url));
+
+    let status = process::spawn(&config.lfs_command, &[], &options, config.run_as.as_deref()).and_then(|mut child| child.wait())
+        .map_err(|e| failed(e.to_string()))?;
+    if !status.success() {
+        return Err(failed(status.to_string()))
+    }
+    Ok(())
+}
+
+/// Deletes untracked files and directories, like `git clean -fd` (`-fdx` with `ignored`). gdep's own state dir is kept
+fn clean_worktree(repo: &Repository, ignored: bool) -> Result<(), GdepError> {
+    let Some(workdir) = repo.workdir() else {
+        return Ok(())
+    };
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(false).include_ignored(ignored).recurse_ignored_dirs(false);
+    let statuses = repo.statuses(Some(&mut options))?;
+    let state_dir = format!("{}/", state::STATE_DIR);
+    let mut removed = 0;
+    for entry in statuses.iter().filter(|e| e.status().intersects(git2::Status::WT_NEW | git2::Status::IGNORED)) {
+        let Some(path) = entry.path().filter(|p| *p != state_dir) else {
+            continue
+        };
+        let full = workdir.join(path);
+        let result = if path.ends_with('/') {fs::remove_dir_all(&full)} else {fs::remove_file(&full)};
+        result.map_err(|e| GdepError::CleanFailed(path.to_string(), e.to_string()))?;
+        debug!("Removed untracked `{}`", path);
+        removed += 1;
+    }
+    if removed > 0 {
+        info!("Removed {} untracked path(s) from the working tree", removed);
+    }
+    Ok(())
+}
+
+/// Applies an update between the `pre_update` and `post_update` hooks.
+/// A failing `pre_update` hook aborts the update, `post_update` gets the new HEAD as `GDEP_COMMIT`
+fn with_hooks(repo: &Repository, config: &Config, clock: &dyn Clock, apply: impl FnOnce() -> Result<(), GdepError>) -> Result<(), GdepError> {
+    run_hook("pre_update", &config.pre_update, repo, config, HashMap::new())?;
+    apply()?;
+    if config.clean_untracked {
+        clean_worktree(repo, config.clean_ignored)?;
+    }
+    if config.submodules {
+        update_submodules(repo)?;
+    }
+    fetch_lfs(repo, config)?;
+    if config.deploy_mode == DeployMode::Symlink {
+        release::stage(repo, config, clock)?;
+    }
+    if config.post_update.is_some() {
+        let head = repo.head()?.peel_to_commit()?.id();
+        run_hook("post_update", &config.post_update, repo, config, HashMap::from([("GDEP_COMMIT".to_string(), head.to_string())]))?;
+    }
+    Ok(())
+}
+
+/// Refuses to deploy `target` if it was committed longer ago than `max_commit_age`,
+/// e.g. because the branch was reset to an ancient state upstream
+fn check_commit_age(repo: &Repository, config: &Config, target: git2::Oid, clock: &dyn Clock) -> Result<(), GdepError> {
+    let Some(max_age) = config.max_commit_age else {
+        return Ok(())
+    };
+    let commit = repo.find_commit(target)?;
+    let age = u64::try_from(clock.now_utc().timestamp() - commit.time().seconds()).unwrap_or(0);
+    if age > max_age {
+        return Err(GdepError::CommitTooOld(target.to_string(), age, max_age))
+    }
+    Ok(())
+}
+
+/// With `require_signed`, refuses to deploy `target` unless gpg accepts its signature
+/// (and it was made by one of `allowed_signers`, if given)
+fn check_signature(repo: &Repository, config: &Config, target: git2::Oid) -> Result<(), GdepError> {
+    if !config.require_signed {
+        return Ok(())
+    }
+    tags::verify_commit(repo, target, &config.allowed_signers)
+        .map_err(|e| GdepError::SignatureVerificationFailed(target.to_string(), e))?;
+    debug!("Signature of commit {} verified", target);
+    Ok(())
+}
+
+/// With `allowed_authors`, refuses to deploy `target` unless every commit it brings in, the ones HEAD doesn't have yet,
+/// was authored by an email matching one of them (case-insensitively)
+fn check_authors(repo: &Repository, config: &Config, target: git2::Oid) -> Result<(), GdepError> {
+    if config.allowed_authors.is_empty() {
+        return Ok(())
+    }
+    let allowed = patterns(&config.allowed_authors);
+    let options = glob::MatchOptions { case_sensitive: false, ..Default::default() };
+    let mut walk = repo.revwalk()?;
+    walk.push(target)?;
+    if let Some(head) = head_id(repo) {
+        walk.hide(head)?;
+    }
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+        let email = commit.author().email().unwrap_or_default().to_string();
+        if !allowed.iter().any(|p| p.matches_with(&email, options)) {
+            return Err(GdepError::UnauthorizedAuthor(commit.id().to_string(), email))
+        }
+    }
+    debug!("Every commit up to {} has an allowed author", target);
+    Ok(())
+}
+
+/// Removes the worktree `name` at `path` and the branch it had checked out, as far as they exist
+fn remove_worktree(repo: &Repository, name: &str, path: &Path) {
+    if let Ok(worktree) = repo.find_worktree(name) {
+        let _ = worktree.prune(Some(git2::WorktreePruneOptions::new().valid(true).locked(true).working_tree(true)));
+    }
+    let _ = fs::remove_dir_all(path);
+    if let Ok(mut branch) = repo.find_branch(name, BranchType::Local) {
+        let _ = branch.delete();
+    }
+}
+
+/// With `verify_script`, runs it in a temporary worktree checked out at `target`, removed again afterwards.
+/// A commit that fails is quarantined, so it is never applied. Whether `target` may be applied
+fn verify_passed(repo: &Repository, config: &Config, branch: &str, state_dir: &Path, target: git2::Oid, clock: &dyn Clock) -> Result<bool, GdepError> {
+    let Some(script) = &config.verify_script else {
+        return Ok(true)
+    };
+    let name = format!("gdep-verify-{}", target);
+    let path = env::temp_dir().join(&name);
+    // Left behind if gdep was killed while verifying
+    remove_worktree(repo, &name, &path);
+
+    info!("Verifying commit {}...", target);
+    let worktree_branch = repo.branch(&name, &repo.find_commit(target)?, true)?;
+    let mut options = git2::WorktreeAddOptions::new();
+    options.reference(Some(worktree_branch.get()));
+    let verified = repo.worktree(&name, &path, Some(&options)).map_err(GdepError::from).map(|_| {
+        probe_passed(config, script, &path.to_string_lossy(), branch, Duration::from_secs(config.verify_timeout), clock).unwrap_or_else(|e| {
+            warn!("Verify script {}", e);
+            false
+        })
+    });
+    remove_worktree(repo, &name, &path);
+    if matches!(verified, Ok(false)) {
+        error!("Commit {} failed verification, not applying it", target);
+        conv_err_e!(state::quarantine(state_dir, &target.to_string()), GdepError::StateDirFailed)?;
+    }
+    verified
+}
+
+/// Follows the tip of `branch_name`, applying every update until gdep stops
+fn track_branch(repo: &Repository, branch_name: &str, config: &Config, state_dir: &Path, stop_flag: &Mutex<bool>, poller: &mut Poller, sender: &mpsc::Sender<UpdateSignal>) -> Result<(), GdepError> {
+    let mut deferred = false;
+    let mut skipped = None;
+    let clock = Arc::clone(&poller.clock);
+    let mut backoff = Backoff::new(config.max_fetch_retries, &*clock);
+    let mut update_backoff = Backoff::new(config.update_retries, &*clock);
+    let mut debounce = Debounce::new(config, &*clock);
+    let mut ignored_ahead = None;
+    while poller.next_cycle() {
+        sender.send(UpdateSignal::Alive).expect("Failed to send alive signal to main thread");
+        events::emit(Some(&config.name), Event::FetchStarted { branch: branch_name });
+        // Someone (or `--deploy-commit`) may have checked out a commit in the meantime
+        attach_head(repo, branch_name)?;
+        if config.branch_selector == BranchSelector::Newest {
+            match newest_branch(repo, config, &*clock) {
+                Ok(Some(newest)) if newest != branch_name => {
+                    info!("Branch `{}` has the newest commit now", newest);
+                    sender.send(UpdateSignal::BranchSelected(newest)).expect("Failed to send branch signal to main thread");
+                    // The main thread stops this thread to switch
+                    continue
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Could not look for a newer branch, staying on `{}`: {}", branch_name, e.message())
+            }
+        }
+
+        let urs = match repo_update_cycle(repo, branch_name, config, &*clock) {
+            Ok(urs) => urs,
+            Err(e) if backoff.retry(&e, stop_flag) => {
+                sender.send(UpdateSignal::FetchFailed).expect("Failed to send fetch signal to main thread");
+                poller.repeat();
+                continue
+            }
+            Err(e) => match tracking_error(e, branch_name) {
+                e if update_backoff.retry_update(&e, stop_flag) => {
+                    poller.repeat();
+                    poller.wake_within(Duration::ZERO);
+                    continue
+                }
+                e => return Err(e)
+            }
+        };
+        backoff.reset();
+        let behind = match urs {
+            UpdateRelationState::Behind(b) | UpdateRelationState::AheadBehind(_, b) => b,
+            _ => 0
+        };
+        sender.send(UpdateSignal::Fetched { behind: Some(behind) }).expect("Failed to send fetch signal to main thread");
+        debug!("Fetched branch `{}`: {:?}", branch_name, urs);
+
+        match urs {
+            UpdateRelationState::Up2Date => {
+                events::emit(Some(&config.name), Event::UpToDate { branch: branch_name });
+                continue
+            }
+            UpdateRelationState::Ahead(a) if !config.force && config.on_ahead == OnAhead::Push => {
+                push_branch(repo, branch_name, a)?;
+            }
+            // With merging enabled, being ahead is just the merge commits on top of the remote tip
+            UpdateRelationState::Ahead(_) if config.conflict_strategy.is_some() && !config.force => { continue }
+            UpdateRelationState::Ahead(a) if !config.force && config.on_ahead == OnAhead::Ignore => {
+                if ignored_ahead != Some(a) {
+                    info!("Local repo is {} commit(s) ahead of origin, not updating until origin moves", a);
+                    ignored_ahead = Some(a);
+                }
+                continue
+            }
+            UpdateRelationState::Ahead(a) if !config.force => {
+                return Err(UpdateErrorRepoAhead(a))
+            }
+            // Diverged branches are only merged if a conflict strategy says how
+            UpdateRelationState::AheadBehind(a, b) if !config.force && config.conflict_strategy.is_none() => {
+                return Err(UpdateErrorAheadBehind(a, b))
+            }
+            UpdateRelationState::Behind(b) | UpdateRelationState::AheadBehind(_, b) if !config.force && config.max_behind.is_some_and(|max| b > max) => {
+                return Err(GdepError::TooFarBehind(b, config.max_behind.unwrap_or_default()))
+            }
+            _ => {
+                let target = repo.refname_to_id(&format!("refs/remotes/origin/{}", branch_name))?;
+                if held_back(state_dir, target, &mut skipped) {
+                    continue
+                }
+                if config.deploy_schedule.as_ref().is_some_and(|s| !s.allows(clock.now_utc())) {
+                    if !deferred {
+                        info!("Update available, deferring it until the deploy window opens");
+                        deferred = true;
+                    }
+                    continue
+                }
+                deferred = false;
+                if debounce.holds(poller) {
+                    continue
+                }
+                check_commit_age(repo, config, target, &*clock)?;
+                check_signature(repo, config, target)?;
+                check_authors(repo, config, target)?;
+                if !verify_passed(repo, config, branch_name, state_dir, target, &*clock)? {
+                    continue
+                }
+                let previous = head_id(repo);
+                let applied = with_hooks(repo, config, &*clock, || with_stash(repo, config, || {
+                    // Without ancestry there is nothing to fast-forward or merge along, the checkout simply moves to the remote tip
+                    if config.force || repo.is_shallow() {
+                        force_reset(repo, target, &urs)
+                    } else {
+                        update_repo(repo, branch_name, target, config).map_err(|e| match e {
+                            GdepError::GitError(msg, code) => UpdateFailed(msg, code),
+                            e => e
+                        })
+                    }
+                }));
+                match applied {
+                    // The whole cycle is gone through again right away, fetch included
+                    Err(e) if update_backoff.retry_update(&e, stop_flag) => {
+                        poller.repeat();
+                        poller.wake_within(Duration::ZERO);
+                        continue
+                    }
+                    result => result?
+                }
+                update_backoff.reset();
+                debounce.applied();
+                send_updated(repo, sender, previous)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Every ref of `repo` and where it points
+fn ref_targets(repo: &Repository) -> Result<HashMap<String, Option<git2::Oid>>, Error> {
+    repo.references()?.map(|r| r.map(|r| (r.name().unwrap_or_default().to_string(), r.target()))).collect()
+}
+
+/// `mode: mirror`: makes every ref of the bare `repo` match origin's on each cycle, deleted ones included
+fn track_mirror(repo: &Repository, config: &Config, stop_flag: &Mutex<bool>, poller: &mut Poller, sender: &mpsc::Sender<UpdateSignal>) -> Result<(), GdepError> {
+    let clock = Arc::clone(&poller.clock);
+    let mut backoff = Backoff::new(config.max_fetch_retries, &*clock);
+    let mut remote = repo.find_remote("origin")?;
+    while poller.next_cycle() {
+        sender.send(UpdateSignal::Alive).expect("Failed to send alive signal to main thread");
+        events::emit(Some(&config.name), Event::FetchStarted { branch: MIRROR_REFSPEC });
+        let before = ref_targets(repo)?;
+        match fetch_refspec(&mut remote, MIRROR_REFSPEC, config, true, &*clock) {
+            Ok(()) => backoff.reset(),
+            Err(e) if backoff.retry(&e, stop_flag) => {
+                sender.send(UpdateSignal::FetchFailed).expect("Failed to send fetch signal to main thread");
+                poller.repeat();
+                continue
+            }
+            Err(e) => return Err(e.into())
+        }
+        sender.send(UpdateSignal::Fetched { behind: None }).expect("Failed to send fetch signal to main thread");
+        let after = ref_targets(repo)?;
+        let changed = after.iter().filter(|(name, target)| before.get(*name) != Some(target)).count()
+            + before.keys().filter(|name| !after.contains_key(*name)).count();
+        if changed == 0 {
+            events::emit(Some(&config.name), Event::UpToDate { branch: MIRROR_REFSPEC });
+        } else {
+            info!("Mirrored {} changed ref(s)", changed);
+        }
+    }
+    Ok(())
+}
+
+/// Puts a detached HEAD (e.g. left behind by a tag or commit deployment) back on `branch`, so tracking it
+/// compares and merges against the right commit. The local branch is created from origin's if there is none
+fn attach_head(repo: &Repository, branch: &str) -> Result<(), GdepError> {
+    if !repo.head_detached()? {
+        return Ok(())
+    }
+    let head = head_id(repo).map(|id| id.to_string()).unwrap_or_default();
+    let refname = format!("refs/heads/{}", branch);
+    if repo.find_reference(&refname).is_err() {
+        let remote = repo.find_reference(&format!("refs/remotes/origin/{}", branch)).and_then(|r| r.peel_to_commit())
+            .map_err(|_| GdepError::DetachedHead(head.clone(), branch.to_string()))?;
+        repo.branch(branch, &remote, false)?;
+    }
+    warn!("HEAD is detached at {}, re-attaching it to branch `{}`", head, branch);
+    repo.set_head(&refname)?;
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+    Ok(())
+}
+
+/// Moves the checkout to `branch` unless it is on it already, creating the local branch from origin's if there is none.
+/// An inferred branch, e.g. one `branch_selector: newest` or `include_branches` picks, need not be the one checked out
+fn check_out_branch(repo: &Repository, branch: &str) -> Result<(), GdepError> {
+    if repo.head()?.shorthand() == Some(branch) {
+        return Ok(())
+    }
+    let refname = format!("refs/heads/{}", branch);
+    if repo.find_reference(&refname).is_err() {
+        let remote = repo.find_reference(&format!("refs/remotes/origin/{}", branch))?.peel_to_commit()?;
+        repo.branch(branch, &remote, false)?;
+    }
+    repo.set_head(&refname)?;
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+    info!("Checked out branch `{}`", branch);
+    Ok(())
+}
+
+/// With `stash_local_changes`, stashes uncommitted changes to tracked files before `apply` overwrites them,
+/// and puts them back afterwards unless `restore_stash` is off. A stash that doesn't apply cleanly is kept
+fn with_stash(repo: &Repository, config: &Config, apply: impl FnOnce() -> Result<(), GdepError>) -> Result<(), GdepError> {
+    if !config.stash_local_changes {
+        return apply()
+    }
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    if repo.statuses(Some(&mut options))?.is_empty() {
+        return apply()
+    }
+
+    // Stashing needs a mutable handle, the shared one is borrowed by the update loop
+    let mut stash_repo = Repository::open(repo.path())?;
+    let sig = merge_signature(repo, config)?;
+    let stash = stash_repo.stash_save(&sig, "gdep: local changes before update", None)?;
+    warn!("Stashed local changes as {} before updating", stash);
+    let result = apply();
+    if !config.restore_stash {
+        return result
+    }
+    // The update rewrote the index behind this handle's back
+    stash_repo.index()?.read(true)?;
+    let failed = |reason: String| GdepError::StashRestoreFailed(stash.to_string(), reason);
+    stash_repo.stash_apply(0, None).map_err(|e| failed(e.message().to_string()))?;
+    // Applying writes conflict markers instead of failing, the deployed tree must not keep them
+    if stash_repo.index()?.has_conflicts() {
+        let head = stash_repo.head()?.peel_to_commit()?;
+        stash_repo.reset(head.as_object(), git2::ResetType::Hard, Some(git2::build::CheckoutBuilder::default().force()))?;
+        return Err(failed("they conflict with the update".to_string()))
+    }
+    stash_repo.stash_drop(0)?;
+    info!("Restored the stashed local changes");
+    result
+}
+
+/// `on_ahead: push`: pushes the `ahead` local commits of `branch_name` to origin with the shared credentials.
+/// Fails if origin rejects the update, e.g. because it moved in the meantime
+fn push_branch(repo: &Repository, branch_name: &str, ahead: usize) -> Result<(), GdepError> {
+    let failed = |reason: &str| GdepError::PushFailed(branch_name.to_string(), reason.to_string());
+    let rejected = Cell::new(None);
+    let mut callbacks = remote::callbacks();
+    callbacks.push_update_reference(|_, status| {
+        rejected.set(status.map(|s| s.to_string()));
+        Ok(())
+    });
+    let mut options = git2::PushOptions::new();
+    options.remote_callbacks(callbacks);
+    options.proxy_options(proxy::options());
+
+    let refspec = format!("refs/heads/{0}:refs/heads/{0}", branch_name);
+    repo.find_remote("origin")?.push(&[&refspec], Some(&mut options)).map_err(|e| failed(e.message()))?;
+    if let Some(reason) = rejected.take() {
+        return Err(failed(&reason))
+    }
+    info!("Pushed {} local commit(s) of `{}` to origin", ahead, branch_name);
+    Ok(())
+}
+
+/// Hard resets the checkout to `target`, the remote tip the update was checked at, discarding local commits and changes
+fn force_reset(repo: &Repository, target: git2::Oid, state: &UpdateRelationState) -> Result<(), GdepError> {
+    if let UpdateRelationState::Ahead(a) | UpdateRelationState::AheadBehind(a, _) = state {
+        warn!("Discarding {} local commit(s)", a);
+    }
+    let target = repo.find_commit(target)?;
+    repo.reset(target.as_object(), git2::ResetType::Hard, Some(git2::build::CheckoutBuilder::default().force()))?;
+    Ok(())
+}
+
+/// Fetches all tags and picks the newest one accepted by `tag_pattern`
+pub fn resolve_latest_tag(repo: &Repository, config: &Config, clock: &dyn Clock) -> Result<(String, git2::Oid), GdepError> {
+    tags::fetch_tags(repo, config, clock)?;
+    pick_latest_tag(repo, config)
+}
+
+/// The newest already fetched tag accepted by `tag_pattern`
+fn pick_latest_tag(repo: &Repository, config: &Config) -> Result<(String, git2::Oid), GdepError> {
+    let filter = TagFilter::parse(config.tag_pattern.as_deref())
+        .map_err(|e| ConfigError::InvalidValue("tag_pattern".to_string(), e))?;
+    tags::latest_tag(repo, &filter, config.require_annotated_tags)?
+        .ok_or_else(|| GdepError::NoMatchingTag(config.tag_pattern.clone().unwrap_or_else(|| "*".to_string())))
+}
+
+/// Logs who tagged `tag` and why, and checks its signature if `verify_tag_signatures` is set
+fn announce_tag(repo: &Repository, config: &Config, tag: &str) -> Result<(), GdepError> {
+    info!("Deploying tag `{}`", tag);
+    if let Some(annotated) = tags::annotated(repo, tag) {
+        if let Some(tagger) = annotated.tagger() {
+            info!("Tagged by {}", tagger);
+        }
+        if let Some(message) = annotated.message() {
+            let message = message.split(tags::SIGNATURE_START).next().unwrap_or_default().trim();
+            if !message.is_empty() {
+                info!("{}", message);
+            }
+        }
+    }
+    if config.verify_tag_signatures {
+        tags::verify_signature(repo, tag).map_err(|e| GdepError::TagSignatureInvalid(tag.to_string(), e))?;
+    }
+    Ok(())
+}
+
+/// Follows the newest matching tag, checking out every newer one as it appears
+fn track_tags(repo: &Repository, branch_name: &str, config: &Config, state_dir: &Path, stop_flag: &Mutex<bool>, poller: &mut Poller, sender: &mpsc::Sender<UpdateSignal>) -> Result<(), GdepError> {
+    let mut deferred = false;
+    let mut skipped = None;
+    let clock = Arc::clone(&poller.clock);
+    let mut backoff = Backoff::new(config.max_fetch_retries, &*clock);
+    let mut debounce = Debounce::new(config, &*clock);
+    // Reported as the branch of fetch events
+    let tag_label = format!("tags/{}", config.tag_pattern.as_deref().unwrap_or("*"));
+    while poller.next_cycle() {
+        sender.send(UpdateSignal::Alive).expect("Failed to send alive signal to main thread");
+        events::emit(Some(&config.name), Event::FetchStarted { branch: &tag_label });
+
+        match tags::fetch_tags(repo, config, &*clock) {
+            Ok(()) => {
+                backoff.reset();
+                sender.send(UpdateSignal::Fetched { behind: None }).expect("Failed to send fetch signal to main thread");
+            }
+            Err(e) if backoff.retry(&e, stop_flag) => {
+                sender.send(UpdateSignal::FetchFailed).expect("Failed to send fetch signal to main thread");
+                poller.repeat();
+                continue
+            }
+            Err(e) => return Err(e.into())
+        }
+        let (tag, target) = pick_latest_tag(repo, config)?;
+        let head = repo.head()?.peel_to_commit()?.id();
+        if head == target {
+            events::emit(Some(&config.name), Event::UpToDate { branch: &tag_label });
+            continue
+        }
+        if held_back(state_dir, target, &mut skipped) {
+            continue
+        }
+
+        let (ahead, behind) = repo.graph_ahead_behind(head, target)?;
+        debug!("Latest tag `{}` is {} behind, {} ahead of HEAD", tag, ahead, behind);
+        if config.deploy_schedule.as_ref().is_some_and(|s| !s.allows(clock.now_utc())) {
+            if !deferred {
+                info!("Tag `{}` available, deferring it until the deploy window opens", tag);
+                deferred = true;
+            }
+            continue
+        }
+        deferred = false;
+        if debounce.holds(poller) {
+            continue
+        }
+
+        announce_tag(repo, config, &tag)?;
+        check_commit_age(repo, config, target, &*clock)?;
+        check_signature(repo, config, target)?;
+        check_authors(repo, config, target)?;
+        if !verify_passed(repo, config, branch_name, state_dir, target, &*clock)? {
+            continue
+        }
+        with_hooks(repo, config, &*clock, || deploy_commit(repo, &target.to_string(), config.depth))?;
+        debounce.applied();
+        send_updated(repo, sender, Some(head))?;
+    }
+    Ok(())
+}
+
+/// Looks for updates on every cycle of `poller` until its `stop_flag` is set. Reports back through `sender`, ending with `Stopped`
+fn update_sync(repo_path: Arc<String>, branch_name: Arc<String>, config: Arc<Config>, mut poller: Poller, sender: mpsc::Sender<UpdateSignal>) {
+    let stop_flag = Arc::clone(&poller.stop_flag);
+    let err = match Repository::open(&*repo_path) {
+        Ok(repo) => {
+            debug!("Update thread opened repo at `{}`", repo_path);
+            let result = conv_err_e!(state::branch_dir(&repo, &branch_name), GdepError::StateDirFailed).and_then(|state_dir| {
+                if config.mode == Mode::Mirror {
+                    track_mirror(&repo, &config, &stop_flag, &mut poller, &sender)
+                } else if let Some(sha) = &config.commit {
+                    hold_pinned_commit(&repo, sha, &config, &mut poller, &sender)
+                } else if config.track == Track::Tag {
+                    track_tags(&repo, &branch_name, &config, &state_dir, &stop_flag, &mut poller, &sender)
+                } else {
+                    track_branch(&repo, &branch_name, &config, &state_dir, &stop_flag, &mut poller, &sender)
+                }
+            });
+            result.err()
+        }
+        // Without the repo no update will ever be applied, so this must not go unnoticed
+        Err(e) => Some(GdepError::RepoOpenFailedInUpdateThread(repo_path.to_string(), e.message().to_string()))
+    };
+
+    if let Some(e) = &err {
+        error!("Error while searching for updates: {}", e);
+        events::emit(Some(&config.name), Event::GdepError { err: e, fatal: false });
+    }
+    sender.send(UpdateSignal::Stopped(err)).expect("Failed to send stop signal to main thread");
+}
+
+/// How the local branch relates to the fetched one, in commits only it has
+#[derive(Debug)]
+pub enum UpdateRelationState {
+    Up2Date,
+    Ahead(usize),
+    Behind(usize),
+    AheadBehind(usize, usize)
+}
+
+/// Brings `branch_name` to `target`, the fetched tip the update was checked at. It doesn't fetch again:
+/// whatever origin got since has not been through `allowed_authors`, `verify_script` and the other checks
+pub fn update_repo(repo: &Repository, branch_name: &str, target: git2::Oid, config: &Config) -> Result<(), GdepError> {
+    merge_updates(repo, branch_name, target, config)
+}
+
+/// Fetches `refspec` and the tags `fetch_tags` asks for from `remote`, pruning refs it covers that are gone upstream if `prune`.
+/// A fetch still running after `fetch_timeout` fails with `ErrorCode::Timeout`, which the backoff retries
+pub(crate) fn fetch_refspec(remote: &mut git2::Remote, refspec: &str, config: &Config, prune: bool, clock: &dyn Clock) -> Result<(), Error> {
+    // Progress is only reported while data arrives, a remote that stops answering is caught by the transport's read timeout,
+    // which `apply_network_settings` set
+    let timeout = config.fetch_timeout.map(Duration::from_secs);
+    let started = clock.now();
+    let timed_out = || timeout.is_some_and(|timeout| clock.elapsed(started) >= timeout);
+
+    let mut progress = remote::Progress::new("Fetching");
+    let mut cb = remote::callbacks();
+    cb.transfer_progress(|p| {
+        progress.update(&p);
+        !timed_out()
+    });
+    let mut fetch_options = remote::fetch_options(config.depth);
+    fetch_options.remote_callbacks(cb);
+    fetch_options.download_tags(match config.fetch_tags {
+        FetchTags::All => AutotagOption::All,
+        FetchTags::Auto => AutotagOption::Auto,
+        FetchTags::None => AutotagOption::None
+    });
+    if prune {
+        fetch_options.prune(git2::FetchPrune::On);
+    }
+
+    remote.fetch(&[refspec], Some(&mut fetch_options), None).map_err(|e| match timeout {
+        Some(timeout) if timed_out() || e.code() == git2::ErrorCode::Timeout => Error::new(
+            git2::ErrorCode::Timeout, git2::ErrorClass::Net,
+            format!("no answer from origin within {} ({})", format_duration(timeout.as_secs()), e.message())
+        ),
+        _ => e
+    })
+}
+
+/// Fetches `branch_name` (and the tags `fetch_tags` asks for) from origin into `origin/<branch_name>` with the configured `refspec`
+/// and returns the fetched tip. The update check fetches through here, the update itself only applies the tip it found
+pub(crate) fn fetch_branch<'a>(repo: &'a Repository, branch_name: &str, config: &Config, clock: &dyn Clock) -> Result<AnnotatedCommit<'a>, Error> {
+    let mut remote = repo.find_remote("origin")?;
+    // The default's leading `+` moves the tracking ref even if upstream was force-pushed, so the comparison sees the real remote tip
+    let refspec = config.refspec.replace("{branch}", branch_name);
+    fetch_refspec(&mut remote, &refspec, config, config.prune, clock)?;
+    if config.prune {
+        prune_stale_branches(repo, &remote, branch_name)?;
+    }
+
+    let tracking = format!("refs/remotes/origin/{}", branch_name);
+    // A source origin doesn't have leaves the tracking ref missing, or where an earlier fetch left it
+    let source = refspec.trim_start_matches('+').split(':').next().unwrap_or_default();
+    let fetched_nothing = !remote.list()?.iter().any(|head| head.name() == source);
+    let tip = match repo.find_reference(&tracking) {
+        Err(e) if e.code() == git2::ErrorCode::NotFound => restore_tracking_ref(repo, &mut remote, branch_name, config, clock)?.ok_or(e)?,
+        Ok(tip) if fetched_nothing => restore_tracking_ref(repo, &mut remote, branch_name, config, clock)?.unwrap_or(tip),
+        tip => tip?
+    };
+    repo.reference_to_annotated_commit(&tip)
+}
+
+/// The tip of `branch_name` the last fetch wrote to FETCH_HEAD, if it fetched that branch
+fn fetch_head_tip(repo: &Repository, branch_name: &str) -> Option<git2::Oid> {
+    let wanted = format!("refs/heads/{}", branch_name);
+    let mut tip = None;
+    // Stopping early makes this return an error, which is no reason to ignore what was found
+    let _ = repo.fetchhead_foreach(|name, _, oid, _| {
+        if name == wanted {
+            tip = Some(*oid);
+        }
+        tip.is_none()
+    });
+    tip
+}
+
+/// The fetch left `origin/<branch>` missing or stale. It is set from FETCH_HEAD if the branch is in there,
+/// else by fetching again with the default refspec. None if origin has no such branch
+fn restore_tracking_ref<'a>(repo: &'a Repository, remote: &mut git2::Remote, branch_name: &str, config: &Config, clock: &dyn Clock) -> Result<Option<git2::Reference<'a>>, Error> {
+    let tracking = format!("refs/remotes/origin/{}", branch_name);
+    if let Some(tip) = fetch_head_tip(repo, branch_name) {
+        debug!("The fetch did not update `{}`, setting it to {} from FETCH_HEAD", tracking, tip);
+        return repo.reference(&tracking, tip, true, "Restoring the tracking ref from FETCH_HEAD").map(Some)
+    }
+    if config.refspec != DEFAULT_REFSPEC {
+        debug!("The refspec `{}` fetched nothing into `{}`, fetching with the default one", config.refspec, tracking);
+        fetch_refspec(remote, &DEFAULT_REFSPEC.replace("{branch}", branch_name), config, false, clock)?;
+    }
+    match repo.find_reference(&tracking) {
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        tip => tip.map(Some)
+    }
+}
+
+/// The fetch only prunes what its refspec covers, the tracked branch. This removes the other `origin/*` refs
+/// whose branch is gone upstream, going by what the remote announced during that fetch
+fn prune_stale_branches(repo: &Repository, remote: &git2::Remote, branch_name: &str) -> Result<(), Error> {
+    let heads: Vec<&str> = remote.list()?.iter().filter_map(|h| h.name().strip_prefix("refs/heads/")).collect();
+    for reference in repo.references_glob("refs/remotes/origin/*")? {
+        let mut reference = reference?;
+        let Some(branch) = reference.name().and_then(|n| n.strip_prefix("refs/remotes/origin/")).map(|b| b.to_string()) else {
+            continue
+        };
+        if branch == "HEAD" || branch == branch_name || heads.contains(&branch.as_str()) {
+            continue
+        }
+        reference.delete()?;
+        info!("Pruned `origin/{}`, the branch is gone upstream", branch);
+    }
+    Ok(())
+}
+
+/// A fetch that can't find `origin/<branch>` afterwards means `prune` removed it: the branch was deleted upstream
+pub fn tracking_error(e: Error, branch: &str) -> GdepError {
+    if e.code() == git2::ErrorCode::NotFound && e.message().contains(&format!("'refs/remotes/origin/{}'", branch)) {
+        return GdepError::TrackedBranchGone(branch.to_string())
+    }
+    e.into()
+}
+
+fn merge_updates(
+    repo: &Repository,
+    remote_branch: &str,
+    target: git2::Oid,
+    config: &Config,
+) -> Result<(), GdepError> {
+    let fetch_commit = repo.find_annotated_commit(target)?;
+    let analysis = repo.merge_analysis(&[&fetch_commit])?;
+    debug!("Merge analysis for `{}` at {}: {:?}", remote_branch, fetch_commit.id(), analysis.0);
+    if analysis.0.is_fast_forward() {
+        fast_forward(repo, &format!("refs/heads/{}", remote_branch), fetch_commit.id(), config)?;
+    } else if analysis.0.is_normal() {
+        let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
+        perform_merge(repo, remote_branch, &head_commit, &fetch_commit, config)?;
+    }
+    Ok(())
+}
+
+/// Checks out `target` and moves the branch `refname` (and HEAD) to it
+fn advance_branch(repo: &Repository, refname: &str, target: git2::Oid, checkout: &mut git2::build::CheckoutBuilder) -> Result<(), Error> {
+    // The working tree goes first, so a refused `safe` checkout leaves the branch where it was
+    repo.checkout_tree(repo.find_commit(target)?.as_object(), Some(checkout))?;
+    match repo.find_reference(refname) {
+        Ok(mut reference) => {
+            reference.set_target(target, "Fast-forward")?;
+        }
+        Err(_) => {
+            repo.reference(refname, target, true, "Setting new branch")?;
+        }
+    }
+    repo.set_head(refname)
+}
+
+/// Fast-forwards the branch `refname` to `target`, treating local changes to files the update touches as `checkout_mode` says:
+/// `force` overwrites them, `safe` refuses the update and `merge` applies them over it, leaving conflict markers where they clash
+fn fast_forward(repo: &Repository, refname: &str, target: git2::Oid, config: &Config) -> Result<(), GdepError> {
+    if config.checkout_mode == CheckoutMode::Force {
+        return Ok(advance_branch(repo, refname, target, git2::build::CheckoutBuilder::default().force())?)
+    }
+
+    let mut conflicts = Vec::new();
+    let mut checkout = git2::build::CheckoutBuilder::default();
+    checkout.safe().notify_on(git2::CheckoutNotificationType::CONFLICT).notify(|_, path, _, _, _| {
+        conflicts.extend(path.map(|p| p.to_string_lossy().to_string()));
+        true
+    });
+    let result = advance_branch(repo, refname, target, &mut checkout);
+    drop(checkout);
+    match result {
+        Err(e) if e.code() == git2::ErrorCode::Conflict && config.checkout_mode == CheckoutMode::Safe =>
+            Err(GdepError::LocalChangesConflict(conflicts)),
+        Err(e) if e.code() == git2::ErrorCode::Conflict => {
+            // Stashing needs a mutable handle, the shared one is borrowed by the update loop
+            let mut stash_repo = Repository::open(repo.path())?;
+            let sig = merge_signature(repo, config)?;
+            let stash = stash_repo.stash_save(&sig, "gdep: local changes before update", None)?;
+            advance_branch(&stash_repo, refname, target, git2::build::CheckoutBuilder::default().force())?;
+            stash_repo.stash_apply(0, None)?;
+            if stash_repo.index()?.has_conflicts() {
+                warn!("Local changes to {} clash with the update, left conflict markers in them and kept them in stash {}", conflicts.join(", "), stash);
+            } else {
+                stash_repo.stash_drop(0)?;
+                info!("Merged local changes to {} into the update", conflicts.join(", "));
+            }
+            Ok(())
+        }
+        result => Ok(result?)
+    }
+}
+
+/// Who merge commits are made by: `committer_name`/`committer_email`, falling back to git's `user.name`/`user.email`
+/// for whatever is not configured
+fn merge_signature(repo: &Repository, config: &Config) -> Result<Signature<'static>, GdepError> {
+    if let (Some(name), Some(email)) = (&config.committer_name, &config.committer_email) {
+        return Ok(Signature::now(name, email)?)
+    }
+    let fallback = repo.signature().map_err(|e| GdepError::GitError(
+        format!("{} (set `committer_name` and `committer_email` to commit merges without a git identity)", e.message()), e.code()))?;
+    let name = config.committer_name.as_deref().or(fallback.name()).unwrap_or_default();
+    let email = config.committer_email.as_deref().or(fallback.email()).unwrap_or_default();
+    Ok(Signature::now(name, email)?)
+}
+
+fn perform_merge(
+    repo: &Repository,
+    branch_name: &str,
+    local: &AnnotatedCommit,
+    remote: &AnnotatedCommit,
+    config: &Config,
+) -> Result<(), GdepError> {
+    let strategy = config.conflict_strategy.unwrap_or(ConflictStrategy::Abort);
+    let local_tree = repo.find_commit(local.id())?.tree()?;
+    let remote_tree = repo.find_commit(remote.id())?.tree()?;
+    let ancestor_tree = repo.find_commit(repo.merge_base(local.id(), remote.id())?)?.tree()?;
+    let mut merge_options = git2::MergeOptions::new();
+    match strategy {
+        ConflictStrategy::Ours => { merge_options.file_favor(git2::FileFavor::Ours); }
+        ConflictStrategy::Theirs => { merge_options.file_favor(git2::FileFavor::Theirs); }
+        ConflictStrategy::Abort => {}
+    }
+    let mut index = repo.merge_trees(&ancestor_tree, &local_tree, &remote_tree, Some(&merge_options))?;
+
+    if index.has_conflicts() {
+        let conflicts = index.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+        let paths: Vec<String> = conflicts.iter()
+            .filter_map(|c| c.our.as_ref().or(c.their.as_ref()).or(c.ancestor.as_ref()))
+            .map(|entry| String::from_utf8_lossy(&entry.path).to_string())
+            .collect();
+        // Nothing has been written to the working tree or HEAD at this point
+        if strategy == ConflictStrategy::Abort {
+            return Err(GdepError::MergeConflict(paths))
+        }
+
+        // Conflicts file_favor can't settle (e.g. modified on one side, deleted on the other) take the chosen side as a whole
+        warn!("Resolving merge conflicts in {} using `{}`", paths.join(", "), strategy);
+        for (conflict, path) in conflicts.into_iter().zip(&paths) {
+            let side = if strategy == ConflictStrategy::Ours {conflict.our} else {conflict.their};
+            index.conflict_remove(Path::new(path))?;
+            if let Some(mut entry) = side {
+                entry.flags &= !INDEX_STAGE_MASK;
+                index.add(&entry)?;
+            }
+        }
+    }
+
+    let result_tree = repo.find_tree(index.write_tree_to(repo)?)?;
+    let sig = merge_signature(repo, config)?;
+    let message = config.merge_message.replace("{branch}", branch_name).replace("{sha}", &remote.id().to_string());
+    let local_commit = repo.find_commit(local.id())?;
+    let remote_commit = repo.find_commit(remote.id())?;
+    repo.commit(Some("HEAD"), &sig, &sig, &message, &result_tree, &[&local_commit, &remote_commit])?;
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+    Ok(())
+}
+
+/// Checks out `sha` as a detached HEAD, fetching it from origin if it is not known locally
+pub fn deploy_commit(repo: &Repository, sha: &str, depth: Option<u32>) -> Result<(), GdepError> {
+    let object = match repo.revparse_single(sha) {
+        Ok(object) => object,
+        Err(_) => {
+            info!("Commit `{}` not found locally, fetching...", sha);
+            let mut remote = repo.find_remote("origin")?;
+            let mut fetch_options = remote::fetch_options(depth);
+            // Fetching a bare object id only works on servers that allow it, so fall back to all heads
+            if let Err(e) = remote.fetch(&[sha], Some(&mut fetch_options), None) {
+                debug!("Fetching `{}` directly failed: {}", sha, e);
+                remote.fetch(&["+refs/heads/*:refs/remotes/origin/*"], Some(&mut fetch_options), None)?;
+            }
+            conv_err!(repo.revparse_single(sha), GdepError::CommitNotFound(sha.to_string()))?
+        }
+    };
+
+    let commit = conv_err!(object.peel_to_commit(), GdepError::CommitNotFound(sha.to_string()))?;
+    repo.checkout_tree(commit.as_object(), Some(git2::build::CheckoutBuilder::default().force()))?;
+    repo.set_head_detached(commit.id())?;
+    info!("Checked out commit {}", commit.id());
+    Ok(())
+}
+
+/// The branch `origin`'s HEAD points to, read from `origin/HEAD`. Without that ref (e.g. the repo wasn't
+/// cloned) the remote is asked, and its answer is stored as `origin/HEAD` for next time
+fn remote_default_branch(repo: &Repository) -> Option<String> {
+    const PREFIX: &str = "refs/remotes/origin/";
+    if let Ok(head) = repo.find_reference("refs/remotes/origin/HEAD") {
+        return head.symbolic_target()?.strip_prefix(PREFIX).map(|b| b.to_string())
+    }
+
+    let mut remote = repo.find_remote("origin").ok()?;
+    let connection = remote.connect_auth(git2::Direction::Fetch, Some(remote::callbacks()), Some(proxy::options()))
+        .inspect_err(|e| debug!("Could not ask origin for its default branch: {}", e.message())).ok()?;
+    let default = connection.default_branch().ok()?;
+    let branch = default.as_str()?.strip_prefix("refs/heads/")?.to_string();
+    if let Err(e) = repo.reference_symbolic("refs/remotes/origin/HEAD", &format!("{}{}", PREFIX, branch), false, "gdep: origin's default branch") {
+        debug!("Could not store origin/HEAD: {}", e.message());
+    }
+    Some(branch)
+}
+
+/// Asks origin whether it has a branch `branch`
+pub(crate) fn remote_has_branch(repo_path: &str, branch: &str) -> Result<bool, Error> {
+    let repo = Repository::open(repo_path)?;
+    let mut remote = repo.find_remote("origin")?;
+    let connection = remote.connect_auth(git2::Direction::Fetch, Some(remote::callbacks()), Some(proxy::options()))?;
+    let refname = format!("refs/heads/{}", branch);
+    Ok(connection.list()?.iter().any(|head| head.name() == refname))
+}
+
+/// Makes sure origin has `branch`, which was given rather than inferred, before the update thread looks for it.
+/// If origin can't be reached now, the update thread reports that later
+fn check_branch_exists(repo: &Repository, branch: &str) -> Result<(), GdepError> {
+    let Ok(mut remote) = repo.find_remote("origin") else {
+        return Ok(())
+    };
+    let branches: Vec<String> = match remote.connect_auth(git2::Direction::Fetch, Some(remote::callbacks()), Some(proxy::options())) {
+        Ok(connection) => connection.list()?.iter().filter_map(|head| head.name().strip_prefix("refs/heads/").map(str::to_string)).collect(),
+        Err(e) => {
+            debug!("Could not ask origin for its branches: {}", e.message());
+            return Ok(())
+        }
+    };
+    if branches.iter().any(|b| b == branch) {
+        return Ok(())
+    }
+    Err(GdepError::BranchNotFound(branch.to_string(), branches))
+}
+
+/// With `branch_selector: newest`: fetches every branch of origin and picks the one whose tip was committed last,
+/// among those matching `include_branches` (all without it) and not `exclude_branches`. None if no branch is left
+fn newest_branch(repo: &Repository, config: &Config, clock: &dyn Clock) -> Result<Option<String>, Error> {
+    let mut remote = repo.find_remote("origin")?;
+    fetch_refspec(&mut remote, ALL_BRANCHES_REFSPEC, config, true, clock)?;
+    let include = patterns(&config.include_branches);
+    let exclude = patterns(&config.exclude_branches);
+    let mut newest: Option<(i64, String)> = None;
+    for branch in repo.branches(Some(BranchType::Remote))? {
+        let (branch, _) = branch?;
+        let Some(name) = branch.name()?.and_then(|n| n.strip_prefix("origin/")).filter(|n| *n != "HEAD") else { continue };
+        if (!include.is_empty() && !include.iter().any(|p| p.matches(name))) || exclude.iter().any(|p| p.matches(name)) {
+            continue
+        }
+        let time = branch.get().peel_to_commit()?.committer().when().seconds();
+        if newest.as_ref().is_none_or(|(newest, _)| time > *newest) {
+            newest = Some((time, name.to_string()));
+        }
+    }
+    Ok(newest.map(|(_, name)| name))
+}
+
+/// Picks the branch to follow when none is given. With `branch_selector: newest`, that is `newest_branch`. Without `include_branches`, that is origin's default branch,
+/// else `main`, else `master`. With it, the first of its globs that matches a branch of origin decides,
+/// preferring origin's default branch and then the alphabetically first among several matches.
+/// Branches matching `exclude_branches` are never picked
+pub fn get_default_branch(repo: &Repository, config: &Config, clock: &dyn Clock) -> Result<String, GdepError> {
+    if config.branch_selector == BranchSelector::Newest {
+        let newest = newest_branch(repo, config, clock)?.ok_or(GdepError::BranchInferFailed)?;
+        info!("Branch inferred to be `{}`, the one with the newest commit", newest);
+        return Ok(newest)
+    }
+    let exclude = patterns(&config.exclude_branches);
+    let origin_default = remote_default_branch(repo).filter(|b| !exclude.iter().any(|p| p.matches(b)));
+    if config.include_branches.is_empty() {
+        if let Some(branch) = origin_default {
+            info!("Branch inferred to be `{}`, origin's default", branch);
+            return Ok(branch)
+        }
+    }
+
+    let mut names = Vec::new();
+    for branch in repo.branches(Some(BranchType::Remote))? {
+        let (branch, _) = branch?;
+        if let Some(name) = branch.name()?.and_then(|n| n.strip_prefix("origin/")).filter(|n| *n != "HEAD") {
+            if !exclude.iter().any(|p| p.matches(name)) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+
+    let preference = if config.include_branches.is_empty() {
+        patterns(&DEFAULT_BRANCHES.map(|b| b.to_string()))
+    } else {
+        patterns(&config.include_branches)
+    };
+    let found = preference.iter().find_map(|p| origin_default.as_ref().filter(|b| p.matches(b))
+        .or_else(|| names.iter().find(|n| p.matches(n))));
+    match found {
+        None => Err(GdepError::BranchInferFailed),
+        Some(fb) => {
+            info!("Branch inferred to be `{}`", fb);
+            Ok(fb.clone())
+        }
+    }
+}
+
+pub fn repo_update_cycle(repo: &Repository, branch: &str, config: &Config, clock: &dyn Clock) -> Result<UpdateRelationState, Error> {
+    let remote_branch = repo.find_commit(fetch_branch(repo, branch, config, clock)?.id())?;
+    relation(repo, remote_branch.id())
+}
+
+/// How the checkout relates to what was fetched of `branch` last, without fetching again
+pub(crate) fn fetched_relation(repo: &Repository, branch: &str) -> Result<UpdateRelationState, Error> {
+    let remote_branch = repo.find_reference(&format!("refs/remotes/origin/{}", branch))?.peel_to_commit()?;
+    relation(repo, remote_branch.id())
+}
+
+fn relation(repo: &Repository, remote: git2::Oid) -> Result<UpdateRelationState, Error> {
+    let head = repo.head()?.peel_to_commit()?;
+
+    // Shallow history ends before any merge base (every fetched tip is a root), so all that can be told is whether the tips differ
+    if repo.is_shallow() {
+        return Ok(if head.id() == remote {UpdateRelationState::Up2Date} else {UpdateRelationState::Behind(1)})
+    }
+
+    let ahead_behind = repo.graph_ahead_behind(head.id(), remote)?;
+
+    Ok(match ahead_behind {
+        (0, 0) => UpdateRelationState::Up2Date,
+        (ahead, 0) => UpdateRelationState::Ahead(ahead),
+        (0, behind) => UpdateRelationState::Behind(behind),
+        (ahead, behind) => UpdateRelationState::AheadBehind(ahead, behind),
+    })
+}
+
+/// The configured `env`, plus the deploy context gdep always provides. Built for every run, so `GDEP_COMMIT` is the current checkout
+fn script_env(config: &Config, repo_path: &str, branch: &str) -> HashMap<String, String> {
+    let mut vars: HashMap<String, String> = config.env.clone().into_iter().collect();
+    vars.insert("GDEP_REPO_PATH".to_string(), repo_path.to_string());
+    vars.insert("GDEP_BRANCH".to_string(), branch.to_string());
+    let head = Repository::open(repo_path).ok()
+        .and_then(|repo| repo.head().ok()?.peel_to_commit().ok().map(|c| c.id()));
+    if let Some(head) = head {
+        vars.insert("GDEP_COMMIT".to_string(), head.to_string());
+    }
+    vars
+}
+
+/// Where scripts run: `working_dir` resolved against the checkout, else the checkout itself.
+/// With `deploy_mode: symlink`, the release of the checkout's HEAD takes the checkout's place
+fn working_dir(config: &Config, repo_path: &str, clock: &dyn Clock) -> PathBuf {
+    let root = match config.deploy_mode {
+        DeployMode::InPlace => PathBuf::from(repo_path),
+        DeployMode::Symlink => match Repository::open(repo_path).map_err(GdepError::from).and_then(|repo| release::stage(&repo, config, clock)) {
+            Ok(release) => release,
+            Err(e) => {
+                error!("{}, running in the checkout instead", e);
+                PathBuf::from(repo_path)
+            }
+        }
+    };
+    match &config.working_dir {
+        Some(dir) => root.join(dir),
+        None => root
+    }
+}
+
+/// With `deploy_mode: symlink`, points `current_link` at the release of HEAD, which came up healthy.
+/// A failure is only logged, the script runs either way
+fn go_live(config: &Config, repo_path: &str, clock: &dyn Clock) {
+    if config.deploy_mode != DeployMode::Symlink {
+        return
+    }
+    if let Err(e) = Repository::open(repo_path).map_err(GdepError::from).and_then(|repo| release::activate(&repo, config, clock)) {
+        error!("{}", e);
+    }
+}
+
+fn spawn_script(config: &Config, script: &str, args: &[String], repo_path: &str, branch: &str, tail: Option<&OutputTail>, clock: &Arc<dyn Clock>) -> Result<ScriptChild, ExitStatus> {
+    spawn_with_env(config, script, args, repo_path, tail, script_env(config, repo_path, branch), clock)
+}
+
+/// Starts `script` with its output forwarded. One that can't be started, say the runner isn't executable, is logged
+/// and counts as exiting with `process::not_started`
+fn spawn_with_env(config: &Config, script: &str, args: &[String], repo_path: &str, tail: Option<&OutputTail>, env: HashMap<String, String>, clock: &Arc<dyn Clock>) -> Result<ScriptChild, ExitStatus> {
+    let forwarding = Forwarding {
+        limiter: config.max_output_rate.map(|rate| Arc::new(Mutex::new(RateLimiter::new(rate, Arc::clone(clock))))),
+        tail: tail.cloned(),
+        stdout_file: config.stdout_file.as_ref().map(PathBuf::from),
+        stderr_file: config.stderr_file.as_ref().map(PathBuf::from),
+        prefix: config.prefix_output
+    };
+    let piped = forwarding.needed();
+
+    let mut options = ScriptOptions::new();
+    options.working_directory = Some(working_dir(config, repo_path, &**clock));
+    options.output_redirection = if piped {IoOptions::Pipe} else {IoOptions::Inherit};
+    options.env_vars = Some(env);
+    set_runner(&mut options, config);
+
+    let mut child = process::spawn(script, args, &options, config.run_as.as_deref()).map_err(|e| {
+        error!("Could not start the script: {}", e);
+        process::not_started()
+    })?;
+    if piped {
+        output::forward_output(&mut child, forwarding);
+    }
+    Ok(child)
+}
+
+/// Runs the steps in order, each to completion, then starts the script, which is what gets supervised.
+/// The status of the first step that fails instead, the script isn't started then, or the one of a script that could not be started
+fn start_script(config: &Config, repo_path: &str, branch: &str, tail: Option<&OutputTail>, clock: &Arc<dyn Clock>) -> Result<ScriptChild, ExitStatus> {
+    for (i, step) in config.steps.iter().enumerate() {
+        info!("Running step {}/{}...", i + 1, config.steps.len());
+        let status = run_to_completion(config, step, repo_path, branch, tail, clock);
+        if !status.success() {
+            error!("Step {}/{} failed ({}), not starting the script", i + 1, config.steps.len(), status);
+            return Err(status)
+        }
+    }
+    spawn_script(config, &config.script, &config.args, repo_path, branch, tail, clock)
+}
+
+/// Runs `script` (a step or an `on_change` one) and waits for it, stopping it early if gdep is interrupted
+fn run_to_completion(config: &Config, script: &str, repo_path: &str, branch: &str, tail: Option<&OutputTail>, clock: &Arc<dyn Clock>) -> ExitStatus {
+    let mut child = match spawn_script(config, script, &[], repo_path, branch, tail, clock) {
+        Ok(child) => child,
+        Err(status) => return status
+    };
+    loop {
+        if let Some(status) = child.try_wait().expect("Waiting failed") {
+            return status
+        }
+        if signals::received().is_some() {
+            return process::terminate(&mut child, Duration::from_secs(config.shutdown_grace), &**clock).expect("Failed to stop the subprocess")
+        }
+        clock.sleep_interruptible(SUPERVISE_INTERVAL, &mut || signals::received().is_some());
+    }
+}
+
+/// How much longer a restart has to wait for `min_restart_interval`, None if the script may be started right away
+fn restart_deferral(config: &Config, health: &Mutex<health::Status>, clock: &dyn Clock) -> Option<Duration> {
+    let last_start = health.lock().unwrap().last_start?;
+    Duration::from_secs(config.min_restart_interval).checked_sub(clock.elapsed(last_start)).filter(|wait| !wait.is_zero())
+}
+
+/// Why the supervised script is restarted, which decides what is done besides restarting it
+#[derive(Clone, Copy)]
+enum Restart {
+    /// Asked for through the control socket, or going back to the version that ran before
+    Plain,
+    /// The branch or the config changed, the restarted script goes live
+    Changed,
+    /// The checkout was updated from the first commit to the second, the restarted script has to get ready and pass the smoke test
+    Update(Option<git2::Oid>, git2::Oid)
+}
+
+impl Restart {
+    /// The one restart that does what both `self`, which is held back, and `later` are for
+    fn and(self, later: Restart) -> Restart {
+        match (self, later) {
+            (Restart::Update(previous, _), Restart::Update(_, current)) => Restart::Update(previous, current),
+            // The update held back was made on the branch or with the config that changed since
+            (Restart::Update(..), Restart::Changed) => Restart::Changed,
+            (Restart::Update(previous, current), Restart::Plain) | (_, Restart::Update(previous, current)) => Restart::Update(previous, current),
+            (Restart::Plain, Restart::Plain) => Restart::Plain,
+            _ => Restart::Changed
+        }
+    }
+}
+
+/// Queues `restart`, along with the one already `pending`. It is done once `min_restart_interval` passed since the script was last started,
+/// the running script keeps being supervised until then
+fn defer_restart(pending: &mut Option<Restart>, restart: Restart, config: &Config, health: &Mutex<health::Status>, clock: &dyn Clock) {
+    if pending.is_none() {
+        if let Some(wait) = restart_deferral(config, health, clock) {
+            info!("Deferring the restart by {}, the script was started less than {} ago (min_restart_interval)",
+                format_duration(wait.as_secs_f64().ceil() as u64), format_duration(config.min_restart_interval));
+        }
+    }
+    *pending = Some(pending.map_or(restart, |held| held.and(restart)));
+}
+
+fn print_output_context(lines: &[String]) {
+    if !lines.is_empty() {
+        error!("Last {} lines of script output:{}", lines.len(), lines.iter().map(|l| format!("\n  | {}", l)).collect::<String>());
+    }
+}
+
+/// Runs the `cleanup` script, telling it why the script stopped: `GDEP_EXIT_REASON` is one of the reasons of the stop summary,
+/// `GDEP_ERROR` the error it stopped with, if any, and `GDEP_SCRIPT_EXIT_CODE` what it exited with, if it exited by itself
+fn run_cleanup(config: &Config, repo_path: &str, branch: &str, error: Option<&GdepError>, status: Option<ExitStatus>, shutdown: bool, clock: &Arc<dyn Clock>) {
+    if let Some(cleanup) = &config.cleanup {
+        info!("Cleaning up...");
+        let mut env = script_env(config, repo_path, branch);
+        env.insert("GDEP_EXIT_REASON".to_string(), Reason::of(error, false, shutdown).as_str().to_string());
+        if let Some(error) = error {
+            env.insert("GDEP_ERROR".to_string(), error.to_string());
+        }
+        if let Some(code) = status.and_then(|s| s.code()) {
+            env.insert("GDEP_SCRIPT_EXIT_CODE".to_string(), code.to_string());
+        }
+        if let Ok(mut cl_child) = spawn_with_env(config, cleanup, &[], repo_path, None, env, clock) {
+            cl_child.wait().expect("Failed to clean up");
+        }
+    }
+}
+
+/// Runs `probe`, a smoke test or a ready check, and tells whether it exited with 0 within `timeout`.
+/// One that could not start or is still running by then fails
+fn probe_passed(config: &Config, probe: &str, repo_path: &str, branch: &str, timeout: Duration, clock: &dyn Clock) -> Result<bool, String> {
+    let mut options = ScriptOptions::new();
+    options.working_directory = Some(working_dir(config, repo_path, clock));
+    options.output_redirection = IoOptions::Inherit;
+    options.env_vars = Some(script_env(config, repo_path, branch));
+    set_runner(&mut options, config);
+    let mut child = process::spawn(probe, &[], &options, config.run_as.as_deref()).map_err(|e| e.to_string())?;
+
+    let deadline = clock.now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(status.success()),
+            Ok(None) if clock.now() < deadline => {
+                clock.sleep_interruptible(SUPERVISE_INTERVAL, &mut || false);
+            }
+            _ => {
+                let _ = process::terminate(&mut child, Duration::from_secs(config.shutdown_grace), clock);
+                return Err(format!("did not finish within {}", format_duration(timeout.as_secs())))
+            }
+        }
+    }
+}
+
+/// Runs `smoke_test` against the freshly restarted script. No smoke test counts as passed
+fn smoke_test_passed(config: &Config, repo_path: &str, branch: &str, clock: &dyn Clock) -> bool {
+    let Some(smoke_test) = &config.smoke_test else {
+        return true
+    };
+    info!("Running smoke test...");
+    probe_passed(config, smoke_test, repo_path, branch, Duration::from_secs(config.smoke_timeout), clock).unwrap_or_else(|e| {
+        warn!("Smoke test {}", e);
+        false
+    })
+}
+
+/// Tries `ready_check` every `READY_INTERVAL` until it passes, for at most `ready_timeout`: a command by exiting with 0,
+/// an `http(s)://` URL by answering with a 2xx status. Without a ready check the script counts as ready once it started
+fn wait_until_ready(config: &Config, repo_path: &str, branch: &str, child: &mut ScriptChild, clock: &dyn Clock) -> Result<(), String> {
+    let Some(check) = &config.ready_check else {
+        return Ok(())
+    };
+    info!("Waiting for the script to become ready...");
+    let deadline = clock.now() + Duration::from_secs(config.ready_timeout);
+    loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            return Err(format!("it exited ({}) first", status))
+        }
+        // Shutting down is up to the caller
+        if signals::received().is_some() {
+            return Ok(())
+        }
+        let remaining = deadline.saturating_duration_since(clock.now());
+        let ready = if check.starts_with("http://") || check.starts_with("https://") {
+            let agent = ureq::AgentBuilder::new().timeout(remaining.max(READY_INTERVAL)).build();
+            agent.get(check).call().is_ok()
+        } else {
+            probe_passed(config, check, repo_path, branch, remaining, clock).unwrap_or(false)
+        };
+        if ready {
+            info!("Script is ready");
+            return Ok(())
+        }
+        if clock.now() >= deadline {
+            return Err(format!("`{}` did not pass within {}", check, format_duration(config.ready_timeout)))
+        }
+        clock.sleep_interruptible(READY_INTERVAL, &mut || signals::received().is_some());
+    }
+}
+
+/// Moves the checkout back to `previous` and quarantines `failed`, so the update thread doesn't deploy it again
+fn roll_back(repo_path: &str, branch: &str, previous: git2::Oid, failed: git2::Oid) -> Result<(), GdepError> {
+    let repo = Repository::open(repo_path)?;
+    let state_dir = conv_err_e!(state::branch_dir(&repo, branch), GdepError::StateDirFailed)?;
+    conv_err_e!(state::quarantine(&state_dir, &failed.to_string()), GdepError::StateDirFailed)?;
+    let commit = repo.find_commit(previous)?;
+    repo.reset(commit.as_object(), git2::ResetType::Hard, Some(git2::build::CheckoutBuilder::default().force()))?;
+    Ok(())
+}
+
+/// Remembers HEAD as the commit that last came up healthy: it got ready (or simply started, without `ready_check`)
+/// and passed its smoke test. See `restore_known_good`
+fn record_known_good(repo_path: &str, branch: &str) {
+    let Some((repo, head)) = Repository::open(repo_path).ok().and_then(|repo| head_id(&repo).map(|head| (repo, head))) else {
+        return
+    };
+    if let Err(e) = state::branch_dir(&repo, branch).and_then(|dir| state::record_known_good(&dir, &head.to_string())) {
+        warn!("Could not remember {} as the last known good commit: {}", head, e);
+    }
+}
+
+/// With `restore_on_start`, resets the checkout to the last known good commit if HEAD moved past it without coming up healthy,
+/// e.g. because gdep was killed in the middle of an update. The update thread then applies what came after it again, with the usual checks
+fn restore_known_good(repo: &Repository, state_dir: &Path) -> Result<(), GdepError> {
+    let (Some(known_good), Some(head)) = (state::known_good(state_dir), head_id(repo)) else {
+        return Ok(())
+    };
+    let Ok(commit) = git2::Oid::from_str(&known_good).and_then(|id| repo.find_commit(id)) else {
+        warn!("The last known good commit {} is gone, keeping HEAD at {}", known_good, head);
+        return Ok(())
+    };
+    // Anything else moved HEAD on purpose, like a force push or `--deploy-commit`
+    if commit.id() == head || !repo.graph_descendant_of(head, commit.id())? {
+        return Ok(())
+    }
+    repo.reset(commit.as_object(), git2::ResetType::Hard, Some(git2::build::CheckoutBuilder::default().force()))?;
+    warn!("HEAD was at {}, which never came up healthy, restored the last known good commit {}", head, commit.id());
+    Ok(())
+}
+
+fn timed_out(config: &Config, started: Instant, clock: &dyn Clock) -> bool {
+    config.script_timeout.is_some_and(|timeout| clock.elapsed(started) >= Duration::from_secs(timeout))
+}
+
+/// Runs the script to completion once, without polling for updates. Its `script_timeout` is measured on `clock`
+pub fn run_once(config: &Config, repo_path: &str, branch: &str, clock: &Arc<dyn Clock>) -> ExitStatus {
+    let tail = config.error_context_lines.map(OutputTail::new);
+    let mut child = match start_script(config, repo_path, branch, tail.as_ref(), clock) {
+        Ok(child) => child,
+        Err(status) => {
+            print_output_context(&tail.map(|t| t.lines()).unwrap_or_default());
+            return status
+        }
+    };
+    let started = clock.now();
+    let (status, stopped_with) = loop {
+        if let Some(status) = child.try_wait().expect("Waiting failed") {
+            events::emit(Some(&config.name), Event::ScriptExited { status });
+            break (status, (!status.success()).then(|| GdepError::ScriptFailed(status, Vec::new())))
+        }
+        if let Some(signal) = signals::received() {
+            break (process::terminate(&mut child, Duration::from_secs(config.shutdown_grace), &**clock).expect("Failed to stop the subprocess"), Some(GdepError::Interrupted(signal)))
+        }
+        if timed_out(config, started, &**clock) {
+            warn!("Script exceeded its timeout of {}, stopping it", format_duration(config.script_timeout.unwrap_or_default()));
+            let timeout = GdepError::ScriptTimeout(config.script_timeout.unwrap_or_default(), Vec::new());
+            break (process::terminate(&mut child, Duration::from_secs(config.shutdown_grace), &**clock).expect("Failed to stop the subprocess"), Some(timeout))
+        }
+        clock.sleep_interruptible(SUPERVISE_INTERVAL, &mut || signals::received().is_some());
+    };
+    let head = Repository::open(repo_path).ok().as_ref().and_then(head_id).map(|h| h.to_string());
+    append_history(repo_path, &history::Entry::script(branch, head, Some(status), stopped_with.as_ref().map(|e| e.to_string())));
+    if status.success() {
+        go_live(config, repo_path, &**clock);
+    } else {
+        error!("Running script failed with exit code: {}", status);
+        print_output_context(&tail.map(|t| t.lines()).unwrap_or_default());
+    }
+    let exited = matches!(stopped_with, None | Some(GdepError::ScriptFailed(..)));
+    run_cleanup(config, repo_path, branch, stopped_with.as_ref(), Some(status).filter(|_| exited), false, clock);
+    status
+}
+
+/// `--once`: looks for an update a single time, applies it if there is one, then runs the script to completion.
+/// Everything happens on this thread, there is no update loop and no restart
+pub fn single_pass(config: Config, repo_path: &str, branch: &str, clock: Arc<dyn Clock>) -> Result<(), GdepError> {
+    let (sender, receiver) = mpsc::channel();
+    if !config.no_update {
+        let poller = Poller::new(&config, Arc::new(AtomicBool::new(false)), Arc::new(Mutex::new(false)), Arc::clone(&clock), true);
+        update_sync(Arc::new(repo_path.to_string()), Arc::new(branch.to_string()), Arc::new(config.clone()), poller, sender);
+    }
+    for signal in receiver.try_iter() {
+        match signal {
+            UpdateSignal::Updated { previous, current } => {
+                let commit = CommitInfo::find(repo_path, current);
+                events::emit(Some(&config.name), Event::UpdateApplied { previous, current, commit: commit.as_ref() });
+                notify(&config, Notification::Update { name: &config.name, branch, previous, current });
+                append_history(repo_path, &history::Entry::update(history::Kind::Update, branch, previous, current));
+            }
+            UpdateSignal::Stopped(Some(e)) => {
+                notify(&config, Notification::Error { name: &config.name, branch, err: &e });
+                return Err(e)
+            }
+            _ => {}
+        }
+    }
+    if let Some(signal) = signals::received() {
+        return Err(GdepError::Interrupted(signal))
+    }
+    if config.mode == Mode::Mirror {
+        return Ok(())
+    }
+
+    let status = run_once(&config, repo_path, branch, &clock);
+    if let Some(signal) = signals::received() {
+        return Err(GdepError::Interrupted(signal))
+    }
+    if status.success() {Ok(())} else {Err(GdepError::ScriptFailed(status, Vec::new()))}
+}
+
+/// Sends `notification` through the configured `notifier` if it is subscribed to the event
+fn notify(config: &Config, notification: Notification) {
+    if let Some(notifier) = &config.notifier {
+        if notifier.events.contains(&notification.event()) {
+            notifier.notifier().notify(&notification);
+        }
+    }
+}
+
+/// Keeps the health status in line with what the update thread reports
+fn record_signal(health: &Mutex<health::Status>, signal: &UpdateSignal) {
+    let mut health = health.lock().unwrap();
+    match signal {
+        UpdateSignal::Alive | UpdateSignal::BranchSelected(_) => {}
+        UpdateSignal::Fetched { behind } => {
+            health.last_fetch = Some(Utc::now());
+            health.behind = *behind;
+        }
+        UpdateSignal::FetchFailed => health.fetch_failures += 1,
+        UpdateSignal::Updated { current, .. } => {
+            health.head = Some(current.to_string());
+            health.updates += 1;
+            health.behind = health.behind.map(|_| 0);
+        }
+        UpdateSignal::Stopped(_) => health.updates_running = false
+    }
+}
+
+/// The thread `update_sync` runs on, with the flag that stops it and the channel it reports on
+struct UpdateThread {
+    /// None with `no_update`, which starts no thread and never reports anything
+    handle: Option<thread::JoinHandle<()>>,
+    stop_flag: Arc<Mutex<bool>>,
+    signals: mpsc::Receiver<UpdateSignal>,
+    /// Keeps `signals` connected while there is no thread
+    _idle: Option<mpsc::Sender<UpdateSignal>>
+}
+
+impl UpdateThread {
+    fn start(config: &Config, repo_path: &str, branch_name: &str, trigger: &Arc<AtomicBool>, clock: &Arc<dyn Clock>) -> Self {
+        let stop_flag = Arc::new(Mutex::new(false));
+        let (tx, signals) = mpsc::channel();
+        if config.no_update {
+            return Self { handle: None, stop_flag, signals, _idle: Some(tx) }
+        }
+
+        let repo_path_arc = Arc::new(repo_path.to_string());
+        let branch_name_arc = Arc::new(branch_name.to_string());
+        let config_arc = Arc::new(config.clone());
+        let poller = Poller::new(config, Arc::clone(trigger), Arc::clone(&stop_flag), Arc::clone(clock), false);
+        let handle = deployment_thread().spawn(move || {
+            update_sync(repo_path_arc, branch_name_arc, config_arc, poller, tx);
+        }).expect("Failed to start update thread");
+        Self { handle: Some(handle), stop_flag, signals, _idle: None }
+    }
+
+    /// Stops the thread and waits for it. Returns what it reported in the meantime
+    fn stop(self) -> Vec<UpdateSignal> {
+        *self.stop_flag.lock().unwrap() = true;
+        if let Some(handle) = self.handle {
+            handle.join().expect("Function thread panicked");
+        }
+        self.signals.try_iter().collect()
+    }
+}
+
+/// Moves the checkout to the remote tip of `branch`, which a reloaded config or the `checkout` command switched to
+fn switch_branch(repo_path: &str, branch: &str, config: &Config, clock: &dyn Clock) -> Result<(), GdepError> {
+    let repo = Repository::open(repo_path)?;
+    let target = repo.find_commit(fetch_branch(&repo, branch, config, clock).map_err(|e| tracking_error(e, branch))?.id())?;
+    let refname = format!("refs/heads/{}", branch);
+    repo.reference(&refname, target.id(), true, "Switching branch")?;
+    repo.set_head(&refname)?;
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+    info!("Switched to branch `{}` at {}", branch, target.id());
+    Ok(())
+}
+
+/// What the loops of one deployment share with its servers, its update thread and whoever stops it
+struct Shared {
+    shutdown: Arc<Mutex<bool>>,
+    triggers: Arc<control::Triggers>,
+    health: Arc<Mutex<health::Status>>,
+    /// What restart delays, script timeouts and the update thread's waits are measured on
+    clock: Arc<dyn Clock>
+}
+
+/// Runs the script and its update thread until the script ends for good, gdep is interrupted or `shutdown` is set.
+/// A script that ends is started again as configured, after `restart_delay` (plus up to `restart_jitter`) and at most `max_restarts` times in a row
+/// if it failed. With a `watcher`, changes to the config file are applied on the fly. Sums up how it ended
+fn execute(config: Config, repo_path: String, branch_name: String, shared: Shared, watcher: Option<reload::ConfigWatcher>) -> Summary {
+    let started = shared.clock.now();
+    let (err, gave_up) = if config.mode == Mode::Mirror {
+        (keep_mirrored(&config, &repo_path, &shared), false)
+    } else {
+        keep_running(config, &repo_path, branch_name, &shared, watcher)
+    };
+    let status = shared.health.lock().unwrap();
+    Summary::new(err, gave_up, *shared.shutdown.lock().unwrap(), status.head.clone(), status.updates, shared.clock.elapsed(started))
+}
+
+/// `restart_delay` plus a random part of `restart_jitter`
+fn restart_delay(config: &Config) -> Duration {
+    let jitter_ms = config.restart_jitter * 1000;
+    let random = if jitter_ms > 0 {RandomState::new().build_hasher().finish() % (jitter_ms + 1)} else {0};
+    Duration::from_secs(config.restart_delay) + Duration::from_millis(random)
+}
+
+/// The loop of `execute` for a script: the error it stopped with, and whether that was because `max_restarts` was reached
+fn keep_running(config: Config, repo_path: &str, branch_name: String, shared: &Shared, watcher: Option<reload::ConfigWatcher>) -> (Option<GdepError>, bool) {
+    let Shared { shutdown, health, clock, .. } = shared;
+    let mut config = config;
+    let mut branch_name = branch_name;
+    let mut watcher = watcher;
+    let mut restarts = 0;
+    loop {
+        let (err, rerun) = run_script_pass(&mut config, repo_path, &mut branch_name, shared, &mut watcher);
+        if !rerun {
+            return (err, false)
+        }
+        if err.is_some() {
+            if config.max_restarts.is_some_and(|max| restarts >= max) {
+                error!("Giving up after {} restart(s) in a row", restarts);
+                return (err, true)
+            }
+            restarts += 1;
+        } else {
+            restarts = 0;
+        }
+
+        let mut delay = restart_delay(&config);
+        if let Some(wait) = restart_deferral(&config, health, &**clock).filter(|wait| *wait > delay) {
+            info!("Deferring the restart, the script was started less than {} ago (min_restart_interval)", format_duration(config.min_restart_interval));
+            delay = wait;
+        }
+        if !delay.is_zero() {
+            info!("Restarting in {}...", format_duration(delay.as_secs_f64().round() as u64));
+            clock.sleep_interruptible(delay, &mut || {
+                systemd::keepalive();
+                signals::received().is_some() || *shutdown.lock().unwrap()
+            });
+            if let Some(signal) = signals::received() {
+                info!("Received signal {}, shutting down...", signal);
+                systemd::stopping();
+                return (Some(GdepError::Interrupted(signal)), false)
+            }
+            if *shutdown.lock().unwrap() {
+                return (None, false)
+            }
+        } else {
+            info!("Restarting...");
+        }
+        health.lock().unwrap().restarts += 1;
+    }
+}
+
+/// The repo-relative paths the update from `previous` to `current` added, removed or modified
+fn changed_paths(repo_path: &str, previous: git2::Oid, current: git2::Oid) -> Result<Vec<PathBuf>, Error> {
+    let repo = Repository::open(repo_path)?;
+    let old = repo.find_commit(previous)?.tree()?;
+    let new = repo.find_commit(current)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&old), Some(&new), None)?;
+    Ok(diff.deltas().flat_map(|d| [d.old_file().path(), d.new_file().path()]).flatten().map(Path::to_path_buf).collect())
+}
+
+fn patterns<'a>(globs: impl IntoIterator<Item = &'a String>) -> Vec<glob::Pattern> {
+    globs.into_iter().filter_map(|g| glob::Pattern::new(g).ok()).collect()
+}
+
+/// Whether the update from `previous` to `current` changed a file matching `watch_paths` (any file without them)
+/// that no `on_change` rule takes care of. Always true without either,
+/// and when the diff can't be made, since restarting needlessly beats missing a change
+fn touches_watched_paths(repo_path: &str, config: &Config, previous: Option<git2::Oid>, current: git2::Oid) -> bool {
+    let Some(previous) = previous.filter(|_| !config.watch_paths.is_empty() || !config.on_change.is_empty()) else {
+        return true
+    };
+    let watched = patterns(&config.watch_paths);
+    let handled = patterns(config.on_change.iter().map(|(glob, _)| glob));
+    match changed_paths(repo_path, previous, current) {
+        Ok(paths) => paths.iter().any(|path| (watched.is_empty() || watched.iter().any(|p| p.matches_path(path)))
+            && !handled.iter().any(|p| p.matches_path(path))),
+        Err(e) => {
+            warn!("Could not tell which files changed ({}), restarting anyway", e.message());
+            true
+        }
+    }
+}
+
+/// Runs the script of every `on_change` rule whose glob matches a file the update from `previous` to `current` changed,
+/// in declaration order. Every one runs when the diff can't be made. The status of the first that fails, skipping the rest
+fn run_on_change(config: &Config, repo_path: &str, branch: &str, tail: Option<&OutputTail>, previous: Option<git2::Oid>, current: git2::Oid, clock: &Arc<dyn Clock>) -> Option<ExitStatus> {
+    let previous = previous.filter(|_| !config.on_change.is_empty())?;
+    let paths = match changed_paths(repo_path, previous, current) {
+        Ok(paths) => Some(paths),
+        Err(e) => {
+            warn!("Could not tell which files changed ({}), running every on_change script", e.message());
+            None
+        }
+    };
+    for (glob, script) in &config.on_change {
+        let pattern = glob::Pattern::new(glob).ok()?;
+        if paths.as_ref().is_some_and(|paths| !paths.iter().any(|p| pattern.matches_path(p))) {
+            continue
+        }
+        info!("Files matching `{}` changed, running its script...", glob);
+        let status = run_to_completion(config, script, repo_path, branch, tail, clock);
+        if !status.success() {
+            error!("The on_change script for `{}` failed ({})", glob, status);
+            return Some(status)
+        }
+    }
+    None
+}
+
+/// Appends `entry` to the deploy history. Failing to is only logged
+fn append_history(repo_path: &str, entry: &history::Entry) {
+    if let Err(e) = history::record(repo_path, entry) {
+        warn!("Could not write to the deploy history: {}", e);
+    }
+}
+
+/// Appends `entry` to the deploy history and makes it the latest one the health endpoint reports, counting the exit code of script runs
+fn record_history(repo_path: &str, health: &Mutex<health::Status>, entry: history::Entry) {
+    append_history(repo_path, &entry);
+    let mut health = health.lock().unwrap();
+    if let Some(code) = entry.exit_code {
+        *health.exit_codes.entry(code).or_default() += 1;
+    }
+    health.last_deploy = Some(entry);
+}
+
+/// `mode: mirror` has no script to supervise, this only runs the update thread until it stops, gdep is interrupted or `shutdown` is set
+fn keep_mirrored(config: &Config, repo_path: &str, shared: &Shared) -> Option<GdepError> {
+    let Shared { shutdown, triggers, health, clock } = shared;
+    let updates = UpdateThread::start(config, repo_path, "", &triggers.update, clock);
+    health.lock().unwrap().updates_running = true;
+    systemd::ready();
+
+    let mut err = loop {
+        systemd::keepalive();
+        if let Some(signal) = signals::received() {
+            info!("Received signal {}, shutting down...", signal);
+            systemd::stopping();
+            break Some(GdepError::Interrupted(signal))
+        }
+        if *shutdown.lock().unwrap() {
+            info!("Shutting down...");
+            systemd::stopping();
+            break None
+        }
+        match updates.signals.recv_timeout(SUPERVISE_INTERVAL) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => panic!("Failed to receive signal from update thread"),
+            Ok(UpdateSignal::Stopped(e)) => break e,
+            Ok(signal) => record_signal(health, &signal)
+        }
+    };
+    for signal in updates.stop() {
+        if let UpdateSignal::Stopped(e) = signal {
+            err = err.or(e);
+        }
+    }
+    let mut health = health.lock().unwrap();
+    health.updates_running = false;
+    if let Some(e) = &err {
+        health.last_error = Some(e.to_string());
+        if !matches!(e, GdepError::Interrupted(_)) {
+            notify(config, Notification::Error { name: &config.name, branch: "*", err: e });
+        }
+    }
+    err
+}
+
+/// The script a `run_script_pass` supervises, with what its restarts and rollbacks change
+struct Script {
+    child: ScriptChild,
+    /// The last lines of its output, with `error_context_lines`
+    tail: Option<OutputTail>,
+    /// When it was last started, `script_timeout` counts from there
+    started: Instant,
+    /// Set when a step failed while restarting the script, which then counts as the script failing
+    step_failure: Option<ExitStatus>,
+    /// The commits before and after the last update, the script failing rolls back to the former with `rollback_on_failure`
+    last_update: Option<(git2::Oid, git2::Oid)>,
+    /// Set while the script exited and its restart is held back, there is nothing to wait for or time out then
+    exited: bool,
+    clock: Arc<dyn Clock>
+}
+
+impl Script {
+    /// Does `restart`. The script restarted for an update has to get ready and pass the smoke test, else the update is rolled back.
+    /// The restart rolling back asks for, or the error the pass stops with
+    fn restart(&mut self, restart: Restart, config: &Config, repo_path: &str, branch: &str, grace: Duration, health: &Mutex<health::Status>) -> Result<Option<Restart>, GdepError> {
+        self.exited = false;
+        let (previous, current) = match restart {
+            Restart::Update(previous, current) => (previous, current),
+            restart => {
+                self.respawn(config, repo_path, branch, grace, health);
+                if self.step_failure.is_none() && matches!(restart, Restart::Changed) {
+                    go_live(config, repo_path, &*self.clock);
+                }
+                return Ok(None)
+            }
+        };
+
+        let not_ready = if config.graceful_restart {
+            self.restart_gracefully(config, repo_path, branch, grace, health).err()
+        } else {
+            info!("Restarting script with updated code...");
+            self.respawn(config, repo_path, branch, grace, health);
+            match self.step_failure {
+                None => wait_until_ready(config, repo_path, branch, &mut self.child, &*self.clock).err(),
+                Some(_) => None
+            }
+        };
+        match &not_ready {
+            Some(reason) => error!("Commit {} did not become ready: {}", current, reason),
+            None => notify(config, Notification::Update { name: &config.name, branch, previous, current })
+        }
+        let smoke_failed = self.step_failure.is_none() && not_ready.is_none() && !smoke_test_passed(config, repo_path, branch, &*self.clock);
+        if smoke_failed {
+            error!("Smoke test failed for commit {}", current);
+        } else if self.step_failure.is_none() && not_ready.is_none() {
+            record_known_good(repo_path, branch);
+            go_live(config, repo_path, &*self.clock);
+        }
+
+        match (previous, not_ready) {
+            (Some(previous), not_ready) if not_ready.is_some() || (smoke_failed && config.rollback_on_smoke_fail) => {
+                self.roll_back(repo_path, branch, previous, current, health)?;
+                if config.graceful_restart && not_ready.is_some() {
+                    warn!("Rolled back to {}, the previous version keeps running", previous);
+                    Ok(None)
+                } else {
+                    warn!("Rolled back to {}, restarting the previous version...", previous);
+                    Ok(Some(Restart::Plain))
+                }
+            }
+            // The script that was running before the update still is
+            (None, Some(reason)) if config.graceful_restart => {
+                let failure = GdepError::NotReady(reason);
+                health.lock().unwrap().last_error = Some(failure.to_string());
+                notify(config, Notification::Error { name: &config.name, branch, err: &failure });
+                Ok(None)
+            }
+            (None, Some(reason)) => Err(GdepError::NotReady(reason)),
+            _ => Ok(None)
+        }
+    }
+
+    /// Stops the script and `start_script`s it again. A failing step, or a script that could not be started, is kept in `step_failure`, the script stays stopped then
+    fn respawn(&mut self, config: &Config, repo_path: &str, branch: &str, grace: Duration, health: &Mutex<health::Status>) {
+        process::terminate(&mut self.child, grace, &*self.clock).expect("Failed to stop the subprocess");
+        health.lock().unwrap().restarts += 1;
+        let started = start_script(config, repo_path, branch, self.tail.as_ref(), &self.clock);
+        self.started = self.clock.now();
+        health.lock().unwrap().last_start = Some(self.started);
+        match started {
+            Ok(new) => self.child = new,
+            Err(status) => self.step_failure = Some(status)
+        }
+    }
+
+    /// `graceful_restart`: starts the script next to the running one, which is only stopped and replaced once the new one is ready.
+    /// Why the new one did not get ready otherwise, it is stopped then and the running one is kept
+    fn restart_gracefully(&mut self, config: &Config, repo_path: &str, branch: &str, grace: Duration, health: &Mutex<health::Status>) -> Result<(), String> {
+        info!("Starting the updated script next to the running one...");
+        let new = start_script(config, repo_path, branch, self.tail.as_ref(), &self.clock);
+        health.lock().unwrap().last_start = Some(self.clock.now());
+        let mut new = new.map_err(|status| format!("a step failed or the script could not be started ({})", status))?;
+        if let Err(reason) = wait_until_ready(config, repo_path, branch, &mut new, &*self.clock) {
+            process::terminate(&mut new, grace, &*self.clock).expect("Failed to stop the subprocess");
+            return Err(reason)
+        }
+        info!("The updated script is ready, stopping the previous one...");
+        process::terminate(&mut self.child, grace, &*self.clock).expect("Failed to stop the subprocess");
+        self.child = new;
+        health.lock().unwrap().restarts += 1;
+        self.started = self.clock.now();
+        Ok(())
+    }
+
+    /// Resets the checkout to `previous`, the update from it to `current` having failed, and records that in the deploy history
+    fn roll_back(&mut self, repo_path: &str, branch: &str, previous: git2::Oid, current: git2::Oid, health: &Mutex<health::Status>) -> Result<(), GdepError> {
+        roll_back(repo_path, branch, previous, current).map_err(|e| GdepError::RollbackFailed(previous.to_string(), e.to_string()))?;
+        self.last_update = None;
+        health.lock().unwrap().head = Some(previous.to_string());
+        record_history(repo_path, health, history::Entry::update(history::Kind::Rollback, branch, Some(current), previous));
+        Ok(())
+    }
+}
+
+/// One run of the script alongside its update thread. Returns why it ended and whether the script is to be run again
+fn run_script_pass(config: &mut Config, repo_path: &str, branch_name: &mut String, shared: &Shared, watcher: &mut Option<reload::ConfigWatcher>) -> (Option<GdepError>, bool) {
+    let Shared { shutdown, triggers, health, clock } = shared;
+    let mut do_rerun = false;
+    let mut err = None;
+    let mut grace = Duration::from_secs(config.shutdown_grace);
+
+    let tail = config.error_context_lines.map(OutputTail::new);
+    let started = start_script(config, repo_path, branch_name, tail.as_ref(), clock);
+    health.lock().unwrap().last_start = Some(clock.now());
+    let mut child = match started {
+        Ok(child) => child,
+        Err(status) => {
+            if let Some(signal) = signals::received() {
+                return (Some(GdepError::Interrupted(signal)), false)
+            }
+            let context = tail.map(|t| t.lines()).unwrap_or_default();
+            let failure = GdepError::ScriptFailed(status, context.clone());
+            let head = health.lock().unwrap().head.clone();
+            record_history(repo_path, health, history::Entry::script(branch_name, head, Some(status), Some(failure.to_string())));
+            health.lock().unwrap().last_error = Some(failure.to_string());
+            notify(config, Notification::Error { name: &config.name, branch: branch_name, err: &failure });
+            let rerun = !config.exit_on_script_error || config.re_run;
+            if rerun {
+                print_output_context(&context);
+            }
+            return (Some(failure), rerun)
+        }
+    };
+    let started = clock.now();
+    if let Err(reason) = wait_until_ready(config, repo_path, branch_name, &mut child, &**clock) {
+        process::terminate(&mut child, grace, &**clock).expect("Failed to stop the subprocess");
+        let failure = GdepError::NotReady(reason);
+        run_cleanup(config, repo_path, branch_name, Some(&failure), None, false, clock);
+        let head = health.lock().unwrap().head.clone();
+        record_history(repo_path, health, history::Entry::script(branch_name, head, None, Some(failure.to_string())));
+        health.lock().unwrap().last_error = Some(failure.to_string());
+        notify(config, Notification::Error { name: &config.name, branch: branch_name, err: &failure });
+        return (Some(failure), !config.exit_on_script_error || config.re_run)
+    }
+    systemd::ready();
+    let mut script = Script { child, tail, started, step_failure: None, last_update: None, exited: false, clock: Arc::clone(clock) };
+    record_known_good(repo_path, branch_name);
+    go_live(config, repo_path, &**clock);
+    // A restart asked for while the script wasn't running is done already
+    triggers.restart.store(false, Ordering::SeqCst);
+
+    let mut updates = UpdateThread::start(config, repo_path, branch_name, &triggers.update, clock);
+    {
+        let mut health = health.lock().unwrap();
+        health.script_running = true;
+        health.updates_running = !config.no_update;
+    }
+
+    let mut result: Option<ExitStatus> = None;
+    let mut interrupted = None;
+    let mut shut_down = false;
+    let mut script_timed_out = false;
+    // The update to announce, one the script is restarted for is announced by `Script::restart` once it got ready
+    let mut announce = None;
+    // The restart `min_restart_interval` holds back
+    let mut pending = None;
+
+    loop {
+        systemd::keepalive();
+        if let Some(signal) = signals::received() {
+            info!("Received signal {}, shutting down...", signal);
+            systemd::stopping();
+            interrupted = Some(signal);
+            break
+        }
+        if *shutdown.lock().unwrap() {
+            info!("Shutting down...");
+            systemd::stopping();
+            shut_down = true;
+            break
+        }
+        if triggers.restart.swap(false, Ordering::SeqCst) {
+            info!("Restarting script as requested...");
+            defer_restart(&mut pending, Restart::Plain, config, health, &**clock);
+        }
+        if let Some(branch) = triggers.checkout.lock().unwrap().take().filter(|b| b != branch_name) {
+            info!("Switching to branch `{}` as requested...", branch);
+            for signal in updates.stop() {
+                record_signal(health, &signal);
+            }
+            match switch_branch(repo_path, &branch, config, &**clock) {
+                Ok(()) => {
+                    *branch_name = branch;
+                    {
+                        let mut health = health.lock().unwrap();
+                        health.branch = branch_name.clone();
+                        health.head = Repository::open(repo_path).ok().and_then(|repo| head_id(&repo)).map(|id| id.to_string());
+                    }
+                    defer_restart(&mut pending, Restart::Changed, config, health, &**clock);
+                }
+                Err(e) => error!("Staying on branch `{}`, switching to `{}` failed: {}", branch_name, branch, e)
+            }
+            updates = UpdateThread::start(config, repo_path, branch_name, &triggers.update, clock);
+            health.lock().unwrap().updates_running = !config.no_update;
+        }
+        let signal = updates.signals.recv_timeout(SUPERVISE_INTERVAL);
+        if let Ok(signal) = &signal {
+            record_signal(health, signal);
+            if let UpdateSignal::Updated { previous, current } = signal {
+                script.last_update = previous.map(|p| (p, *current));
+                let commit = CommitInfo::find(repo_path, *current);
+                events::emit(Some(&config.name), Event::UpdateApplied { previous: *previous, current: *current, commit: commit.as_ref() });
+                record_history(repo_path, health, history::Entry::update(history::Kind::Update, branch_name, *previous, *current));
+                announce = Some((*previous, *current));
+                script.step_failure = run_on_change(config, repo_path, branch_name, script.tail.as_ref(), *previous, *current, clock);
+            }
+        }
+        match signal {
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => panic!("Failed to receive signal from update thread"),
+            Ok(UpdateSignal::Stopped(e)) => {
+                err = e;
+                break
+            }
+            // A failed `on_change` script counts as the script failing, so it is stopped instead of restarted
+            Ok(UpdateSignal::Updated { .. }) if script.step_failure.is_some() => {
+                process::terminate(&mut script.child, grace, &**clock).expect("Failed to stop the subprocess");
+            }
+            Ok(UpdateSignal::Updated { previous, current }) if config.restart_after_update && !touches_watched_paths(repo_path, config, previous, current) => {
+                info!("No watched path changed, keeping the script running");
+            }
+            Ok(UpdateSignal::Updated { previous, current }) if config.restart_after_update => {
+                // Announced once the restarted script got ready
+                announce = None;
+                defer_restart(&mut pending, Restart::Update(previous, current), config, health, &**clock);
+            }
+            Ok(UpdateSignal::BranchSelected(branch)) => {
+                *triggers.checkout.lock().unwrap() = Some(branch);
+            }
+            Ok(UpdateSignal::Updated { .. } | UpdateSignal::Alive | UpdateSignal::Fetched { .. } | UpdateSignal::FetchFailed) => {}
+        }
+        let forced = triggers.reload.swap(false, Ordering::SeqCst);
+        match watcher.as_mut().and_then(|w| w.poll(forced).map(|r| (r, w.path().to_string()))) {
+            None => {}
+            Some((Err(e), path)) => warn!("Ignoring the changed config `{}`, it does not load: {}", path, e),
+            Some((Ok(new), path)) => {
+                let changes = watcher.as_ref().unwrap().compare(config, &new, branch_name);
+                info!("Config `{}` changed, applying it...", path);
+                for key in &changes.ignored {
+                    warn!("`{}` changed, restart gdep to apply it", key);
+                }
+                let mut restart_script = changes.script;
+                if changes.updates || changes.branch.is_some() {
+                    for signal in updates.stop() {
+                        record_signal(health, &signal);
+                        restart_script |= match signal {
+                            UpdateSignal::Updated { previous, current } => {
+                                script.step_failure = script.step_failure.or_else(|| run_on_change(&new, repo_path, branch_name, script.tail.as_ref(), previous, current, clock));
+                                new.restart_after_update && touches_watched_paths(repo_path, &new, previous, current)
+                            }
+                            _ => false
+                        };
+                    }
+                    if let Some(branch) = &changes.branch {
+                        match switch_branch(repo_path, branch, &new, &**clock) {
+                            Ok(()) => {
+                                *branch_name = branch.clone();
+                                health.lock().unwrap().branch = branch.clone();
+                                restart_script = true;
+                            }
+                            Err(e) => error!("Staying on branch `{}`, switching to `{}` failed: {}", branch_name, branch, e)
+                        }
+                    }
+                    updates = UpdateThread::start(&new, repo_path, branch_name, &triggers.update, clock);
+                    health.lock().unwrap().updates_running = !new.no_update;
+                }
+                *config = new;
+                grace = Duration::from_secs(config.shutdown_grace);
+                if script.step_failure.is_some() {
+                    process::terminate(&mut script.child, grace, &**clock).expect("Failed to stop the subprocess");
+                } else if restart_script {
+                    info!("Restarting script with the new config...");
+                    defer_restart(&mut pending, Restart::Changed, config, health, &**clock);
+                }
+            }
+        }
+        if pending.is_some() && restart_deferral(config, health, &**clock).is_none() {
+            match script.restart(pending.take().unwrap(), config, repo_path, branch_name, grace, health) {
+                Ok(then) => if let Some(then) = then {
+                    defer_restart(&mut pending, then, config, health, &**clock);
+                },
+                Err(e) => {
+                    err = Some(e);
+                    break
+                }
+            }
+        }
+        if let Some((previous, current)) = announce.take() {
+            notify(config, Notification::Update { name: &config.name, branch: branch_name, previous, current });
+        }
+        let status = match script.step_failure.take() {
+            Some(status) => Ok(Some(status)),
+            None if script.exited => Ok(None),
+            None => script.child.try_wait()
+        };
+        match status {
+            Ok(status) => result = status,
+            Err(_) => {
+                *updates.stop_flag.lock().unwrap() = true;
+                break
+            }
+        }
+        if let Some(status) = result {
+            events::emit(Some(&config.name), Event::ScriptExited { status });
+            let error = (!status.success()).then(|| GdepError::ScriptFailed(status, Vec::new()).to_string());
+            let head = health.lock().unwrap().head.clone();
+            record_history(repo_path, health, history::Entry::script(branch_name, head, Some(status), error));
+        }
+        if let (Some(status), Some((previous, current)), true) = (result.filter(|s| !s.success()), script.last_update, config.rollback_on_failure) {
+            error!("Script failed ({}) after the update to {}, rolling back to {}...", status, current, previous);
+            print_output_context(&script.tail.as_ref().map(|t| t.lines()).unwrap_or_default());
+            if let Err(e) = script.roll_back(repo_path, branch_name, previous, current, health) {
+                err = Some(e);
+                result = None;
+                break
+            }
+            warn!("Rolled back to {}, restarting the script at it", previous);
+            // It was live before the update, so it goes back to being live right away
+            go_live(config, repo_path, &**clock);
+            result = None;
+            // Anything held back was for the version the script is no longer at
+            pending = None;
+            defer_restart(&mut pending, Restart::Plain, config, health, &**clock);
+            script.exited = true;
+            continue
+        }
+        if result.is_some() {
+            break;
+        }
+        if !script.exited && timed_out(config, script.started, &**clock) {
+            warn!("Script exceeded its timeout of {}, stopping it", format_duration(config.script_timeout.unwrap_or_default()));
+            script_timed_out = true;
+            do_rerun = !config.exit_on_script_error;
+            let timeout = GdepError::ScriptTimeout(config.script_timeout.unwrap_or_default(), Vec::new()).to_string();
+            let head = health.lock().unwrap().head.clone();
+            record_history(repo_path, health, history::Entry::script(branch_name, head, None, Some(timeout.clone())));
+            health.lock().unwrap().last_error = Some(timeout);
+            break
+        }
+    }
+
+    health.lock().unwrap().script_running = false;
+    let mut script_failure = None;
+    if let Some(status) = result {
+        if !status.success() {
+            error!("Running script failed with exit code: {}", status);
+            health.lock().unwrap().last_error = Some(GdepError::ScriptFailed(status, Vec::new()).to_string());
+            do_rerun = !config.exit_on_script_error;
+            script_failure = Some(status);
+        }
+    }
+    
+    if err.is_some() {
+        do_rerun = !config.exit_on_gdep_error;
+    }
+
+    *updates.stop_flag.lock().unwrap() = true;
+
+    process::terminate(&mut script.child, grace, &**clock).expect("Failed to stop the subprocess");
+
+    let stopped_with = match interrupted {
+        Some(signal) => Some(GdepError::Interrupted(signal)),
+        None => err.clone()
+            .or_else(|| script_failure.map(|status| GdepError::ScriptFailed(status, Vec::new())))
+            .or_else(|| script_timed_out.then(|| GdepError::ScriptTimeout(config.script_timeout.unwrap_or_default(), Vec::new())))
+    };
+    run_cleanup(config, repo_path, branch_name, stopped_with.as_ref(), result, shut_down, clock);
+
+    if let Some(handle) = updates.handle {
+        handle.join().expect("Function thread panicked");
+    }
+    {
+        let mut health = health.lock().unwrap();
+        health.updates_running = false;
+        if let Some(e) = &err {
+            health.last_error = Some(e.to_string());
+        }
+    }
+
+    if let Some(signal) = interrupted {
+        return (Some(GdepError::Interrupted(signal)), false)
+    }
+    if shut_down {
+        return (None, false)
+    }
+
+    let context = script.tail.map(|t| t.lines()).unwrap_or_default();
+    let failure = match script_failure {
+        Some(status) => Some(GdepError::ScriptFailed(status, context.clone())),
+        None if script_timed_out => Some(GdepError::ScriptTimeout(config.script_timeout.unwrap_or_default(), context.clone())),
+        None => None
+    };
+    if let Some(e) = err.as_ref().or(failure.as_ref()) {
+        notify(config, Notification::Error { name: &config.name, branch: branch_name, err: e });
+    }
+    let rerun = do_rerun || config.re_run;
+    if failure.is_some() && rerun {
+        print_output_context(&context);
+    }
+    (err.or(failure), rerun)
+}
+
+/// A builder for a helper thread of the current deployment, named like it so its log lines can be told apart
+pub(crate) fn deployment_thread() -> thread::Builder {
+    match thread::current().name().filter(|n| *n != "main") {
+        Some(name) => thread::Builder::new().name(name.to_string()),
+        None => thread::Builder::new()
+    }
+}
+
+/// Runs one deployment: starts its webhook listener, health and metrics endpoints and control socket, if any, and `execute`s it.
+/// The summary of how it ended is logged and emitted as the `stopped` event
+pub fn deploy(config: Config, repo_path: String, branch_name: String, shutdown: Arc<Mutex<bool>>, watcher: Option<reload::ConfigWatcher>, clock: Arc<dyn Clock>) -> Summary {
+    let name = config.name.clone();
+    let summary = serve_and_execute(config, repo_path, branch_name, shutdown, watcher, clock);
+    info!("{}", summary);
+    events::emit(Some(&name), Event::Stopped { summary: &summary });
+    summary
+}
+
+fn serve_and_execute(config: Config, repo_path: String, branch_name: String, shutdown: Arc<Mutex<bool>>, watcher: Option<reload::ConfigWatcher>, clock: Arc<dyn Clock>) -> Summary {
+    let triggers = Arc::new(control::Triggers::default());
+    let head = Repository::open(&repo_path).ok().and_then(|repo| head_id(&repo)).map(|id| id.to_string());
+    let mut status = health::Status::new(&branch_name, head);
+    status.mirror = config.mode == Mode::Mirror;
+    status.last_deploy = history::last(&repo_path, 1).ok().and_then(|mut entries| entries.pop());
+    let health = Arc::new(Mutex::new(status));
+    let servers_stop = Arc::new(Mutex::new(false));
+    let mut servers = Vec::new();
+
+    if let Some(hook) = config.webhook.clone() {
+        let bind = hook.bind.clone();
+        match webhook::serve(hook, Arc::clone(&triggers.update), Arc::clone(&servers_stop)) {
+            Ok(handle) => servers.push(handle),
+            Err(e) => return Summary::new(Some(GdepError::WebhookFailed(bind, e.to_string())), false, false, None, 0, Duration::ZERO)
+        }
+    }
+    // The metrics are served by the health server when both share an address
+    let shared = config.health_addr.is_some() && config.metrics_addr == config.health_addr;
+    if let Some(addr) = &config.health_addr {
+        match health::serve(addr, Arc::clone(&health), Arc::clone(&servers_stop), health::Endpoints { healthz: true, metrics: shared, healthz_auth: config.health_auth.clone(), metrics_auth: config.metrics_auth.clone() }) {
+            Ok(handle) => servers.push(handle),
+            Err(e) => {
+                *servers_stop.lock().unwrap() = true;
+                servers.into_iter().for_each(|h| h.join().expect("Webhook listener panicked"));
+                return Summary::new(Some(GdepError::HealthEndpointFailed(addr.clone(), e.to_string())), false, false, None, 0, Duration::ZERO)
+            }
+        }
+    }
+    if let Some(addr) = config.metrics_addr.as_ref().filter(|_| !shared) {
+        match health::serve(addr, Arc::clone(&health), Arc::clone(&servers_stop), health::Endpoints { healthz: false, metrics: true, healthz_auth: None, metrics_auth: config.metrics_auth.clone() }) {
+            Ok(handle) => servers.push(handle),
+            Err(e) => {
+                *servers_stop.lock().unwrap() = true;
+                servers.into_iter().for_each(|h| h.join().expect("HTTP server thread panicked"));
+                return Summary::new(Some(GdepError::MetricsEndpointFailed(addr.clone(), e.to_string())), false, false, None, 0, Duration::ZERO)
+            }
+        }
+    }
+    if let Some(path) = &config.control_socket {
+        match control::serve(path, &repo_path, Arc::clone(&triggers), Arc::clone(&health), Arc::clone(&shutdown), Arc::clone(&servers_stop)) {
+            Ok(handle) => servers.push(handle),
+            Err(e) => {
+                *servers_stop.lock().unwrap() = true;
+                servers.into_iter().for_each(|h| h.join().expect("HTTP server thread panicked"));
+                return Summary::new(Some(GdepError::ControlSocketFailed(path.clone(), e.to_string())), false, false, None, 0, Duration::ZERO)
+            }
+        }
+    }
+
+    let summary = execute(config, repo_path, branch_name, Shared { shutdown, triggers, health, clock }, watcher);
+
+    *servers_stop.lock().unwrap() = true;
+    for handle in servers {
+        handle.join().expect("Server thread panicked");
+    }
+    summary
+}
+
+/// Runs `deploy` for every deployment in its own thread, all sharing one shutdown flag.
+/// A deployment that stops with an error only takes the others down if it is `critical`
+pub fn supervise(deployments: Vec<(Config, String, String, Option<reload::ConfigWatcher>)>, clock: &Arc<dyn Clock>) -> Option<GdepError> {
+    let shutdown = Arc::new(Mutex::new(false));
+    let handles: Vec<_> = deployments.into_iter().map(|(config, repo_path, branch, watcher)| {
+        let name = config.name.clone();
+        let shutdown = Arc::clone(&shutdown);
+        let clock = Arc::clone(clock);
+        let handle = thread::Builder::new().name(name.clone()).spawn(move || {
+            let critical = config.critical;
+            let err = deploy(config, repo_path, branch, Arc::clone(&shutdown), watcher, clock).error;
+            match &err {
+                Some(GdepError::Interrupted(_)) | None => {}
+                Some(_) if critical => *shutdown.lock().unwrap() = true,
+                Some(e) => error!("Deployment stopped, the others keep running: {}", e)
+            }
+            err
+        }).expect("Failed to start deployment thread");
+        (name, handle)
+    }).collect();
+
+    // Every handle is joined, even after a signal, so no deployment's cleanup is cut short by gdep exiting
+    let mut first_err = None;
+    let mut interrupted = None;
+    for (name, handle) in handles {
+        match handle.join().expect("Deployment thread panicked") {
+            Some(GdepError::Interrupted(signal)) => { interrupted.get_or_insert(signal); }
+            Some(e) => { first_err.get_or_insert(GdepError::DeploymentFailed(name, Box::new(e))); }
+            None => {}
+        }
+    }
+    interrupted.map(GdepError::Interrupted).or(first_err)
+}
+
+/// How the initial clone is made: shallow if `depth` is set, aborted and retried (at most `stall_retries` times)
+/// if it receives nothing for `stall_timeout`, and retried (at most `retries` times) after transient network errors
+#[derive(Clone)]
+pub struct CloneSettings {
+    /// A bare clone with origin's refs as they are, for `mode: mirror`
+    mirror: bool,
+    /// The branch to check out right away instead of origin's default one
+    branch: Option<String>,
+    depth: Option<u32>,
+    stall_timeout: Option<Duration>,
+    stall_retries: u64,
+    retries: u64
+}
+
+impl Default for CloneSettings {
+    fn default() -> Self {
+        Self { mirror: false, branch: None, depth: None, stall_timeout: None, stall_retries: 0, retries: DEFAULT_CLONE_RETRIES }
+    }
+}
+
+impl CloneSettings {
+    /// `branch` is the one given on the command line, which wins over the config's
+    fn from_config(config: &Config, branch: Option<&String>) -> Self {
+        Self {
+            mirror: config.mode == Mode::Mirror,
+            branch: branch.or(config.branch.as_ref()).cloned(),
+            depth: config.depth,
+            stall_timeout: config.stall_timeout.map(Duration::from_secs),
+            stall_retries: config.stall_retries,
+            retries: config.clone_retries
+        }
+    }
+
+    /// Clones `branch` instead of origin's default branch, if it is known
+    pub fn with_branch(self, branch: Option<&String>) -> Self {
+        Self { branch: branch.cloned(), ..self }
+    }
+}
+
+/// Sets libgit2's read timeout for every transfer of the process, see `with_read_timeout`
+fn set_read_timeout(timeout: Duration) -> Result<(), Error> {
+    let timeout_ms = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+    // Safety: libgit2 only reads it when a transport connects
+    unsafe { git2::opts::set_server_timeout_in_milliseconds(timeout_ms) }
+}
+
+/// Runs `transfer` with libgit2's read timeout set to `timeout`, putting the previous one (`fetch_timeout`'s or the default) back afterwards
+fn with_read_timeout<T>(timeout: Option<Duration>, transfer: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    let Some(timeout) = timeout else {
+        return transfer()
+    };
+    // Safety: see `set_read_timeout`
+    let previous = unsafe { git2::opts::get_server_timeout_in_milliseconds()? };
+    set_read_timeout(timeout)?;
+    let result = transfer();
+    // Safety: see `set_read_timeout`
+    unsafe { git2::opts::set_server_timeout_in_milliseconds(previous)? };
+    result
+}
+
+fn clone_repo(url: &str, path: &str, settings: &CloneSettings, clock: &dyn Clock) -> Result<Repository, Error> {
+    // A connection that hangs completely never reports progress, so the transport's own read timeout covers that case
+    with_read_timeout(settings.stall_timeout, || clone_with_retries(url, path, settings, clock))
+}
+
+/// Retries a clone that stalls or fails on a transient error, as `settings` allow. Stalls are timed and the retry delay slept on `clock`
+fn clone_with_retries(url: &str, path: &str, settings: &CloneSettings, clock: &dyn Clock) -> Result<Repository, Error> {
+    let mut attempt = 0;
+    let mut failures = 0;
+    loop {
+        let stalled = Cell::new(false);
+        let last_progress = Cell::new((clock.now(), 0));
+        let mut report = remote::Progress::new("Cloning");
+        let mut cb = remote::callbacks();
+        cb.transfer_progress(|progress| {
+            report.update(&progress);
+            let (at, received) = last_progress.get();
+            if progress.received_bytes() != received {
+                last_progress.set((clock.now(), progress.received_bytes()));
+            } else if settings.stall_timeout.is_some_and(|timeout| clock.elapsed(at) >= timeout) {
+                stalled.set(true);
+                return false
+            }
+            true
+        });
+        let mut fetch_options = remote::fetch_options(settings.depth);
+        fetch_options.remote_callbacks(cb);
+
+        let mut builder = git2::build::RepoBuilder::new();
+        builder.fetch_options(fetch_options);
+        if settings.mirror {
+            builder.bare(true).remote_create(|repo, name, url| repo.remote_with_fetch(name, url, MIRROR_REFSPEC));
+        } else if let Some(branch) = &settings.branch {
+            builder.branch(branch);
+        }
+        let result = builder.clone(url, Path::new(path));
+        match result {
+            Ok(repo) if settings.mirror => {
+                adopt_remote_head(&repo)?;
+                return Ok(repo)
+            }
+            Ok(repo) => return Ok(repo),
+            Err(e) if settings.stall_timeout.is_some() && (stalled.get() || e.code() == git2::ErrorCode::Timeout) && attempt < settings.stall_retries => {
+                attempt += 1;
+                warn!("Clone stalled, retrying ({}/{})", attempt, settings.stall_retries);
+            }
+            Err(e) if retry::is_transient(&e) && failures < settings.retries => {
+                failures += 1;
+                warn!("Clone failed ({}), retrying in {}s ({}/{})", e.message(), CLONE_RETRY_DELAY.as_secs(), failures, settings.retries);
+                clock.sleep_interruptible(CLONE_RETRY_DELAY, &mut || signals::received().is_some());
+            }
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// The clone records origin's HEAD as `refs/remotes/origin/HEAD`, pointing at one of the mirrored branches.
+/// A mirror has no remote-tracking refs, so HEAD takes that branch instead
+fn adopt_remote_head(repo: &Repository) -> Result<(), Error> {
+    let Ok(mut origin_head) = repo.find_reference("refs/remotes/origin/HEAD") else {
+        return Ok(())
+    };
+    if let Some(branch) = origin_head.symbolic_target() {
+        repo.set_head(branch)?;
+    }
+    origin_head.delete()
+}
+
+/// Opens the repo at `repo_path`, cloning `repo_url` there if there is none. Whether it was cloned comes along with it
+pub fn get_repo(repo_path: &String, repo_url: Option<&String>, settings: CloneSettings, clock: &dyn Clock) -> Result<(Repository, bool), GdepError> {
+    match Repository::open(repo_path) {
+        Ok(repo) => {
+            debug!("Opened existing repo at `{}`", repo_path);
+            use_ssh_port(&repo);
+            Ok((repo, false))
+        }
+        Err(e) => {
+            debug!("Could not open `{}`: {}", repo_path, e);
+            if repo_url.is_none() {
+                return Err(GdepError::LocalRepoNotFound(repo_path.to_owned()))
+            }
+            debug!("Cloning `{}` into `{}`", repo_url.unwrap(), repo_path);
+            match clone_repo(&remote::with_ssh_port(repo_url.unwrap()), repo_path, &settings, clock) {
+                Ok(repo) => {
+                    Ok((repo, true))
+                }
+                Err(e) => {
+                    debug!("Cloning failed: {}", e);
+                    if proxy::auth_failed(&e) {
+                        return Err(GdepError::ProxyAuthFailed(proxy::describe(), e.message().to_string()))
+                    }
+                    // The clone went through, but origin has no such branch to check out
+                    if let Some(branch) = settings.branch.as_ref().filter(|_| e.code() == git2::ErrorCode::NotFound && e.class() == git2::ErrorClass::Reference) {
+                        return Err(GdepError::CloneBranchNotFound(repo_url.unwrap().to_owned(), branch.clone()))
+                    }
+                    Err(GdepError::RemoteRepoNotFound(repo_url.unwrap().to_owned()))
+                }
+            }
+        }
+    }
+}
+
+/// Points origin at the port of `ssh_command` if its URL names none, see `remote::with_ssh_port`
+fn use_ssh_port(repo: &Repository) {
+    let Some(url) = repo.find_remote("origin").ok().and_then(|r| r.url().map(str::to_string)) else {
+        return
+    };
+    let moved = remote::with_ssh_port(&url);
+    if moved != url {
+        match repo.remote_set_url("origin", &moved) {
+            Ok(()) => info!("Fetching from `{}` on the port of the SSH command", moved),
+            Err(e) => warn!("Could not point origin at `{}`: {}", moved, e.message())
+        }
+    }
+}
+
+/// `branch` is the one given on the command line, a fresh clone checks it (or the config's) out directly
+pub fn get_repo_config(config: &Config, provided_repo_path: &String, branch: Option<&String>, clock: &dyn Clock) -> Result<(Repository, bool), GdepError> {
+    match &config.repo {
+        RepoLike::Remote(r) => {get_repo(provided_repo_path, Some(r), CloneSettings::from_config(config, branch), clock)}
+        RepoLike::Local(l) => {get_repo(l, None, CloneSettings::default(), clock)}
+        RepoLike::Remote2(r, d) => {get_repo(d, Some(r), CloneSettings::from_config(config, branch), clock)}
+    }
+}
+
+
+/// Applies the config's `proxy`, credentials, `fetch_timeout` and `ca_bundle` to every later fetch and clone
+pub fn apply_network_settings(config: &Config) -> Result<(), GdepError> {
+    proxy::configure(config.proxy.as_deref());
+    if let Some(timeout) = config.fetch_timeout {
+        set_read_timeout(Duration::from_secs(timeout))?;
+    }
+    if let Some(proxy) = &config.proxy {
+        debug!("Using proxy `{}`", proxy::redact(proxy));
+    }
+    if let Some(key) = &config.ssh_key {
+        if !Path::new(key).is_file() {
+            return Err(ConfigError::InvalidValue("ssh_key".to_string(), format!("`{}` is not a file", key)).into())
+        }
+    }
+    remote::configure(config);
+    apply_ca_bundle(config)
+}
+
+/// Makes libgit2 trust the certificates in `ca_bundle` for HTTPS remotes.
+/// They are loaded in addition to the host's default trust store (OpenSSL's default locations), not instead of it.
+/// This is process wide, so it has to happen before the first fetch or clone
+fn apply_ca_bundle(config: &Config) -> Result<(), GdepError> {
+    let Some(bundle) = &config.ca_bundle else {
+        return Ok(())
+    };
+    let invalid = |msg: String| GdepError::CaBundleInvalid(bundle.clone(), msg);
+    let content = fs::read_to_string(bundle).map_err(|e| invalid(e.to_string()))?;
+    if !content.contains("-----BEGIN CERTIFICATE-----") {
+        return Err(invalid("no PEM certificates found".to_string()))
+    }
+    // Safety: called once during startup, before any other thread uses libgit2
+    unsafe { git2::opts::set_ssl_cert_file(bundle) }.map_err(|e| invalid(e.message().to_string()))
+}
+
+/// Checks that the configured `shell` or `runner` is installed and `run_as` can be switched to, resolves the branch to deploy
+/// and brings the checkout to the commit or tag the config asks for
+pub fn prepare(repo: &Repository, config: &Config, branch: Option<&String>, clock: &dyn Clock) -> Result<String, GdepError> {
+    config.check_runner()?;
+    if config.mode == Mode::Mirror {
+        if !repo.is_bare() {
+            return Err(ConfigError::InvalidValue("mode".to_string(), format!("a mirror is a bare repo, `{}` has a working tree", repo_root(repo))).into())
+        }
+        // A mirror follows every branch
+        return Ok(String::new())
+    }
+    let given = branch.or(config.branch.as_ref());
+    let branch = match given {
+        Some(t) => t.clone(),
+        // Nothing is fetched, so the branch is whatever is checked out
+        None if config.no_update => repo.head().ok().and_then(|h| h.shorthand().map(str::to_string)).unwrap_or_default(),
+        None => get_default_branch(repo, config, clock)?
+    };
+
+    if let Some(expected) = &config.expect_branch {
+        if *expected != branch {
+            return Err(GdepError::UnexpectedBranch(expected.clone(), branch))
+        }
+    }
+
+    let state_dir = conv_err_e!(state::branch_dir(repo, &branch), GdepError::StateDirFailed)?;
+    debug!("Using state directory `{}`", state_dir.display());
+    check_dirty(repo, config)?;
+
+    if config.no_update {
+        info!("Not looking for updates, only supervising the script");
+    } else if let Some(sha) = &config.commit {
+        deploy_commit(repo, sha, config.depth)?;
+    } else if config.track == Track::Tag {
+        let (tag, target) = resolve_latest_tag(repo, config, clock)?;
+        announce_tag(repo, config, &tag)?;
+        check_commit_age(repo, config, target, clock)?;
+        deploy_commit(repo, &target.to_string(), config.depth)?;
+    } else {
+        if given.is_some() {
+            check_branch_exists(repo, &branch)?;
+        }
+        attach_head(repo, &branch)?;
+        if given.is_none() {
+            check_out_branch(repo, &branch)?;
+        }
+        if config.restore_on_start {
+            restore_known_good(repo, &state_dir)?;
+        }
+    }
+    // Covers a fresh clone as well as whatever was just checked out
+    if config.submodules && !config.no_update {
+        update_submodules(repo)?;
+    }
+    if !config.no_update {
+        fetch_lfs(repo, config)?;
+    }
+    if config.deploy_mode == DeployMode::Symlink {
+        release::stage(repo, config, clock)?;
+    }
+    if config.working_dir.is_some() {
+        let dir = working_dir(config, &repo_root(repo), clock);
+        if !dir.is_dir() {
+            return Err(ConfigError::InvalidValue("working_dir".to_string(), format!("`{}` is not a directory", dir.display())).into())
+        }
+    }
+    Ok(branch)
+}
+
+/// Warns about files of the checkout with uncommitted changes, before anything is checked out over them.
+/// `fail_on_dirty` refuses to go on instead
+fn check_dirty(repo: &Repository, config: &Config) -> Result<(), GdepError> {
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let paths: Vec<String> = repo.statuses(Some(&mut options))?.iter().filter_map(|e| e.path().map(|p| p.to_string())).collect();
+    if paths.is_empty() {
+        return Ok(())
+    }
+    if config.fail_on_dirty {
+        return Err(GdepError::WorkingTreeDirty(paths))
+    }
+    let fate = if config.stash_local_changes {"they are stashed before updates"} else {"updates may overwrite them"};
+    warn!("The checkout has uncommitted changes to {}, {}", paths.join(", "), fate);
+    Ok(())
+}
+
+/// Runs the `bootstrap` script on a checkout gdep has just cloned, before the script starts.
+/// Its failure stops gdep unless `script_err_ignore` is set
+pub fn bootstrap(config: &Config, repo_path: &str, branch: &str, clock: &Arc<dyn Clock>) -> Result<(), GdepError> {
+    let Some(script) = &config.bootstrap else {
+        return Ok(())
+    };
+    info!("Fresh clone, running the bootstrap script...");
+    let status = run_to_completion(config, script, repo_path, branch, None, clock);
+    if let Some(signal) = signals::received() {
+        return Err(GdepError::Interrupted(signal))
+    }
+    if status.success() {
+        return Ok(())
+    }
+    if config.exit_on_script_error {
+        return Err(GdepError::HookFailed("bootstrap".to_string(), status.to_string()))
+    }
+    warn!("The bootstrap script failed ({}), starting the script anyway", status);
+    Ok(())
+}
+
+/// The directory of `repo`: its working tree, or the repo itself if it is bare
+pub fn repo_root(repo: &Repository) -> String {
+    let path = if repo.is_bare() {repo.path()} else {repo.path().parent().unwrap()};
+    path.to_string_lossy().trim_end_matches(std::path::MAIN_SEPARATOR).to_string()
+}
+
+/// Puts origin's original URL back when dropped, see `override_remote`
+pub struct RemoteOverride {
+    repo_path: String,
+    original: String
+}
+
+impl Drop for RemoteOverride {
+    fn drop(&mut self) {
+        match Repository::open(&self.repo_path).and_then(|repo| repo.remote_set_url("origin", &self.original)) {
+            Ok(()) => info!("Restored origin's URL to `{}`", self.original),
+            Err(e) => warn!("Could not restore origin's URL to `{}`: {}", self.original, e.message())
+        }
+    }
+}
+
+/// Points origin at `url` from now on, once gdep could connect to it. With `temporary`,
+/// the returned guard restores the original URL when it is dropped
+pub fn override_remote(repo: &Repository, url: &str, temporary: bool) -> Result<Option<RemoteOverride>, GdepError> {
+    let failed = |e: Error| GdepError::RemoteOverrideFailed(url.to_string(), e.message().to_string());
+    repo.remote_anonymous(url).and_then(|mut remote| {
+        remote.connect_auth(git2::Direction::Fetch, Some(remote::callbacks()), Some(proxy::options())).map(drop)
+    }).map_err(failed)?;
+
+    let original = repo.find_remote("origin")?.url().unwrap_or_default().to_string();
+    repo.remote_set_url("origin", url).map_err(failed)?;
+    info!("Fetching from `{}` instead of `{}`", url, original);
+    Ok(temporary.then(|| RemoteOverride { repo_path: repo_root(repo), original }))
+}
+
+/// Makes sure no other gdep works on `repo`. Held until the returned lock is dropped
+pub fn lock_repo(repo: &Repository) -> Result<state::Lock, GdepError> {
+    let path = repo_root(repo);
+    state::lock(repo).map_err(|e| match e.kind() {
+        std::io::ErrorKind::WouldBlock => GdepError::AlreadyRunning(path, state::lock_holder(repo)),
+        _ => GdepError::StateDirFailed(e.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use yaml_rust2::YamlLoader;
+
+    fn config(extra: &str) -> Config {
+        let yaml = format!("name: test\nrepo: https://example.com/repo.git\nscript: ./run.sh\n{}", extra);
+        Config::from_yaml(&YamlLoader::load_from_str(&yaml).unwrap()[0], "gdep.yml").unwrap()
+    }
+
+    fn poller(config: &Config, clock: &Arc<MockClock>, once: bool) -> Poller {
+        Poller::new(config, Arc::new(AtomicBool::new(false)), Arc::new(Mutex::new(false)), Arc::clone(clock) as Arc<dyn Clock>, once)
+    }
+
+    #[test]
+    fn cycles_are_poll_interval_apart() {
+        let clock = Arc::new(MockClock::new());
+        let mut poller = poller(&config("poll_interval: 30s"), &clock, false);
+        for _ in 0..4 {
+            assert!(poller.next_cycle());
+        }
+        assert_eq!(clock.sleeps(), [Duration::from_secs(30); 3]);
+    }
+
+    #[test]
+    fn wake_within_shortens_the_next_wait_only() {
+        let clock = Arc::new(MockClock::new());
+        let mut poller = poller(&config("poll_interval: 30s"), &clock, false);
+        assert!(poller.next_cycle());
+        poller.wake_within(Duration::from_secs(5));
+        assert!(poller.next_cycle());
+        poller.wake_within(Duration::from_secs(60));
+        assert!(poller.next_cycle());
+        assert!(poller.next_cycle());
+        assert_eq!(clock.sleeps(), [5, 30, 30].map(Duration::from_secs));
+    }
+
+    #[test]
+    fn once_runs_a_single_cycle_unless_it_is_repeated() {
+        let clock = Arc::new(MockClock::new());
+        let mut poller = poller(&config("poll_interval: 30s"), &clock, true);
+        assert!(poller.next_cycle());
+        poller.repeat();
+        assert!(poller.next_cycle());
+        assert!(!poller.next_cycle());
+        assert!(clock.sleeps().is_empty());
+    }
+
+    #[test]
+    fn stopping_ends_the_cycles() {
+        let clock = Arc::new(MockClock::new());
+        let mut poller = poller(&config("poll_interval: 30s"), &clock, false);
+        assert!(poller.next_cycle());
+        *poller.stop_flag.lock().unwrap() = true;
+        assert!(!poller.next_cycle());
+        assert!(clock.sleeps().is_empty());
+    }
+
+    #[test]
+    fn a_trigger_cuts_the_wait_short() {
+        let clock = Arc::new(MockClock::new());
+        let mut poller = poller(&config("poll_interval: 30s"), &clock, false);
+        assert!(poller.next_cycle());
+        poller.trigger.store(true, Ordering::SeqCst);
+        assert!(poller.next_cycle());
+        assert!(!poller.trigger.load(Ordering::SeqCst));
+        assert!(clock.sleeps().is_empty());
+    }
+
+    #[test]
+    fn debounce_holds_updates_until_the_window_ends() {
+        let clock = Arc::new(MockClock::new());
+        let config = config("poll_interval: 30s\ndebounce: 10s");
+        let mut poller = poller(&config, &clock, false);
+        let mut debounce = Debounce::new(&config, &*clock);
+        assert!(!debounce.holds(&mut poller));
+
+        debounce.applied();
+        clock.advance(Duration::from_secs(4));
+        assert!(debounce.holds(&mut poller));
+        assert_eq!(poller.next_wait, Some(Duration::from_secs(6)));
+
+        clock.advance(Duration::from_secs(6));
+        assert!(!debounce.holds(&mut poller));
+    }
+
+    #[test]
+    fn restarts_wait_for_min_restart_interval() {
+        let clock = MockClock::new();
+        let config = config("min_restart_interval: 1m");
+        let health = Mutex::new(health::Status::new("main", None));
+        assert_eq!(restart_deferral(&config, &health, &clock), None);
+
+        health.lock().unwrap().last_start = Some(clock.now());
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(restart_deferral(&config, &health, &clock), Some(Duration::from_secs(40)));
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(restart_deferral(&config, &health, &clock), None);
+    }
+
+    #[test]
+    fn script_timeout_counts_from_the_start() {
+        let clock = MockClock::new();
+        let config = config("script_timeout: 5m");
+        let started = clock.now();
+        clock.advance(Duration::from_secs(299));
+        assert!(!timed_out(&config, started, &clock));
+        clock.advance(Duration::from_secs(1));
+        assert!(timed_out(&config, started, &clock));
+    }
+
+    #[test]
+    fn commit_age_is_measured_on_the_clock() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let signature = git2::Signature::new("gdep", "gdep@example.com", &git2::Time::new(1_700_000_000, 0)).unwrap();
+        let tree = repo.find_tree(repo.index().unwrap().write_tree().unwrap()).unwrap();
+        let commit = repo.commit(None, &signature, &signature, "old", &tree, &[]).unwrap();
+
+        let clock = MockClock::at(chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+        let config = config("max_commit_age: 1h");
+        clock.advance(Duration::from_secs(3600));
+        assert!(check_commit_age(&repo, &config, commit, &clock).is_ok());
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(check_commit_age(&repo, &config, commit, &clock), Err(GdepError::CommitTooOld(_, 3601, 3600))));
+    }
+
+    #[test]
+    fn clone_retries_wait_on_the_clock() {
+        // Nothing listens there once the listener is gone, which fails every attempt the same transient way
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let dir = tempfile::tempdir().unwrap();
+        let settings = CloneSettings { retries: 2, ..CloneSettings::default() };
+        let clock = MockClock::new();
+        let url = format!("http://127.0.0.1:{}/repo.git", port);
+        let Err(err) = clone_with_retries(&url, &dir.path().join("work").to_string_lossy(), &settings, &clock) else {
+            panic!("cloned from nowhere")
+        };
+        assert!(retry::is_transient(&err), "{}", err);
+        assert_eq!(clock.sleeps(), [CLONE_RETRY_DELAY; 2]);
+    }
+
+    #[test]
+    fn the_restart_delay_gets_up_to_restart_jitter_added() {
+        assert_eq!(restart_delay(&config("")), Duration::ZERO);
+        assert_eq!(restart_delay(&config("restart_delay: 2\n")), Duration::from_secs(2));
+        let jittered = config("restart_delay: 2\nrestart_jitter: 1\n");
+        for _ in 0..20 {
+            let delay = restart_delay(&jittered);
+            assert!((Duration::from_secs(2)..=Duration::from_secs(3)).contains(&delay), "{:?}", delay);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The log file gdep's own output is copied to, once `open` was called
+static LOG_FILE: Mutex<Option<RotatingFile>> = Mutex::new(None);
+
+/// A buffered log file that is rotated once it grows past `max_size`:
+/// `gdep.log` becomes `gdep.log.1`, `gdep.log.1` becomes `gdep.log.2` and so on, keeping at most `keep` old files
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    size: u64,
+    writer: BufWriter<File>
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), max_size, keep, size, writer: BufWriter::new(file) })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        if self.keep == 0 {
+            self.writer = BufWriter::new(File::create(&self.path)?);
+            self.size = 0;
+            return Ok(())
+        }
+
+        let _ = fs::remove_file(self.rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.writer = BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Only rotate between writes, a single record is never split across files
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.writer.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+/// Starts copying all log output to `path`. Replaces the previous log file, if any
+pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<()> {
+    let file = RotatingFile::open(path, max_size, keep)?;
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+    Ok(())
+}
+
+/// Flushes and closes the log file. Statics are never dropped, so this has to be called before exiting
+pub fn close() {
+    LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+/// Writes `line` to the log file alone, for what gdep prints to stderr itself instead of logging it
+pub fn append(line: &str) {
+    if let Some(file) = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+/// The logger's target: writes to stderr and, once opened, the log file.
+/// Failing to write the log file never fails the log call, stderr still got the line
+pub struct Tee;
+
+/// `buf` without the color escape sequences (`ESC [ ... m`) of `--color`, which don't belong in a file
+fn strip_colors(buf: &[u8]) -> Cow<'_, [u8]> {
+    if !buf.contains(&0x1b) {
+        return Cow::Borrowed(buf)
+    }
+    let mut out = Vec::with_capacity(buf.len());
+    let mut bytes = buf.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        if byte == 0x1b && bytes.peek() == Some(&b'[') {
+            // Parameters and intermediates up to the final byte, which is in `@`..=`~`
+            for byte in bytes.by_ref().skip(1) {
+                if (0x40..=0x7e).contains(&byte) {
+                    break
+                }
+            }
+            continue
+        }
+        out.push(byte);
+    }
+    Cow::Owned(out)
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        if let Some(file) = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            let _ = file.write_all(&strip_colors(buf));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            let _ = file.flush();
+        }
+        io::stderr().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_lines(file: &mut RotatingFile, lines: &[&str]) {
+        for line in lines {
+            file.write_all(format!("{}\n", line).as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+    }
+
+    #[test]
+    fn files_rotate_past_max_size_keeping_the_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gdep.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        // Every line is 6 bytes, so each one after the first starts a new file
+        write_lines(&mut file, &["line1", "line2", "line3", "line4"]);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line4\n");
+        assert_eq!(fs::read_to_string(dir.path().join("gdep.log.1")).unwrap(), "line3\n");
+        assert_eq!(fs::read_to_string(dir.path().join("gdep.log.2")).unwrap(), "line2\n");
+        assert!(!dir.path().join("gdep.log.3").exists());
+    }
+
+    #[test]
+    fn a_reopened_file_counts_what_it_already_holds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gdep.log");
+        fs::write(&path, "old lines\n").unwrap();
+        let mut file = RotatingFile::open(&path, 12, 1).unwrap();
+        write_lines(&mut file, &["new"]);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert_eq!(fs::read_to_string(dir.path().join("gdep.log.1")).unwrap(), "old lines\n");
+    }
+
+    #[test]
+    fn without_old_files_the_file_starts_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gdep.log");
+        let mut file = RotatingFile::open(&path, 8, 0).unwrap();
+        write_lines(&mut file, &["line1", "line2"]);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line2\n");
+        assert!(!dir.path().join("gdep.log.1").exists());
+    }
+
+    #[test]
+    fn colors_are_stripped() {
+        assert_eq!(&*strip_colors(b"\x1b[1m\x1b[32mINFO\x1b[0m done"), b"INFO done");
+        assert_eq!(&*strip_colors(b"plain"), b"plain");
+    }
+}
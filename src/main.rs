@@ -1,358 +1,535 @@
-mod config;
-mod errors;
-
-use std::{env};
-use std::path::PathBuf;
-use std::process::{ExitStatus};
-use git2::{Error, Repository, BranchType, RemoteCallbacks, Cred, AnnotatedCommit, FetchOptions, AutotagOption};
-use std::string::ToString;
+use std::{env, fs, io, slice};
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::sync::{Arc, Mutex, mpsc};
+use git2::Repository;
 use clap::{Arg, ArgMatches, ColorChoice};
-use run_script::ScriptOptions;
-use run_script::types::IoOptions;
-use crate::config::{Config, ConfigError, RepoLike};
-use crate::errors::GdepError;
-use crate::errors::GdepError::{UpdateErrorAheadBehind, UpdateErrorRepoAhead, UpdateFailed};
-
-pub const NAME: &str = env!("CARGO_PKG_NAME");
-pub const VERSION: &str = env!("CARGO_PKG_VERSION");
-pub const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
-static DEFAULT_REPO_PATH: &str = "gdep_used_repo";
-
-#[macro_export]
-macro_rules! conv_err {
-    ($pre:expr, $err: expr) => {
-        $pre.or_else(|_| { Err($err) })
-    };
+use log::{error, info, warn};
+use gdep::{conv_err, events, history, logfile, notify, output, reload, schema, signals, state, systemd, CloneSettings, UpdateRelationState, DEFAULT_REPO_PATH, DESCRIPTION, NAME, VERSION};
+use gdep::{apply_network_settings, bootstrap, deploy, deploy_commit, get_default_branch, get_repo, get_repo_config, head_id, lock_repo, override_remote, prepare, repo_root,
+    repo_update_cycle, resolve_latest_tag, run_once, single_pass, supervise, tracking_error, update_submodules};
+use gdep::clock::{Clock, SystemClock};
+use gdep::config::{self, Config, DEFAULT_LOG_KEEP, DEFAULT_LOG_MAX_SIZE, ConfigError, Mode, OnAhead, RepoLike, Track};
+use gdep::errors::GdepError;
+use gdep::events::Event;
+
+/// `<dir>/gdep.yaml`, unless there only is a `gdep.toml`
+fn default_config_file(dir: &str) -> String {
+    let yaml = Path::new(dir).join("gdep.yaml");
+    let toml = Path::new(dir).join("gdep.toml");
+    let file = if !yaml.exists() && toml.exists() {toml} else {yaml};
+    file.to_string_lossy().to_string()
 }
 
-#[macro_export]
-macro_rules! conv_err_e {
-    ($pre:expr, $err: expr) => {
-        $pre.or_else(|e| { Err($err(e.to_string())) })
-    };
+/// The config files to merge, in order. `-s` can be given several times, `-s -` reads the config from stdin,
+/// relative to `--config-base` if given
+fn config_file_paths(matches: &ArgMatches, repo_path: &str) -> Vec<String> {
+    let base = matches.try_get_one::<String>("config-base").ok().flatten();
+    if let Some(files) = matches.get_many::<String>("config-file-o") {
+        return files.map(|t1| match base {
+            Some(base) if t1 == config::STDIN_PATH => Path::new(base).join(config::STDIN_PATH).to_string_lossy().to_string(),
+            _ => t1.to_owned()
+        }).collect()
+    }
+    let path = matches.get_one::<String>("config-file-i").map(|t| format!("{}/{}", repo_path, t))
+        .unwrap_or_else(|| if matches.get_flag("config-inside") {default_config_file(repo_path)}
+            else { default_config_file("") });
+    vec![path]
 }
 
-fn update_sync(repo_path: Arc<String>, branch_name: Arc<String>, stop_flag: Arc<Mutex<bool>>, sender: mpsc::Sender<(Option<GdepError>, bool)>) {
-    let mut err = None;
-    let repo_x = Repository::open(&*repo_path);
-    
-    if repo_x.is_ok() {
-        let repo = repo_x.unwrap();
-        while !*stop_flag.lock().unwrap() {
-            sender.send((None, false)).expect("Failed to send alive signal to main thread");
-
-            let res = repo_update_cycle(&repo, &branch_name);
-            if res.is_err() {
-                err = Some(GdepError::from(res.unwrap_err()));
-                break
-            }
+fn load_cfg(matches: &ArgMatches, repo_path: &str) -> Result<Config, ConfigError> {
+    Config::load_from_files(&config_file_paths(matches, repo_path))
+}
 
-            let urs = res.unwrap();
-            match urs {
-                UpdateRelationState::Up2Date => { continue }
-                UpdateRelationState::Ahead(a) => {
-                    err = Some(UpdateErrorRepoAhead(a));
-                    break
-                }
-                UpdateRelationState::Behind(_) => {
-                    let tmp_err = update_repo(&repo, &*branch_name);
-                    if tmp_err.is_err() {
-                        let unw_err = tmp_err.unwrap_err();
-                        err = Some(UpdateFailed(unw_err.to_string(), unw_err.code()))
-                    } else {
-                        println!("Successfully updated local repo")
-                    }
-                    break
-                }
-                UpdateRelationState::AheadBehind(a, b) => {
-                    err = Some(UpdateErrorAheadBehind(a, b));
-                    break
-                }
-            }
-        }
-    }
+fn migrate_cfg(matches: &ArgMatches) -> Result<(), GdepError> {
+    let input = matches.get_one::<String>("input").unwrap();
+    let yaml = match Config::load_deployments(slice::from_ref(input))? {
+        Some(deployments) => Config::deployments_to_yaml_string(&deployments)?,
+        None => Config::load_from_files(slice::from_ref(input))?.to_yaml_string()?
+    };
 
-    if err.is_some() {
-        println!("Error while searching for updates!");
-        println!("{}", err.clone().unwrap())
+    match matches.get_one::<String>("output") {
+        Some(output) => {
+            conv_err!(fs::write(output, yaml), ConfigError::EmitFailed(format!("Could not write `{}`", output)))?;
+            info!("Migrated `{}` to `{}`", input, output);
+        }
+        None => print!("{}", yaml)
     }
-    sender.send((err, true)).expect("Failed to send stop signal to main thread");
+    Ok(())
 }
 
-#[derive(Debug)]
-pub enum UpdateRelationState {
-    Up2Date,
-    Ahead(usize),
-    Behind(usize),
-    AheadBehind(usize, usize)
+fn print_schema(matches: &ArgMatches) -> Result<(), GdepError> {
+    let schema = schema::schema();
+    match matches.get_one::<String>("output") {
+        Some(output) => {
+            conv_err!(fs::write(output, schema), ConfigError::EmitFailed(format!("Could not write `{}`", output)))?;
+            info!("Wrote the config schema to `{}`", output);
+        }
+        None => print!("{}", schema)
+    }
+    Ok(())
 }
 
-pub fn update_repo(repo: &Repository, branch_name: &str) -> Result<(), Error> {
-    let remote_name = "origin";
-    let mut remote = repo.find_remote(remote_name)?;
-    let fetch_commit = fetch_updates(repo, &[branch_name], &mut remote)?;
-    merge_updates(repo, branch_name, fetch_commit)
+/// Asks for `key` on the terminal unless its flag was given. Without a terminal, a missing flag is an error
+fn init_value(matches: &ArgMatches, key: &str) -> Result<String, ConfigError> {
+    if let Some(value) = matches.get_one::<String>(key) {
+        return Ok(value.clone())
+    }
+    if !io::stdin().is_terminal() {
+        return Err(ConfigError::MissingContent(key.to_string()))
+    }
+    eprint!("{}: ", key);
+    let mut value = String::new();
+    conv_err!(io::stdin().read_line(&mut value), ConfigError::MissingContent(key.to_string()))?;
+    match value.trim() {
+        "" => Err(ConfigError::MissingContent(key.to_string())),
+        value => Ok(value.to_string())
+    }
 }
 
-fn fetch_updates<'a>(
-    repo: &'a Repository,
-    refs: &[&str],
-    remote: &'a mut git2::Remote,
-) -> Result<AnnotatedCommit<'a>, Error> {
-    let mut fo = FetchOptions::new();
-    fo.download_tags(AutotagOption::All);
-    remote.fetch(refs, Some(&mut fo), None)?;
-
-    let fetch_head = repo.find_reference("FETCH_HEAD")?;
-    Ok(repo.reference_to_annotated_commit(&fetch_head)?)
+/// `init`: writes a starter `gdep.yaml` to the working directory
+fn init_cfg(matches: &ArgMatches) -> Result<(), GdepError> {
+    let path = "gdep.yaml";
+    if Path::new(path).exists() && !matches.get_flag("force") {
+        return Err(GdepError::ConfigExists(path.to_string()))
+    }
+    let yaml = Config::starter_yaml(&init_value(matches, "name")?, &init_value(matches, "repo")?, &init_value(matches, "script")?)?;
+    conv_err!(fs::write(path, yaml), ConfigError::EmitFailed(format!("Could not write `{}`", path)))?;
+    info!("Wrote `{}`, run `gdep -s {}` to start", path, path);
+    Ok(())
 }
 
-fn merge_updates(
-    repo: &Repository,
-    remote_branch: &str,
-    fetch_commit: AnnotatedCommit,
-) -> Result<(), Error> {
-    let analysis = repo.merge_analysis(&[&fetch_commit])?;
-    if analysis.0.is_fast_forward() {
-        let refname = format!("refs/heads/{}", remote_branch);
-        match repo.find_reference(&refname) {
-            Ok(mut reference) => {
-                reference.set_target(fetch_commit.id(), "Fast-forward")?;
-                repo.set_head(&refname)?;
-                repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+/// `--check`: validates the config file without touching git, reporting every problem found
+fn check_cfg(matches: &ArgMatches) -> Result<(), GdepError> {
+    let paths = config_file_paths(matches, &repo_path_for(matches, None));
+
+    match Config::check_files(&paths) {
+        Ok(_) if matches.get_flag("quiet") => Ok(()),
+        Ok(deployments) => {
+            println!("`{}` is valid", paths.join("` + `"));
+            for config in deployments {
+                let repo = match &config.repo {
+                    RepoLike::Remote(r) => format!("clones `{}`", r),
+                    RepoLike::Remote2(r, d) => format!("clones `{}` into `{}`", r, d),
+                    RepoLike::Local(l) => format!("uses the local repo `{}`", l)
+                };
+                let follows = match (&config.commit, config.track) {
+                    _ if config.mode == Mode::Mirror => "follows every ref".to_string(),
+                    _ if config.no_update => "never looks for updates".to_string(),
+                    (Some(sha), _) => format!("holds commit {}", sha),
+                    (None, Track::Tag) => format!("follows tags matching `{}`", config.tag_pattern.as_deref().unwrap_or("*")),
+                    (None, Track::Branch) => format!("follows branch `{}`", config.branch.as_deref().unwrap_or("main/master")),
+                };
+                let script = match &config.script_file {
+                    Some(file) => format!("script file `{}`", file),
+                    None if config.mode == Mode::Mirror => "no script, it mirrors every ref".to_string(),
+                    None if config.steps.is_empty() => format!("a {} line script", config.script.lines().count()),
+                    None => format!("{} step(s), then a {} line script", config.steps.len(), config.script.lines().count())
+                };
+                println!("  {}: {}, {}, runs {}{}", config.name, repo, follows, script, if config.cleanup.is_some() {" with cleanup"} else {""});
             }
-            Err(_) => {
-                repo.reference(&refname, fetch_commit.id(), true, "Setting new branch")?;
-                repo.set_head(&refname)?;
-                repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+            Ok(())
+        }
+        Err(problems) => {
+            println!("`{}` has {} problem(s):", paths.join("` + `"), problems.len());
+            for problem in &problems {
+                println!("  - {}", problem);
             }
+            Err(GdepError::ConfigCheckFailed(problems.len()))
         }
-    } else if analysis.0.is_normal() {
-        let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-        perform_merge(repo, &head_commit, &fetch_commit)?;
     }
-    Ok(())
 }
 
-fn perform_merge(
-    repo: &Repository,
-    local: &AnnotatedCommit,
-    remote: &AnnotatedCommit,
-) -> Result<(), Error> {
-    let local_tree = repo.find_commit(local.id())?.tree()?;
-    let remote_tree = repo.find_commit(remote.id())?.tree()?;
-    let ancestor_tree = repo.find_commit(repo.merge_base(local.id(), remote.id())?)?.tree()?;
-    let mut index = repo.merge_trees(&ancestor_tree, &local_tree, &remote_tree, None)?;
-
-    if index.has_conflicts() {
-        println!("Merge conflicts detected...");
-        repo.checkout_index(Some(&mut index), None)?;
-        return Ok(());
-    }
-
-    let result_tree = repo.find_tree(index.write_tree_to(repo)?)?;
-    let sig = repo.signature()?;
-    let local_commit = repo.find_commit(local.id())?;
-    let remote_commit = repo.find_commit(remote.id())?;
-    repo.commit(Some("HEAD"), &sig, &sig, "Merge commit", &result_tree, &[&local_commit, &remote_commit])?;
-    repo.checkout_head(None)?;
+/// `--print-config`: the config as gdep would run it, with the defaults and the command line overrides filled in.
+/// Remote repos get the `into_path` they would be cloned into
+fn print_cfg(matches: &ArgMatches) -> Result<(), GdepError> {
+    let paths = config_file_paths(matches, &repo_path_for(matches, None));
+    let (mut deployments, multi) = match Config::load_deployments(&paths)? {
+        Some(deployments) => (deployments, true),
+        None => (vec![Config::load_from_files(&paths)?], false)
+    };
+    for config in &mut deployments {
+        if let RepoLike::Remote(r) = &config.repo {
+            let provided_repo_path = repo_path_for(matches, Some(config));
+            let path = if multi {Path::new(&provided_repo_path).join(&config.name).to_string_lossy().to_string()} else {provided_repo_path};
+            config.repo = RepoLike::Remote2(r.clone(), path);
+        }
+        if multi {
+            continue
+        }
+        if let Some(args) = matches.get_many::<String>("script-args") {
+            config.args = args.cloned().collect();
+        }
+        if let Some(branch) = matches.get_one::<String>("branch") {
+            config.branch = Some(branch.clone());
+        }
+    }
+    print!("{}", Config::effective_string(&deployments, matches.get_one::<String>("output").is_some_and(|o| o == "json"))?);
     Ok(())
 }
 
-fn fetch_updates2(repo: &Repository, remote_name: &str, branch_name: &String) -> Result<(), Error> {
-    let mut remote = repo.find_remote(remote_name)?;
+fn open_log_file(matches: &ArgMatches, config: &Config) -> Result<(), GdepError> {
+    // Reopened now that the rotation settings are known
+    if let Some(path) = matches.get_one::<String>("log-file").or(config.log_file.as_ref()) {
+        logfile::open(Path::new(path), config.log_max_size, config.log_keep).map_err(|e| GdepError::LogFileFailed(path.clone(), e.to_string()))?;
+    }
+    Ok(())
+}
 
-    let mut cb = RemoteCallbacks::new();
-    cb.credentials(|_, _, _| Cred::default()); // Use default credentials
+/// What a deployment would do next, worked out without touching the working tree.
+/// The only side effect is the fetch, which updates remote-tracking refs and tags
+fn dry_run_report(repo: &Repository, config: &Config, branch: Option<&String>, clock: &dyn Clock) -> Result<(), GdepError> {
+    if config.mode == Mode::Mirror {
+        println!("Would mirror every ref of origin into the bare repo, running no script");
+        return Ok(())
+    }
+    let branch = match branch.or(config.branch.as_ref()) {
+        Some(t) => t.clone(),
+        None => get_default_branch(repo, config, clock)?
+    };
+    println!("Branch: {}", branch);
+    if let Some(head) = head_id(repo) {
+        println!("HEAD: {}", head);
+    }
 
-    let mut fetch_options = FetchOptions::new();
-    fetch_options.remote_callbacks(cb);
+    if let Some(sha) = &config.commit {
+        println!("Would hold the pinned commit {}", sha);
+    } else if config.track == Track::Tag {
+        let (tag, target) = resolve_latest_tag(repo, config, clock)?;
+        if head_id(repo) == Some(target) {
+            println!("Latest tag `{}` is deployed, nothing to update", tag);
+        } else {
+            println!("Would deploy tag `{}` ({})", tag, target);
+        }
+    } else {
+        let urs = repo_update_cycle(repo, &branch, config, clock).map_err(|e| tracking_error(e, &branch))?;
+        println!("Relation to origin/{}: {:?}", branch, urs);
+        let action = match urs {
+            UpdateRelationState::Up2Date => "nothing to update".to_string(),
+            _ if config.deploy_schedule.as_ref().is_some_and(|s| !s.allows(clock.now_utc())) => "would defer the update until the deploy window opens".to_string(),
+            UpdateRelationState::Behind(n) => format!("would fast-forward {} commit(s)", n),
+            _ if config.force || repo.is_shallow() => "would reset to the remote tip, dropping local commits".to_string(),
+            UpdateRelationState::Ahead(n) if config.on_ahead == OnAhead::Push => format!("would push {} local commit(s) to origin", n),
+            UpdateRelationState::Ahead(_) if config.conflict_strategy.is_some() || config.on_ahead == OnAhead::Ignore => "nothing to update".to_string(),
+            UpdateRelationState::AheadBehind(..) if config.conflict_strategy.is_some() =>
+                format!("would merge, resolving conflicts with `{}`", config.conflict_strategy.unwrap()),
+            _ => "would stop with an error, the local branch has diverged".to_string()
+        };
+        println!("Action: {}", action);
+    }
 
-    remote.fetch(&[branch_name], Some(&mut fetch_options), None)?;
+    for (i, step) in config.steps.iter().enumerate() {
+        println!("Would run step {}/{}:\n{}", i + 1, config.steps.len(), step.lines().map(|l| format!("  | {}", l)).collect::<Vec<_>>().join("\n"));
+    }
+    println!("Would run script:\n{}", config.script.lines().map(|l| format!("  | {}", l)).collect::<Vec<_>>().join("\n"));
+    if !config.args.is_empty() {
+        println!("With arguments: {:?}", config.args);
+    }
+    if let Some(cleanup) = &config.cleanup {
+        println!("Would clean up with:\n{}", cleanup.lines().map(|l| format!("  | {}", l)).collect::<Vec<_>>().join("\n"));
+    }
     Ok(())
 }
 
-fn get_default_branch(repo: &Repository) -> Result<String, GdepError> {
-    let branches = repo.branches(Some(BranchType::Remote))?;
-
-    // Look for "origin/main" or "origin/master"
-    let mut found_branch = None;
-    for branch in branches {
-        let (branch, _) = branch?;
-        if let Some(name) = branch.name()? {
-            if name.ends_with("/main") || name.ends_with("/master") {
-                found_branch = Some(name.split('/').last().unwrap().to_string());
-                break;
+/// `--dry-run`: loads the config and reports what every deployment would do, without cloning, checking out or running anything
+fn dry_run(matches: &ArgMatches, clock: &dyn Clock) -> Result<(), GdepError> {
+    let config_in_repo = matches.get_flag("config-inside") || matches.get_one::<String>("config-file-i").is_some();
+    if config_in_repo {
+        let provided_repo_path = &repo_path_for(matches, None);
+        let Ok(repo) = Repository::open(provided_repo_path) else {
+            match matches.get_one::<String>("repo-url") {
+                Some(url) => println!("Would clone `{}` into `{}`, the config inside it can't be checked before that", url, provided_repo_path),
+                None => return Err(GdepError::LocalRepoNotFound(provided_repo_path.to_string()))
             }
+            return Ok(())
+        };
+        let mut config = load_cfg(matches, provided_repo_path)?;
+        if let Some(args) = matches.get_many::<String>("script-args") {
+            config.args = args.cloned().collect();
         }
+        println!("Deployment `{}` in `{}`", config.name, provided_repo_path);
+        return dry_run_report(&repo, &config, matches.get_one::<String>("branch"), clock)
     }
 
-    if found_branch.is_none() {
-        Err(GdepError::BranchInferFailed)
-    } else {
-        let fb = found_branch.unwrap();
-        println!("Branch inferred to be `{}`", fb);
-        Ok(fb)
+    let (deployments, multi) = match Config::load_deployments(&config_file_paths(matches, ""))? {
+        Some(deployments) => (deployments, true),
+        None => (vec![load_cfg(matches, "")?], false)
+    };
+    apply_network_settings(&deployments[0])?;
+    for mut config in deployments {
+        let provided_repo_path = repo_path_for(matches, Some(&config));
+        let (path, url) = match &config.repo {
+            RepoLike::Remote(r) if multi => (Path::new(&provided_repo_path).join(&config.name).to_string_lossy().to_string(), Some(r)),
+            RepoLike::Remote(r) => (provided_repo_path, Some(r)),
+            RepoLike::Remote2(r, d) => (d.clone(), Some(r)),
+            RepoLike::Local(l) => (l.clone(), None)
+        };
+        println!("Deployment `{}` in `{}`", config.name, path);
+        let Ok(repo) = Repository::open(&path) else {
+            match url {
+                Some(url) => println!("Would clone `{}` into `{}`", url, path),
+                None => return Err(GdepError::LocalRepoNotFound(path))
+            }
+            continue
+        };
+        if !multi {
+            if let Some(args) = matches.get_many::<String>("script-args") {
+                config.args = args.cloned().collect();
+            }
+        }
+        dry_run_report(&repo, &config, if multi {None} else {matches.get_one::<String>("branch")}, clock)?;
     }
+    Ok(())
 }
 
-fn repo_update_cycle(repo: &Repository, branch: &String) -> Result<UpdateRelationState, Error> {
-    fetch_updates2(repo, "origin", branch)?;
-    let head = repo.head()?.peel_to_commit()?;
-
-    let remote_branch = repo.find_reference(format!("refs/remotes/origin/{}", branch).as_str())?.peel_to_commit()?;
-
-    let ahead_behind = repo.graph_ahead_behind(head.id(), remote_branch.id())?;
-
-    Ok(match ahead_behind {
-        (0, 0) => UpdateRelationState::Up2Date,
-        (ahead, 0) => UpdateRelationState::Ahead(ahead),
-        (0, behind) => UpdateRelationState::Behind(behind),
-        (ahead, behind) => UpdateRelationState::AheadBehind(ahead, behind),
-    })
+/// Where a deployment stands, as reported by `gdep status`
+struct DeployStatus {
+    name: String,
+    path: String,
+    branch: Option<String>,
+    head: Option<git2::Oid>,
+    relation: Option<UpdateRelationState>,
+    /// PID of the running instance, if gdep runs there. None inside if it didn't write one
+    running: Option<Option<u32>>,
+    /// The latest entry of the deploy history
+    last_deploy: Option<history::Entry>
 }
 
-fn execute(config: Config, repo_path: String, branch_name: String) -> Option<GdepError> {
-    let mut do_rerun = config.restart_after_update;
-    
-    let stop_flag = Arc::new(Mutex::new(false));
-    let (tx, rx) = mpsc::channel();
-
-    let repo_path_arc = Arc::new(repo_path.clone());
-    let branch_name_arc = Arc::new(branch_name.clone());
-
-    let mut options = ScriptOptions::new();
-    options.working_directory = Some(PathBuf::from(&repo_path));
-    options.output_redirection = IoOptions::Inherit;
-
-    let args = vec![];
-    let mut child = run_script::spawn(config.script.as_str(), &args, &options).expect("Failed to start subprocess");
-
-    let stop_flag_clone = Arc::clone(&stop_flag);
-
-    let update_handle = thread::spawn(move || {
-        update_sync(repo_path_arc, branch_name_arc, stop_flag_clone, tx);
-    });
-
-    let mut result: Option<ExitStatus> = None;
-    
-    let (mut err, mut stop) = rx.recv().expect("Failed to receive singal from update thread");
-
-    while !stop {
-        let boring_result = child.try_wait();
-        if boring_result.is_err() {
-            *stop_flag.lock().unwrap() = true;
-            break
-        } else {
-            result = boring_result.unwrap();
-        }
-        if result.is_some() {
-            break;
-        }
-        (err, stop) = rx.recv().expect("Failed to receive singal from update thread");
-    }
-
-    if result.is_none() {
-        child.kill().expect("Error while killing child :(");
-        result = child.try_wait().expect("try_wait failed");
+impl DeployStatus {
+    fn new(config: &Config, path: &str) -> Self {
+        Self { name: config.name.clone(), path: path.to_string(), branch: None, head: None, relation: None, running: None, last_deploy: None }
     }
 
-    if result.is_some() {
-        if !result.unwrap().success() {
-            println!("Running script failed with exit code: {}", result.unwrap());
-            do_rerun = !config.exit_on_script_error;
+    /// Fetches the branch to compare against origin, but never updates the checkout
+    fn of(repo: &Repository, config: &Config, path: &str, branch: Option<&String>, clock: &dyn Clock) -> Result<Self, GdepError> {
+        if config.mode == Mode::Mirror {
+            // Fetching into a mirror is what updating it means, so its relation to origin stays unknown
+            return Ok(Self {
+                head: head_id(repo),
+                running: state::is_locked(repo).then(|| state::lock_holder(repo)),
+                branch: Some("*".to_string()),
+                ..Self::new(config, path)
+            })
         }
+        let branch = match branch.or(config.branch.as_ref()) {
+            Some(t) => t.clone(),
+            None => get_default_branch(repo, config, clock)?
+        };
+        Ok(Self {
+            head: head_id(repo),
+            relation: Some(repo_update_cycle(repo, &branch, config, clock).map_err(|e| tracking_error(e, &branch))?),
+            running: state::is_locked(repo).then(|| state::lock_holder(repo)),
+            last_deploy: history::last(path, 1).ok().and_then(|mut entries| entries.pop()),
+            branch: Some(branch),
+            ..Self::new(config, path)
+        })
     }
-    
-    if err.is_some() {
-        do_rerun = !config.exit_on_gdep_error;
-    }
-
-    *stop_flag.lock().unwrap() = true;
 
-    child.kill().expect("Failed to kill the subprocess");
-    child.wait().expect("Waiting failed");
-
-    if config.cleanup.is_some() {
-        println!("Cleaning up...");
-        let mut cl_options = ScriptOptions::new();
-        cl_options.working_directory = Some(PathBuf::from(&repo_path));
-        cl_options.output_redirection = IoOptions::Inherit;
-        let mut cl_child = run_script::spawn(&*config.cleanup.clone().unwrap(), &args, &options).expect("Failed to start subprocess");
-        cl_child.wait().expect("Failed to clean up");
+    fn print(&self) {
+        println!("Deployment `{}` in `{}`", self.name, self.path);
+        let Some(branch) = &self.branch else {
+            println!("Not cloned yet");
+            return
+        };
+        println!("Branch: {}", branch);
+        println!("HEAD: {}", self.head.map(|h| h.to_string()).unwrap_or_else(|| "none".to_string()));
+        if let Some(relation) = &self.relation {
+            println!("Relation to origin/{}: {:?}", branch, relation);
+        }
+        match self.running {
+            Some(Some(pid)) => println!("Running: yes, PID {}", pid),
+            Some(None) => println!("Running: yes"),
+            None => println!("Running: no")
+        }
+        if let Some(entry) = &self.last_deploy {
+            println!("Last deploy: {}", entry.summary());
+        }
     }
 
-    update_handle.join().expect("Function thread panicked");
-
-    if do_rerun || config.re_run {
-        println!("Restarting...");
-        execute(config, repo_path, branch_name);
+    fn to_json(&self) -> serde_json::Value {
+        let (relation, ahead, behind) = match self.relation {
+            None => (None, 0, 0),
+            Some(UpdateRelationState::Up2Date) => (Some("up_to_date"), 0, 0),
+            Some(UpdateRelationState::Ahead(n)) => (Some("ahead"), n, 0),
+            Some(UpdateRelationState::Behind(n)) => (Some("behind"), 0, n),
+            Some(UpdateRelationState::AheadBehind(a, b)) => (Some("ahead_behind"), a, b)
+        };
+        serde_json::json!({
+            "name": self.name,
+            "path": self.path,
+            "cloned": self.branch.is_some(),
+            "branch": self.branch,
+            "head": self.head.map(|h| h.to_string()),
+            "relation": relation,
+            "ahead": ahead,
+            "behind": behind,
+            "running": self.running.is_some(),
+            "pid": self.running.flatten(),
+            "last_deploy": self.last_deploy.as_ref().map(history::Entry::to_json)
+        })
     }
-    
-    err
 }
 
-fn load_cfg(matches: &ArgMatches, repo_path: &String) -> Result<Config, ConfigError> {
-    let config_file_path = matches.get_one::<String>("config-file-o").and_then(|t1| {Some(t1.to_owned())})
-        .or(matches.get_one::<String>("config-file-i").and_then(|t1| {Some(t1.to_owned())}).and_then(|t| {
-            Some(format!("{}/{}", repo_path, t)) })
-            .or(if matches.get_flag("config-inside") {Some(format!("{}/gdep.yaml", repo_path))}
-            else { Some("gdep.yaml".to_string()) })).unwrap();
-
-    Config::load_from_file(&config_file_path)
+/// Where the repo of `config` is, `multi` if it is an entry of `deployments`
+fn deployment_path(matches: &ArgMatches, config: &Config, multi: bool) -> String {
+    let provided_repo_path = repo_path_for(matches, Some(config));
+    match &config.repo {
+        RepoLike::Remote(_) if multi => Path::new(&provided_repo_path).join(&config.name).to_string_lossy().to_string(),
+        RepoLike::Remote(_) => provided_repo_path,
+        RepoLike::Remote2(_, d) => d.clone(),
+        RepoLike::Local(l) => l.clone()
+    }
 }
 
-fn get_repo(repo_path: &String, repo_url: Option<&String>) -> Result<Repository, GdepError> {
-    match Repository::open(&repo_path) {
-        Ok(repo) => Ok(repo),
-        Err(_) => {
-            if repo_url.is_none() {
-                return Err(GdepError::LocalRepoNotFound(repo_path.to_owned()))
-            }
-            match Repository::clone(&repo_url.unwrap(), &repo_path) {
-                Ok(repo) => {
-                    Ok(repo)
-                }
-                Err(_) => {
-                    Err(GdepError::RemoteRepoNotFound(repo_url.unwrap().to_owned()))
-                }
+/// `gdep history`: prints the last `count` deploy history entries of every deployment of the config,
+/// or of just the repo if it was given without a config outside of it
+fn history(matches: &ArgMatches, count: usize, json: bool) -> Result<(), GdepError> {
+    let config_in_repo = matches.get_flag("config-inside") || matches.get_one::<String>("config-file-i").is_some();
+    let repos = if config_in_repo || (matches.contains_id("repo-path") && !matches.contains_id("config-file-o")) {
+        vec![(None, repo_path_for(matches, None))]
+    } else {
+        match Config::load_deployments(&config_file_paths(matches, ""))? {
+            Some(deployments) => deployments.iter().map(|c| (Some(c.name.clone()), deployment_path(matches, c, true))).collect(),
+            None => {
+                let config = load_cfg(matches, "")?;
+                vec![(Some(config.name.clone()), deployment_path(matches, &config, false))]
             }
         }
+    };
+    for (name, path) in repos {
+        let entries = history::last(&path, count).map_err(|e| GdepError::StateDirFailed(e.to_string()))?;
+        if json {
+            entries.iter().for_each(|e| println!("{}", e.to_json()));
+            continue
+        }
+        match name {
+            Some(name) => println!("Deployment `{}` in `{}`", name, path),
+            None => println!("Repo `{}`", path)
+        }
+        if entries.is_empty() {
+            println!("  Nothing deployed yet");
+        }
+        for entry in entries {
+            println!("  {}", entry.summary());
+        }
     }
+    Ok(())
 }
 
-fn get_repo_config(config: &Config, provided_repo_path: &&String) -> Result<Repository, GdepError> {
-    match &config.repo {
-        RepoLike::Remote(r) => {get_repo(provided_repo_path, Some(&r))}
-        RepoLike::Local(l) => {get_repo(&l, None)}
-        RepoLike::Remote2(r, d) => {get_repo(&d, Some(&r))}
+/// `gdep status`: reports branch, HEAD, the relation to origin and whether gdep runs there, for every deployment
+/// of the config. Fetches, but never clones, checks out or starts anything
+fn status(matches: &ArgMatches, json: bool, clock: &dyn Clock) -> Result<(), GdepError> {
+    let report = |status: DeployStatus| if json {println!("{}", status.to_json())} else {status.print()};
+    let config_in_repo = matches.get_flag("config-inside") || matches.get_one::<String>("config-file-i").is_some();
+    if config_in_repo {
+        let path = repo_path_for(matches, None);
+        let repo = Repository::open(&path).map_err(|_| GdepError::LocalRepoNotFound(path.clone()))?;
+        let config = load_cfg(matches, &path)?;
+        apply_network_settings(&config)?;
+        report(DeployStatus::of(&repo, &config, &path, matches.get_one::<String>("branch"), clock)?);
+        return Ok(())
     }
+
+    let (deployments, multi) = match Config::load_deployments(&config_file_paths(matches, ""))? {
+        Some(deployments) => (deployments, true),
+        None => (vec![load_cfg(matches, "")?], false)
+    };
+    apply_network_settings(&deployments[0])?;
+    for config in deployments {
+        let path = deployment_path(matches, &config, multi);
+        let status = match Repository::open(&path) {
+            Ok(repo) => DeployStatus::of(&repo, &config, &path, if multi {None} else {matches.get_one::<String>("branch")}, clock)?,
+            Err(_) if matches!(config.repo, RepoLike::Local(_)) => return Err(GdepError::LocalRepoNotFound(path)),
+            Err(_) => DeployStatus::new(&config, &path)
+        };
+        report(status);
+    }
+    Ok(())
 }
 
-fn run(matches: &ArgMatches) -> Result<(), GdepError> {
+/// Where the repo lives unless the config names it itself. The first of: `--local-repo`,
+/// `$GDEP_REPO_PATH`, `default_repo_path` from the config and `gdep_used_repo`.
+/// A config inside the repo can't say where that repo is, so it is not consulted then
+fn repo_path_for(matches: &ArgMatches, config: Option<&Config>) -> String {
+    matches.get_one::<String>("repo-path").cloned()
+        .or_else(|| env::var("GDEP_REPO_PATH").ok().filter(|p| !p.is_empty()))
+        .or_else(|| config.and_then(|c| c.default_repo_path.clone()))
+        .unwrap_or_else(|| DEFAULT_REPO_PATH.to_string())
+}
+
+fn run(matches: &ArgMatches, clock: Arc<dyn Clock>) -> Result<(), GdepError> {
     let opt_repo_url = matches.get_one::<String>("repo-url");
 
-    let binding = DEFAULT_REPO_PATH.to_string();
-    let provided_repo_path = matches.get_one::<String>("repo-path").or(Some(&binding)).unwrap();
+    if matches.get_flag("dry-run") {
+        return dry_run(matches, &*clock)
+    }
 
     let config_in_repo = matches.get_flag("config-inside") || matches.get_one::<String>("config-file-i").is_some();
 
-    let (repo, repo_path, config) = if config_in_repo {
-        let repo = get_repo(provided_repo_path, opt_repo_url)?;
-        let repo_path = repo.path().parent().unwrap().to_str().unwrap().to_string();
-        (repo, repo_path.clone(), load_cfg(&matches, &repo_path)?)
+    let (repo, cloned, repo_path, mut config) = if config_in_repo {
+        // The config lives in the repo, so the initial clone can't use its `ca_bundle` yet
+        let (repo, cloned) = get_repo(&repo_path_for(matches, None), opt_repo_url, CloneSettings::default().with_branch(matches.get_one::<String>("branch")), &*clock)?;
+        let repo_path = repo_root(&repo);
+        let config = load_cfg(matches, &repo_path)?;
+        apply_network_settings(&config)?;
+        (repo, cloned, repo_path, config)
     } else {
-        let config = load_cfg(&matches, &provided_repo_path)?;
-        let repo = get_repo_config(&config, &provided_repo_path)?;
-        let repo_path = repo.path().parent().unwrap().to_str().unwrap().to_string();
-        (repo, repo_path, config)
+        // The repo path only locates configs inside the repo
+        if let Some(deployments) = Config::load_deployments(&config_file_paths(matches, ""))? {
+            return run_deployments(matches, deployments, &clock)
+        }
+        let config = load_cfg(matches, "")?;
+        apply_network_settings(&config)?;
+        let (repo, cloned) = get_repo_config(&config, &repo_path_for(matches, Some(&config)), matches.get_one::<String>("branch"), &*clock)?;
+        let repo_path = repo_root(&repo);
+        (repo, cloned, repo_path, config)
+    };
+
+    open_log_file(matches, &config)?;
+    let _lock = lock_repo(&repo)?;
+    let _remote = match matches.get_one::<String>("remote-override") {
+        Some(url) => override_remote(&repo, url, matches.get_flag("remote-override-temporary"))?,
+        None => None
     };
 
-    let branch = matches.get_one::<String>("branch").and_then(|t| { Some(t.clone()) }).or(Some(get_default_branch(&repo)?)).unwrap();
+    if let Some(args) = matches.get_many::<String>("script-args") {
+        config.args = args.cloned().collect();
+    }
+
+    let branch = prepare(&repo, &config, matches.get_one::<String>("branch"), &*clock)?;
+
+    if let Some(sha) = matches.get_one::<String>("deploy-commit") {
+        deploy_commit(&repo, sha, config.depth)?;
+        if config.submodules {
+            update_submodules(&repo)?;
+        }
+        if cloned {
+            bootstrap(&config, &repo_path, &branch, &clock)?;
+        }
+        let status = run_once(&config, &repo_path, &branch, &clock);
+        if let Some(signal) = signals::received() {
+            return Err(GdepError::Interrupted(signal))
+        }
+        if !matches.get_flag("resume-tracking") {
+            return if status.success() {Ok(())} else {Err(GdepError::ScriptFailed(status, Vec::new()))}
+        }
+        info!("Resuming tracking of branch `{}`", branch);
+    } else if cloned {
+        bootstrap(&config, &repo_path, &branch, &clock)?;
+    }
+
+    if matches.get_flag("once") {
+        return single_pass(config, &repo_path, &branch, clock)
+    }
 
-    match execute(config, repo_path, branch) {
+    // Also there without `watch_config`, for the `reload` command and SIGHUP
+    let watcher = {
+        let paths = config_file_paths(matches, if config_in_repo {&repo_path} else {""});
+        let args = matches.get_many::<String>("script-args").map(|a| a.cloned().collect());
+        Some(reload::ConfigWatcher::new(&paths, &config, args, matches.contains_id("branch")))
+    };
+    match deploy(config, repo_path, branch, Arc::new(Mutex::new(false)), watcher, clock).error {
         None => {
             Ok(())
         }
@@ -362,35 +539,133 @@ fn run(matches: &ArgMatches) -> Result<(), GdepError> {
     }
 }
 
+/// Runs every entry of a `deployments` config side by side. Entries that clone a remote
+/// without `into_path` get their own directory `<repo-path>/<name>`
+fn run_deployments(matches: &ArgMatches, deployments: Vec<Config>, clock: &Arc<dyn Clock>) -> Result<(), GdepError> {
+    for (id, flag) in [("repo-url", "--remote-repo"), ("remote-override", "--remote-override"), ("branch", "--branch"), ("deploy-commit", "--deploy-commit"), ("once", "--once"), ("script-args", "script arguments")] {
+        if matches.contains_id(id) {
+            warn!("{} only apply to a single deployment, ignoring them", flag);
+        }
+    }
+    // Process wide settings are the same for every entry
+    apply_network_settings(&deployments[0])?;
+    open_log_file(matches, &deployments[0])?;
+
+    let mut prepared = Vec::new();
+    let mut locks = Vec::new();
+    for config in deployments {
+        let path = match &config.repo {
+            RepoLike::Remote(_) => Path::new(&repo_path_for(matches, Some(&config))).join(&config.name).to_string_lossy().to_string(),
+            _ => repo_path_for(matches, Some(&config))
+        };
+        let result = get_repo_config(&config, &path, None, &**clock).and_then(|(repo, cloned)| {
+            let lock = lock_repo(&repo)?;
+            let repo_path = repo_root(&repo);
+            let branch = prepare(&repo, &config, None, &**clock)?;
+            if cloned {
+                bootstrap(&config, &repo_path, &branch, clock)?;
+            }
+            Ok((lock, repo_path, branch))
+        });
+        match result {
+            Ok((lock, repo_path, branch)) => {
+                locks.push(lock);
+                let watcher = Some(reload::ConfigWatcher::new(&config_file_paths(matches, ""), &config, None, false));
+                prepared.push((config, repo_path, branch, watcher));
+            }
+            Err(e) if config.critical => return Err(GdepError::DeploymentFailed(config.name, Box::new(e))),
+            Err(e) => error!("Skipping deployment `{}`: {}", config.name, e)
+        }
+    }
+
+    match supervise(prepared, clock) {
+        None => Ok(()),
+        Some(err) => Err(err)
+    }
+}
+
+fn format_error(err: &GdepError, debug: bool) -> String {
+    if debug {
+        format!("Gdep Error => {:?}", err)
+    } else {
+        format!("Gdep Error => {}", err)
+    }
+}
+
+/// `--color` for clap's own help and errors, which it prints before the arguments are parsed
+fn color_choice() -> ColorChoice {
+    let mut args = env::args_os().skip(1).map(|a| a.to_string_lossy().to_string());
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--color=") {
+            Some(value) => Some(value.to_string()),
+            None if arg == "--color" => args.next(),
+            None if arg == "--" => break,
+            None => continue
+        };
+        return value.and_then(|v| v.parse().ok()).unwrap_or(ColorChoice::Auto)
+    }
+    ColorChoice::Auto
+}
+
+/// What gdep itself logs: errors with `--quiet`, debug lines with `--debug` or `-v`, trace lines with `-vv`
+fn log_level(matches: &ArgMatches) -> log::LevelFilter {
+    if matches.get_flag("quiet") {
+        return log::LevelFilter::Error
+    }
+    match (matches.get_count("verbose"), matches.get_flag("debug")) {
+        (0, false) => log::LevelFilter::Info,
+        (0 | 1, _) => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace
+    }
+}
+
 fn main() {
     let matches = clap::Command::new(NAME)
         .about(DESCRIPTION)
         .version(VERSION)
-        .color(ColorChoice::Never)
+        .color(color_choice())
         .disable_version_flag(true)
+        .after_help("Exit codes: the script's own if it failed (1 if it is one of gdep's), 128 + the signal if killed, 160-179 config errors, \
+            180-199 repo and fetch errors, 200-219 update errors, 220-239 refused or failed deployments, 240-254 errors of gdep's own environment")
         .arg(Arg::new("repo-url")
             .long("remote-repo")
             .short('r')
             .help("Remote repo to clone")
             .value_hint(clap::ValueHint::Url)
             .action(clap::ArgAction::Set))
+        .arg(Arg::new("remote-override")
+            .long("remote-override")
+            .help("Point origin of the checkout at this URL before the first fetch. Unlike --remote-repo, this also applies to existing checkouts")
+            .value_hint(clap::ValueHint::Url)
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("remote-override-temporary")
+            .long("remote-override-temporary")
+            .help("Restore origin's original URL when gdep exits")
+            .requires("remote-override")
+            .action(clap::ArgAction::SetTrue))
         .arg(Arg::new("repo-path")
             .long("local-repo")
             .short('l')
-            .help("Local repo to use. If paired with --remote-repo, this acts as a destination path. Ignored if it already exists")
+            .help("Local repo to use. If paired with --remote-repo, this acts as a destination path. Ignored if it already exists. Defaults to $GDEP_REPO_PATH, then default_repo_path")
             .value_hint(clap::ValueHint::DirPath)
             .action(clap::ArgAction::Set))
         .arg(Arg::new("config-file-i")
             .long("repo-config")
             .short('c')
-            .help("Config file name (inside of repo). Defaults to <repo>/<config-file-i>")
+            .help("Config file name (inside of repo). Defaults to <repo>/<config-file-i>. YAML or TOML")
             .value_hint(clap::ValueHint::FilePath)
             .action(clap::ArgAction::Set))
         .arg(Arg::new("config-file-o")
             .long("static-config")
             .short('s')
-            .help("Config file name (outside of repo). Overwrites --repo-config. Defaults to <repo>/gdep.yaml (uses --repo-config)")
+            .help("Config file name (outside of repo). Overwrites --repo-config. Defaults to <repo>/gdep.yaml (uses --repo-config). `-` reads it from stdin. Can be given several times, later files override single keys of earlier ones")
             .value_hint(clap::ValueHint::FilePath)
+            .action(clap::ArgAction::Append))
+        .arg(Arg::new("config-base")
+            .long("config-base")
+            .help("With --static-config -, resolve relative paths in the config (script files, into_path) against this directory instead of the working directory")
+            .value_hint(clap::ValueHint::DirPath)
+            .requires("config-file-o")
             .action(clap::ArgAction::Set))
         .arg(Arg::new("branch")
             .long("branch")
@@ -403,20 +678,254 @@ fn main() {
             .short('i')
             .help("Config file is inside the repo. Only used if neither --repo-config nor --static-config are provided")
             .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("deploy-commit")
+            .long("deploy-commit")
+            .help("Check out exactly this commit, run the script once and exit (see --resume-tracking)")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("resume-tracking")
+            .long("resume-tracking")
+            .help("After --deploy-commit finished, continue tracking the branch as usual")
+            .requires("deploy-commit")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("once")
+            .long("once")
+            .help("Look for an update once, apply it, run the script to completion and exit with its exit code. For cron")
+            .conflicts_with_all(["deploy-commit", "dry-run", "check"])
+            .action(clap::ArgAction::SetTrue))
         .arg(Arg::new("version")
-            .short('v')
+            .short('V')
             .long("version")
             .help("Displays the version")
             .action(clap::ArgAction::Version))
         .arg(Arg::new("debug")
             .long("debug")
             .short('d')
-            .help("Enable debug mode -> print errors as reals [currently unused]")
+            .help("Enable debug mode -> print errors as reals and log at debug level (RUST_LOG takes finer filters)")
+            .conflicts_with("quiet")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("verbose")
+            .long("verbose")
+            .short('v')
+            .help("Log more: -v logs at debug level like --debug, -vv at trace level")
+            .global(true)
+            .action(clap::ArgAction::Count))
+        .arg(Arg::new("quiet")
+            .long("quiet")
+            .short('q')
+            .help("Only log errors")
+            .conflicts_with("verbose")
+            .global(true)
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("color")
+            .long("color")
+            .help("Color the logs and help. `auto` colors them on a terminal unless $NO_COLOR is set")
+            .value_parser(clap::value_parser!(ColorChoice))
+            .default_value("auto")
+            .global(true)
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("check")
+            .long("check")
+            .help("Only validate the config file and report every problem in it, without touching git")
             .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("print-config")
+            .long("print-config")
+            .help("Print the config as gdep would run it, with every default filled in, and exit. Secrets are redacted. YAML, or JSON with --output json")
+            .conflicts_with_all(["check", "dry-run", "once", "deploy-commit"])
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("dry-run")
+            .long("dry-run")
+            .help("Report what would be updated and run, without cloning, checking out or starting anything")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("output")
+            .long("output")
+            .help("`json` prints events (fetches, updates, script exits, errors) as one JSON object per line on stdout. The log stays on stderr")
+            .value_parser(["human", "json"])
+            .default_value("human")
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("log-file")
+            .long("log-file")
+            .help("Also write gdep's own log output (not the script's) to this file, rotated by size. Overwrites log_file")
+            .value_hint(clap::ValueHint::FilePath)
+            .action(clap::ArgAction::Set))
+        .arg(Arg::new("script-args")
+            .help("Arguments passed to the script (after --). Replace `args` from the config")
+            .num_args(1..)
+            .last(true)
+            .action(clap::ArgAction::Set))
+        .subcommand(clap::Command::new("config-migrate")
+            .about("Read a config and write it back out in the current canonical format. Comments are not preserved")
+            .arg(Arg::new("input")
+                .help("Config file to migrate")
+                .value_hint(clap::ValueHint::FilePath)
+                .default_value("gdep.yaml")
+                .action(clap::ArgAction::Set))
+            .arg(Arg::new("output")
+                .long("output")
+                .short('o')
+                .help("Write the migrated config to this file instead of stdout")
+                .value_hint(clap::ValueHint::FilePath)
+                .action(clap::ArgAction::Set)))
+        .subcommand(clap::Command::new("schema")
+            .about("Print a JSON Schema of the config file, for editors to complete and check gdep.yaml with")
+            .arg(Arg::new("output")
+                .long("output")
+                .short('o')
+                .help("Write the schema to this file instead of stdout")
+                .value_hint(clap::ValueHint::FilePath)
+                .action(clap::ArgAction::Set)))
+        .subcommand(clap::Command::new("init")
+            .about("Write a starter gdep.yaml to the working directory, asking for the mandatory settings the flags leave out")
+            .arg(Arg::new("name")
+                .long("name")
+                .help("Name of the deployment")
+                .action(clap::ArgAction::Set))
+            .arg(Arg::new("repo")
+                .long("repo")
+                .help("URL or path of the repo to deploy")
+                .action(clap::ArgAction::Set))
+            .arg(Arg::new("script")
+                .long("script")
+                .help("Script to run in the checkout")
+                .action(clap::ArgAction::Set))
+            .arg(Arg::new("force")
+                .long("force")
+                .help("Overwrite an existing gdep.yaml")
+                .action(clap::ArgAction::SetTrue)))
+        .subcommand(clap::Command::new("status")
+            .about("Report the branch, HEAD and relation to the remote of a deployment and whether gdep runs there. Fetches, but changes nothing")
+            .arg(Arg::new("repo-path")
+                .long("local-repo")
+                .short('l')
+                .help("Local repo to look at. Defaults to $GDEP_REPO_PATH, then default_repo_path")
+                .value_hint(clap::ValueHint::DirPath)
+                .action(clap::ArgAction::Set))
+            .arg(Arg::new("config-file-i")
+                .long("repo-config")
+                .short('c')
+                .help("Config file name (inside of repo)")
+                .value_hint(clap::ValueHint::FilePath)
+                .action(clap::ArgAction::Set))
+            .arg(Arg::new("config-file-o")
+                .long("static-config")
+                .short('s')
+                .help("Config file name (outside of repo). Overwrites --repo-config. Can be given several times, later files override earlier ones")
+                .value_hint(clap::ValueHint::FilePath)
+                .action(clap::ArgAction::Append))
+            .arg(Arg::new("config-inside")
+                .long("config-inside")
+                .short('i')
+                .help("Config file is inside the repo")
+                .action(clap::ArgAction::SetTrue))
+            .arg(Arg::new("branch")
+                .long("branch")
+                .short('b')
+                .help("Branch to compare. Will otherwise be the configured or auto-inferred one")
+                .action(clap::ArgAction::Set))
+            .arg(Arg::new("output")
+                .long("output")
+                .help("`json` prints one JSON object per deployment")
+                .value_parser(["human", "json"])
+                .action(clap::ArgAction::Set)))
+        .subcommand(clap::Command::new("history")
+            .about("Print the latest entries of the deploy history: applied updates, rollbacks and how script runs ended")
+            .arg(Arg::new("repo-path")
+                .long("local-repo")
+                .short('l')
+                .help("Local repo to look at. Without --static-config, no config is read. Defaults to $GDEP_REPO_PATH, then default_repo_path")
+                .value_hint(clap::ValueHint::DirPath)
+                .action(clap::ArgAction::Set))
+            .arg(Arg::new("config-file-i")
+                .long("repo-config")
+                .short('c')
+                .help("Config file name (inside of repo)")
+                .value_hint(clap::ValueHint::FilePath)
+                .action(clap::ArgAction::Set))
+            .arg(Arg::new("config-file-o")
+                .long("static-config")
+                .short('s')
+                .help("Config file name (outside of repo). Overwrites --repo-config. Can be given several times, later files override earlier ones")
+                .value_hint(clap::ValueHint::FilePath)
+                .action(clap::ArgAction::Append))
+            .arg(Arg::new("config-inside")
+                .long("config-inside")
+                .short('i')
+                .help("Config file is inside the repo")
+                .action(clap::ArgAction::SetTrue))
+            .arg(Arg::new("count")
+                .long("count")
+                .short('n')
+                .help("How many entries to print per deployment")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10")
+                .action(clap::ArgAction::Set))
+            .arg(Arg::new("output")
+                .long("output")
+                .help("`json` prints the entries as they are recorded, one JSON object per line")
+                .value_parser(["human", "json"])
+                .action(clap::ArgAction::Set)))
         .get_matches();
-    
-    let result = run(&matches);
-    if result.is_err() {
-        println!("Gdep Error => {}", result.unwrap_err())
+
+    let debug = matches.get_flag("debug");
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    match log_level(&matches) {
+        log::LevelFilter::Info => {}
+        // Nothing else has anything to say then either
+        log::LevelFilter::Error => { logger.filter_level(log::LevelFilter::Error); }
+        level => { logger.filter_module(module_path!(), level); }
+    }
+    let colored = match matches.get_one::<ColorChoice>("color").copied().unwrap_or(ColorChoice::Auto) {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+    };
+    logger.write_style(if colored {env_logger::WriteStyle::Always} else {env_logger::WriteStyle::Never});
+    logger.target(env_logger::Target::Pipe(Box::new(logfile::Tee)));
+    // Threads of a deployment are named after it, which tells their lines apart when running several
+    logger.format(|buf, record| {
+        use std::io::Write;
+        let thread = thread::current();
+        let deployment = thread.name().filter(|n| *n != "main").map(|n| format!(" {}", n)).unwrap_or_default();
+        let style = buf.default_level_style(record.level());
+        writeln!(buf, "[{} {style}{:<5}{style:#} {}{}] {}", buf.timestamp(), record.level(), record.target(), deployment, record.args())
+    });
+    logger.init();
+    if matches.get_one::<String>("output").is_some_and(|o| o == "json") {
+        events::enable();
+    }
+    signals::install().expect("Failed to install signal handlers");
+    // Opened right away so nothing before the config is loaded gets lost
+    let early_log = match matches.get_one::<String>("log-file") {
+        Some(path) => logfile::open(Path::new(path), DEFAULT_LOG_MAX_SIZE, DEFAULT_LOG_KEEP).map_err(|e| GdepError::LogFileFailed(path.clone(), e.to_string())),
+        None => Ok(())
+    };
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let result = early_log.and_then(|_| match matches.subcommand() {
+        Some(("config-migrate", sub)) => migrate_cfg(sub),
+        Some(("init", sub)) => init_cfg(sub),
+        Some(("schema", sub)) => print_schema(sub),
+        Some(("status", sub)) => status(sub, [&matches, sub].iter().any(|m| m.get_one::<String>("output").is_some_and(|o| o == "json")), &*clock),
+        Some(("history", sub)) => history(sub, *sub.get_one::<usize>("count").unwrap(),
+            [&matches, sub].iter().any(|m| m.get_one::<String>("output").is_some_and(|o| o == "json"))),
+        _ if matches.get_flag("check") => check_cfg(&matches),
+        _ if matches.get_flag("print-config") => print_cfg(&matches),
+        _ => run(&matches, clock)
+    });
+    if let Err(err) = &result {
+        // Printed rather than logged, so no log filter can hide why gdep stopped
+        let line = format_error(err, debug);
+        eprintln!("{}", line);
+        logfile::append(&line);
+        let deployment = match err {
+            GdepError::DeploymentFailed(name, _) => Some(name.as_str()),
+            _ => None
+        };
+        events::emit(deployment, Event::GdepError { err, fatal: true });
+    }
+    systemd::stopping();
+    output::flush();
+    notify::flush();
+    logfile::close();
+    if let Err(err) = result {
+        std::process::exit(err.exit_code())
     }
 }
\ No newline at end of file
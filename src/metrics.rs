@@ -0,0 +1,61 @@
+use std::fmt::Write;
+use chrono::Utc;
+use crate::health::Status;
+
+/// The Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Appends `name` with its help line, type and one sample per `(labels, value)`
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, String)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// `status` as served on `/metrics`. Gauges that don't apply (yet) are left out
+pub fn render(status: &Status) -> String {
+    let mut out = String::new();
+    metric(&mut out, "gdep_updates_applied_total", "counter", "Updates applied since gdep started",
+        &[(String::new(), status.updates.to_string())]);
+    metric(&mut out, "gdep_fetch_failures_total", "counter", "Fetches that failed and were retried",
+        &[(String::new(), status.fetch_failures.to_string())]);
+    metric(&mut out, "gdep_script_restarts_total", "counter", "Times the script was started again",
+        &[(String::new(), status.restarts.to_string())]);
+    let exits: Vec<_> = status.exit_codes.iter().map(|(code, count)| (format!("{{code=\"{}\"}}", code), count.to_string())).collect();
+    metric(&mut out, "gdep_script_exits_total", "counter", "Times the script exited, by exit code", &exits);
+    if let Some(behind) = status.behind {
+        metric(&mut out, "gdep_behind_commits", "gauge", "Commits the checkout was behind origin at the last fetch",
+            &[(String::new(), behind.to_string())]);
+    }
+    if let Some(last_fetch) = status.last_fetch {
+        let since = (Utc::now() - last_fetch).num_milliseconds().max(0) as f64 / 1000.0;
+        metric(&mut out, "gdep_seconds_since_last_fetch", "gauge", "Seconds since the last successful fetch",
+            &[(String::new(), since.to_string())]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_always_there_and_gauges_once_known() {
+        let mut status = Status { updates: 2, restarts: 3, ..Status::default() };
+        let rendered = render(&status);
+        assert!(rendered.contains("# TYPE gdep_updates_applied_total counter\ngdep_updates_applied_total 2\n"), "{}", rendered);
+        assert!(rendered.contains("\ngdep_script_restarts_total 3\n"), "{}", rendered);
+        assert!(!rendered.contains("gdep_behind_commits"), "{}", rendered);
+        assert!(!rendered.contains("gdep_seconds_since_last_fetch"), "{}", rendered);
+
+        status.exit_codes = [(0, 1), (1, 4)].into();
+        status.behind = Some(5);
+        status.last_fetch = Some(Utc::now());
+        let rendered = render(&status);
+        assert!(rendered.contains("\ngdep_script_exits_total{code=\"0\"} 1\ngdep_script_exits_total{code=\"1\"} 4\n"), "{}", rendered);
+        assert!(rendered.contains("# TYPE gdep_behind_commits gauge\ngdep_behind_commits 5\n"), "{}", rendered);
+        assert!(rendered.contains("\ngdep_seconds_since_last_fetch 0"), "{}", rendered);
+    }
+}
@@ -0,0 +1,112 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// `$NETRC`, else `~/.netrc`
+fn path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("NETRC").filter(|p| !p.is_empty()) {
+        return Some(PathBuf::from(path))
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".netrc"))
+}
+
+/// A `machine` (or the `default`) entry
+#[derive(Default)]
+struct Entry {
+    matches: bool,
+    login: Option<String>,
+    password: Option<String>
+}
+
+impl Entry {
+    fn credentials(self) -> Option<(String, String)> {
+        if !self.matches {
+            return None
+        }
+        Some((self.login?, self.password?))
+    }
+}
+
+/// Login and password of the `machine` entry for `host`, else of the `default` entry.
+/// Entries without a password are skipped, `macdef` bodies (up to the next empty line) are ignored
+fn find(content: &str, host: &str) -> Option<(String, String)> {
+    let mut default: Option<Entry> = None;
+    let mut current: Option<Entry> = None;
+    let mut in_default = false;
+
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" => {
+                    if let Some(found) = current.take().and_then(Entry::credentials) {
+                        return Some(found)
+                    }
+                    current = Some(Entry { matches: tokens.next() == Some(host), ..Entry::default() });
+                    in_default = false;
+                }
+                "default" => {
+                    if let Some(found) = current.take().and_then(Entry::credentials) {
+                        return Some(found)
+                    }
+                    default = Some(Entry { matches: true, ..Entry::default() });
+                    in_default = true;
+                }
+                "login" | "password" => {
+                    let value = tokens.next().map(|v| v.to_string());
+                    let entry = if in_default {default.as_mut()} else {current.as_mut()};
+                    match entry {
+                        Some(entry) if token == "login" => entry.login = value,
+                        Some(entry) => entry.password = value,
+                        None => {}
+                    }
+                }
+                "macdef" => {
+                    for line in lines.by_ref() {
+                        if line.trim().is_empty() {
+                            break
+                        }
+                    }
+                    break
+                }
+                _ => {}
+            }
+        }
+    }
+    // The default entry only counts if no machine matched
+    current.and_then(Entry::credentials).or_else(|| default.and_then(Entry::credentials))
+}
+
+/// The credentials `.netrc` has for `host`, if it has any
+pub fn lookup(host: &str) -> Option<(String, String)> {
+    let content = fs::read_to_string(path()?).ok()?;
+    find(&content, host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(login: &str, password: &str) -> Option<(String, String)> {
+        Some((login.to_string(), password.to_string()))
+    }
+
+    #[test]
+    fn the_machine_entry_of_the_host_wins_over_the_default() {
+        let netrc = "default login anonymous password guest\n\
+            machine git.example.com\n  login deploy\n  password s3cret\n\
+            machine other.example.com login other password other\n";
+        assert_eq!(find(netrc, "git.example.com"), pair("deploy", "s3cret"));
+        assert_eq!(find(netrc, "other.example.com"), pair("other", "other"));
+        assert_eq!(find(netrc, "unknown.example.com"), pair("anonymous", "guest"));
+        assert_eq!(find("machine git.example.com login deploy password s3cret\n", "unknown.example.com"), None);
+    }
+
+    #[test]
+    fn entries_without_a_password_and_macros_are_skipped() {
+        assert_eq!(find("machine git.example.com login deploy\ndefault login anonymous password guest\n", "git.example.com"), pair("anonymous", "guest"));
+        let netrc = "macdef init\n  machine git.example.com login macro password macro\n\nmachine git.example.com login deploy password s3cret\n";
+        assert_eq!(find(netrc, "git.example.com"), pair("deploy", "s3cret"));
+    }
+}
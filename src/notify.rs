@@ -0,0 +1,277 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use log::{debug, warn};
+use serde_json::{json, Value};
+use yaml_rust2::Yaml;
+use yaml_rust2::yaml::Hash;
+use crate::config::ConfigError;
+use crate::errors::GdepError;
+
+/// A notification that is never answered within this is given up on
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Notifications still being sent, so the last ones aren't lost when gdep exits
+static PENDING: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// What the `notifier` is told about
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NotifyEvent {
+    /// An update was applied
+    Update,
+    /// A deployment stopped or restarted because of an error
+    Error
+}
+
+impl NotifyEvent {
+    pub const ALL: [NotifyEvent; 2] = [NotifyEvent::Update, NotifyEvent::Error];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.to_string() == name)
+    }
+}
+
+impl fmt::Display for NotifyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotifyEvent::Update => write!(f, "update"),
+            NotifyEvent::Error => write!(f, "error")
+        }
+    }
+}
+
+/// What a notification is about
+pub enum Notification<'a> {
+    Update { name: &'a str, branch: &'a str, previous: Option<git2::Oid>, current: git2::Oid },
+    Error { name: &'a str, branch: &'a str, err: &'a GdepError }
+}
+
+impl Notification<'_> {
+    pub fn event(&self) -> NotifyEvent {
+        match self {
+            Notification::Update { .. } => NotifyEvent::Update,
+            Notification::Error { .. } => NotifyEvent::Error
+        }
+    }
+
+    /// The machine readable payload of the `generic` backend
+    fn to_json(&self) -> Value {
+        match self {
+            Notification::Update { name, branch, previous, current } => json!({
+                "event": "update", "name": name, "branch": branch, "previous": previous.map(|p| p.to_string()), "current": current.to_string()
+            }),
+            Notification::Error { name, branch, err } => json!({
+                "event": "error", "name": name, "branch": branch, "error": err.variant(), "message": err.to_string()
+            })
+        }
+    }
+
+    /// One line for people to read, `bold` being the chat's markup for it
+    fn summary(&self, bold: fn(&str) -> String) -> String {
+        let short = |id: git2::Oid| id.to_string().chars().take(7).collect::<String>();
+        match self {
+            Notification::Update { name, branch, previous: Some(previous), current } =>
+                format!("\u{1F680} {} deployed `{}` at `{}` (was `{}`)", bold(name), branch, short(*current), short(*previous)),
+            Notification::Update { name, branch, previous: None, current } =>
+                format!("\u{1F680} {} deployed `{}` at `{}`", bold(name), branch, short(*current)),
+            Notification::Error { name, branch, err } =>
+                format!("\u{274C} {} failed on `{}`: {}", bold(name), branch, err)
+        }
+    }
+}
+
+/// A service notifications are posted to
+pub trait Notifier {
+    /// Sends `notification` without waiting for it. Failures are only logged, a deploy never breaks on them
+    fn notify(&self, notification: &Notification);
+}
+
+/// POSTs the JSON of `Notification::to_json` as is
+struct Generic {
+    url: String
+}
+
+impl Notifier for Generic {
+    fn notify(&self, notification: &Notification) {
+        send(&self.url, notification.to_json().to_string());
+    }
+}
+
+/// A Slack incoming webhook
+struct Slack {
+    url: String
+}
+
+impl Notifier for Slack {
+    fn notify(&self, notification: &Notification) {
+        let text = notification.summary(|s| format!("*{}*", s));
+        send(&self.url, json!({"text": text}).to_string());
+    }
+}
+
+/// A Discord channel webhook
+struct Discord {
+    url: String
+}
+
+impl Notifier for Discord {
+    fn notify(&self, notification: &Notification) {
+        let content = notification.summary(|s| format!("**{}**", s));
+        send(&self.url, json!({"content": content}).to_string());
+    }
+}
+
+/// The kinds of services `notifier` can post to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Generic,
+    Slack,
+    Discord
+}
+
+impl Backend {
+    pub(crate) const ALL: [Backend; 3] = [Backend::Generic, Backend::Slack, Backend::Discord];
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|b| b.to_string() == name)
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Generic => write!(f, "generic"),
+            Backend::Slack => write!(f, "slack"),
+            Backend::Discord => write!(f, "discord")
+        }
+    }
+}
+
+fn invalid(msg: String) -> ConfigError {
+    ConfigError::InvalidValue("notifier".to_string(), msg)
+}
+
+fn as_events(value: &Yaml, key: &str) -> Result<Vec<NotifyEvent>, ConfigError> {
+    let invalid = |msg: String| ConfigError::InvalidValue(key.to_string(), msg);
+    match value {
+        Yaml::BadValue | Yaml::Null => Ok(NotifyEvent::ALL.to_vec()),
+        Yaml::Array(events) => events.iter().map(|e| e.as_str().and_then(NotifyEvent::parse)
+            .ok_or_else(|| invalid(format!("`{:?}` is not one of `update`, `error`", e)))).collect(),
+        other => Err(invalid(format!("expected a list, got `{:?}`", other)))
+    }
+}
+
+/// Where notifications go and which events are sent there
+#[derive(Clone, Debug)]
+pub struct NotifierConfig {
+    pub backend: Backend,
+    pub url: String,
+    pub events: Vec<NotifyEvent>
+}
+
+impl NotifierConfig {
+    /// Builds it from the `notifier` mapping (`backend`, `url`, `events`), or from `notify_url` and `notify_events`,
+    /// which are a `generic` notifier. None if there is neither
+    pub fn from_yaml(doc: &Yaml) -> Result<Option<Self>, ConfigError> {
+        let notifier = &doc["notifier"];
+        if notifier.is_badvalue() || notifier.is_null() {
+            return match doc["notify_url"].as_str() {
+                Some(url) => Ok(Some(Self { backend: Backend::Generic, url: url.to_string(), events: as_events(&doc["notify_events"], "notify_events")? })),
+                None => Ok(None)
+            }
+        }
+        if notifier.as_hash().is_none() {
+            return Err(invalid(format!("expected a mapping, got `{:?}`", notifier)))
+        }
+        if !doc["notify_url"].is_badvalue() {
+            return Err(invalid("set either `notifier` or `notify_url`, not both".to_string()))
+        }
+        let url = notifier["url"].as_str().ok_or_else(|| invalid("`url` is missing".to_string()))?;
+        let backend = match notifier["backend"].as_str() {
+            None => Backend::Generic,
+            Some(name) => Backend::parse(name).ok_or_else(|| invalid(format!("unknown backend `{}`, expected `generic`, `slack` or `discord`", name)))?
+        };
+        Ok(Some(Self { backend, url: url.to_string(), events: as_events(&notifier["events"], "notifier")? }))
+    }
+
+    pub fn to_yaml(&self) -> Yaml {
+        let mut notifier = Hash::new();
+        notifier.insert(Yaml::String("backend".to_string()), Yaml::String(self.backend.to_string()));
+        notifier.insert(Yaml::String("url".to_string()), Yaml::String(self.url.clone()));
+        notifier.insert(Yaml::String("events".to_string()), Yaml::Array(self.events.iter().map(|e| Yaml::String(e.to_string())).collect()));
+        Yaml::Hash(notifier)
+    }
+
+    pub fn notifier(&self) -> Box<dyn Notifier> {
+        let url = self.url.clone();
+        match self.backend {
+            Backend::Generic => Box::new(Generic { url }),
+            Backend::Slack => Box::new(Slack { url }),
+            Backend::Discord => Box::new(Discord { url })
+        }
+    }
+}
+
+/// POSTs `payload` to `url` from a new thread, so a slow or dead receiver never holds up gdep.
+/// Failures are only logged
+fn send(url: &str, payload: String) {
+    let target = url.to_string();
+    let spawned = crate::deployment_thread().spawn(move || {
+        let url = target;
+        let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+        match agent.post(&url).set("Content-Type", "application/json").send_string(&payload) {
+            Ok(_) => debug!("Notified `{}`", url),
+            Err(e) => warn!("Could not notify `{}`: {}", url, e)
+        }
+    });
+    match spawned {
+        Ok(handle) => {
+            let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+            pending.retain(|h| !h.is_finished());
+            pending.push(handle);
+        }
+        Err(e) => warn!("Could not notify `{}`: {}", url, e)
+    }
+}
+
+/// Waits for the notifications still in flight, each gives up after the request timeout
+pub fn flush() {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()));
+    for handle in pending {
+        let _ = handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chats_get_one_line_with_short_commits() {
+        let (previous, current) = (git2::Oid::from_str("1234567890abcdef").unwrap(), git2::Oid::from_str("abcdef1234567890").unwrap());
+        let update = Notification::Update { name: "web", branch: "main", previous: Some(previous), current };
+        assert_eq!(update.summary(|s| format!("*{}*", s)), "\u{1F680} *web* deployed `main` at `abcdef1` (was `1234567`)");
+        let first = Notification::Update { name: "web", branch: "main", previous: None, current };
+        assert_eq!(first.summary(|s| format!("**{}**", s)), "\u{1F680} **web** deployed `main` at `abcdef1`");
+
+        let err = GdepError::TrackedBranchGone("main".to_string());
+        let error = Notification::Error { name: "web", branch: "main", err: &err };
+        assert_eq!(error.summary(|s| format!("*{}*", s)), "\u{274C} *web* failed on `main`: Branch `main` was deleted upstream");
+        assert_eq!(error.to_json()["error"], "TrackedBranchGone");
+    }
+
+    #[test]
+    fn notifier_takes_a_backend_or_the_notify_url() {
+        let parse = |yaml: &str| NotifierConfig::from_yaml(&yaml_rust2::YamlLoader::load_from_str(yaml).unwrap()[0]).map_err(|e| e.to_string());
+        let slack = parse("notifier:\n  backend: slack\n  url: https://hooks.example.com/x\n  events: [update]\n").unwrap().unwrap();
+        assert_eq!((slack.backend, slack.url.as_str(), slack.events), (Backend::Slack, "https://hooks.example.com/x", vec![NotifyEvent::Update]));
+        let generic = parse("notify_url: https://example.com/hook\n").unwrap().unwrap();
+        assert_eq!((generic.backend, generic.events), (Backend::Generic, NotifyEvent::ALL.to_vec()));
+        assert!(parse("name: app\n").unwrap().is_none());
+
+        assert_eq!(parse("notifier:\n  backend: teams\n  url: x\n").unwrap_err(),
+            "Invalid value for `notifier`: unknown backend `teams`, expected `generic`, `slack` or `discord`");
+        assert_eq!(parse("notifier:\n  url: x\nnotify_url: y\n").unwrap_err(), "Invalid value for `notifier`: set either `notifier` or `notify_url`, not both");
+    }
+}
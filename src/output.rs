@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{SecondsFormat, Utc};
+use log::{info, warn};
+use crate::clock::Clock;
+
+/// Readers still forwarding script output, so what the script wrote last isn't lost when gdep exits
+static READERS: Mutex<Vec<thread::JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// Limits how many lines per second are forwarded from the child.
+/// Lines over the limit are dropped and counted, the count is reported once the window ends.
+/// A limit of 0 drops every line
+pub struct RateLimiter {
+    max_per_second: u64,
+    clock: Arc<dyn Clock>,
+    window_start: Instant,
+    count: u64,
+    suppressed: u64
+}
+
+impl RateLimiter {
+    pub fn new(max_per_second: u64, clock: Arc<dyn Clock>) -> Self {
+        let window_start = clock.now();
+        Self { max_per_second, clock, window_start, count: 0, suppressed: 0 }
+    }
+
+    /// Returns whether the next line may be printed
+    fn admit(&mut self) -> bool {
+        if self.clock.elapsed(self.window_start) >= Duration::from_secs(1) {
+            self.report();
+            self.window_start = self.clock.now();
+            self.count = 0;
+        }
+
+        if self.count < self.max_per_second {
+            self.count += 1;
+            true
+        } else {
+            self.suppressed += 1;
+            false
+        }
+    }
+
+    fn report(&mut self) {
+        if self.suppressed > 0 {
+            warn!("Suppressed {} lines of script output", self.suppressed);
+            self.suppressed = 0;
+        }
+    }
+}
+
+/// Last lines written by the script, kept so they can be reported when it fails
+#[derive(Clone)]
+pub struct OutputTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    open_streams: Arc<AtomicUsize>,
+    capacity: usize
+}
+
+impl OutputTail {
+    pub fn new(capacity: usize) -> Self {
+        Self { lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))), open_streams: Arc::new(AtomicUsize::new(0)), capacity }
+    }
+
+    fn push(&self, line: &[u8]) {
+        if self.capacity == 0 {
+            return
+        }
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(String::from_utf8_lossy(line).trim_end().to_string());
+    }
+
+    /// Gives the readers a moment to drain what the script wrote right before exiting, then returns the lines
+    pub fn lines(&self) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_millis(500);
+        while self.open_streams.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Everything that happens to piped script output on its way to gdep's own streams (or the files replacing them)
+#[derive(Clone, Default)]
+pub struct Forwarding {
+    pub limiter: Option<Arc<Mutex<RateLimiter>>>,
+    pub tail: Option<OutputTail>,
+    pub stdout_file: Option<PathBuf>,
+    pub stderr_file: Option<PathBuf>,
+    /// `prefix_output`
+    pub prefix: bool
+}
+
+impl Forwarding {
+    /// Whether the script's output has to be piped through gdep at all
+    pub fn needed(&self) -> bool {
+        self.limiter.is_some() || self.tail.is_some() || self.stdout_file.is_some() || self.stderr_file.is_some() || self.prefix
+    }
+}
+
+/// `file` opened for appending, or `fallback` if there is no file or it can't be opened
+fn sink(file: Option<&Path>, fallback: impl Write + Send + 'static) -> Box<dyn Write + Send> {
+    let Some(file) = file else {
+        return Box::new(fallback)
+    };
+    match OpenOptions::new().create(true).append(true).open(file) {
+        Ok(file) => Box::new(file),
+        Err(e) => {
+            warn!("Cannot write script output to `{}`, using gdep's own stream instead: {}", file.display(), e);
+            Box::new(fallback)
+        }
+    }
+}
+
+/// `sink` is where the lines go, None to log them through gdep's logger. With `prefix_output`, each line is tagged with
+/// the stream it came from, and lines written to a file get a timestamp as well
+fn forward_stream<R>(stream: R, mut sink: Option<Box<dyn Write + Send>>, tag: &'static str, forwarding: Forwarding)
+where R: Read + Send + 'static {
+    if let Some(tail) = &forwarding.tail {
+        tail.open_streams.fetch_add(1, Ordering::SeqCst);
+    }
+    let handle = crate::deployment_thread().spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if let Some(tail) = &forwarding.tail {
+                        tail.push(&line);
+                    }
+                    if forwarding.limiter.as_ref().is_none_or(|l| l.lock().unwrap().admit()) {
+                        match sink.as_mut() {
+                            None => info!(target: "script", "[{}] {}", tag, String::from_utf8_lossy(&line).trim_end()),
+                            Some(sink) => {
+                                if forwarding.prefix {
+                                    let _ = write!(sink, "{} [{}] ", Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true), tag);
+                                }
+                                let _ = sink.write_all(&line);
+                                let _ = sink.flush();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(limiter) = &forwarding.limiter {
+            limiter.lock().unwrap().report();
+        }
+        if let Some(tail) = &forwarding.tail {
+            tail.open_streams.fetch_sub(1, Ordering::SeqCst);
+        }
+    }).expect("Failed to start an output reader");
+    let mut readers = READERS.lock().unwrap_or_else(|e| e.into_inner());
+    readers.retain(|h| !h.is_finished());
+    readers.push(handle);
+}
+
+/// Takes the piped stdout/stderr of `child` and forwards them to `stdout_file` / `stderr_file`, or else gdep's own streams.
+/// With `prefix_output`, gdep's logger takes the place of its own streams
+pub fn forward_output(child: &mut Child, forwarding: Forwarding) {
+    let sink = |file: Option<&Path>, fallback: Box<dyn Write + Send>| (file.is_some() || !forwarding.prefix).then(|| sink(file, fallback));
+    if let Some(stdout) = child.stdout.take() {
+        forward_stream(stdout, sink(forwarding.stdout_file.as_deref(), Box::new(io::stdout())), "stdout", forwarding.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_stream(stderr, sink(forwarding.stderr_file.as_deref(), Box::new(io::stderr())), "stderr", forwarding.clone());
+    }
+}
+
+/// Gives the readers a moment to forward what the script wrote right before gdep exits.
+/// A pipe still held open by something the script left running is not waited for
+pub fn flush() {
+    let readers = std::mem::take(&mut *READERS.lock().unwrap_or_else(|e| e.into_inner()));
+    let deadline = Instant::now() + Duration::from_millis(500);
+    while readers.iter().any(|h| !h.is_finished()) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn admitted(limiter: &mut RateLimiter, lines: usize) -> usize {
+        (0..lines).filter(|_| limiter.admit()).count()
+    }
+
+    #[test]
+    fn lines_over_the_rate_are_suppressed_until_the_window_ends() {
+        let clock = Arc::new(MockClock::new());
+        let mut limiter = RateLimiter::new(3, clock.clone());
+        assert_eq!(admitted(&mut limiter, 5), 3);
+        assert_eq!(limiter.suppressed, 2);
+
+        clock.advance(Duration::from_millis(999));
+        assert_eq!(admitted(&mut limiter, 1), 0);
+        assert_eq!(limiter.suppressed, 3);
+
+        // The next window reports what was suppressed and starts counting over
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(admitted(&mut limiter, 4), 3);
+        assert_eq!((limiter.count, limiter.suppressed), (3, 1));
+        limiter.report();
+        assert_eq!(limiter.suppressed, 0);
+    }
+
+    #[test]
+    fn a_rate_of_zero_drops_every_line() {
+        let clock = Arc::new(MockClock::new());
+        let mut limiter = RateLimiter::new(0, clock.clone());
+        assert_eq!(admitted(&mut limiter, 3), 0);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(admitted(&mut limiter, 2), 0);
+        assert_eq!(limiter.suppressed, 2);
+    }
+
+    /// A sink the test can still look into once the reader has it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_stream_forwards_what_the_limiter_admits_and_reports_the_rest_when_it_ends() {
+        let limiter = Arc::new(Mutex::new(RateLimiter::new(2, Arc::new(MockClock::new()))));
+        let sink = Shared::default();
+        let forwarding = Forwarding { limiter: Some(Arc::clone(&limiter)), ..Default::default() };
+        forward_stream(io::Cursor::new(b"one\ntwo\nthree\nfour\n".to_vec()), Some(Box::new(sink.clone())), "stdout", forwarding);
+        flush();
+
+        assert_eq!(String::from_utf8_lossy(&sink.0.lock().unwrap()), "one\ntwo\n");
+        let limiter = limiter.lock().unwrap();
+        assert_eq!(limiter.count, 2);
+        assert_eq!(limiter.suppressed, 0, "the suppressed lines were not reported");
+    }
+}
@@ -0,0 +1,251 @@
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{env, fs, process};
+use run_script::ScriptOptions;
+use run_script::types::IoOptions;
+use crate::clock::Clock;
+
+static SCRIPT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The shells `shell` accepts. They get the flags they need to run a script file, anything else is a `runner`
+pub const SHELLS: [&str; 9] = ["sh", "bash", "dash", "zsh", "ksh", "fish", "powershell", "pwsh", "cmd"];
+
+/// A running script. Derefs to the underlying `Child` and removes the temporary script file on drop
+pub struct ScriptChild {
+    child: Child,
+    file: PathBuf
+}
+
+impl Deref for ScriptChild {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl DerefMut for ScriptChild {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl Drop for ScriptChild {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.file);
+    }
+}
+
+fn stdio(option: IoOptions) -> Stdio {
+    match option {
+        IoOptions::Null => Stdio::null(),
+        IoOptions::Pipe => Stdio::piped(),
+        IoOptions::Inherit => Stdio::inherit()
+    }
+}
+
+/// Who a script runs as with `run_as`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct User {
+    pub uid: u32,
+    pub gid: u32
+}
+
+/// Resolves `run_as`: a user name or uid, optionally followed by `:` and a group name or gid.
+/// Without a group the user's primary one is used
+#[cfg(unix)]
+pub fn user(spec: &str) -> Result<User, String> {
+    use nix::unistd::{Gid, Group, Uid};
+    let (name, group) = spec.split_once(':').map_or((spec, None), |(n, g)| (n, Some(g)));
+    let entry = match name.parse::<u32>() {
+        Ok(uid) => nix::unistd::User::from_uid(Uid::from_raw(uid)),
+        Err(_) => nix::unistd::User::from_name(name)
+    }.map_err(|e| format!("looking up user `{}` failed: {}", name, e))?;
+    let uid = match (&entry, name.parse::<u32>()) {
+        (Some(entry), _) => entry.uid.as_raw(),
+        (None, Ok(uid)) => uid,
+        (None, Err(_)) => return Err(format!("there is no user `{}`", name))
+    };
+    let gid = match group {
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => Group::from_name(group).map_err(|e| format!("looking up group `{}` failed: {}", group, e))?
+                .ok_or_else(|| format!("there is no group `{}`", group))?.gid.as_raw()
+        },
+        None => entry.map(|e| e.gid.as_raw()).ok_or_else(|| format!("uid {} has no user entry, give its group as `{}:<gid>`", uid, uid))?
+    };
+    // Only root can switch to somebody else, gdep's own user and group are always fine
+    if !Uid::effective().is_root() && (uid != Uid::effective().as_raw() || gid != Gid::effective().as_raw()) {
+        return Err(format!("gdep runs as uid {}, only root can run scripts as uid {} and gid {}", Uid::effective(), uid, gid))
+    }
+    Ok(User { uid, gid })
+}
+
+#[cfg(not(unix))]
+pub fn user(_spec: &str) -> Result<User, String> {
+    Err("running scripts as another user needs Unix".to_string())
+}
+
+/// Spawns `script` the same way `run_script::spawn` does (written to a temporary file and run by the shell),
+/// except that on Unix the child becomes the leader of a new process group,
+/// so `terminate` can signal everything the script started, and runs as the `run_as` user if given.
+/// PowerShell only runs `.ps1` files and cmd `.bat` ones, so the file is named for `options.runner`
+pub fn spawn(script: &str, args: &[String], options: &ScriptOptions, run_as: Option<&str>) -> io::Result<ScriptChild> {
+    let user = run_as.map(user).transpose().map_err(io::Error::other)?;
+    let runner = options.runner.clone().unwrap_or_else(|| (if cfg!(windows) { "cmd.exe" } else { "sh" }).to_string());
+    let name = Path::new(&runner).file_stem().map(|n| n.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+    let extension = match name.as_str() {
+        "powershell" | "pwsh" => "ps1",
+        "cmd" => "bat",
+        _ if cfg!(windows) => "bat",
+        _ => "sh"
+    };
+    let file = env::temp_dir().join(format!("gdep_{}_{}.{}", process::id(), SCRIPT_COUNTER.fetch_add(1, Ordering::Relaxed), extension));
+    fs::File::create(&file)?.write_all(script.as_bytes())?;
+    #[cfg(unix)]
+    if let Some(user) = user {
+        std::os::unix::fs::chown(&file, Some(user.uid), Some(user.gid))?;
+    }
+
+    let mut command = Command::new(&runner);
+    if let Some(runner_args) = &options.runner_args {
+        command.args(runner_args);
+    }
+    match name.as_str() {
+        "cmd" => { command.arg("/C"); }
+        "powershell" | "pwsh" => { command.args(["-NoProfile", "-NonInteractive", "-File"]); }
+        _ => {}
+    }
+    command.arg(&file).args(args);
+
+    if let Some(dir) = &options.working_directory {
+        command.current_dir(dir);
+    }
+    if let Some(vars) = &options.env_vars {
+        command.envs(vars);
+    }
+    command.stdin(stdio(options.input_redirection));
+    command.stdout(stdio(options.output_redirection));
+    command.stderr(stdio(options.output_redirection));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+        // The root's supplementary groups are dropped along with it
+        if let Some(user) = user {
+            command.uid(user.uid).gid(user.gid);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = user;
+
+    match command.spawn() {
+        Ok(child) => Ok(ScriptChild { child, file }),
+        Err(e) => {
+            let _ = fs::remove_file(&file);
+            Err(e)
+        }
+    }
+}
+
+/// Whether `program` can be started: an existing file if it is a path, else a file in one of the `$PATH` directories
+pub fn exists(program: &str) -> bool {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file()
+    }
+    let extensions: &[&str] = if cfg!(windows) { &["", ".exe", ".cmd", ".bat", ".com"] } else { &[""] };
+    env::var_os("PATH").is_some_and(|dirs| env::split_paths(&dirs)
+        .any(|dir| extensions.iter().any(|ext| dir.join(format!("{}{}", program, ext)).is_file())))
+}
+
+/// What a script that could not be started counts as exiting with: 127, which is what a shell reports for a command it can't run
+pub fn not_started() -> ExitStatus {
+    #[cfg(unix)]
+    use std::os::unix::process::ExitStatusExt;
+    #[cfg(windows)]
+    use std::os::windows::process::ExitStatusExt;
+    #[cfg(unix)]
+    return ExitStatus::from_raw(127 << 8);
+    #[cfg(windows)]
+    return ExitStatus::from_raw(127);
+}
+
+#[cfg(unix)]
+fn signal_group(child: &Child, signal: nix::sys::signal::Signal) {
+    let pgid = nix::unistd::Pid::from_raw(child.id() as i32);
+    let _ = nix::sys::signal::killpg(pgid, signal);
+}
+
+/// Asks the child's process group to stop with SIGTERM and force kills it with SIGKILL
+/// if it is still alive after `grace`, measured on `clock`. Elsewhere the child is killed right away.
+pub fn terminate(child: &mut Child, grace: Duration, clock: &dyn Clock) -> io::Result<ExitStatus> {
+    if let Some(status) = child.try_wait()? {
+        return Ok(status)
+    }
+
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::Signal;
+        signal_group(child, Signal::SIGTERM);
+        let mut exited = Ok(None);
+        clock.sleep_interruptible(grace, &mut || {
+            exited = child.try_wait();
+            !matches!(exited, Ok(None))
+        });
+        if let Some(status) = exited? {
+            return Ok(status)
+        }
+        log::warn!("Script did not stop within {}s, killing it", grace.as_secs());
+        signal_group(child, Signal::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    let _ = (grace, clock);
+
+    child.kill()?;
+    child.wait()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::time::Instant;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn terminate_kills_what_outlives_the_grace_period() {
+        let mut child = Command::new("sh").args(["-c", "trap '' TERM; echo ready; sleep 30"])
+            .process_group(0).stdout(Stdio::piped()).spawn().unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+
+        let clock = MockClock::new();
+        let started = Instant::now();
+        let status = terminate(&mut child, Duration::from_secs(60), &clock).unwrap();
+        assert_eq!(status.signal(), Some(9));
+        assert_eq!(clock.sleeps(), [Duration::from_secs(60)]);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn run_as_takes_names_ids_and_groups() {
+        let me = nix::unistd::User::from_uid(nix::unistd::Uid::effective()).unwrap().unwrap();
+        let (uid, gid) = (me.uid.as_raw(), me.gid.as_raw());
+        assert_eq!(user(&me.name), Ok(User { uid, gid }));
+        assert_eq!(user(&uid.to_string()), Ok(User { uid, gid }));
+        assert_eq!(user(&format!("{}:{}", me.name, gid)), Ok(User { uid, gid }));
+
+        assert_eq!(user("no-such-user-here"), Err("there is no user `no-such-user-here`".to_string()));
+        assert_eq!(user(&format!("{}:no-such-group-here", uid)), Err("there is no group `no-such-group-here`".to_string()));
+        if !nix::unistd::Uid::effective().is_root() {
+            assert!(user("0").unwrap_err().starts_with(&format!("gdep runs as uid {}, only root can run scripts as uid 0", uid)));
+        }
+    }
+}
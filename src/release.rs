@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use chrono::{DateTime, Utc};
+use git2::{BranchType, Repository};
+use log::info;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::errors::GdepError;
+
+/// How many hex digits of the commit a release directory is named with
+const SHORT_SHA: usize = 12;
+/// With milliseconds, so releases checked out within the same second still sort in the order they were made
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%3f";
+/// What releases were named with before, still recognized so they get pruned
+const OLD_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S";
+
+/// Held while a release is checked out, switched to or pruned. The update thread and the script's thread both check out
+/// releases, which must not race for the same directory
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn lock() -> MutexGuard<'static, ()> {
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// When it was checked out, `at`, and the commit, like `20260114T093000123-1a2b3c4d5e6f`, so the names sort oldest first
+fn release_name(commit: git2::Oid, at: DateTime<Utc>) -> String {
+    format!("{}-{}", at.format(TIMESTAMP_FORMAT), &commit.to_string()[..SHORT_SHA])
+}
+
+/// Whether `name` is one `release_name` made, anything else in `releases_dir` is left alone
+fn is_release(name: &str) -> bool {
+    name.split_once('-').is_some_and(|(time, sha)| [TIMESTAMP_FORMAT, OLD_TIMESTAMP_FORMAT].iter().any(|f| chrono::NaiveDateTime::parse_from_str(time, f).is_ok())
+        && sha.len() == SHORT_SHA && sha.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// The releases in `dir`, oldest first
+fn releases(dir: &Path) -> Vec<String> {
+    let mut releases: Vec<String> = fs::read_dir(dir).into_iter().flatten().flatten()
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|e| e.file_name().to_str().map(str::to_string))
+        .filter(|name| is_release(name))
+        .collect();
+    releases.sort();
+    releases
+}
+
+/// Every release is a worktree of the checkout, registered under this name
+fn worktree_name(release: &str) -> String {
+    format!("gdep-release-{}", release)
+}
+
+/// `releases_dir`, created if it is missing. Absolute, as `current_link` would resolve a relative target against its own directory
+fn releases_dir(config: &Config) -> Result<PathBuf, GdepError> {
+    let dir = config.releases_dir.as_ref().expect("`deploy_mode: symlink` has a `releases_dir`");
+    fs::create_dir_all(dir).and_then(|_| fs::canonicalize(dir)).map_err(|e| GdepError::ReleaseFailed(dir.clone(), e.to_string()))
+}
+
+/// The release of HEAD, checked out into `releases_dir` unless there is one already, like the one a rollback goes back to.
+/// A new one is named with the time on `clock`
+fn stage_locked(repo: &Repository, config: &Config, clock: &dyn Clock) -> Result<PathBuf, GdepError> {
+    let dir = releases_dir(config)?;
+    let head = crate::head_id(repo).ok_or_else(|| GdepError::ReleaseFailed(dir.display().to_string(), "the checkout has no commit".to_string()))?;
+    let suffix = format!("-{}", &head.to_string()[..SHORT_SHA]);
+    if let Some(existing) = releases(&dir).into_iter().rev().find(|r| r.ends_with(&suffix)) {
+        return Ok(dir.join(existing))
+    }
+
+    let name = release_name(head, clock.now_utc());
+    let path = dir.join(&name);
+    let worktree = worktree_name(&name);
+    let failed = |reason: String| GdepError::ReleaseFailed(path.display().to_string(), reason);
+    // Half a release would be taken for a whole one next time
+    let discard = || crate::remove_worktree(repo, &worktree, &path);
+    info!("Checking out release `{}`...", name);
+    let checkout = || -> Result<Repository, git2::Error> {
+        let branch = repo.branch(&worktree, &repo.find_commit(head)?, true)?;
+        let mut options = git2::WorktreeAddOptions::new();
+        options.reference(Some(branch.get()));
+        let release = Repository::open_from_worktree(&repo.worktree(&worktree, &path, Some(&options))?)?;
+        // Detached, so the branch it was added with can go again
+        release.set_head_detached(head)?;
+        repo.find_branch(&worktree, BranchType::Local)?.delete()?;
+        Ok(release)
+    };
+    let release = checkout().map_err(|e| {
+        discard();
+        failed(e.message().to_string())
+    })?;
+    let materialized = if config.submodules {crate::update_submodules(&release)} else {Ok(())}
+        .and_then(|_| crate::fetch_lfs(&release, config));
+    if let Err(e) = materialized {
+        discard();
+        return Err(e)
+    }
+    Ok(path)
+}
+
+/// `deploy_mode: symlink`: the directory HEAD of `repo` is deployed from, see `stage_locked`
+pub fn stage(repo: &Repository, config: &Config, clock: &dyn Clock) -> Result<PathBuf, GdepError> {
+    let _lock = lock();
+    stage_locked(repo, config, clock)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::other("switching releases needs Unix"))
+}
+
+/// Removes the oldest releases beyond `keep_releases`, never `live`
+fn prune(repo: &Repository, config: &Config, live: &Path) {
+    let Some(keep) = config.keep_releases else {
+        return
+    };
+    let Some(dir) = live.parent() else {
+        return
+    };
+    let releases = releases(dir);
+    let excess = releases.len().saturating_sub(keep);
+    for release in releases.iter().take(excess).filter(|r| dir.join(r) != live) {
+        crate::remove_worktree(repo, &worktree_name(release), &dir.join(release));
+        info!("Removed the old release `{}`", release);
+    }
+}
+
+/// Points `current_link` at the release of HEAD. The new link is made next to it and renamed over it,
+/// so it is switched in one step and never missing. Then prunes the releases beyond `keep_releases`
+pub fn activate(repo: &Repository, config: &Config, clock: &dyn Clock) -> Result<(), GdepError> {
+    let _lock = lock();
+    let release = stage_locked(repo, config, clock)?;
+    let link = PathBuf::from(config.current_link.as_ref().expect("`deploy_mode: symlink` has a `current_link`"));
+    if fs::read_link(&link).is_ok_and(|target| target == release) {
+        prune(repo, config, &release);
+        return Ok(())
+    }
+    let failed = |e: std::io::Error| GdepError::ReleaseFailed(release.display().to_string(), format!("could not point `{}` at it: {}", link.display(), e));
+    let file_name = link.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let new_link = link.with_file_name(format!(".{}.gdep-new", file_name));
+    let _ = fs::remove_file(&new_link);
+    symlink(&release, &new_link).and_then(|_| fs::rename(&new_link, &link)).map_err(|e| {
+        let _ = fs::remove_file(&new_link);
+        failed(e)
+    })?;
+    info!("`{}` points at the release `{}` now", link.display(), release.file_name().unwrap_or_default().to_string_lossy());
+    prune(repo, config, &release);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_names_are_recognized_and_sort_by_when_they_were_made() {
+        let commit = git2::Oid::from_str("1a2b3c4d5e6f7a8b9c0d1a2b3c4d5e6f7a8b9c0d").unwrap();
+        let at = DateTime::parse_from_rfc3339("2026-01-14T09:30:00.123Z").unwrap().with_timezone(&Utc);
+        let first = release_name(commit, at);
+        assert_eq!(first, "20260114T093000123-1a2b3c4d5e6f");
+        assert!(is_release(&first));
+        // A millisecond later still sorts after it, whatever the commit
+        let second = release_name(git2::Oid::from_str("0000000000007a8b9c0d1a2b3c4d5e6f7a8b9c0d").unwrap(), at + chrono::Duration::milliseconds(1));
+        assert!(first < second, "{} {}", first, second);
+
+        assert!(is_release("20260114T093000-1a2b3c4d5e6f"));
+        assert!(is_release("20260114T093000123-1a2b3c4d5e6f"));
+        for other in ["current", "20260114T093000-1a2b3c", "20260114T093000-not a sha!!!", "yesterday-1a2b3c4d5e6f"] {
+            assert!(!is_release(other), "{}", other);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+use yaml_rust2::Yaml;
+use log::{info, warn};
+use crate::config::{self, Config, ConfigError};
+use crate::signals;
+
+/// How often the config file's modification time is looked at
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings that are only read when gdep starts, changing them needs a restart
+const STARTUP_KEYS: [&str; 25] = ["name", "mode", "repo", "local_repo", "into_path", "default_repo_path", "webhook", "health_addr", "metrics_addr",
+    "health_auth", "metrics_auth", "control_socket", "ca_bundle", "proxy", "ssh_key", "ssh_key_passphrase", "ssh_command", "http_username", "http_token", "fetch_timeout", "log_file", "log_max_size", "log_keep", "watch_config", "restore_on_start"];
+
+/// Reloads the config files of a deployment when asked to (the `reload` command, SIGHUP) and, with `watch_config`,
+/// when one changes. Overrides from the command line are applied to every reloaded config again
+pub struct ConfigWatcher {
+    paths: Vec<String>,
+    name: String,
+    args: Option<Vec<String>>,
+    branch_fixed: bool,
+    /// `watch_config`, without it the files are only read again when asked to
+    watch: bool,
+    /// Of every file not read from stdin
+    modified: Vec<Option<SystemTime>>,
+    checked: Instant,
+    /// The count of `signals::reloads` last acted on
+    reloads: usize
+}
+
+/// What differs between the running config and a reloaded one
+pub struct Changes {
+    /// The script, its arguments, its environment or where it runs changed, so it has to be restarted
+    pub script: bool,
+    /// A setting of the update thread changed, so it has to be restarted
+    pub updates: bool,
+    /// The branch to switch to
+    pub branch: Option<String>,
+    /// Changed settings that only a restart of gdep applies
+    pub ignored: Vec<&'static str>
+}
+
+/// Keys that don't concern the update thread: the script's, the bootstrap, the cleanup's, `watch_paths`, `on_change` and the startup ones
+const NOT_FOR_UPDATES: [&str; 15] = ["script", "script_use_file", "file_path", "args", "env", "shell", "runner", "run_as", "working_dir", "bootstrap", "cleanup",
+    "cleanup_use_file", "cleanup_file_path", "watch_paths", "on_change"];
+
+fn update_settings(doc: &Yaml) -> Yaml {
+    let mut doc = doc.as_hash().cloned().unwrap_or_default();
+    for key in NOT_FOR_UPDATES.iter().chain(&STARTUP_KEYS) {
+        doc.remove(&Yaml::String(key.to_string()));
+    }
+    Yaml::Hash(doc)
+}
+
+/// Leaves out a config read from stdin, which can't change
+fn modified(paths: &[String]) -> Vec<Option<SystemTime>> {
+    paths.iter().filter(|p| !config::is_stdin(p)).map(|p| fs::metadata(p).and_then(|m| m.modified()).ok()).collect()
+}
+
+impl ConfigWatcher {
+    /// `args` are the script arguments given on the command line, `branch_fixed` is set if the branch was
+    pub fn new(paths: &[String], config: &Config, args: Option<Vec<String>>, branch_fixed: bool) -> Self {
+        if config.watch_config && paths.iter().all(|p| config::is_stdin(p)) {
+            warn!("The config was read from stdin, watch_config has nothing to watch");
+        }
+        Self { paths: paths.to_vec(), name: config.name.clone(), args, branch_fixed, watch: config.watch_config, modified: modified(paths),
+            checked: Instant::now(), reloads: signals::reloads() }
+    }
+
+    /// The watched files, for log messages
+    pub fn path(&self) -> String {
+        self.paths.join(", ")
+    }
+
+    /// The reloaded config if a file changed since the last call or a reload was asked for (`forced` or SIGHUP),
+    /// an error if it does not load anymore
+    pub fn poll(&mut self, forced: bool) -> Option<Result<Config, ConfigError>> {
+        let reloads = signals::reloads();
+        if forced || reloads != self.reloads {
+            self.reloads = reloads;
+            info!("Reloading the config `{}`...", self.path());
+            self.modified = modified(&self.paths);
+            return Some(self.load())
+        }
+        if !self.watch || self.checked.elapsed() < CHECK_INTERVAL {
+            return None
+        }
+        self.checked = Instant::now();
+        let modified = modified(&self.paths);
+        // A file that is missing is probably being replaced right now
+        if modified.is_empty() || modified.contains(&None) || modified == self.modified {
+            return None
+        }
+        self.modified = modified;
+        Some(self.load())
+    }
+
+    fn load(&self) -> Result<Config, ConfigError> {
+        let mut config = match Config::load_deployments(&self.paths)? {
+            Some(deployments) => deployments.into_iter().find(|c| c.name == self.name).ok_or_else(|| ConfigError::InvalidValue(
+                "deployments".to_string(), format!("there is no deployment named `{}` anymore", self.name)))?,
+            None => Config::load_from_files(&self.paths)?
+        };
+        if let Some(args) = &self.args {
+            config.args = args.clone();
+        }
+        config.check_runner()?;
+        Ok(config)
+    }
+
+    pub fn compare(&self, old: &Config, new: &Config, branch_name: &str) -> Changes {
+        let (old_doc, new_doc) = (old.to_yaml(), new.to_yaml());
+        let ignored = STARTUP_KEYS.into_iter().filter(|k| old_doc[*k] != new_doc[*k]).collect();
+        let branch = match &new.branch {
+            Some(branch) if !self.branch_fixed && new.branch != old.branch && branch != branch_name => Some(branch.clone()),
+            _ => None
+        };
+        Changes {
+            script: old.steps != new.steps || old.script != new.script || old.args != new.args || old.env != new.env
+                || old.shell != new.shell || old.runner != new.runner || old.run_as != new.run_as || old.working_dir != new.working_dir,
+            updates: update_settings(&old_doc) != update_settings(&new_doc),
+            branch,
+            ignored
+        }
+    }
+}
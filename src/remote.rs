@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use git2::{Cred, CredentialType, Error, FetchOptions, RemoteCallbacks};
+use log::{debug, info, warn};
+use crate::config::{Config, RepoLike};
+use crate::{auth, netrc, proxy};
+
+/// Used when the config sets no `http_token`
+const TOKEN_VAR: &str = "GDEP_HTTP_TOKEN";
+/// Used when the config sets no `http_username`
+const USERNAME_VAR: &str = "GDEP_HTTP_USERNAME";
+/// Read like git does when the config sets no `ssh_command`
+const SSH_COMMAND_VAR: &str = "GIT_SSH_COMMAND";
+/// Options of `ssh` that take an argument, which is skipped along with options `SshCommand` doesn't know
+const SSH_ARG_OPTIONS: &str = "BbcDEeFIiJLlmOopQRSWw";
+
+/// How often a running clone or fetch logs how far it got
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The credentials of the config, once `configure` was called
+#[derive(Clone)]
+struct Settings {
+    ssh_key: Option<PathBuf>,
+    ssh_key_passphrase: Option<String>,
+    http_username: Option<String>,
+    http_token: Option<String>,
+    /// The SSH port and user of `ssh_command`
+    ssh_port: Option<u16>,
+    ssh_user: Option<String>,
+    /// The host `repo` is cloned from, None for local repos
+    repo_host: Option<String>
+}
+
+/// What libgit2, which doesn't run `ssh`, can take from an `ssh_command` like `ssh -p 2222 -i ~/.ssh/deploy`:
+/// `-p`, `-i` and `-l`, also as `-o Port=`, `-o IdentityFile=` and `-o User=`. Arguments are split at whitespace
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SshCommand {
+    pub port: Option<u16>,
+    pub identity: Option<String>,
+    pub user: Option<String>,
+    /// Options that have no effect, to warn about
+    pub ignored: Vec<String>
+}
+
+impl SshCommand {
+    pub fn parse(command: &str) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut args = command.split_whitespace().skip(1).peekable();
+        while let Some(arg) = args.next() {
+            let Some(option) = arg.strip_prefix('-').and_then(|o| o.chars().next()) else {
+                return Err(format!("`{}` is not an option, the host comes from `repo`", arg))
+            };
+            let inline = &arg[1 + option.len_utf8()..];
+            let value = match (SSH_ARG_OPTIONS.contains(option), inline) {
+                (false, _) => {
+                    parsed.ignored.push(arg.to_string());
+                    continue
+                }
+                (true, "") => args.next().ok_or_else(|| format!("`{}` needs a value", arg))?.to_string(),
+                (true, inline) => inline.to_string()
+            };
+            let (option, value) = match option {
+                'o' => match value.split_once('=') {
+                    Some((key, value)) if key.eq_ignore_ascii_case("Port") => ('p', value.to_string()),
+                    Some((key, value)) if key.eq_ignore_ascii_case("IdentityFile") => ('i', value.to_string()),
+                    Some((key, value)) if key.eq_ignore_ascii_case("User") => ('l', value.to_string()),
+                    _ => (option, value)
+                },
+                _ => (option, value)
+            };
+            match option {
+                'p' => parsed.port = Some(value.parse().map_err(|_| format!("`{}` is not a port", value))?),
+                'i' => parsed.identity = Some(expand_home(&value)),
+                'l' => parsed.user = Some(value),
+                _ => parsed.ignored.push(format!("-{} {}", option, value))
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// `~/` at the start of `path` is the home directory, as the shell running `GIT_SSH_COMMAND` would have it
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home.trim_end_matches('/'), rest),
+        _ => path.to_string()
+    }
+}
+
+/// `ssh_command` from the config, else `$GIT_SSH_COMMAND`, which only gets a warning if it doesn't parse
+fn ssh_command(config: &Config) -> Option<SshCommand> {
+    if let Some(command) = &config.ssh_command {
+        return SshCommand::parse(command).ok()
+    }
+    let command = env::var(SSH_COMMAND_VAR).ok().filter(|c| !c.trim().is_empty())?;
+    match SshCommand::parse(&command) {
+        Ok(parsed) => {
+            debug!("Using ${} `{}`", SSH_COMMAND_VAR, command);
+            Some(parsed)
+        }
+        Err(e) => {
+            warn!("Ignoring ${}: {}", SSH_COMMAND_VAR, e);
+            None
+        }
+    }
+}
+
+/// `url` on the port of `ssh_command`, if it is an `ssh://` URL that names none.
+/// Other URLs are returned as they are, `user@host:path` has no room for a port
+pub fn with_ssh_port(url: &str) -> String {
+    match settings().and_then(|s| s.ssh_port) {
+        Some(port) => with_port(url, port),
+        None => url.to_string()
+    }
+}
+
+fn with_port(url: &str, port: u16) -> String {
+    let Some((scheme, rest)) = url.split_once("://").filter(|(scheme, _)| ["ssh", "git+ssh", "ssh+git"].contains(scheme)) else {
+        return url.to_string()
+    };
+    let (authority, path) = rest.split_once('/').map_or((rest, None), |(authority, path)| (authority, Some(path)));
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    if host.contains(':') {
+        return url.to_string()
+    }
+    match path {
+        Some(path) => format!("{}://{}:{}/{}", scheme, authority, port, path),
+        None => format!("{}://{}:{}", scheme, authority, port)
+    }
+}
+
+static SETTINGS: Mutex<Option<Settings>> = Mutex::new(None);
+
+fn settings() -> Option<Settings> {
+    SETTINGS.lock().unwrap().clone()
+}
+
+/// Ways to authenticate, tried in this order until the remote accepts one
+#[derive(Clone, Copy, PartialEq)]
+enum Method {
+    /// `http_token` from the config, else `$GDEP_HTTP_TOKEN`. Only offered to the host of `repo`,
+    /// so a submodule that lives elsewhere doesn't get it
+    Token,
+    /// The entry for the remote's host in `.netrc`
+    Netrc,
+    /// The configured `ssh_key`
+    Key,
+    Agent,
+    /// libgit2's default credentials (NTLM/Negotiate), which is all gdep offered before
+    Default
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Method::Token => write!(f, "the configured token"),
+            Method::Netrc => write!(f, ".netrc"),
+            Method::Key => write!(f, "the configured ssh_key"),
+            Method::Agent => write!(f, "ssh-agent"),
+            Method::Default => write!(f, "default credentials")
+        }
+    }
+}
+
+impl Method {
+    const ALL: [Method; 5] = [Method::Token, Method::Netrc, Method::Key, Method::Agent, Method::Default];
+
+    /// None if the method doesn't apply: the remote doesn't accept it or there is nothing configured for it
+    fn credential(self, url: &str, username: &str, allowed: CredentialType) -> Option<Result<Cred, Error>> {
+        let plaintext = allowed.contains(CredentialType::USER_PASS_PLAINTEXT);
+        match self {
+            Method::Token if plaintext => {
+                let settings = settings();
+                if settings.as_ref().and_then(|s| s.repo_host.as_deref()).is_some_and(|h| host(url) != Some(h)) {
+                    return None
+                }
+                let token = settings.as_ref().and_then(|s| s.http_token.clone())
+                    .or_else(|| env::var(TOKEN_VAR).ok().filter(|t| !t.is_empty()))?;
+                let username = settings.and_then(|s| s.http_username)
+                    .or_else(|| env::var(USERNAME_VAR).ok().filter(|u| !u.is_empty()))
+                    .unwrap_or_else(|| username.to_string());
+                Some(Cred::userpass_plaintext(&username, &token))
+            }
+            Method::Netrc if plaintext => {
+                let (login, password) = netrc::lookup(host(url)?)?;
+                Some(Cred::userpass_plaintext(&login, &password))
+            }
+            Method::Key if allowed.contains(CredentialType::SSH_KEY) => {
+                let settings = settings()?;
+                Some(Cred::ssh_key(username, None, settings.ssh_key.as_deref()?, settings.ssh_key_passphrase.as_deref()))
+            }
+            Method::Agent if allowed.contains(CredentialType::SSH_KEY) => Some(Cred::ssh_key_from_agent(username)),
+            Method::Default => Some(Cred::default()),
+            _ => None
+        }
+    }
+}
+
+/// The host of a `scheme://[user@]host[:port]/path` or `user@host:path` URL
+fn host(url: &str) -> Option<&str> {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit_once('@').map(|(_, host)| host).unwrap_or(authority);
+    host.split(':').next().filter(|h| !h.is_empty())
+}
+
+/// Sets the credentials (`http_username`/`http_token`, `ssh_key`) and the SSH options of `ssh_command`
+/// every later connection uses. This is process wide, like `proxy`
+pub fn configure(config: &Config) {
+    let ssh = ssh_command(config).unwrap_or_default();
+    for option in &ssh.ignored {
+        warn!("`{}` of the SSH command has no effect, only -p, -i and -l are applied", option);
+    }
+    *SETTINGS.lock().unwrap() = Some(Settings {
+        ssh_key: config.ssh_key.as_ref().map(PathBuf::from).or(ssh.identity.map(PathBuf::from)),
+        ssh_key_passphrase: config.ssh_key_passphrase.clone(),
+        http_username: config.http_username.clone(),
+        http_token: config.http_token.clone(),
+        ssh_port: ssh.port,
+        ssh_user: ssh.user,
+        repo_host: match &config.repo {
+            RepoLike::Remote(url) | RepoLike::Remote2(url, _) => host(url).map(|h| h.to_string()),
+            RepoLike::Local(_) => None
+        }
+    });
+}
+
+/// libgit2 asks again every time the remote turns the credentials down, naming what it accepts in `allowed`.
+/// Each method is offered at most once per URL, so a remote that refuses everything ends the connection
+/// with an error instead of asking forever
+fn next_credential(tried: &mut HashMap<String, Vec<Method>>, url: &str, username: Option<&str>, allowed: CredentialType) -> Result<Cred, Error> {
+    let ssh_user = settings().and_then(|s| s.ssh_user);
+    let username = username.or(ssh_user.as_deref()).unwrap_or("git");
+    if allowed.contains(CredentialType::USERNAME) {
+        return Cred::username(username)
+    }
+    let attempts = tried.entry(url.to_string()).or_default();
+    for method in Method::ALL {
+        if attempts.contains(&method) {
+            continue
+        }
+        let Some(credential) = method.credential(url, username, allowed) else {
+            continue
+        };
+        attempts.push(method);
+        match credential {
+            Ok(credential) => {
+                debug!("Authenticating to {} with {}", url, method);
+                return Ok(credential)
+            }
+            Err(e) => debug!("Can't use {} for {}: {}", method, url, e.message())
+        }
+    }
+    Err(Error::from_str(&format!("no credentials left to try for {} (tried {})",
+        url, attempts.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(", "))))
+}
+
+/// Logs the progress of a clone or fetch, `update` being its `transfer_progress` callback.
+/// At most once per `PROGRESS_INTERVAL`, so transfers that finish sooner say nothing
+pub struct Progress {
+    action: &'static str,
+    interval: Duration,
+    reported: Instant
+}
+
+impl Progress {
+    pub fn new(action: &'static str) -> Self {
+        Self { action, interval: PROGRESS_INTERVAL, reported: Instant::now() }
+    }
+
+    /// Whether it logged a line for `progress`
+    pub fn update(&mut self, progress: &git2::Progress) -> bool {
+        if self.reported.elapsed() < self.interval || progress.total_objects() == 0 {
+            return false
+        }
+        self.reported = Instant::now();
+        let (received, total) = (progress.received_objects(), progress.total_objects());
+        if received < total {
+            info!("{}: {}/{} objects ({}%), {} received", self.action, received, total, received * 100 / total, format_bytes(progress.received_bytes()));
+        } else {
+            info!("{}: resolving deltas {}/{}", self.action, progress.indexed_deltas(), progress.total_deltas());
+        }
+        true
+    }
+}
+
+fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1} GiB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1u64 << 10) as f64),
+        b => format!("{} B", b)
+    }
+}
+
+/// `value` quoted for the shell running `GIT_SSH_COMMAND`
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// The environment that has the `git` command authenticate to `url` like gdep does, for the programs gdep runs
+/// (`lfs_command`). The SSH key, port and user go into `GIT_SSH_COMMAND`, the token into an `Authorization` header
+/// git sends to the host of `repo` only, the proxy into `HTTPS_PROXY`. A passphrase can't be handed over,
+/// so `ssh` fails instead of asking for it
+pub fn git_env(url: &str) -> HashMap<String, String> {
+    let mut env = HashMap::new();
+    let Some(settings) = settings() else {
+        return env
+    };
+    let mut ssh = Vec::new();
+    if let Some(key) = &settings.ssh_key {
+        ssh.push(format!("-i {} -o IdentitiesOnly=yes", shell_quote(&key.to_string_lossy())));
+    }
+    if let Some(port) = settings.ssh_port {
+        ssh.push(format!("-p {}", port));
+    }
+    if let Some(user) = &settings.ssh_user {
+        ssh.push(format!("-l {}", shell_quote(user)));
+    }
+    if !ssh.is_empty() {
+        env.insert(SSH_COMMAND_VAR.to_string(), format!("ssh -o BatchMode=yes {}", ssh.join(" ")));
+    }
+    let token = settings.http_token.clone().or_else(|| env::var(TOKEN_VAR).ok().filter(|t| !t.is_empty()));
+    let scheme = url.split_once("://").map(|(scheme, _)| scheme).filter(|s| ["http", "https"].contains(s));
+    if let (Some(token), Some(scheme), Some(host)) = (token, scheme, host(url).filter(|h| settings.repo_host.as_deref() == Some(*h))) {
+        let username = settings.http_username.clone()
+            .or_else(|| env::var(USERNAME_VAR).ok().filter(|u| !u.is_empty()))
+            .unwrap_or_else(|| "git".to_string());
+        let credentials = auth::base64(format!("{}:{}", username, token).as_bytes());
+        env.insert("GIT_CONFIG_COUNT".to_string(), "1".to_string());
+        env.insert("GIT_CONFIG_KEY_0".to_string(), format!("http.{}://{}/.extraHeader", scheme, host));
+        env.insert("GIT_CONFIG_VALUE_0".to_string(), format!("Authorization: Basic {}", credentials));
+    }
+    if let Some(proxy) = proxy::configured() {
+        env.insert("HTTPS_PROXY".to_string(), proxy);
+    }
+    env
+}
+
+/// The callbacks of every connection to a remote, so fetches, clones and submodule updates all authenticate the same way
+pub fn callbacks<'a>() -> RemoteCallbacks<'a> {
+    let mut cb = RemoteCallbacks::new();
+    let mut tried = HashMap::new();
+    cb.credentials(move |url, username, allowed| next_credential(&mut tried, url, username, allowed));
+    cb
+}
+
+/// Options for fetching with the shared callbacks and the configured proxy, shallow if `depth` is set
+pub fn fetch_options<'a>(depth: Option<u32>) -> FetchOptions<'a> {
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks());
+    fetch_options.proxy_options(proxy::options());
+    if let Some(depth) = depth {
+        fetch_options.depth(depth as i32);
+    }
+    fetch_options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_method_is_offered_once_per_url() {
+        env::set_var("NETRC", "/nonexistent/netrc");
+        *SETTINGS.lock().unwrap() = Some(Settings {
+            ssh_key: Some(PathBuf::from("/keys/deploy")),
+            ssh_key_passphrase: None,
+            http_username: Some("deploy".to_string()),
+            http_token: Some("s3cret".to_string()),
+            ssh_port: None,
+            ssh_user: None,
+            repo_host: None
+        });
+        let allowed = CredentialType::USER_PASS_PLAINTEXT | CredentialType::SSH_KEY;
+        let mut tried = HashMap::new();
+        let url = "https://example.com/app.git";
+
+        // Asking for the username alone is not an attempt
+        next_credential(&mut tried, url, None, CredentialType::USERNAME).unwrap();
+        assert!(!tried.contains_key(url));
+        for _ in 0..4 {
+            next_credential(&mut tried, url, None, allowed).unwrap();
+        }
+        let methods: Vec<_> = tried[url].iter().map(|m| m.to_string()).collect();
+        assert_eq!(methods, ["the configured token", "the configured ssh_key", "ssh-agent", "default credentials"]);
+        let Err(e) = next_credential(&mut tried, url, None, allowed) else {
+            panic!("a method was offered twice")
+        };
+        assert_eq!(e.message(), format!("no credentials left to try for {} (tried {})", url, methods.join(", ")));
+
+        // Another URL starts over
+        next_credential(&mut tried, "https://example.com/lib.git", None, allowed).unwrap();
+        assert_eq!(tried["https://example.com/lib.git"].len(), 1);
+    }
+
+    /// Fetches `main` of the repo at `url` into a new bare repo at `into`, feeding `progress`. How often it was fed and how often it logged
+    fn fetch_counting(url: &str, into: &std::path::Path, progress: &mut Progress) -> (usize, usize) {
+        let (mut calls, mut reports) = (0, 0);
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.transfer_progress(|p| {
+            calls += 1;
+            reports += progress.update(&p) as usize;
+            true
+        });
+        let mut options = FetchOptions::new();
+        options.remote_callbacks(callbacks);
+        let repo = git2::Repository::init_bare(into).unwrap();
+        repo.remote_anonymous(url).unwrap().fetch(&["refs/heads/main:refs/heads/main"], Some(&mut options), None).unwrap();
+        drop(options);
+        (calls, reports)
+    }
+
+    #[test]
+    fn a_fetch_reports_its_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = git2::Repository::init(dir.path().join("source")).unwrap();
+        let signature = git2::Signature::now("dev", "dev@example.com").unwrap();
+        let mut builder = source.treebuilder(None).unwrap();
+        for i in 0..20 {
+            builder.insert(format!("file{}.txt", i), source.blob(format!("content {}\n", i).as_bytes()).unwrap(), 0o100644).unwrap();
+        }
+        let tree = source.find_tree(builder.write().unwrap()).unwrap();
+        source.commit(Some("refs/heads/main"), &signature, &signature, "files", &tree, &[]).unwrap();
+        let url = dir.path().join("source").to_string_lossy().to_string();
+
+        let (calls, reports) = fetch_counting(&url, &dir.path().join("target"), &mut Progress { interval: Duration::ZERO, ..Progress::new("Fetching") });
+        assert!(calls > 0);
+        assert!(reports > 0);
+
+        // With the real interval a transfer this quick says nothing
+        let (calls, reports) = fetch_counting(&url, &dir.path().join("again"), &mut Progress::new("Fetching"));
+        assert!(calls > 0);
+        assert_eq!(reports, 0);
+    }
+
+    #[test]
+    fn an_ssh_command_gives_port_identity_and_user() {
+        let parsed = SshCommand::parse("ssh -p 2222 -i /keys/deploy -l git").unwrap();
+        assert_eq!(parsed, SshCommand { port: Some(2222), identity: Some("/keys/deploy".to_string()), user: Some("git".to_string()), ignored: Vec::new() });
+        let parsed = SshCommand::parse("ssh -p2222 -o IdentityFile=/keys/deploy -o User=git").unwrap();
+        assert_eq!((parsed.port, parsed.identity.as_deref(), parsed.user.as_deref()), (Some(2222), Some("/keys/deploy"), Some("git")));
+        assert_eq!(SshCommand::parse("ssh -o Port=2200").unwrap().port, Some(2200));
+        assert_eq!(SshCommand::parse("ssh").unwrap(), SshCommand::default());
+
+        // What libgit2 cannot do is kept to warn about, with its argument
+        let parsed = SshCommand::parse("ssh -v -J bastion -o StrictHostKeyChecking=no -p 22").unwrap();
+        assert_eq!(parsed.ignored, ["-v", "-J bastion", "-o StrictHostKeyChecking=no"]);
+        assert_eq!(parsed.port, Some(22));
+
+        assert_eq!(SshCommand::parse("ssh -p"), Err("`-p` needs a value".to_string()));
+        assert_eq!(SshCommand::parse("ssh -p twenty"), Err("`twenty` is not a port".to_string()));
+        assert_eq!(SshCommand::parse("ssh git@example.com"), Err("`git@example.com` is not an option, the host comes from `repo`".to_string()));
+    }
+
+    #[test]
+    fn ssh_urls_get_the_port_unless_they_name_one() {
+        assert_eq!(with_port("ssh://git@example.com/app.git", 2222), "ssh://git@example.com:2222/app.git");
+        assert_eq!(with_port("git+ssh://example.com", 2222), "git+ssh://example.com:2222");
+        assert_eq!(with_port("ssh://git@example.com:22/app.git", 2222), "ssh://git@example.com:22/app.git");
+        assert_eq!(with_port("git@example.com:app.git", 2222), "git@example.com:app.git");
+        assert_eq!(with_port("https://example.com/app.git", 2222), "https://example.com/app.git");
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use git2::{Error, ErrorClass, ErrorCode};
+use log::warn;
+use crate::clock::Clock;
+use crate::errors::GdepError;
+
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Network trouble that may go away by itself, as opposed to e.g. a missing ref or rejected credentials
+pub fn is_transient(e: &Error) -> bool {
+    match e.code() {
+        ErrorCode::Timeout => true,
+        // A 4xx answer (no such repo, access denied) won't change by asking again
+        ErrorCode::GenericError if e.class() == ErrorClass::Http => !e.message().contains("status code: 4"),
+        ErrorCode::GenericError => matches!(e.class(), ErrorClass::Net | ErrorClass::Os | ErrorClass::Ssl | ErrorClass::Ssh),
+        _ => false
+    }
+}
+
+/// A failure applying an update that may go away by itself: the index or a ref was locked by another process.
+/// Conflicts and diverged branches stay the way they are, so they are not retried
+pub fn is_retryable_update(e: &GdepError) -> bool {
+    match e {
+        GdepError::GitError(_, code) | GdepError::UpdateFailed(_, code) => *code == ErrorCode::Locked,
+        _ => false
+    }
+}
+
+/// Exponential backoff (1s, 2s, 4s, ... up to a minute) for up to `max_retries` consecutive failures
+pub struct Backoff<'a> {
+    failures: u64,
+    max_retries: u64,
+    clock: &'a dyn Clock
+}
+
+impl<'a> Backoff<'a> {
+    pub fn new(max_retries: u64, clock: &'a dyn Clock) -> Self {
+        Self { failures: 0, max_retries, clock }
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Waits before the next attempt if `e` is worth retrying. False once retries are used up or for permanent errors.
+    /// Returns early if `stop_flag` gets set, so the caller's loop can wind down
+    pub fn retry(&mut self, e: &Error, stop_flag: &Mutex<bool>) -> bool {
+        is_transient(e) && self.wait(&format!("Fetch failed ({})", e.message()), stop_flag)
+    }
+
+    /// Like `retry`, for the update as a whole, see `is_retryable_update`
+    pub fn retry_update(&mut self, e: &GdepError, stop_flag: &Mutex<bool>) -> bool {
+        is_retryable_update(e) && self.wait(&e.to_string(), stop_flag)
+    }
+
+    fn wait(&mut self, failure: &str, stop_flag: &Mutex<bool>) -> bool {
+        if self.failures >= self.max_retries {
+            return false
+        }
+        let delay = Duration::from_secs(1 << self.failures.min(6)).min(MAX_DELAY);
+        self.failures += 1;
+        warn!("{}, retrying in {}s ({}/{})", failure, delay.as_secs(), self.failures, self.max_retries);
+
+        self.clock.sleep_interruptible(delay, &mut || *stop_flag.lock().unwrap());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn timeout() -> Error {
+        Error::new(ErrorCode::Timeout, ErrorClass::Net, "timed out")
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        let clock = MockClock::new();
+        let stop_flag = Mutex::new(false);
+        let mut backoff = Backoff::new(9, &clock);
+        while backoff.retry(&timeout(), &stop_flag) {}
+        assert_eq!(clock.sleeps(), [1, 2, 4, 8, 16, 32, 60, 60, 60].map(Duration::from_secs));
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let clock = MockClock::new();
+        let stop_flag = Mutex::new(false);
+        let mut backoff = Backoff::new(3, &clock);
+        assert!(backoff.retry(&timeout(), &stop_flag));
+        assert!(backoff.retry(&timeout(), &stop_flag));
+        backoff.reset();
+        assert!(backoff.retry(&timeout(), &stop_flag));
+        assert_eq!(clock.sleeps(), [1, 2, 1].map(Duration::from_secs));
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let clock = MockClock::new();
+        let stop_flag = Mutex::new(false);
+        let mut backoff = Backoff::new(3, &clock);
+        assert!(!backoff.retry(&Error::new(ErrorCode::NotFound, ErrorClass::Reference, "no such ref"), &stop_flag));
+        assert!(!backoff.retry(&Error::new(ErrorCode::GenericError, ErrorClass::Http, "unexpected http status code: 404"), &stop_flag));
+        assert!(clock.sleeps().is_empty());
+    }
+
+    #[test]
+    fn only_locked_updates_are_retried() {
+        let clock = MockClock::new();
+        let stop_flag = Mutex::new(false);
+        let mut backoff = Backoff::new(1, &clock);
+        assert!(!backoff.retry_update(&GdepError::UpdateFailed("conflict".to_string(), ErrorCode::Conflict), &stop_flag));
+        assert!(backoff.retry_update(&GdepError::UpdateFailed("index.lock exists".to_string(), ErrorCode::Locked), &stop_flag));
+        assert!(!backoff.retry_update(&GdepError::UpdateFailed("index.lock exists".to_string(), ErrorCode::Locked), &stop_flag));
+        assert_eq!(clock.sleeps(), [Duration::from_secs(1)]);
+    }
+}
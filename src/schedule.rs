@@ -0,0 +1,153 @@
+use chrono::{DateTime, Datelike, Local, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use yaml_rust2::Yaml;
+use crate::config::ConfigError;
+
+/// Restricts when updates may be applied (e.g. no Friday deploys).
+/// Updates detected outside of it are deferred until it opens again.
+#[derive(Clone, Debug)]
+pub struct DeploySchedule {
+    /// Start and end of the daily window. Wraps around midnight if the end is before the start
+    pub window: Option<(NaiveTime, NaiveTime)>,
+    /// Days on which updates are allowed, judged by the local date at the time of the update
+    pub days: Option<Vec<Weekday>>,
+    /// Timezone the window and days are evaluated in. The host's local time if unset
+    pub timezone: Option<Tz>
+}
+
+fn invalid(key: &str, msg: String) -> ConfigError {
+    ConfigError::InvalidValue(key.to_string(), msg)
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, ConfigError> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M").map_err(|_| invalid("deploy_window", format!("`{}` is not a HH:MM time", value)))
+}
+
+impl DeploySchedule {
+    /// Builds the schedule from the `deploy_window`, `deploy_days` and `timezone` keys. None if neither restriction is set
+    pub fn from_yaml(doc: &Yaml) -> Result<Option<Self>, ConfigError> {
+        let window = match doc["deploy_window"].as_str() {
+            Some(spec) => {
+                let (start, end) = spec.split_once('-')
+                    .ok_or_else(|| invalid("deploy_window", format!("`{}` is not of the form HH:MM-HH:MM", spec)))?;
+                Some((parse_time(start)?, parse_time(end)?))
+            }
+            None => None
+        };
+
+        let days = match doc["deploy_days"].as_vec() {
+            Some(days) => Some(days.iter().map(|d| {
+                d.as_str().and_then(|d| d.parse::<Weekday>().ok())
+                    .ok_or_else(|| invalid("deploy_days", format!("`{:?}` is not a weekday", d)))
+            }).collect::<Result<Vec<_>, _>>()?),
+            None => None
+        };
+
+        let timezone = match doc["timezone"].as_str() {
+            Some(tz) => Some(tz.parse::<Tz>().map_err(|_| invalid("timezone", format!("unknown timezone `{}`", tz)))?),
+            None => None
+        };
+
+        if window.is_none() && days.is_none() {
+            return Ok(None)
+        }
+        Ok(Some(Self { window, days, timezone }))
+    }
+
+    pub fn allows(&self, now: DateTime<Utc>) -> bool {
+        let (weekday, time) = match self.timezone {
+            Some(tz) => {
+                let local = now.with_timezone(&tz);
+                (local.weekday(), local.time())
+            }
+            None => {
+                let local = now.with_timezone(&Local);
+                (local.weekday(), local.time())
+            }
+        };
+
+        let day_ok = self.days.as_ref().is_none_or(|days| days.contains(&weekday));
+        let time_ok = self.window.is_none_or(|(start, end)| {
+            if start <= end {
+                start <= time && time < end
+            } else {
+                time >= start || time < end
+            }
+        });
+        day_ok && time_ok
+    }
+
+    pub fn to_yaml(&self, mut put: impl FnMut(&str, Yaml)) {
+        if let Some((start, end)) = self.window {
+            put("deploy_window", Yaml::String(format!("{}-{}", start.format("%H:%M"), end.format("%H:%M"))));
+        }
+        if let Some(days) = &self.days {
+            put("deploy_days", Yaml::Array(days.iter().map(|d| Yaml::String(d.to_string().to_lowercase())).collect()));
+        }
+        if let Some(tz) = self.timezone {
+            put("timezone", Yaml::String(tz.name().to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use yaml_rust2::YamlLoader;
+    use super::*;
+
+    fn schedule(yaml: &str) -> Result<Option<DeploySchedule>, ConfigError> {
+        DeploySchedule::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0])
+    }
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn days_end_at_midnight_of_the_timezone() {
+        let weekdays = schedule("deploy_days: [mon, tue, wed, thu, fri]\ntimezone: Europe/Berlin\n").unwrap().unwrap();
+        // Friday 23:59 and Saturday 00:00 in Berlin, which is UTC+2 in October
+        assert!(weekdays.allows(at(2026, 10, 16, 21, 59)));
+        assert!(!weekdays.allows(at(2026, 10, 16, 22, 0)));
+        // Still Sunday in New York while it already is Monday in UTC
+        let new_york = schedule("deploy_days: [mon]\ntimezone: America/New_York\n").unwrap().unwrap();
+        assert!(!new_york.allows(at(2026, 10, 19, 3, 0)));
+        assert!(new_york.allows(at(2026, 10, 19, 4, 0)));
+    }
+
+    #[test]
+    fn windows_wrap_around_midnight() {
+        let night = schedule("deploy_window: 22:00-06:00\ntimezone: UTC\n").unwrap().unwrap();
+        assert!(night.allows(at(2026, 10, 14, 22, 0)));
+        assert!(night.allows(at(2026, 10, 14, 23, 30)));
+        assert!(night.allows(at(2026, 10, 15, 5, 59)));
+        assert!(!night.allows(at(2026, 10, 15, 6, 0)));
+        assert!(!night.allows(at(2026, 10, 15, 12, 0)));
+    }
+
+    #[test]
+    fn windows_follow_daylight_saving_time() {
+        let office = schedule("deploy_window: 09:00-17:00\ntimezone: Europe/Berlin\n").unwrap().unwrap();
+        // 07:30 UTC is 08:30 before the switch on 2026-03-29 and 09:30 after it
+        assert!(!office.allows(at(2026, 3, 28, 7, 30)));
+        assert!(office.allows(at(2026, 3, 29, 7, 30)));
+        assert!(!office.allows(at(2026, 3, 29, 15, 0)));
+    }
+
+    #[test]
+    fn days_and_window_both_have_to_allow() {
+        let both = schedule("deploy_window: 09:00-17:00\ndeploy_days: [fri]\ntimezone: UTC\n").unwrap().unwrap();
+        assert!(both.allows(at(2026, 10, 16, 10, 0)));
+        assert!(!both.allows(at(2026, 10, 16, 18, 0)));
+        assert!(!both.allows(at(2026, 10, 15, 10, 0)));
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        assert!(schedule("timezone: UTC\n").unwrap().is_none());
+        for yaml in ["deploy_window: 09:00\n", "deploy_window: 9-17\n", "deploy_days: [someday]\n", "deploy_days: [mon]\ntimezone: Mars/Olympus\n"] {
+            assert!(matches!(schedule(yaml), Err(ConfigError::InvalidValue(..))), "{}", yaml);
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use yaml_rust2::Yaml;
+use yaml_rust2::yaml::Hash;
+use crate::config::{self, Config};
+use crate::notify::{Backend, NotifyEvent};
+
+/// Durations as `as_duration` reads them, seconds with an optional unit
+const DURATION_PATTERN: &str = r"^\s*[0-9]+\s*[wdhms]?\s*$";
+/// Sizes as `as_size` reads them, bytes with an optional binary unit
+const SIZE_PATTERN: &str = r"^\s*[0-9]+\s*[KMG]?\s*$";
+
+/// What a key holds, as far as the schema can tell before `from_yaml` looks at it
+enum Kind {
+    Bool,
+    Str,
+    Int,
+    Duration,
+    Size,
+    Choice(&'static [&'static str]),
+    /// Globs, fingerprints and other lists of strings
+    Strings,
+    /// Any scalar, taken as a string
+    Scalars,
+    /// A mapping of names to scalars
+    ScalarMap,
+    /// A mapping of path globs to scripts
+    ScriptMap,
+    /// A string, or a list of strings run one after the other
+    Script,
+    /// A command line, or the program and its arguments as a list
+    CommandLine,
+    /// A name, or a bare uid
+    User,
+    Auth,
+    Webhook,
+    Notifier,
+    Events,
+    Weekdays
+}
+
+/// Every key of a deployment, with what it holds and what it does. `Config::from_yaml` rejects any other key, so this is
+/// the one list of them. Defaults are not listed here, `schema` takes them from `Config::to_effective_yaml` so they can't drift from the parser
+const KEYS: &[(&str, Kind, &str)] = &[
+    ("name", Kind::Str, "Name of the deployment, unique among the deployments of a file"),
+    ("mode", Kind::Choice(&["deploy", "mirror"]), "`deploy` keeps a checkout up to date and runs the script on it, `mirror` keeps a bare copy of every ref of origin"),
+    ("repo", Kind::Str, "URL of the repo to clone, or the path of a local one with `local_repo`"),
+    ("local_repo", Kind::Bool, "`repo` is a path to an existing repo instead of a URL"),
+    ("into_path", Kind::Str, "Where a remote repo is cloned to, relative to the config file"),
+    ("deploy_mode", Kind::Choice(&["in_place", "symlink"]), "`symlink` checks out every commit into a release directory of its own and points `current_link` at it once it came up healthy"),
+    ("releases_dir", Kind::Str, "Where `deploy_mode: symlink` checks out the releases, relative to the config file"),
+    ("current_link", Kind::Str, "The symlink `deploy_mode: symlink` points at the live release, relative to the config file"),
+    ("keep_releases", Kind::Int, "How many releases `deploy_mode: symlink` keeps, every one without it"),
+    ("default_repo_path", Kind::Str, "The repo path used when neither --local-repo nor $GDEP_REPO_PATH is given"),
+    ("script", Kind::Script, "The script to run in the checkout. A list runs each entry as a step before the last one, which is kept running"),
+    ("script_use_file", Kind::Bool, "Read the script from the file at `file_path`"),
+    ("file_path", Kind::Str, "The script file with `script_use_file`, relative to the config file"),
+    ("args", Kind::Scalars, "Arguments passed to the script"),
+    ("env", Kind::ScalarMap, "Environment variables of the script"),
+    ("shell", Kind::Choice(&crate::process::SHELLS), "The shell every script runs with instead of the platform's"),
+    ("runner", Kind::CommandLine, "An interpreter and its arguments every script runs with, instead of a shell"),
+    ("run_as", Kind::User, "The user every script runs as: `name`, `uid`, `name:group` or `uid:gid`"),
+    ("working_dir", Kind::Str, "Where scripts run, relative to the checkout or absolute"),
+    ("bootstrap", Kind::Str, "Runs once before the script when the repo was just cloned"),
+    ("cleanup", Kind::Str, "Runs after the script was stopped"),
+    ("cleanup_use_file", Kind::Bool, "Read the cleanup script from the file at `cleanup_file_path`"),
+    ("cleanup_file_path", Kind::Str, "The cleanup script file with `cleanup_use_file`, relative to the config file"),
+    ("final", Kind::Bool, "Don't start the script again once it exited"),
+    ("restart_update", Kind::Bool, "Restart the script when an update was applied"),
+    ("script_err_ignore", Kind::Bool, "Keep going when the script fails"),
+    ("gdep_err_ignore", Kind::Bool, "Keep going when gdep itself runs into an error"),
+    ("max_restarts", Kind::Int, "How often the script is started again before gdep gives up"),
+    ("restart_delay", Kind::Duration, "How long to wait before starting the script again"),
+    ("restart_jitter", Kind::Duration, "Up to this much is added to `restart_delay` at random"),
+    ("min_restart_interval", Kind::Duration, "The script is started at most once in this long, a restart asked for sooner waits"),
+    ("shutdown_grace", Kind::Int, "Seconds the script has to exit after it was asked to, before it is killed"),
+    ("script_timeout", Kind::Duration, "The script is stopped once it ran this long"),
+    ("max_output_rate", Kind::Int, "Lines per second of the script's output that are forwarded, the rest is dropped and counted. 0 drops every line"),
+    ("error_context_lines", Kind::Int, "How many of the script's last output lines are logged when it fails"),
+    ("stdout_file", Kind::Str, "Writes the script's stdout to this file"),
+    ("stderr_file", Kind::Str, "Writes the script's stderr to this file"),
+    ("prefix_output", Kind::Bool, "Log the script's output through gdep, each line tagged `[stdout]` or `[stderr]`"),
+    ("watch_paths", Kind::Strings, "Path globs, an update only restarts the script if it changes a file matching one"),
+    ("on_change", Kind::ScriptMap, "Path globs and the script to run when an update changes a file matching them"),
+    ("branch", Kind::Str, "The branch to follow instead of origin's default one"),
+    ("include_branches", Kind::Strings, "Globs of the branches inferring the branch picks from"),
+    ("exclude_branches", Kind::Strings, "Globs of the branches inferring the branch never picks"),
+    ("branch_selector", Kind::Choice(&["default", "newest"]), "`newest` follows whichever branch has the newest commit"),
+    ("expect_branch", Kind::Str, "Stop unless the branch followed is this one"),
+    ("commit", Kind::Str, "Pin the checkout to this commit"),
+    ("track", Kind::Choice(&["branch", "tag"]), "Follow the tip of the branch or the newest tag"),
+    ("tag_pattern", Kind::Str, "The tags `track: tag` considers"),
+    ("require_annotated_tags", Kind::Bool, "Ignore lightweight tags"),
+    ("verify_tag_signatures", Kind::Bool, "Only deploy tags with a valid signature"),
+    ("require_signed", Kind::Bool, "Only deploy commits with a valid signature"),
+    ("allowed_signers", Kind::Strings, "Fingerprints of the keys signatures are accepted from"),
+    ("allowed_authors", Kind::Strings, "Globs of the author emails whose commits may be deployed"),
+    ("max_commit_age", Kind::Duration, "Refuse to deploy commits older than this"),
+    ("max_behind", Kind::Int, "Refuse updates bringing in more commits than this unless `force` is set"),
+    ("deploy_window", Kind::Str, "Updates are only applied between these times, `HH:MM-HH:MM`"),
+    ("deploy_days", Kind::Weekdays, "Updates are only applied on these days"),
+    ("timezone", Kind::Str, "The timezone of `deploy_window` and `deploy_days`, the host's without it"),
+    ("pre_update", Kind::Str, "Runs before an update is applied, a failure cancels it"),
+    ("post_update", Kind::Str, "Runs after an update was applied"),
+    ("smoke_test", Kind::Str, "Runs after an update was applied, telling whether it works"),
+    ("smoke_timeout", Kind::Duration, "How long `smoke_test` may take"),
+    ("rollback_on_smoke_fail", Kind::Bool, "Go back to the previous commit when `smoke_test` fails"),
+    ("verify_script", Kind::Str, "Runs in a temporary worktree at a fetched commit, which is only applied if it passes"),
+    ("verify_timeout", Kind::Duration, "How long `verify_script` may take"),
+    ("ready_check", Kind::Str, "A command or an `http(s)://` URL that tells when the started script is ready"),
+    ("ready_timeout", Kind::Duration, "How long the script has to become ready"),
+    ("graceful_restart", Kind::Bool, "Start the updated script next to the old one, which only stops once `ready_check` passes"),
+    ("rollback_on_failure", Kind::Bool, "Go back to the previous commit when the updated script fails"),
+    ("restore_on_start", Kind::Bool, "On startup, go back to the last commit that came up healthy if HEAD is past it"),
+    ("force", Kind::Bool, "Apply updates the safety checks would refuse"),
+    ("checkout_mode", Kind::Choice(&["force", "safe", "merge"]), "What an update does with local changes to files it touches"),
+    ("on_ahead", Kind::Choice(&["error", "ignore", "push"]), "What to do when the local branch has commits origin doesn't"),
+    ("conflict_strategy", Kind::Choice(&["abort", "ours", "theirs"]), "Merge diverged branches, resolving conflicts like this"),
+    ("merge_message", Kind::Str, "The message of merge commits, `{branch}` and `{sha}` are replaced"),
+    ("committer_name", Kind::Str, "The committer of merge commits"),
+    ("committer_email", Kind::Str, "The committer email of merge commits"),
+    ("stash_local_changes", Kind::Bool, "Stash local changes before an update"),
+    ("restore_stash", Kind::Bool, "Apply the stash again after the update"),
+    ("fail_on_dirty", Kind::Bool, "Refuse updates while the checkout has local changes"),
+    ("clean_untracked", Kind::Bool, "Remove untracked files on an update"),
+    ("clean_ignored", Kind::Bool, "Remove ignored files on an update as well"),
+    ("refspec", Kind::Str, "What a fetch fetches, `{branch}` is replaced by the tracked branch"),
+    ("prune", Kind::Bool, "Remove remote-tracking refs origin doesn't have anymore"),
+    ("fetch_tags", Kind::Choice(&["all", "auto", "none"]), "Which tags a fetch brings along"),
+    ("depth", Kind::Int, "Clone and fetch only this many commits"),
+    ("submodules", Kind::Bool, "Update the submodules after every checkout"),
+    ("lfs", Kind::Bool, "Run `lfs_command` after every checkout"),
+    ("lfs_command", Kind::Str, "Fetches the LFS files with `lfs`"),
+    ("no_update", Kind::Bool, "Only supervise the script, the checkout is never fetched or updated"),
+    ("poll_interval", Kind::Duration, "How long to wait between looking for updates"),
+    ("debounce", Kind::Duration, "Newer updates wait this long after one was applied, to be applied together"),
+    ("max_fetch_retries", Kind::Int, "How often a failed fetch is tried again"),
+    ("update_retries", Kind::Int, "How often an update that failed on a locked index or ref is tried again"),
+    ("fetch_timeout", Kind::Duration, "How long a single fetch may take before it is aborted and retried"),
+    ("stall_timeout", Kind::Duration, "A transfer making no progress for this long is aborted"),
+    ("stall_retries", Kind::Int, "How often a stalled transfer is tried again"),
+    ("clone_retries", Kind::Int, "How often a failed clone is tried again"),
+    ("critical", Kind::Bool, "Stop every deployment once this one stops with an error"),
+    ("watch_config", Kind::Bool, "Reload the config when the file changes"),
+    ("webhook", Kind::Webhook, "Lets the forge announce a push, so the update thread fetches right away"),
+    ("health_addr", Kind::Str, "Serves GET /healthz on this address"),
+    ("health_auth", Kind::Auth, "Credentials /healthz asks for"),
+    ("metrics_addr", Kind::Str, "Serves GET /metrics in the Prometheus text format on this address"),
+    ("metrics_auth", Kind::Auth, "Credentials /metrics asks for"),
+    ("control_socket", Kind::Str, "Accepts commands like `reload` on this Unix socket"),
+    ("notifier", Kind::Notifier, "Where notifications go"),
+    ("notify_url", Kind::Str, "Posts notifications to this URL, a `generic` notifier"),
+    ("notify_events", Kind::Events, "The events posted to `notify_url`"),
+    ("ca_bundle", Kind::Str, "CA certificates to trust for HTTPS remotes"),
+    ("proxy", Kind::Str, "The proxy to reach remotes through"),
+    ("ssh_key", Kind::Str, "The private key to authenticate with over SSH"),
+    ("ssh_key_passphrase", Kind::Str, "The passphrase of `ssh_key`"),
+    ("ssh_command", Kind::Str, "Like $GIT_SSH_COMMAND, of which the port, key and user are applied"),
+    ("http_username", Kind::Str, "The username to authenticate with over HTTPS"),
+    ("http_token", Kind::Str, "The token to authenticate with over HTTPS"),
+    ("log_file", Kind::Str, "Also write gdep's own log output to this file, rotated by size"),
+    ("log_max_size", Kind::Size, "The size at which `log_file` is rotated"),
+    ("log_keep", Kind::Int, "How many rotated log files are kept")
+];
+
+fn key(k: &str) -> Yaml {
+    Yaml::String(k.to_string())
+}
+
+fn object(entries: Vec<(&str, Yaml)>) -> Yaml {
+    Yaml::Hash(entries.into_iter().map(|(k, v)| (key(k), v)).collect())
+}
+
+fn strings(values: &[&str]) -> Yaml {
+    Yaml::Array(values.iter().map(|v| key(v)).collect())
+}
+
+fn typed(name: &str) -> Yaml {
+    object(vec![("type", key(name))])
+}
+
+fn list_of(items: Yaml) -> Yaml {
+    object(vec![("type", key("array")), ("items", items)])
+}
+
+fn scalar() -> Yaml {
+    object(vec![("type", strings(&["string", "integer", "number", "boolean"]))])
+}
+
+fn auth() -> Yaml {
+    let credentials = |names: &[&str]| object(vec![("required", strings(names))]);
+    object(vec![
+        ("type", key("object")),
+        ("properties", object(vec![("username", typed("string")), ("password", typed("string")), ("token", typed("string"))])),
+        ("additionalProperties", Yaml::Boolean(false)),
+        ("oneOf", Yaml::Array(vec![credentials(&["username", "password"]), credentials(&["token"])]))
+    ])
+}
+
+fn events() -> Yaml {
+    let names: Vec<_> = NotifyEvent::ALL.iter().map(|e| key(&e.to_string())).collect();
+    list_of(object(vec![("enum", Yaml::Array(names))]))
+}
+
+fn type_of(kind: &Kind) -> Yaml {
+    match kind {
+        Kind::Bool => typed("boolean"),
+        Kind::Str => typed("string"),
+        Kind::Int => object(vec![("type", key("integer")), ("minimum", Yaml::Integer(0))]),
+        Kind::Duration => object(vec![("type", strings(&["integer", "string"])), ("minimum", Yaml::Integer(0)), ("pattern", key(DURATION_PATTERN))]),
+        Kind::Size => object(vec![("type", strings(&["integer", "string"])), ("minimum", Yaml::Integer(0)), ("pattern", key(SIZE_PATTERN))]),
+        Kind::Choice(choices) => object(vec![("enum", strings(choices))]),
+        Kind::Strings => list_of(typed("string")),
+        Kind::Scalars => list_of(scalar()),
+        Kind::ScalarMap => object(vec![("type", key("object")), ("additionalProperties", scalar())]),
+        Kind::ScriptMap => object(vec![("type", key("object")), ("additionalProperties", typed("string"))]),
+        Kind::Script => object(vec![("oneOf", Yaml::Array(vec![typed("string"),
+            object(vec![("type", key("array")), ("items", typed("string")), ("minItems", Yaml::Integer(1))])]))]),
+        Kind::CommandLine => object(vec![("oneOf", Yaml::Array(vec![typed("string"), list_of(scalar())]))]),
+        Kind::User => object(vec![("type", strings(&["string", "integer"]))]),
+        Kind::Auth => auth(),
+        Kind::Webhook => object(vec![
+            ("type", key("object")),
+            ("properties", object(vec![("bind", typed("string")), ("path", typed("string")), ("secret", typed("string")), ("auth", auth())])),
+            ("required", strings(&["bind", "secret"])),
+            ("additionalProperties", Yaml::Boolean(false))
+        ]),
+        Kind::Notifier => {
+            let backends: Vec<_> = Backend::ALL.iter().map(|b| key(&b.to_string())).collect();
+            object(vec![
+                ("type", key("object")),
+                ("properties", object(vec![("backend", object(vec![("enum", Yaml::Array(backends))])), ("url", typed("string")), ("events", events())])),
+                ("required", strings(&["url"])),
+                ("additionalProperties", Yaml::Boolean(false))
+            ])
+        }
+        Kind::Events => events(),
+        Kind::Weekdays => list_of(typed("string"))
+    }
+}
+
+/// `if` the boolean `flag` is set
+fn flag_set(flag: &str, value: Yaml) -> Yaml {
+    object(vec![("properties", object(vec![(flag, object(vec![("const", value)]))])), ("required", strings(&[flag]))])
+}
+
+/// The mandatory keys of a deployment as `check_doc` sees them: `name`, `repo` unless `no_update` is set,
+/// and the script unless it is a mirror, which is `file_path` with `script_use_file`
+fn mandatory() -> Yaml {
+    let script = object(vec![
+        ("if", flag_set("script_use_file", Yaml::Boolean(true))),
+        ("then", object(vec![("required", strings(&["file_path"]))])),
+        ("else", object(vec![("required", strings(&["script"]))]))
+    ]);
+    Yaml::Array(vec![
+        object(vec![("required", strings(&["name"]))]),
+        object(vec![("if", flag_set("no_update", Yaml::Boolean(true))), ("else", object(vec![("required", strings(&["repo"]))]))]),
+        object(vec![("if", flag_set("mode", key("mirror"))), ("else", script)])
+    ])
+}
+
+/// Whether `name` is one of `KEYS`
+pub(crate) fn is_key(name: &str) -> bool {
+    KEYS.iter().any(|(key, _, _)| *key == name)
+}
+
+/// The properties of every key in `keys`, with the default `to_effective_yaml` gives it
+fn properties(defaults: &Hash, keys: impl Fn(&str) -> bool) -> Yaml {
+    let mut properties = Hash::new();
+    for (name, kind, description) in KEYS.iter().filter(|(name, _, _)| keys(name)) {
+        let Yaml::Hash(mut property) = type_of(kind) else { unreachable!() };
+        property.insert(key("description"), key(description));
+        if let Some(default) = defaults.get(&key(name)).filter(|_| !["name", "repo", "script"].contains(name)) {
+            property.insert(key("default"), default.clone());
+        }
+        properties.insert(key(name), Yaml::Hash(property));
+    }
+    Yaml::Hash(properties)
+}
+
+/// `gdep schema`: a JSON Schema (draft 7) of the config file, for editors to complete and check `gdep.yaml` with.
+/// It describes either a single deployment or a `deployments` list, whose entries may leave the mandatory keys
+/// to the shared ones next to it
+pub fn schema() -> String {
+    let minimal = object(vec![("name", key("-")), ("repo", key("-")), ("script", key("-"))]);
+    let config = Config::from_yaml(&minimal, "").expect("The minimal config is valid");
+    let Yaml::Hash(defaults) = config.to_effective_yaml() else { unreachable!() };
+
+    let entry = object(vec![
+        ("type", key("object")),
+        ("properties", properties(&defaults, |k| !config::PROCESS_WIDE_KEYS.contains(&k))),
+        ("additionalProperties", Yaml::Boolean(false))
+    ]);
+    let Yaml::Hash(mut top) = properties(&defaults, |_| true) else { unreachable!() };
+    top.insert(key("deployments"), object(vec![
+        ("type", key("array")),
+        ("description", key("Several deployments, each inheriting the keys next to the list it doesn't set itself")),
+        ("items", entry),
+        ("minItems", Yaml::Integer(1))
+    ]));
+    let doc = object(vec![
+        ("$schema", key("http://json-schema.org/draft-07/schema#")),
+        ("title", key("gdep config")),
+        ("type", key("object")),
+        ("properties", Yaml::Hash(top)),
+        ("additionalProperties", Yaml::Boolean(false)),
+        ("if", object(vec![("required", strings(&["deployments"]))])),
+        ("else", object(vec![("allOf", mandatory())]))
+    ]);
+    format!("{}\n", config::yaml_to_json(&doc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed() -> serde_json::Value {
+        serde_json::from_str(&schema()).unwrap()
+    }
+
+    #[test]
+    fn name_repo_and_script_are_required() {
+        let schema = parsed();
+        assert_eq!(schema["if"]["required"], serde_json::json!(["deployments"]));
+        let mandatory = &schema["else"]["allOf"];
+        assert_eq!(mandatory[0]["required"], serde_json::json!(["name"]));
+        assert_eq!(mandatory[1]["if"]["properties"]["no_update"]["const"], true);
+        assert_eq!(mandatory[1]["else"]["required"], serde_json::json!(["repo"]));
+        assert_eq!(mandatory[2]["if"]["properties"]["mode"]["const"], "mirror");
+        assert_eq!(mandatory[2]["else"]["then"]["required"], serde_json::json!(["file_path"]));
+        assert_eq!(mandatory[2]["else"]["else"]["required"], serde_json::json!(["script"]));
+
+        // Neither mandatory key has a default to offer
+        for name in ["name", "repo", "script"] {
+            assert!(schema["properties"][name]["default"].is_null(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn optional_keys_get_their_type_and_default() {
+        let properties = &parsed()["properties"];
+        assert_eq!(properties["restore_on_start"]["type"], "boolean");
+        assert_eq!(properties["restore_on_start"]["default"], false);
+        assert_eq!(properties["max_behind"]["type"], "integer");
+        assert!(properties["max_behind"]["default"].is_null());
+        assert_eq!(properties["poll_interval"]["pattern"], DURATION_PATTERN);
+        assert_eq!(properties["deploy_mode"]["enum"], serde_json::json!(["in_place", "symlink"]));
+        assert_eq!(properties["metrics_auth"]["oneOf"][1]["required"], serde_json::json!(["token"]));
+
+        // Process wide keys can only be set next to `deployments`, not in one of them
+        let entry = &properties["deployments"]["items"]["properties"];
+        assert!(entry["proxy"].is_null() && !properties["proxy"].is_null());
+        assert!(!entry["script"].is_null());
+    }
+}
@@ -0,0 +1,37 @@
+use std::io;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+static RECEIVED: OnceLock<Arc<AtomicUsize>> = OnceLock::new();
+/// How many times SIGHUP was received
+static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Records SIGINT / SIGTERM instead of letting them kill gdep, so `execute` can shut down cleanly.
+/// SIGHUP asks every deployment to reload its config, see `reloads`
+pub fn install() -> io::Result<()> {
+    let received = RECEIVED.get_or_init(|| Arc::new(AtomicUsize::new(0)));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_usize(signal, Arc::clone(received), signal as usize)?;
+    }
+    #[cfg(unix)]
+    // Only an atomic increment happens in the handler, which is async-signal-safe
+    unsafe {
+        signal_hook::low_level::register(signal_hook::consts::SIGHUP, || { RELOADS.fetch_add(1, Ordering::SeqCst); })?;
+    }
+    Ok(())
+}
+
+/// How many reloads SIGHUP asked for so far. Each deployment compares it with the count it last acted on,
+/// so a single SIGHUP reloads all of them
+pub fn reloads() -> usize {
+    RELOADS.load(Ordering::SeqCst)
+}
+
+/// The shutdown signal received so far, if any
+pub fn received() -> Option<i32> {
+    RECEIVED.get()
+        .map(|r| r.load(Ordering::SeqCst))
+        .filter(|signal| *signal != 0)
+        .map(|signal| signal as i32)
+}
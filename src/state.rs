@@ -0,0 +1,181 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use git2::Repository;
+
+/// Root of gdep's bookkeeping inside the repo working tree
+pub const STATE_DIR: &str = ".gdep";
+
+/// Commits that failed their smoke test or verification, one SHA per line
+const QUARANTINE_FILE: &str = "quarantine";
+
+/// The SHA of the commit that last came up healthy
+const KNOWN_GOOD_FILE: &str = "known_good";
+
+/// Held by the gdep instance working on the repo, contains its PID
+const LOCK_FILE: &str = "gdep.lock";
+
+/// Branch names may contain `/`, which would make e.g. `a` and `a/b` share a directory
+fn encode_branch(branch: &str) -> String {
+    branch.replace('%', "%25").replace('/', "%2F")
+}
+
+/// Only one process works on a repo (see `lock`), but its branches may add the entry at the same time
+static EXCLUDE: Mutex<()> = Mutex::new(());
+
+/// Makes sure the state dir never shows up as untracked in the repo it lives in
+fn exclude_state_dir(repo: &Repository) -> io::Result<()> {
+    let _guard = EXCLUDE.lock().unwrap_or_else(|e| e.into_inner());
+    let exclude = repo.path().join("info").join("exclude");
+    let entry = format!("/{}/", STATE_DIR);
+    let current = fs::read_to_string(&exclude).unwrap_or_default();
+    if current.lines().any(|l| l.trim() == entry) {
+        return Ok(())
+    }
+
+    fs::create_dir_all(exclude.parent().unwrap())?;
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(&exclude)?;
+    if !current.is_empty() && !current.ends_with('\n') {
+        writeln!(file)?;
+    }
+    writeln!(file, "{}", entry)
+}
+
+/// Returns (and creates) the state directory of `branch`: `<repo>/.gdep/<branch>/`.
+/// Every tracked branch gets its own directory so their state files never collide.
+pub fn branch_dir(repo: &Repository, branch: &str) -> io::Result<PathBuf> {
+    let root = repo.workdir().unwrap_or(repo.path()).join(STATE_DIR);
+    let dir = root.join(encode_branch(branch));
+    fs::create_dir_all(&dir)?;
+    exclude_state_dir(repo)?;
+    Ok(dir)
+}
+
+/// Records `sha` as bad, so it is never deployed automatically again
+pub fn quarantine(dir: &Path, sha: &str) -> io::Result<()> {
+    if is_quarantined(dir, sha) {
+        return Ok(())
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(dir.join(QUARANTINE_FILE))?;
+    writeln!(file, "{}", sha)
+}
+
+pub fn is_quarantined(dir: &Path, sha: &str) -> bool {
+    fs::read_to_string(dir.join(QUARANTINE_FILE)).is_ok_and(|list| list.lines().any(|l| l.trim() == sha))
+}
+
+/// Replaces the last known good commit with `sha`. Written to a temporary file first, so a crash never leaves half a SHA behind
+pub fn record_known_good(dir: &Path, sha: &str) -> io::Result<()> {
+    if known_good(dir).as_deref() == Some(sha) {
+        return Ok(())
+    }
+    let temporary = dir.join(format!("{}.tmp", KNOWN_GOOD_FILE));
+    fs::write(&temporary, format!("{}\n", sha))?;
+    fs::rename(temporary, dir.join(KNOWN_GOOD_FILE))
+}
+
+pub fn known_good(dir: &Path) -> Option<String> {
+    fs::read_to_string(dir.join(KNOWN_GOOD_FILE)).ok().map(|sha| sha.trim().to_string()).filter(|sha| !sha.is_empty())
+}
+
+/// Exclusive claim on a repo, released on drop. The lock file stays: removing it would let a process that still has
+/// the old file open lock it while another one locks a new file at the same path
+pub struct Lock {
+    file: File
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Only the PID goes, while the lock is still held
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+fn lock_path(repo: &Repository) -> PathBuf {
+    repo.workdir().unwrap_or(repo.path()).join(STATE_DIR).join(LOCK_FILE)
+}
+
+/// Takes the advisory lock on `<repo>/.gdep/gdep.lock` and writes our PID into it.
+/// Fails with `WouldBlock` if another process holds it
+pub fn lock(repo: &Repository) -> io::Result<Lock> {
+    let path = lock_path(repo);
+    fs::create_dir_all(path.parent().unwrap())?;
+    exclude_state_dir(repo)?;
+    let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => return Err(io::ErrorKind::WouldBlock.into()),
+        Err(fs::TryLockError::Error(e)) => return Err(e)
+    }
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(Lock { file })
+}
+
+/// The PID written by the process holding the lock, None once it was released
+pub fn lock_holder(repo: &Repository) -> Option<u32> {
+    fs::read_to_string(lock_path(repo)).ok()?.trim().parse().ok()
+}
+
+/// Whether a running gdep holds the lock on `repo`. Never creates the lock file
+pub fn is_locked(repo: &Repository) -> bool {
+    let Ok(file) = File::open(lock_path(repo)) else {
+        return false
+    };
+    match file.try_lock() {
+        Ok(()) => {
+            let _ = file.unlock();
+            false
+        }
+        Err(fs::TryLockError::WouldBlock) => true,
+        Err(fs::TryLockError::Error(_)) => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn branches_get_directories_of_their_own() {
+        let temp = tempfile::tempdir().unwrap();
+        let repo = Repository::init(temp.path()).unwrap();
+        let a = branch_dir(&repo, "a").unwrap();
+        let nested = branch_dir(&repo, "a/b").unwrap();
+        assert_ne!(a, nested);
+        assert_eq!(nested.parent(), a.parent());
+    }
+
+    #[test]
+    fn two_branches_write_state_concurrently() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().to_path_buf();
+        Repository::init(&path).unwrap();
+
+        let writers: Vec<_> = ["main", "feature/x"].into_iter().map(|branch| {
+            let path = path.clone();
+            thread::spawn(move || {
+                let repo = Repository::open(&path).unwrap();
+                let dir = branch_dir(&repo, branch).unwrap();
+                for i in 0..50 {
+                    record_known_good(&dir, &format!("{}-{}", branch, i)).unwrap();
+                    quarantine(&dir, &format!("{}-bad-{}", branch, i % 5)).unwrap();
+                }
+                dir
+            })
+        }).collect();
+        let dirs: Vec<_> = writers.into_iter().map(|w| w.join().unwrap()).collect();
+
+        for (dir, branch) in dirs.iter().zip(["main", "feature/x"]) {
+            assert_eq!(known_good(dir), Some(format!("{}-49", branch)));
+            let quarantined = fs::read_to_string(dir.join(QUARANTINE_FILE)).unwrap();
+            assert_eq!(quarantined.lines().count(), 5);
+            assert!(quarantined.lines().all(|l| l.starts_with(&format!("{}-bad-", branch))));
+        }
+        let exclude = fs::read_to_string(path.join(".git").join("info").join("exclude")).unwrap();
+        assert_eq!(exclude.lines().filter(|l| *l == "/.gdep/").count(), 1);
+    }
+}
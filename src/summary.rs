@@ -0,0 +1,130 @@
+use std::fmt;
+use std::time::Duration;
+use crate::config::format_duration;
+use crate::errors::GdepError;
+use serde_json::{json, Map, Value};
+
+/// Why a deployment stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    /// The script ended and was not started again, successfully or with `ScriptFailed`
+    ScriptExited,
+    /// gdep itself failed, the error says how
+    GdepError,
+    /// gdep received this signal
+    Signal(i32),
+    /// The script failed more than `max_restarts` times in a row
+    MaxRestarts,
+    /// Somebody asked gdep to stop, on the control socket or because a `critical` deployment failed
+    Shutdown
+}
+
+impl Reason {
+    /// Why a deployment stopped with `error`, see `Summary::new`
+    pub fn of(error: Option<&GdepError>, gave_up: bool, shutdown: bool) -> Self {
+        match error {
+            _ if gave_up => Reason::MaxRestarts,
+            Some(GdepError::Interrupted(signal)) => Reason::Signal(*signal),
+            Some(GdepError::ScriptFailed(..)) => Reason::ScriptExited,
+            Some(_) => Reason::GdepError,
+            None if shutdown => Reason::Shutdown,
+            None => Reason::ScriptExited
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reason::ScriptExited => "script_exited",
+            Reason::GdepError => "gdep_error",
+            Reason::Signal(_) => "signal",
+            Reason::MaxRestarts => "max_restarts",
+            Reason::Shutdown => "shutdown"
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::ScriptExited => write!(f, "the script exited"),
+            Reason::GdepError => write!(f, "gdep failed"),
+            Reason::Signal(signal) => write!(f, "received signal {}", signal),
+            Reason::MaxRestarts => write!(f, "the script failed too often"),
+            Reason::Shutdown => write!(f, "stop requested")
+        }
+    }
+}
+
+/// What a deployment ended with, logged when it stops and reported as the `stopped` event with `--output json`
+#[derive(Debug)]
+pub struct Summary {
+    pub reason: Reason,
+    /// The error the deployment stopped with, None if it stopped cleanly
+    pub error: Option<GdepError>,
+    /// The commit checked out in the end
+    pub head: Option<String>,
+    /// How many updates were applied while it ran
+    pub updates: u64,
+    pub uptime: Duration
+}
+
+impl Summary {
+    /// `gave_up` is set when `max_restarts` was reached, `shutdown` when stopping was asked for
+    pub fn new(error: Option<GdepError>, gave_up: bool, shutdown: bool, head: Option<String>, updates: u64, uptime: Duration) -> Self {
+        Self { reason: Reason::of(error.as_ref(), gave_up, shutdown), error, head, updates, uptime }
+    }
+
+    /// What gdep exits with for this deployment
+    pub fn exit_code(&self) -> i32 {
+        self.error.as_ref().map_or(0, GdepError::exit_code)
+    }
+
+    /// The fields of the `stopped` event
+    pub fn to_json(&self) -> Map<String, Value> {
+        let json = json!({
+            "reason": self.reason.as_str(),
+            "error": self.error.as_ref().map(GdepError::variant),
+            "message": self.error.as_ref().map(ToString::to_string),
+            "exit_code": self.exit_code(),
+            "head": self.head,
+            "updates": self.updates,
+            "uptime_secs": self.uptime.as_secs()
+        });
+        let Value::Object(fields) = json else { unreachable!() };
+        fields
+    }
+}
+
+/// `Stopped, the script exited: exit code 0, HEAD 1a2b3c4, 2 update(s) applied, up for 1h`
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let head = self.head.as_deref().map(|h| &h[..h.len().min(7)]).unwrap_or("none");
+        write!(f, "Stopped, {}: exit code {}, HEAD {}, {} update(s) applied, up for {}",
+            self.reason, self.exit_code(), head, self.updates, format_duration(self.uptime.as_secs()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_reason_follows_from_how_the_loop_ended() {
+        assert_eq!(Reason::of(None, false, false), Reason::ScriptExited);
+        assert_eq!(Reason::of(None, false, true), Reason::Shutdown);
+        assert_eq!(Reason::of(Some(&GdepError::Interrupted(15)), false, false), Reason::Signal(15));
+        assert_eq!(Reason::of(Some(&GdepError::BranchNotFound("main".into(), vec![])), false, false), Reason::GdepError);
+        assert_eq!(Reason::of(Some(&GdepError::Interrupted(15)), true, false), Reason::MaxRestarts);
+    }
+
+    #[test]
+    fn the_summary_line_has_every_field() {
+        let summary = Summary::new(None, false, false, Some("1a2b3c4d5e6f".into()), 2, Duration::from_secs(3900));
+        assert_eq!(summary.to_string(), "Stopped, the script exited: exit code 0, HEAD 1a2b3c4, 2 update(s) applied, up for 1h");
+        let stopped = Summary::new(Some(GdepError::Interrupted(15)), false, false, None, 0, Duration::ZERO);
+        assert_eq!(stopped.to_json()["reason"], "signal");
+        assert_eq!(stopped.to_json()["error"], "Interrupted");
+        assert_eq!(stopped.to_json()["exit_code"], 143);
+        assert!(stopped.to_string().contains("HEAD none"));
+    }
+}
@@ -0,0 +1,82 @@
+use std::env;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use log::debug;
+
+/// Set once `READY=1` was sent. Restarts of the script later on don't concern systemd
+static READY: AtomicBool = AtomicBool::new(false);
+static STOPPING: AtomicBool = AtomicBool::new(false);
+/// When `WATCHDOG=1` was sent last
+static LAST_KEEPALIVE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Half of `$WATCHDOG_USEC`, so a late keepalive still arrives in time. None if the watchdog is off or meant for another process
+fn keepalive_interval() -> Option<Duration> {
+    static INTERVAL: OnceLock<Option<Duration>> = OnceLock::new();
+    *INTERVAL.get_or_init(|| {
+        if env::var("WATCHDOG_PID").ok().is_some_and(|pid| pid.parse() != Ok(std::process::id())) {
+            return None
+        }
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok().filter(|u| *u > 0)?;
+        Some(Duration::from_micros(usec / 2))
+    })
+}
+
+/// Sends `state` to `$NOTIFY_SOCKET`. Does nothing when not run by systemd
+#[cfg(unix)]
+fn send(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+    let Some(path) = env::var_os("NOTIFY_SOCKET").filter(|p| !p.is_empty()) else {
+        return
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| match path.to_str().and_then(|p| p.strip_prefix('@')) {
+        Some(name) => send_abstract(&socket, name, state),
+        None => socket.send_to(state.as_bytes(), &path)
+    });
+    match result {
+        Ok(_) => debug!("Notified systemd: {}", state),
+        Err(e) => debug!("Could not notify systemd ({}): {}", state, e)
+    }
+}
+
+#[cfg(not(unix))]
+fn send(_state: &str) {}
+
+/// A socket path starting with `@` lives in the abstract namespace, which only Linux has
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &std::os::unix::net::UnixDatagram, name: &str, state: &str) -> std::io::Result<usize> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &addr)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn send_abstract(_socket: &std::os::unix::net::UnixDatagram, name: &str, _state: &str) -> std::io::Result<usize> {
+    Err(std::io::Error::other(format!("abstract socket `@{}` is only supported on Linux", name)))
+}
+
+/// `READY=1`, once the script was started the first time
+pub fn ready() {
+    if !READY.swap(true, Ordering::Relaxed) {
+        send("READY=1");
+    }
+}
+
+/// `STOPPING=1`, once gdep starts shutting down
+pub fn stopping() {
+    if !STOPPING.swap(true, Ordering::Relaxed) {
+        send("STOPPING=1");
+    }
+}
+
+/// `WATCHDOG=1` if `$WATCHDOG_USEC` is set and the last keepalive is due for renewal. Cheap enough for every supervision tick
+pub fn keepalive() {
+    let Some(interval) = keepalive_interval() else {
+        return
+    };
+    let mut last = LAST_KEEPALIVE.lock().unwrap();
+    if !last.is_some_and(|t| t.elapsed() < interval) {
+        send("WATCHDOG=1");
+        *last = Some(Instant::now());
+    }
+}
@@ -0,0 +1,165 @@
+use std::cmp::Ordering;
+use std::process::Command;
+use std::{env, fs, process};
+use git2::{Error, ErrorCode, Oid, Repository, Tag};
+use crate::clock::Clock;
+use crate::config::Config;
+use semver::{Version, VersionReq};
+
+/// How `tag_pattern` selects tags: a semver range (`^1.2`, `>=1.0, <2.0`) if it parses as one, else a glob (`release-*`)
+pub enum TagFilter {
+    Any,
+    Range(VersionReq),
+    Glob(glob::Pattern)
+}
+
+impl TagFilter {
+    pub fn parse(pattern: Option<&str>) -> Result<Self, String> {
+        match pattern {
+            None => Ok(TagFilter::Any),
+            Some(p) => match VersionReq::parse(p) {
+                Ok(req) => Ok(TagFilter::Range(req)),
+                Err(_) => glob::Pattern::new(p).map(TagFilter::Glob).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    fn matches(&self, tag: &str) -> bool {
+        match self {
+            TagFilter::Any => true,
+            TagFilter::Range(req) => parse_version(tag).is_some_and(|v| req.matches(&v)),
+            TagFilter::Glob(pattern) => pattern.matches(tag)
+        }
+    }
+}
+
+fn parse_version(tag: &str) -> Option<Version> {
+    Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()
+}
+
+/// Semver tags by precedence (`v1.2.10` above `v1.2.9`), above all tags that aren't versions, which go by name
+fn compare_tags(a: &str, b: &str) -> Ordering {
+    match (parse_version(a), parse_version(b)) {
+        (Some(va), Some(vb)) => va.cmp(&vb),
+        (Some(_), None) => Ordering::Greater,
+        (None, Some(_)) => Ordering::Less,
+        (None, None) => a.cmp(b)
+    }
+}
+
+/// Fetches every tag of origin, `track: tag` is only accepted with `fetch_tags: all`
+pub fn fetch_tags(repo: &Repository, config: &Config, clock: &dyn Clock) -> Result<(), Error> {
+    let mut remote = repo.find_remote("origin")?;
+    crate::fetch_refspec(&mut remote, "+refs/tags/*:refs/tags/*", config, false, clock)
+}
+
+/// The tag object behind `name`, None if it is a lightweight tag (a plain ref to a commit)
+pub fn annotated<'r>(repo: &'r Repository, name: &str) -> Option<Tag<'r>> {
+    repo.refname_to_id(&format!("refs/tags/{}", name)).ok()
+        .and_then(|id| repo.find_tag(id).ok())
+}
+
+/// The highest tag accepted by `filter` and the commit it points to.
+/// Lightweight tags are skipped if `annotated_only` is set
+pub fn latest_tag(repo: &Repository, filter: &TagFilter, annotated_only: bool) -> Result<Option<(String, Oid)>, Error> {
+    let names = repo.tag_names(None)?;
+    let best = names.iter().flatten()
+        .filter(|name| filter.matches(name))
+        .filter(|name| !annotated_only || annotated(repo, name).is_some())
+        .max_by(|a, b| compare_tags(a, b));
+
+    match best {
+        None => Ok(None),
+        Some(name) => {
+            let commit = repo.revparse_single(&format!("refs/tags/{}", name))?.peel_to_commit()?;
+            Ok(Some((name.to_string(), commit.id())))
+        }
+    }
+}
+
+pub const SIGNATURE_START: &str = "-----BEGIN PGP SIGNATURE-----";
+
+/// Checks the PGP signature embedded in the annotated tag `name` with `gpg --verify` against the local keyring
+pub fn verify_signature(repo: &Repository, name: &str) -> Result<(), String> {
+    let tag = annotated(repo, name).ok_or("lightweight tags cannot be signed")?;
+    let odb = repo.odb().map_err(|e| e.to_string())?;
+    let object = odb.read(tag.id()).map_err(|e| e.to_string())?;
+    let raw = object.data();
+    let start = raw.windows(SIGNATURE_START.len()).position(|w| w == SIGNATURE_START.as_bytes())
+        .ok_or("the tag is not signed")?;
+    gpg_verify(&format!("tag_{}", tag.id()), &raw[..start], &raw[start..], &[])
+}
+
+/// Checks the signature of commit `id` with `gpg --verify`. If `allowed` isn't empty,
+/// the signing key (or its primary key) must also have one of these fingerprints
+pub fn verify_commit(repo: &Repository, id: Oid, allowed: &[String]) -> Result<(), String> {
+    let (signature, payload) = match repo.extract_signature(&id, None) {
+        Ok(extracted) => extracted,
+        Err(e) if e.code() == ErrorCode::NotFound => return Err("the commit is not signed".to_string()),
+        Err(e) => return Err(e.message().to_string())
+    };
+    gpg_verify(&format!("commit_{}", id), &payload, &signature, allowed)
+}
+
+fn gpg_verify(label: &str, payload: &[u8], signature: &[u8], allowed: &[String]) -> Result<(), String> {
+    let base = env::temp_dir().join(format!("gdep_{}_{}", process::id(), label));
+    let (payload_file, signature_file) = (base.with_extension("payload"), base.with_extension("sig"));
+    let result = fs::write(&payload_file, payload)
+        .and_then(|_| fs::write(&signature_file, signature))
+        .and_then(|_| Command::new("gpg").args(["--status-fd", "1", "--verify"]).arg(&signature_file).arg(&payload_file).output());
+    let _ = fs::remove_file(&payload_file);
+    let _ = fs::remove_file(&signature_file);
+
+    let output = result.map_err(|e| format!("could not run gpg: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr.lines().last().unwrap_or("gpg rejected the signature").to_string())
+    }
+    if allowed.is_empty() {
+        return Ok(())
+    }
+
+    // `[GNUPG:] VALIDSIG <fingerprint> ... <primary key fingerprint>`
+    let status = String::from_utf8_lossy(&output.stdout);
+    let fingerprints: Vec<&str> = status.lines()
+        .filter_map(|l| l.strip_prefix("[GNUPG:] VALIDSIG "))
+        .flat_map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            [fields.first().copied(), fields.last().copied()]
+        })
+        .flatten()
+        .collect();
+    if fingerprints.iter().any(|f| allowed.iter().any(|a| a.eq_ignore_ascii_case(f))) {
+        Ok(())
+    } else {
+        Err(format!("signed by {}, which is not in `allowed_signers`", fingerprints.first().unwrap_or(&"an unknown key")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn highest<'a>(filter: &TagFilter, tags: &[&'a str]) -> Option<&'a str> {
+        tags.iter().copied().filter(|t| filter.matches(t)).max_by(|a, b| compare_tags(a, b))
+    }
+
+    #[test]
+    fn versions_go_by_precedence_above_other_tags() {
+        let tags = ["v1.2.9", "v1.2.10", "v1.10.0-rc.1", "zzz", "1.3.0"];
+        assert_eq!(highest(&TagFilter::Any, &tags), Some("v1.10.0-rc.1"));
+        assert_eq!(compare_tags("v1.2.10", "v1.2.9"), Ordering::Greater);
+        assert_eq!(compare_tags("v0.0.1", "zzz"), Ordering::Greater);
+        assert_eq!(compare_tags("alpha", "beta"), Ordering::Less);
+    }
+
+    #[test]
+    fn patterns_are_ranges_or_globs() {
+        let tags = ["v1.2.9", "v1.2.10", "v2.0.0", "release-2026-01", "release-2026-02"];
+        assert_eq!(highest(&TagFilter::parse(Some("^1.2")).unwrap(), &tags), Some("v1.2.10"));
+        assert_eq!(highest(&TagFilter::parse(Some(">=1.0, <3.0")).unwrap(), &tags), Some("v2.0.0"));
+        assert_eq!(highest(&TagFilter::parse(Some("release-*")).unwrap(), &tags), Some("release-2026-02"));
+        assert_eq!(highest(&TagFilter::parse(Some("^3")).unwrap(), &tags), None);
+        assert!(TagFilter::parse(Some("release-[")).is_err());
+    }
+}
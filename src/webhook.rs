@@ -0,0 +1,146 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use sha2::Sha256;
+use tiny_http::{Method, Request, Response, Server};
+use yaml_rust2::Yaml;
+use yaml_rust2::yaml::Hash;
+use crate::auth::{self, header, HttpAuth};
+use crate::config::ConfigError;
+use crate::signals;
+
+/// Pushes above this size are rejected, a push event is a few KiB at most
+const MAX_BODY: usize = 1 << 20;
+
+/// How often the listener looks at the stop flag while no request comes in
+const POLL_STOP: Duration = Duration::from_millis(100);
+
+/// Lets the forge (GitHub, GitLab, ...) announce a push, so the update thread fetches right away
+/// instead of at the next `poll_interval`
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// Address the listener binds to, e.g. `0.0.0.0:9000`
+    pub bind: String,
+    /// Only POSTs to this path are accepted
+    pub path: String,
+    /// GitHub signs the body with it (`X-Hub-Signature-256`), GitLab sends it as is (`X-Gitlab-Token`)
+    pub secret: String,
+    /// Credentials asked for before the signature is even looked at, for a listener reachable by more than the forge
+    pub auth: Option<HttpAuth>
+}
+
+fn invalid(msg: String) -> ConfigError {
+    ConfigError::InvalidValue("webhook".to_string(), msg)
+}
+
+impl WebhookConfig {
+    /// Builds it from the `webhook` mapping (`bind`, `path`, `secret`, `auth`). None if there is none
+    pub fn from_yaml(doc: &Yaml) -> Result<Option<Self>, ConfigError> {
+        let webhook = &doc["webhook"];
+        if webhook.is_badvalue() || webhook.is_null() {
+            return Ok(None)
+        }
+        if webhook.as_hash().is_none() {
+            return Err(invalid(format!("expected a mapping, got `{:?}`", webhook)))
+        }
+        let bind = webhook["bind"].as_str().ok_or_else(|| invalid("`bind` is missing".to_string()))?;
+        let secret = webhook["secret"].as_str().filter(|s| !s.is_empty())
+            .ok_or_else(|| invalid("`secret` is missing, unauthenticated webhooks are not supported".to_string()))?;
+        let path = webhook["path"].as_str().unwrap_or("/");
+        if !path.starts_with('/') {
+            return Err(invalid(format!("path `{}` does not start with `/`", path)))
+        }
+        let auth = HttpAuth::from_yaml(webhook, "auth").map_err(|e| match e {
+            ConfigError::InvalidValue(_, msg) => invalid(format!("`auth`: {}", msg)),
+            e => e
+        })?;
+        Ok(Some(Self { bind: bind.to_string(), path: path.to_string(), secret: secret.to_string(), auth }))
+    }
+
+    pub fn to_yaml(&self) -> Yaml {
+        let mut webhook = Hash::new();
+        webhook.insert(Yaml::String("bind".to_string()), Yaml::String(self.bind.clone()));
+        webhook.insert(Yaml::String("path".to_string()), Yaml::String(self.path.clone()));
+        webhook.insert(Yaml::String("secret".to_string()), Yaml::String(self.secret.clone()));
+        if let Some(auth) = &self.auth {
+            webhook.insert(Yaml::String("auth".to_string()), auth.to_yaml());
+        }
+        Yaml::Hash(webhook)
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Checks the request against `secret`, either by the HMAC-SHA256 signature of the body or by the plain token
+fn authorized(request: &Request, body: &[u8], secret: &str) -> bool {
+    if let Some(signature) = header(request, "X-Hub-Signature-256") {
+        let Some(signature) = signature.strip_prefix("sha256=").and_then(decode_hex) else {
+            return false
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(body);
+        return mac.verify_slice(&signature).is_ok()
+    }
+    if let Some(token) = header(request, "X-Gitlab-Token") {
+        return auth::constant_time_eq(token.as_bytes(), secret.as_bytes())
+    }
+    false
+}
+
+fn handle(mut request: Request, config: &WebhookConfig, trigger: &AtomicBool) -> io::Result<()> {
+    let path = request.url().split('?').next().unwrap_or_default();
+    if path != config.path {
+        return request.respond(Response::from_string("not found").with_status_code(404))
+    }
+    if let Some(response) = auth::check(config.auth.as_ref(), &request) {
+        warn!("Rejected webhook from {}: bad credentials", request.remote_addr().map(|a| a.to_string()).unwrap_or_default());
+        return request.respond(response)
+    }
+    if *request.method() != Method::Post {
+        return request.respond(Response::from_string("only POST is supported").with_status_code(405))
+    }
+
+    let mut body = Vec::new();
+    request.as_reader().take(MAX_BODY as u64 + 1).read_to_end(&mut body)?;
+    if body.len() > MAX_BODY {
+        return request.respond(Response::from_string("payload too large").with_status_code(413))
+    }
+    if !authorized(&request, &body, &config.secret) {
+        warn!("Rejected webhook from {}: bad signature", request.remote_addr().map(|a| a.to_string()).unwrap_or_default());
+        return request.respond(Response::from_string("bad signature").with_status_code(401))
+    }
+
+    info!("Webhook received, checking for updates...");
+    trigger.store(true, Ordering::SeqCst);
+    request.respond(Response::from_string("update triggered").with_status_code(202))
+}
+
+/// Binds the listener and serves it from a new thread until `stop` is set or gdep is interrupted.
+/// Every accepted request sets `trigger`, which the update thread picks up before its next wait
+pub fn serve(config: WebhookConfig, trigger: Arc<AtomicBool>, stop: Arc<Mutex<bool>>) -> io::Result<thread::JoinHandle<()>> {
+    let server = Server::http(&config.bind).map_err(io::Error::other)?;
+    info!("Listening for webhooks on {}{}", config.bind, config.path);
+
+    crate::deployment_thread().spawn(move || {
+        while !*stop.lock().unwrap() && signals::received().is_none() {
+            match server.recv_timeout(POLL_STOP) {
+                Ok(Some(request)) => {
+                    if let Err(e) = handle(request, &config, &trigger) {
+                        debug!("Failed to answer webhook: {}", e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Webhook listener failed to receive a request: {}", e)
+            }
+        }
+    })
+}
@@ -0,0 +1,477 @@
+//! The command line of gdep: flags, subcommands, output and exit codes
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use common::{wait_for, Fixture, TIMEOUT};
+
+fn stderr(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn debug_prints_the_error_details() {
+    let fixture = Fixture::new();
+    fixture.write("gdep.yml", "repo: x\nscript: ./run.sh\n");
+
+    let plain = stderr(&fixture.run(&[]));
+    assert!(plain.contains("Gdep Error => Failed to load configuration: Missing mandatory property: name"), "{}", plain);
+
+    let debug = stderr(&fixture.run(&["--debug"]));
+    assert!(debug.contains("Gdep Error => ConfigLoadError(MissingContent(\"name\"))"), "{}", debug);
+}
+
+#[test]
+fn the_final_error_reaches_stderr_with_logging_off() {
+    let fixture = Fixture::new();
+    fixture.write("gdep.yml", "repo: x\nscript: ./run.sh\n");
+
+    let output = fixture.gdep().args(["-s", "gdep.yml"]).env("RUST_LOG", "off").output().unwrap();
+    assert_eq!(output.status.code(), Some(160));
+    let err = stderr(&output);
+    assert!(err.contains("Gdep Error => Failed to load configuration: Missing mandatory property: name"), "{}", err);
+}
+
+#[test]
+fn deploy_commit_checks_out_the_given_commit_and_runs_once() {
+    let fixture = Fixture::new();
+    let v1 = fixture.tip("main");
+    fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    fixture.config("script: cp app.txt ../deployed.txt\n");
+
+    let output = fixture.run(&["--deploy-commit", &v1.to_string()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fixture.head(), v1);
+    assert_eq!(fixture.read("deployed.txt"), "v1\n");
+}
+
+#[test]
+fn expect_branch_refuses_an_inferred_branch_that_differs() {
+    let fixture = Fixture::new();
+    fixture.config("script: touch ../ran.txt\nexpect_branch: production\n");
+
+    let output = fixture.run(&["--once"]);
+    assert_eq!(output.status.code(), Some(164));
+    assert!(stderr(&output).contains("Expected to deploy branch `production`, but resolved `main`"), "{}", stderr(&output));
+    assert!(!fixture.path("ran.txt").exists());
+}
+
+#[test]
+fn an_unusable_ca_bundle_stops_gdep_before_the_clone() {
+    let fixture = Fixture::new();
+    fixture.write("bundle.pem", "not a certificate\n");
+    fixture.config("script: touch ../ran.txt\nca_bundle: bundle.pem\n");
+
+    let output = fixture.run(&["--once"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("no PEM certificates found"), "{}", stderr(&output));
+    assert!(!fixture.work().exists());
+}
+
+#[test]
+fn log_file_gets_a_copy_of_the_log_and_rotates() {
+    let fixture = Fixture::new();
+    fixture.config("script: \"true\"\nlog_max_size: 1K\nlog_keep: 1\n");
+
+    let output = fixture.run(&["--once", "--log-file", "logs/gdep.log"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let log = fixture.read("logs/gdep.log");
+    assert!(log.contains("INFO  gdep] Branch inferred to be `main`"), "{}", log);
+
+    // Each run appends, until the file is past 1K and starts over
+    for _ in 0..20 {
+        fixture.run(&["--once", "--log-file", "logs/gdep.log"]);
+    }
+    assert!(fixture.path("logs/gdep.log.1").exists());
+    assert!(!fixture.path("logs/gdep.log.2").exists());
+    assert!(fixture.read("logs/gdep.log").len() <= 1024);
+}
+
+#[test]
+fn a_second_instance_on_the_same_repo_refuses_to_run() {
+    let fixture = Fixture::new();
+    fixture.config("script: touch ../started.txt; exec sleep 60\n");
+    let mut first = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.path("started.txt").exists()), "{}", first.log());
+
+    let second = fixture.run(&[]);
+    assert_eq!(second.status.code(), Some(246));
+    let expected = format!("Another gdep (PID {}) is already running on", first.pid());
+    assert!(stderr(&second).contains(&expected), "{}", stderr(&second));
+
+    // Released on shutdown, so the next one gets to run
+    first.stop();
+    fixture.config("script: \"true\"\n");
+    assert!(fixture.run(&["--once"]).status.success());
+}
+
+#[test]
+fn dry_run_reports_the_update_without_applying_it() {
+    let fixture = Fixture::new();
+    fixture.config("script: touch ../ran.txt\n");
+
+    let output = fixture.run(&["--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(stdout.contains(&format!("Would clone `{}` into", fixture.origin_url())), "{}", stdout);
+    assert!(!fixture.work().exists());
+
+    let v1 = fixture.clone_work().head().unwrap().target().unwrap();
+    fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    let output = fixture.run(&["--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(stdout.contains("Relation to origin/main: Behind(1)"), "{}", stdout);
+    assert!(stdout.contains("Action: would fast-forward 1 commit(s)"), "{}", stdout);
+    assert!(stdout.contains("Would run script:\n  | touch ../ran.txt"), "{}", stdout);
+
+    assert_eq!(fixture.head(), v1);
+    assert_eq!(fixture.read("work/app.txt"), "v1\n");
+    assert!(!fixture.path("ran.txt").exists());
+}
+
+#[test]
+fn check_validates_the_config_without_touching_git() {
+    let fixture = Fixture::new();
+    fixture.config("script: ./run.sh\n");
+    let output = fixture.run(&["--check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(stdout.contains("`gdep.yml` is valid"), "{}", stdout);
+    assert!(stdout.contains(&format!("test: clones `{}` into", fixture.origin_url())), "{}", stdout);
+    assert!(!fixture.work().exists());
+
+    fixture.write("gdep.yml", "repo: x\nscript_use_file: true\nfile_path: missing.sh\npoll_interval: often\n");
+    let output = fixture.run(&["--check"]);
+    assert_eq!(output.status.code(), Some(161));
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(stdout.contains("`gdep.yml` has 3 problem(s):\n  - Missing mandatory property: name\n  - Script file not found:"), "{}", stdout);
+    assert!(stdout.contains("  - Invalid value for `poll_interval`"), "{}", stdout);
+}
+
+#[test]
+fn the_repo_path_comes_from_the_flag_then_the_env_then_the_config() {
+    let fixture = Fixture::new();
+    let clones = |config: &str, env: Option<&str>, args: &[&str]| {
+        let dir = tempfile::tempdir_in(fixture.dir.path()).unwrap();
+        fs::write(dir.path().join("gdep.yml"), format!("name: test\nrepo: {}\nscript: \"true\"\n{}", fixture.origin_url(), config)).unwrap();
+        let mut command = fixture.gdep();
+        command.current_dir(dir.path()).args(["-s", "gdep.yml", "--once"]).args(args);
+        if let Some(env) = env {
+            command.env("GDEP_REPO_PATH", env);
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        let mut cloned: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| dir.path().join(name).join(".git").exists()).collect();
+        cloned.sort();
+        cloned
+    };
+
+    assert_eq!(clones("", None, &[]), ["gdep_used_repo"]);
+    assert_eq!(clones("default_repo_path: from-config\n", None, &[]), ["from-config"]);
+    assert_eq!(clones("default_repo_path: from-config\n", Some("from-env"), &[]), ["from-env"]);
+    assert_eq!(clones("default_repo_path: from-config\n", Some("from-env"), &["--local-repo", "from-flag"]), ["from-flag"]);
+}
+
+#[test]
+fn json_output_reports_an_update_with_both_commits() {
+    let fixture = Fixture::new();
+    let v1 = fixture.tip("main");
+    fixture.config("script: exec sleep 60\npoll_interval: 1\n");
+    let mut gdep = fixture.spawn(&["--output", "json"]);
+    let events = |gdep: &common::Running| -> Vec<serde_json::Value> {
+        gdep.log().lines().filter(|l| l.starts_with('{')).map(|l| serde_json::from_str(l).unwrap()).collect()
+    };
+    assert!(wait_for(TIMEOUT, || events(&gdep).iter().any(|e| e["event"] == "up_to_date")), "{}", gdep.log());
+
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || events(&gdep).iter().any(|e| e["event"] == "update_applied")), "{}", gdep.log());
+    let applied = events(&gdep).into_iter().find(|e| e["event"] == "update_applied").unwrap();
+    assert_eq!(applied["previous"], v1.to_string());
+    assert_eq!(applied["current"], v2.to_string());
+    assert_eq!(applied["summary"], "v2");
+    assert!(events(&gdep).iter().all(|e| e["time"].is_string()));
+    gdep.stop();
+}
+
+#[test]
+fn an_update_names_the_deployed_commit_in_the_log_and_the_event() {
+    let fixture = Fixture::new();
+    fixture.config("script: exec sleep 60\npoll_interval: 1\n");
+    let mut gdep = fixture.spawn(&["-v"]);
+    gdep.wait_for_log("Fetched branch `main`: Up2Date");
+    let v2 = fixture.commit_as("main", &[("app.txt", Some("v2\n"))], "Fix the login page\n\nThe body is left out", "Alice", "alice@example.com");
+    let short = &v2.to_string()[..7];
+    gdep.wait_for_log(&format!("Updated to {} \"Fix the login page\" by Alice <alice@example.com>", short));
+    assert!(!gdep.log().contains("The body is left out"), "{}", gdep.log());
+    gdep.stop();
+
+    let v3 = fixture.commit_as("main", &[("app.txt", Some("v3\n"))], "Add a logout button", "Bob", "bob@example.com");
+    let mut gdep = fixture.spawn(&["--output", "json"]);
+    gdep.wait_for_log("\"event\":\"update_applied\"");
+    let applied: serde_json::Value = gdep.log().lines().filter(|l| l.contains("\"update_applied\"")).map(|l| serde_json::from_str(l).unwrap()).next().unwrap();
+    assert_eq!(applied["current"], v3.to_string());
+    assert_eq!(applied["summary"], "Add a logout button");
+    assert_eq!(applied["author"], "Bob <bob@example.com>");
+    gdep.stop();
+}
+
+#[test]
+fn status_reports_how_far_behind_the_checkout_is() {
+    let fixture = Fixture::new();
+    let v1 = fixture.clone_work().head().unwrap().target().unwrap();
+    fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    fixture.commit("main", &[("app.txt", Some("v3\n"))], "v3");
+    fixture.config("script: \"true\"\n");
+    let status = |args: &[&str]| {
+        let output = fixture.gdep().args(["status", "-s", "gdep.yml"]).args(args).output().unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    let human = status(&[]);
+    assert!(human.contains(&format!("Branch: main\nHEAD: {}\nRelation to origin/main: Behind(2)\nRunning: no", v1)), "{}", human);
+
+    let json: serde_json::Value = serde_json::from_str(&status(&["--output", "json"])).unwrap();
+    assert_eq!(json["relation"], "behind");
+    assert_eq!(json["behind"], 2);
+    assert_eq!(json["ahead"], 0);
+    assert_eq!(json["running"], false);
+    assert_eq!(fixture.head(), v1);
+}
+
+#[test]
+fn the_default_branch_is_taken_from_origin_head() {
+    let fixture = Fixture::with_default_branch("develop");
+    fixture.commit("main", &[("app.txt", Some("main\n"))], "main");
+    fixture.config("script: cp app.txt ../deployed.txt\n");
+
+    let output = fixture.run(&["--once"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Branch inferred to be `develop`, origin's default"), "{}", stderr(&output));
+    assert_eq!(fixture.read("deployed.txt"), "v1\n");
+
+    // An existing checkout has origin/HEAD after the fetch as well
+    fixture.checkout().find_reference("refs/remotes/origin/HEAD").unwrap().delete().unwrap();
+    fixture.commit("develop", &[("app.txt", Some("v2\n"))], "v2");
+    let output = fixture.run(&["--once"]);
+    assert!(stderr(&output).contains("Branch inferred to be `develop`, origin's default"), "{}", stderr(&output));
+    assert_eq!(fixture.read("deployed.txt"), "v2\n");
+}
+
+#[test]
+fn quiet_keeps_only_the_errors() {
+    let fixture = Fixture::new();
+    fixture.config("script: \"true\"\n");
+    let output = fixture.run(&["--once", "-q"]);
+    assert!(output.status.success());
+    assert_eq!(stderr(&output), "");
+
+    fixture.config("script: exit 3\nfinal: true\n");
+    let output = fixture.run(&["-q"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("Gdep Error =>"), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("INFO"), "{}", stderr(&output));
+
+    assert_eq!(fixture.run(&["-q", "-v"]).status.code(), Some(2));
+}
+
+#[test]
+fn verbosity_and_color_shape_the_log() {
+    let fixture = Fixture::new();
+    fixture.config("script: \"true\"\n");
+    let plain = stderr(&fixture.run(&["--once"]));
+    assert!(plain.contains("INFO") && !plain.contains("DEBUG"), "{}", plain);
+    assert!(!plain.contains('\x1b'), "{}", plain);
+
+    let verbose = stderr(&fixture.run(&["--once", "-v", "--color", "always"]));
+    assert!(verbose.contains("DEBUG") && !verbose.contains("TRACE"), "{}", verbose);
+    assert!(verbose.contains('\x1b'), "{}", verbose);
+    assert!(!stderr(&fixture.run(&["--once", "-v", "--color", "never"])).contains('\x1b'));
+}
+
+#[test]
+fn history_has_a_record_of_every_update() {
+    let fixture = Fixture::new();
+    let v1 = fixture.clone_work().head().unwrap().target().unwrap();
+    fixture.config("script: \"true\"\n");
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(fixture.run(&["--once"]).status.success());
+    let v3 = fixture.commit("main", &[("app.txt", Some("v3\n"))], "v3");
+    assert!(fixture.run(&["--once"]).status.success());
+    let history = |args: &[&str]| {
+        let output = fixture.gdep().args(["history", "-s", "gdep.yml"]).args(args).output().unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+
+    let entries: Vec<serde_json::Value> = history(&["--output", "json"]).lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    let updates: Vec<_> = entries.iter().filter(|e| e["kind"] == "update").map(|e| (e["previous"].clone(), e["current"].clone())).collect();
+    assert_eq!(updates, [(v1.to_string().into(), v2.to_string().into()), (v2.to_string().into(), v3.to_string().into())]);
+    assert!(entries.iter().filter(|e| e["kind"] == "script").all(|e| e["exit_code"] == 0), "{:?}", entries);
+
+    let human = history(&["-n", "1"]);
+    assert_eq!(human.lines().count(), 2, "{}", human);
+    assert!(human.starts_with("Deployment `test` in `work`\n"), "{}", human);
+    assert!(human.contains(&format!("main script at {:.7} exited with 0", v3.to_string())), "{}", human);
+}
+
+#[test]
+fn a_config_that_is_not_a_mapping_is_a_clean_error() {
+    let fixture = Fixture::new();
+    for content in ["", "- name: test\n", "name\n"] {
+        fixture.write("gdep.yml", content);
+        let output = fixture.run(&["--once"]);
+        assert!(!output.status.success());
+        assert!(stderr(&output).contains("Gdep Error => Failed to load configuration: Parsing failed: "), "{}", stderr(&output));
+        assert!(!stderr(&output).contains("panicked"), "{}", stderr(&output));
+    }
+}
+
+/// Runs gdep with `args`, `config` piped into it
+fn piped(fixture: &Fixture, args: &[&str], config: &str) -> std::process::Output {
+    let mut gdep = fixture.gdep().args(args).stdin(std::process::Stdio::piped()).stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped()).spawn().unwrap();
+    std::io::Write::write_all(&mut gdep.stdin.take().unwrap(), config.as_bytes()).unwrap();
+    gdep.wait_with_output().unwrap()
+}
+
+#[test]
+fn a_config_piped_in_finds_its_files_from_the_working_dir_or_the_config_base() {
+    let fixture = Fixture::new();
+    fixture.write("scripts/deploy.sh", "cat app.txt >> ../ran.txt\n");
+    let config = format!("name: test\nrepo: {}\ninto_path: work\nscript_use_file: true\nfile_path: scripts/deploy.sh\n", fixture.origin_url());
+    let output = piped(&fixture, &["-s", "-", "--once"], &config);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fixture.read("ran.txt"), "v1\n");
+
+    let config = format!("name: test\nrepo: {}\ninto_path: ../work\nscript_use_file: true\nfile_path: deploy.sh\n", fixture.origin_url());
+    let output = piped(&fixture, &["-s", "-", "--config-base", "scripts", "--once"], &config);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fixture.read("ran.txt"), "v1\nv1\n");
+
+    let output = piped(&fixture, &["-s", "-", "--once"], "");
+    assert!(stderr(&output).contains("Parsing failed: stdin is empty"), "{}", stderr(&output));
+}
+
+#[test]
+fn init_writes_a_config_gdep_runs_and_keeps_an_existing_one() {
+    let fixture = Fixture::new();
+    let init = |args: &[&str]| fixture.gdep().arg("init").args(args).stdin(std::process::Stdio::null()).output().unwrap();
+    let flags = ["--name", "app", "--repo", &fixture.origin_url(), "--script", "cat app.txt > ../ran.txt"];
+    assert!(init(&flags).status.success());
+    let output = fixture.gdep().args(["-s", "gdep.yaml", "--once"]).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fixture.read("ran.txt"), "v1\n");
+
+    let written = fixture.read("gdep.yaml");
+    let output = init(&["--name", "other", "--repo", "other", "--script", "true"]);
+    assert_eq!(output.status.code(), Some(162));
+    assert!(stderr(&output).contains("`gdep.yaml` already exists, pass --force to overwrite it"), "{}", stderr(&output));
+    assert_eq!(fixture.read("gdep.yaml"), written);
+    assert!(init(&["--name", "other", "--repo", "other", "--script", "true", "--force"]).status.success());
+    assert!(fixture.read("gdep.yaml").starts_with("---\nname: other\n"), "{}", fixture.read("gdep.yaml"));
+
+    // Without a terminal to ask on, a missing value is an error
+    let output = init(&["--name", "third", "--force"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("repo"), "{}", stderr(&output));
+    assert!(fixture.read("gdep.yaml").starts_with("---\nname: other\n"));
+}
+
+#[test]
+fn repeated_config_files_merge_left_to_right() {
+    let fixture = Fixture::new();
+    fixture.config("script: echo base > ../ran.txt\n");
+    fixture.write("host.yml", "script: echo host > ../ran.txt\n");
+    let output = fixture.gdep().args(["-s", "gdep.yml", "-s", "host.yml", "--once"]).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fixture.read("ran.txt"), "host\n");
+
+    fixture.write("broken.yml", "script: [\n");
+    let output = fixture.gdep().args(["-s", "gdep.yml", "-s", "broken.yml", "--once"]).output().unwrap();
+    assert!(stderr(&output).contains("`broken.yml`: "), "{}", stderr(&output));
+}
+
+#[test]
+fn print_config_shows_the_flags_over_the_file_and_the_defaults() {
+    let fixture = Fixture::new();
+    fixture.config("script: ./run.sh\nbranch: main\nhttp_token: hunter2\nenv:\n  MODE: ${GDEP_TEST_MODE:-dev}\n");
+    let output = fixture.gdep().args(["-s", "gdep.yml", "--print-config", "-b", "release", "--", "--port", "1"]).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let printed = String::from_utf8_lossy(&output.stdout);
+    for line in ["into_path: work\n", "branch: release\n", "  MODE: dev\n", "http_token: \"<redacted>\"\n", "args:\n  - \"--port\"\n  - \"1\"\n", "max_fetch_retries: 5\n"] {
+        assert!(printed.contains(line), "no `{}` in\n{}", line, printed);
+    }
+    assert!(!printed.contains("hunter2"), "{}", printed);
+    assert!(!fixture.work().exists());
+
+    let output = fixture.gdep().args(["-s", "gdep.yml", "--print-config", "--output", "json"]).env("GDEP_TEST_MODE", "prod").output().unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["branch"], "main");
+    assert_eq!(json["env"]["MODE"], "prod");
+    assert_eq!(json["into_path"], "work");
+    assert_eq!(json["restart_delay"], "0s");
+}
+
+#[test]
+fn gdep_exits_with_the_code_of_what_stopped_it() {
+    let fixture = Fixture::new();
+    assert_eq!(fixture.run(&["--once"]).status.code(), Some(160));
+    fixture.config("script: exit 3\n");
+    assert_eq!(fixture.run(&["--once"]).status.code(), Some(3));
+    assert_eq!(fixture.run(&["--once", "-b", "nope"]).status.code(), Some(190));
+    fixture.config("script: exit 200\n");
+    assert_eq!(fixture.run(&["--once"]).status.code(), Some(1));
+    fixture.config("script: kill -9 $$\n");
+    assert_eq!(fixture.run(&["--once"]).status.code(), Some(137));
+    fixture.config("script: \"true\"\n");
+    assert_eq!(fixture.run(&["--once"]).status.code(), Some(0));
+}
+
+#[test]
+fn schema_prints_or_writes_the_json_schema() {
+    let fixture = Fixture::new();
+    let output = fixture.gdep().arg("schema").output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let schema: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(schema["$schema"], "http://json-schema.org/draft-07/schema#");
+    assert_eq!(schema["else"]["allOf"][0]["required"], serde_json::json!(["name"]));
+    assert_eq!(schema["properties"]["restore_on_start"]["default"], false);
+
+    let output = fixture.gdep().args(["schema", "-o", "gdep.schema.json"]).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(output.stdout.is_empty());
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&fixture.read("gdep.schema.json")).unwrap(), schema);
+}
+
+#[test]
+fn config_migrate_writes_a_config_that_runs_the_same() {
+    let fixture = Fixture::new();
+    fixture.write("run.sh", "echo \"$GDEP_BRANCH\" > ../ran.txt\n");
+    fixture.write("legacy.yml", &format!("name: test\nrepo: {}\ninto_path: work\nscript_use_file: true\nfile_path: run.sh\nfinal: true\n\
+        gdep_err_ignore: true\npoll_interval: 2m\n", fixture.origin_url()));
+    let output = fixture.gdep().args(["config-migrate", "legacy.yml", "-o", "migrated.yml"]).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(output.stdout.is_empty());
+    assert!(stderr(&output).contains("Migrated `legacy.yml` to `migrated.yml`"), "{}", stderr(&output));
+
+    let migrated = fixture.read("migrated.yml");
+    assert!(migrated.starts_with("---\nname: test\n"), "{}", migrated);
+    for line in ["script_use_file: true", "file_path: run.sh", "final: true", "gdep_err_ignore: true", "poll_interval: 2m"] {
+        assert!(migrated.lines().any(|l| l == line), "{} missing from:\n{}", line, migrated);
+    }
+    // What it printed without `-o` is what it wrote
+    let printed = fixture.gdep().args(["config-migrate", "legacy.yml"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&printed.stdout), migrated);
+
+    let output = fixture.gdep().args(["-s", "migrated.yml", "--once"]).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fixture.read("ran.txt"), "main\n");
+
+    let missing = fixture.gdep().args(["config-migrate", "nope.yml", "-o", "out.yml"]).output().unwrap();
+    assert_eq!(missing.status.code(), Some(160));
+    assert!(!fixture.path("out.yml").exists());
+}
@@ -0,0 +1,204 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tiny_http::{Header, Response, Server};
+
+/// A request the server got, as far as the tests care
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub authorization: Option<String>
+}
+
+/// Serves the repos below a directory over git's smart HTTP protocol, by handing every request to `git http-backend`.
+/// It can turn requests down, to stand in for a remote that is unreachable or wants credentials
+pub struct GitServer {
+    server: Arc<Server>,
+    handle: Option<JoinHandle<()>>,
+    pub requests: Arc<Mutex<Vec<Request>>>,
+    port: u16
+}
+
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Answers this many requests with a 503 before serving any
+    pub fail_first: usize,
+    /// The `Authorization` header every request has to carry, 401 without it
+    pub authorization: Option<String>
+}
+
+impl GitServer {
+    pub fn start(root: &Path) -> Self {
+        Self::with_options(root, Options::default())
+    }
+
+    pub fn with_options(root: &Path, options: Options) -> Self {
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let port = server.server_addr().to_ip().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let failed = AtomicUsize::new(0);
+        let handle = {
+            let (server, requests, root) = (server.clone(), requests.clone(), root.to_path_buf());
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let authorization = Some(header(&request, "Authorization")).filter(|a| !a.is_empty());
+                    requests.lock().unwrap().push(Request { method: request.method().to_string(), url: request.url().to_string(), authorization: authorization.clone() });
+                    if failed.load(Ordering::SeqCst) < options.fail_first {
+                        failed.fetch_add(1, Ordering::SeqCst);
+                        let _ = request.respond(Response::empty(503));
+                        continue
+                    }
+                    if options.authorization.is_some() && authorization != options.authorization {
+                        let challenge = Header::from_bytes("WWW-Authenticate", "Basic realm=\"git\"").unwrap();
+                        let _ = request.respond(Response::empty(401).with_header(challenge));
+                        continue
+                    }
+                    let response = backend(&root, &mut request);
+                    let _ = request.respond(response);
+                }
+            })
+        };
+        Self { server, handle: Some(handle), requests, port }
+    }
+
+    /// The URL of `repo`, a path below the root
+    pub fn url(&self, repo: &str) -> String {
+        format!("http://127.0.0.1:{}/{}", self.port, repo)
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for GitServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn header(request: &tiny_http::Request, name: &'static str) -> String {
+    request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.to_string()).unwrap_or_default()
+}
+
+/// Runs `git http-backend` as the CGI program it is
+fn backend(root: &Path, request: &mut tiny_http::Request) -> Response<std::io::Cursor<Vec<u8>>> {
+    let (path, query) = request.url().split_once('?').map_or((request.url().to_string(), String::new()), |(p, q)| (p.to_string(), q.to_string()));
+    let (content_type, encoding, protocol) = (header(request, "Content-Type"), header(request, "Content-Encoding"), header(request, "Git-Protocol"));
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body).unwrap();
+
+    let mut child = Command::new("git").arg("http-backend")
+        .env("GIT_PROJECT_ROOT", root)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("GIT_CONFIG_COUNT", "1")
+        .env("GIT_CONFIG_KEY_0", "http.receivepack")
+        .env("GIT_CONFIG_VALUE_0", "true")
+        .env("REQUEST_METHOD", request.method().to_string())
+        .env("PATH_INFO", path)
+        .env("QUERY_STRING", query)
+        .env("CONTENT_TYPE", content_type)
+        .env("HTTP_CONTENT_ENCODING", encoding)
+        .env("HTTP_GIT_PROTOCOL", protocol)
+        .env("CONTENT_LENGTH", body.len().to_string())
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null())
+        .spawn().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || {
+        let _ = stdin.write_all(&body);
+    });
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap();
+
+    let split = output.stdout.windows(4).position(|w| w == b"\r\n\r\n").map(|i| (i, 4))
+        .or_else(|| output.stdout.windows(2).position(|w| w == b"\n\n").map(|i| (i, 2)))
+        .unwrap_or((output.stdout.len(), 0));
+    let (head, body) = (String::from_utf8_lossy(&output.stdout[..split.0]).to_string(), output.stdout[split.0 + split.1..].to_vec());
+    let mut response = Response::from_data(body);
+    for line in head.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue
+        };
+        if name.eq_ignore_ascii_case("Status") {
+            response = response.with_status_code(value.trim().split(' ').next().unwrap().parse::<u16>().unwrap());
+        } else {
+            response = response.with_header(Header::from_bytes(name.trim(), value.trim()).unwrap());
+        }
+    }
+    response
+}
+
+/// A port nothing listens on right now, for a server of gdep's to bind
+pub fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Sends one HTTP/1.0 request and returns the status and body of the answer, None if nothing listens
+pub fn send(port: u16, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> Option<(u16, String)> {
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).ok()?;
+    let mut head = format!("{} {} HTTP/1.0\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n", method, path, body.len());
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).ok()?;
+    stream.write_all(body).ok()?;
+    let mut response = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut response).ok()?;
+    let status = response.split(' ').nth(1)?.parse().ok()?;
+    let body = response.split_once("\r\n\r\n").map(|(_, b)| b.to_string()).unwrap_or_default();
+    Some((status, body))
+}
+
+/// Takes every request it gets and answers 200, for the tests to look at what gdep sent
+pub struct Receiver {
+    server: Arc<Server>,
+    handle: Option<JoinHandle<()>>,
+    bodies: Arc<Mutex<Vec<String>>>,
+    port: u16
+}
+
+impl Receiver {
+    pub fn start() -> Self {
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let port = server.server_addr().to_ip().unwrap().port();
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let handle = {
+            let (server, bodies) = (server.clone(), bodies.clone());
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let mut body = String::new();
+                    let _ = request.as_reader().read_to_string(&mut body);
+                    bodies.lock().unwrap().push(body);
+                    let _ = request.respond(Response::empty(200));
+                }
+            })
+        };
+        Self { server, handle: Some(handle), bodies, port }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}/hook", self.port)
+    }
+
+    /// The bodies received so far, parsed as JSON
+    pub fn payloads(&self) -> Vec<serde_json::Value> {
+        self.bodies.lock().unwrap().iter().map(|b| serde_json::from_str(b).unwrap()).collect()
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,374 @@
+// Every test binary includes this, none of them uses all of it
+#![allow(dead_code)]
+
+use std::cell::Cell;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use git2::{Index, IndexEntry, IndexTime, Oid, Repository, Signature, Time};
+use tempfile::TempDir;
+
+pub mod http;
+
+/// How long a test waits for gdep to get somewhere before it fails
+pub const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// A temporary directory holding `origin.git`, a bare repo that gdep clones into `work`.
+/// Commits go straight into the bare repo, which is what a push would leave there
+pub struct Fixture {
+    pub dir: TempDir,
+    pub origin: Repository,
+    /// Commit times count up from here, so "newer" means "committed later" even within the same second
+    time: Cell<i64>
+}
+
+impl Fixture {
+    /// `origin.git` with `app.txt` = `v1` on `main`, its default branch
+    pub fn new() -> Self {
+        Self::with_default_branch("main")
+    }
+
+    pub fn with_default_branch(branch: &str) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = Repository::init_bare(dir.path().join("origin.git")).unwrap();
+        origin.set_head(&format!("refs/heads/{}", branch)).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let fixture = Self { dir, origin, time: Cell::new(now - 1000) };
+        fixture.commit(branch, &[("app.txt", Some("v1\n"))], "v1");
+        fixture
+    }
+
+    pub fn path(&self, rel: &str) -> PathBuf {
+        self.dir.path().join(rel)
+    }
+
+    /// What `repo` of the config points at
+    pub fn origin_url(&self) -> String {
+        self.path("origin.git").to_string_lossy().to_string()
+    }
+
+    /// The checkout gdep clones into
+    pub fn work(&self) -> PathBuf {
+        self.path("work")
+    }
+
+    pub fn read(&self, rel: &str) -> String {
+        fs::read_to_string(self.path(rel)).unwrap_or_default()
+    }
+
+    pub fn write(&self, rel: &str, content: &str) {
+        let path = self.path(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn signature(&self, name: &str, email: &str) -> Signature<'static> {
+        let time = self.time.get() + 1;
+        self.time.set(time);
+        Signature::new(name, email, &Time::new(time, 0)).unwrap()
+    }
+
+    /// Commits `files` on top of `branch`, creating it if it doesn't exist yet. A `None` content deletes the file
+    pub fn commit(&self, branch: &str, files: &[(&str, Option<&str>)], message: &str) -> Oid {
+        self.commit_as(branch, files, message, "dev", "dev@example.com")
+    }
+
+    pub fn commit_as(&self, branch: &str, files: &[(&str, Option<&str>)], message: &str, name: &str, email: &str) -> Oid {
+        self.commit_to(&self.origin, branch, &blobs(files), message, &self.signature(name, email))
+    }
+
+    /// A commit on no branch, with `parents`, for merges and rewritten histories
+    pub fn commit_on(&self, parents: &[Oid], files: &[(&str, Option<&str>)], message: &str) -> Oid {
+        let parents: Vec<_> = parents.iter().map(|p| self.origin.find_commit(*p).unwrap()).collect();
+        let tree = tree(&self.origin, parents.first(), &blobs(files));
+        let signature = self.signature("dev", "dev@example.com");
+        let parents: Vec<_> = parents.iter().collect();
+        self.origin.commit(None, &signature, &signature, message, &self.origin.find_tree(tree).unwrap(), &parents).unwrap()
+    }
+
+    /// Another bare repo next to origin, `<name>.git` with `lib.txt` = `lib v1` on `main`, e.g. for a submodule
+    pub fn other_repo(&self, name: &str) -> Repository {
+        let repo = Repository::init_bare(self.path(&format!("{}.git", name))).unwrap();
+        repo.set_head("refs/heads/main").unwrap();
+        self.commit_in(&repo, "main", &[("lib.txt", Some("lib v1\n"))], "lib v1");
+        repo
+    }
+
+    pub fn commit_in(&self, repo: &Repository, branch: &str, files: &[(&str, Option<&str>)], message: &str) -> Oid {
+        self.commit_to(repo, branch, &blobs(files), message, &self.signature("dev", "dev@example.com"))
+    }
+
+    /// Commits `<name>.git` at `commit` as the submodule `path` of origin, registering it in `.gitmodules`
+    pub fn commit_submodule(&self, branch: &str, path: &str, url: &str, commit: Oid, message: &str) -> Oid {
+        let gitmodules = format!("[submodule \"{}\"]\n\tpath = {}\n\turl = {}\n", path, path, url);
+        let files = [(".gitmodules", File::Blob(Some(gitmodules))), (path, File::Gitlink(commit))];
+        self.commit_to(&self.origin, branch, &files, message, &self.signature("dev", "dev@example.com"))
+    }
+
+    /// Makes a signing key for `dev@example.com` in `$HOME/.gnupg`, where gdep's gpg finds it too. Its fingerprint,
+    /// None if there is no gpg to make it with
+    pub fn gpg_key(&self) -> Option<String> {
+        let generated = self.gpg().args(["--passphrase", "", "--quick-gen-key", "dev <dev@example.com>", "ed25519", "sign", "never"]).output().ok()?;
+        assert!(generated.status.success(), "{}", String::from_utf8_lossy(&generated.stderr));
+        let listed = self.gpg().args(["--with-colons", "--list-secret-keys"]).output().unwrap();
+        let listed = String::from_utf8_lossy(&listed.stdout).to_string();
+        listed.lines().find_map(|l| l.strip_prefix("fpr:")).map(|l| l.trim_matches(':').to_string())
+    }
+
+    /// Like `commit`, signed with the key of `gpg_key`
+    pub fn commit_signed(&self, branch: &str, files: &[(&str, Option<&str>)], message: &str) -> Oid {
+        let reference = format!("refs/heads/{}", branch);
+        let parent = self.origin.find_reference(&reference).unwrap().peel_to_commit().unwrap();
+        let tree = self.origin.find_tree(tree(&self.origin, Some(&parent), &blobs(files))).unwrap();
+        let signature = self.signature("dev", "dev@example.com");
+        let buffer = self.origin.commit_create_buffer(&signature, &signature, message, &tree, &[&parent]).unwrap();
+
+        let mut gpg = self.gpg().args(["--armor", "--detach-sign"]).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        gpg.stdin.take().unwrap().write_all(&buffer).unwrap();
+        let signed = gpg.wait_with_output().unwrap();
+        assert!(signed.status.success());
+        let id = self.origin.commit_signed(std::str::from_utf8(&buffer).unwrap(), &String::from_utf8(signed.stdout).unwrap(), None).unwrap();
+        self.set_branch(branch, id);
+        id
+    }
+
+    fn gpg(&self) -> Command {
+        let mut command = Command::new("gpg");
+        command.arg("--batch").env("HOME", self.dir.path()).env_remove("GNUPGHOME");
+        command
+    }
+
+    fn commit_to(&self, repo: &Repository, branch: &str, files: &[(&str, File)], message: &str, signature: &Signature) -> Oid {
+        let reference = format!("refs/heads/{}", branch);
+        let parent = repo.find_reference(&reference).ok().and_then(|r| r.peel_to_commit().ok());
+        let parents: Vec<_> = parent.iter().collect();
+        let tree = tree(repo, parent.as_ref(), files);
+        repo.commit(Some(&reference), signature, signature, message, &repo.find_tree(tree).unwrap(), &parents).unwrap()
+    }
+
+    /// Points `branch` at `target`, which moves it even if that isn't a fast-forward
+    pub fn set_branch(&self, branch: &str, target: Oid) {
+        self.origin.reference(&format!("refs/heads/{}", branch), target, true, "test").unwrap();
+    }
+
+    pub fn delete_branch(&self, branch: &str) {
+        self.origin.find_reference(&format!("refs/heads/{}", branch)).unwrap().delete().unwrap();
+    }
+
+    pub fn tip(&self, branch: &str) -> Oid {
+        self.origin.refname_to_id(&format!("refs/heads/{}", branch)).unwrap()
+    }
+
+    pub fn lightweight_tag(&self, name: &str, target: Oid) {
+        self.origin.reference(&format!("refs/tags/{}", name), target, true, "test").unwrap();
+    }
+
+    pub fn annotated_tag(&self, name: &str, target: Oid) -> Oid {
+        let object = self.origin.find_object(target, None).unwrap();
+        self.origin.tag(name, &object, &self.signature("dev", "dev@example.com"), name, true).unwrap()
+    }
+
+    /// Writes `gdep.yml` cloning origin into `work`, with `yaml` (the script and anything else) after that
+    pub fn config(&self, yaml: &str) -> PathBuf {
+        self.config_for(&self.origin_url(), yaml)
+    }
+
+    /// Like `config`, cloning `repo` instead, e.g. origin served over HTTP
+    pub fn config_for(&self, repo: &str, yaml: &str) -> PathBuf {
+        let path = self.path("gdep.yml");
+        fs::write(&path, format!("name: test\nrepo: {}\ninto_path: work\n{}", repo, yaml)).unwrap();
+        path
+    }
+
+    /// The gdep binary, run in the fixture directory with a home of its own
+    pub fn gdep(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_gdep"));
+        command.current_dir(self.dir.path())
+            .env("HOME", self.dir.path())
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env_remove("GDEP_REPO_PATH")
+            .env_remove("GNUPGHOME")
+            .env_remove("GIT_SSH_COMMAND")
+            .env_remove("NETRC")
+            .env_remove("NOTIFY_SOCKET")
+            .env_remove("RUST_LOG");
+        command
+    }
+
+    /// Runs gdep with `-s gdep.yml` and `args` to the end
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.gdep().args(["-s", "gdep.yml"]).args(args).output().unwrap()
+    }
+
+    /// Starts gdep with `-s gdep.yml` and `args` in the background, its log going to `gdep.log`
+    pub fn spawn(&self, args: &[&str]) -> Running {
+        self.spawn_with_env(args, &[])
+    }
+
+    /// Like `spawn`, with `env` set for gdep
+    pub fn spawn_with_env(&self, args: &[&str], env: &[(&str, &str)]) -> Running {
+        let log = fs::File::create(self.path("gdep.log")).unwrap();
+        let child = self.gdep().args(["-s", "gdep.yml"]).args(args).envs(env.iter().copied())
+            .stdin(Stdio::null()).stdout(log.try_clone().unwrap()).stderr(log)
+            .spawn().unwrap();
+        Running { child, log: self.path("gdep.log") }
+    }
+
+    /// The checkout, once gdep cloned it
+    pub fn checkout(&self) -> Repository {
+        Repository::open(self.work()).unwrap()
+    }
+
+    pub fn head(&self) -> Oid {
+        self.checkout().head().unwrap().target().unwrap()
+    }
+
+    /// HEAD of the checkout, None while there is none, to wait for
+    pub fn head_id(&self) -> Option<Oid> {
+        Repository::open(self.work()).ok()?.head().ok()?.target()
+    }
+
+    /// Clones origin into `work` like gdep would, for tests that start from an existing checkout
+    pub fn clone_work(&self) -> Repository {
+        Repository::clone(&self.origin_url(), self.work()).unwrap()
+    }
+
+    /// Writes `files` into the checkout and commits them on its current branch, as a local change nobody pushed
+    pub fn local_commit(&self, files: &[(&str, &str)], message: &str) -> Oid {
+        let repo = self.checkout();
+        let mut index = repo.index().unwrap();
+        for (path, content) in files {
+            self.write(&format!("work/{}", path), content);
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = repo.head().unwrap().peel_to_commit().unwrap();
+        let signature = self.signature("local", "local@example.com");
+        repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &[&parent]).unwrap()
+    }
+}
+
+/// What a path of a commit is set to
+enum File {
+    /// A file with this content, None deletes it
+    Blob(Option<String>),
+    /// A submodule at this commit
+    Gitlink(Oid)
+}
+
+fn blobs<'a>(files: &[(&'a str, Option<&str>)]) -> Vec<(&'a str, File)> {
+    files.iter().map(|(path, content)| (*path, File::Blob(content.map(str::to_string)))).collect()
+}
+
+/// The tree of `parent` with `files` written over it
+fn tree(repo: &Repository, parent: Option<&git2::Commit>, files: &[(&str, File)]) -> Oid {
+    let mut index = Index::new().unwrap();
+    if let Some(parent) = parent {
+        index.read_tree(&parent.tree().unwrap()).unwrap();
+    }
+    for (path, file) in files {
+        match file {
+            File::Blob(Some(content)) => {
+                let id = repo.blob(content.as_bytes()).unwrap();
+                let mode = if path.ends_with(".sh") {0o100755} else {0o100644};
+                index.add(&entry(path, id, mode, content.len() as u32)).unwrap();
+            }
+            File::Blob(None) => index.remove_path(Path::new(path)).unwrap(),
+            File::Gitlink(id) => index.add(&entry(path, *id, 0o160000, 0)).unwrap()
+        }
+    }
+    index.write_tree_to(repo).unwrap()
+}
+
+fn entry(path: &str, id: Oid, mode: u32, size: u32) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        file_size: size,
+        id,
+        flags: path.len().min(0xfff) as u16,
+        flags_extended: 0,
+        path: path.as_bytes().to_vec()
+    }
+}
+
+/// A gdep started by `Fixture::spawn`, killed when dropped
+pub struct Running {
+    pub child: Child,
+    log: PathBuf
+}
+
+impl Running {
+    pub fn log(&self) -> String {
+        fs::read_to_string(&self.log).unwrap_or_default()
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.child.id() as i32
+    }
+
+    /// Waits until the log has a line containing `text`, panicking with the log if it doesn't show up
+    pub fn wait_for_log(&self, text: &str) {
+        if !wait_for(TIMEOUT, || self.log().contains(text)) {
+            panic!("`{}` never showed up in the log:\n{}", text, self.log())
+        }
+    }
+
+    /// Whether gdep exited within `timeout`, with the code it exited with
+    pub fn wait_exit(&mut self, timeout: Duration) -> Option<Option<i32>> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return Some(status.code())
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        None
+    }
+
+    pub fn signal(&self, signal: nix::sys::signal::Signal) {
+        nix::sys::signal::kill(nix::unistd::Pid::from_raw(self.pid()), signal).unwrap();
+    }
+
+    /// Sends SIGTERM and returns the exit code gdep stopped with
+    pub fn stop(&mut self) -> Option<i32> {
+        self.signal(nix::sys::signal::Signal::SIGTERM);
+        match self.wait_exit(TIMEOUT) {
+            Some(code) => code,
+            None => panic!("gdep didn't stop on SIGTERM:\n{}", self.log())
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Polls `condition` until it holds or `timeout` passes, telling which
+pub fn wait_for(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if condition() {
+            return true
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    condition()
+}
+
+/// Whether `pid` still runs (or is a zombie nobody waited for yet)
+pub fn alive(pid: i32) -> bool {
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None).is_ok()
+}
@@ -0,0 +1,64 @@
+//! Several deployments from one config file, each with its own repo, script and update thread
+#![cfg(unix)]
+
+mod common;
+
+use common::{wait_for, Fixture, TIMEOUT};
+
+/// `app` deploys origin, `lib` a second repo. Each appends what it started on to a file of its own
+fn two_deployments(fixture: &Fixture, lib_final: bool) {
+    let lib = fixture.path("lib.git").to_string_lossy().to_string();
+    fixture.write("gdep.yml", &format!("poll_interval: 1\nrestart_update: true\ndeployments:\n\
+        - name: app\n  repo: {}\n  into_path: app\n  script: cat app.txt >> ../app-runs.txt; exec sleep 60\n\
+        - name: lib\n  repo: {}\n  into_path: lib\n  script: cat lib.txt >> ../lib-runs.txt; {}\n  final: {}\n",
+        fixture.origin_url(), lib, if lib_final {"exit 1"} else {"exec sleep 60"}, lib_final));
+}
+
+#[test]
+fn every_deployment_runs_and_updates_on_its_own() {
+    let fixture = Fixture::new();
+    let lib = fixture.other_repo("lib");
+    two_deployments(&fixture, false);
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.read("app-runs.txt") == "v1\n" && fixture.read("lib-runs.txt") == "lib v1\n"), "{}", gdep.log());
+
+    fixture.commit_in(&lib, "main", &[("lib.txt", Some("lib v2\n"))], "lib v2");
+    assert!(wait_for(TIMEOUT, || fixture.read("lib-runs.txt") == "lib v1\nlib v2\n"), "{}", gdep.log());
+    assert_eq!(fixture.read("app-runs.txt"), "v1\n");
+
+    fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || fixture.read("app-runs.txt") == "v1\nv2\n"), "{}", gdep.log());
+    assert_eq!(fixture.read("lib-runs.txt"), "lib v1\nlib v2\n");
+    gdep.stop();
+}
+
+#[test]
+fn a_failing_deployment_leaves_the_others_running() {
+    let fixture = Fixture::new();
+    fixture.other_repo("lib");
+    two_deployments(&fixture, true);
+    let mut gdep = fixture.spawn(&[]);
+    gdep.wait_for_log("Deployment stopped, the others keep running");
+
+    fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || fixture.read("app-runs.txt") == "v1\nv2\n"), "{}", gdep.log());
+    gdep.stop();
+}
+
+#[test]
+fn a_signal_waits_for_every_deployment_to_clean_up() {
+    let fixture = Fixture::new();
+    let lib = fixture.path("lib.git").to_string_lossy().to_string();
+    fixture.other_repo("lib");
+    // lib cleans up slower than app, gdep would be gone by then if it stopped after the first deployment
+    fixture.write("gdep.yml", &format!("poll_interval: 1\ndeployments:\n\
+        - name: app\n  repo: {}\n  into_path: app\n  script: touch ../app-started; exec sleep 60\n  cleanup: sleep 0.5; touch ../app-cleaned\n\
+        - name: lib\n  repo: {}\n  into_path: lib\n  script: touch ../lib-started; exec sleep 60\n  cleanup: sleep 1.5; touch ../lib-cleaned\n",
+        fixture.origin_url(), lib));
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.path("app-started").exists() && fixture.path("lib-started").exists()), "{}", gdep.log());
+
+    gdep.stop();
+    assert!(fixture.path("app-cleaned").exists(), "{}", gdep.log());
+    assert!(fixture.path("lib-cleaned").exists(), "{}", gdep.log());
+}
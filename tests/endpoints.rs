@@ -0,0 +1,208 @@
+//! The servers gdep runs next to the deployment: webhook listener, health check, metrics and control socket
+#![cfg(unix)]
+
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use common::http::{free_port, send};
+use common::{wait_for, Fixture, TIMEOUT};
+
+fn github_signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+#[test]
+fn a_signed_webhook_triggers_a_fetch_right_away() {
+    let fixture = Fixture::new();
+    let port = free_port();
+    fixture.config(&format!("script: exec sleep 60\npoll_interval: 3600\nwebhook:\n  bind: 127.0.0.1:{}\n  path: /push\n  secret: s3cret\n", port));
+    let mut gdep = fixture.spawn(&["-v"]);
+    // The first fetch happens right away, anything after it waits for the webhook
+    gdep.wait_for_log("Fetched branch `main`: Up2Date");
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    let body = br#"{"ref":"refs/heads/main"}"#;
+
+    let forged = send(port, "POST", "/push", &[("X-Hub-Signature-256", &github_signature("guess", body))], body);
+    assert_eq!(forged.map(|r| r.0), Some(401));
+    assert_eq!(send(port, "POST", "/push", &[("X-Gitlab-Token", "guess")], body).map(|r| r.0), Some(401));
+    assert_eq!(send(port, "POST", "/elsewhere", &[], body).map(|r| r.0), Some(404));
+    assert!(!wait_for(Duration::from_secs(2), || fixture.head_id() == Some(v2)), "updated without a valid webhook");
+
+    let signed = send(port, "POST", "/push", &[("X-Hub-Signature-256", &github_signature("s3cret", body))], body);
+    assert_eq!(signed.map(|r| r.0), Some(202));
+    assert!(wait_for(Duration::from_secs(5), || fixture.head_id() == Some(v2)), "{}", gdep.log());
+
+    // GitLab sends the secret as it is
+    let v3 = fixture.commit("main", &[("app.txt", Some("v3\n"))], "v3");
+    assert_eq!(send(port, "POST", "/push", &[("X-Gitlab-Token", "s3cret")], body).map(|r| r.0), Some(202));
+    assert!(wait_for(TIMEOUT, || fixture.head_id() == Some(v3)), "{}", gdep.log());
+    gdep.stop();
+}
+
+fn healthz(port: u16) -> Option<(u16, serde_json::Value)> {
+    send(port, "GET", "/healthz", &[], b"").map(|(status, body)| (status, serde_json::from_str(&body).unwrap()))
+}
+
+#[test]
+fn healthz_turns_unhealthy_while_the_script_is_down() {
+    let fixture = Fixture::new();
+    let port = free_port();
+    fixture.config(&format!("script: echo $$ > ../script.pid; exec sleep 60\npoll_interval: 1\nrestart_delay: 3\nhealth_addr: 127.0.0.1:{}\n", port));
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || healthz(port).is_some_and(|(s, _)| s == 200)), "{}", gdep.log());
+    let (_, body) = healthz(port).unwrap();
+    assert_eq!(body["branch"], "main");
+    assert_eq!(body["head"], fixture.head().to_string());
+    assert_eq!(body["script_running"], true);
+    assert!(wait_for(TIMEOUT, || healthz(port).unwrap().1["last_fetch"].is_string()));
+
+    let script: i32 = fixture.read("script.pid").trim().parse().unwrap();
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(script), nix::sys::signal::Signal::SIGKILL).unwrap();
+    assert!(wait_for(TIMEOUT, || healthz(port).is_some_and(|(s, _)| s == 503)), "{}", gdep.log());
+    assert_eq!(healthz(port).unwrap().1["script_running"], false);
+
+    // Healthy again once the script is back after `restart_delay`
+    assert!(wait_for(TIMEOUT, || healthz(port).is_some_and(|(s, _)| s == 200)), "{}", gdep.log());
+    gdep.stop();
+    assert!(healthz(port).is_none(), "the health server outlived gdep");
+}
+
+/// Sends `commands` on one connection to the control socket, returning the line each one got back
+fn control(fixture: &Fixture, commands: &[&str]) -> Vec<String> {
+    let stream = UnixStream::connect(fixture.path("gdep.sock")).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut lines = BufReader::new(stream).lines();
+    commands.iter().map(|command| {
+        writeln!(writer, "{}", command).unwrap();
+        lines.next().unwrap().unwrap()
+    }).collect()
+}
+
+#[test]
+fn the_control_socket_answers_status_and_stops_gdep() {
+    let fixture = Fixture::new();
+    let v1 = fixture.tip("main");
+    fixture.config("script: echo run >> ../runs.txt; exec sleep 60\npoll_interval: 3600\ncontrol_socket: gdep.sock\n");
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.path("gdep.sock").exists() && fixture.read("runs.txt") == "run\n"), "{}", gdep.log());
+
+    let answers = control(&fixture, &["status", "dance", "restart"]);
+    assert_eq!(answers[0], format!("ok branch=main head={} relation=Up2Date script_running=true updates_running=true", v1));
+    assert!(answers[1].starts_with("error unknown command `dance`"), "{}", answers[1]);
+    assert_eq!(answers[2], "ok restarting the script");
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "run\nrun\n"), "{}", gdep.log());
+
+    assert_eq!(control(&fixture, &["stop"]), ["ok stopping"]);
+    assert_eq!(gdep.wait_exit(TIMEOUT), Some(Some(0)), "{}", gdep.log());
+    assert!(!fixture.path("gdep.sock").exists());
+}
+
+#[test]
+fn reload_on_the_control_socket_applies_the_edited_config() {
+    let fixture = Fixture::new();
+    fixture.config("script: echo old >> ../runs.txt; exec sleep 60\npoll_interval: 3600\ncontrol_socket: gdep.sock\n");
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.path("gdep.sock").exists() && fixture.read("runs.txt") == "old\n"), "{}", gdep.log());
+
+    fixture.config("script: echo new >> ../runs.txt; exec sleep 60\npoll_interval: 3600\ncontrol_socket: gdep.sock\n");
+    assert_eq!(control(&fixture, &["reload"]), ["ok reloading the config"]);
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "old\nnew\n"), "{}", gdep.log());
+    gdep.stop();
+}
+
+#[test]
+fn checkout_on_the_control_socket_switches_the_deployed_branch() {
+    let fixture = Fixture::new();
+    fixture.commit("release", &[("app.txt", Some("release 1\n"))], "release 1");
+    fixture.config("script: cat app.txt >> ../runs.txt; exec sleep 60\npoll_interval: 1\ncontrol_socket: gdep.sock\n");
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.path("gdep.sock").exists() && fixture.read("runs.txt") == "v1\n"), "{}", gdep.log());
+
+    assert_eq!(control(&fixture, &["checkout nope", "checkout release"]), ["error origin has no branch `nope`", "ok switching to branch `release`"]);
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "v1\nrelease 1\n"), "{}", gdep.log());
+    assert_eq!(fixture.checkout().head().unwrap().shorthand(), Some("release"));
+
+    // From now on the new branch is what is kept up to date
+    let release2 = fixture.commit("release", &[("app.txt", Some("release 2\n"))], "release 2");
+    assert!(wait_for(TIMEOUT, || fixture.head_id() == Some(release2)), "{}", gdep.log());
+    assert!(control(&fixture, &["status"])[0].starts_with("ok branch=release "));
+    gdep.stop();
+}
+
+#[test]
+fn min_restart_interval_spaces_out_restarts_whatever_asked_for_them() {
+    let fixture = Fixture::new();
+    fixture.config("script: date +%s.%N >> ../starts.txt; cat app.txt >> ../runs.txt; exec sleep 60\npoll_interval: 1\nrestart_update: true\n\
+        min_restart_interval: 4\ncontrol_socket: gdep.sock\n");
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.path("gdep.sock").exists() && fixture.read("runs.txt") == "v1\n"), "{}", gdep.log());
+
+    // An update right after the start, then a restart asked for right after the update restarted the script
+    fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    gdep.wait_for_log("Deferring the restart by");
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "v1\nv2\n"), "{}", gdep.log());
+    assert_eq!(control(&fixture, &["restart"]), ["ok restarting the script"]);
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "v1\nv2\nv2\n"), "{}", gdep.log());
+
+    let starts: Vec<f64> = fixture.read("starts.txt").lines().map(|l| l.parse().unwrap()).collect();
+    assert!(starts.windows(2).all(|w| w[1] - w[0] >= 3.9), "{:?}", starts);
+    assert_eq!(gdep.log().matches("Deferring the restart by").count(), 2, "{}", gdep.log());
+    gdep.stop();
+}
+
+/// The value of the sample `name` on `/metrics`
+fn sample(port: u16, name: &str) -> Option<String> {
+    let (_, body) = send(port, "GET", "/metrics", &[], b"").filter(|(status, _)| *status == 200)?;
+    body.lines().find_map(|l| l.strip_prefix(name)?.strip_prefix(' ').map(str::to_string))
+}
+
+#[test]
+fn metrics_count_the_updates_applied() {
+    let fixture = Fixture::new();
+    let port = free_port();
+    fixture.config(&format!("script: exec sleep 60\npoll_interval: 1\nrestart_update: true\nmetrics_addr: 127.0.0.1:{}\n", port));
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || sample(port, "gdep_behind_commits").as_deref() == Some("0")), "{}", gdep.log());
+    assert_eq!(sample(port, "gdep_updates_applied_total").as_deref(), Some("0"));
+    assert!(sample(port, "gdep_seconds_since_last_fetch").is_some());
+    let (_, body) = send(port, "GET", "/metrics", &[], b"").unwrap();
+    assert!(body.contains("# TYPE gdep_updates_applied_total counter"), "{}", body);
+
+    fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || sample(port, "gdep_updates_applied_total").as_deref() == Some("1")), "{}", gdep.log());
+    assert!(wait_for(TIMEOUT, || sample(port, "gdep_script_restarts_total").as_deref() == Some("1")), "{}", gdep.log());
+    assert_eq!(send(port, "GET", "/healthz", &[], b"").map(|r| r.0), Some(404));
+    gdep.stop();
+}
+
+#[test]
+fn metrics_and_health_auth_turn_away_requests_without_the_credentials() {
+    let fixture = Fixture::new();
+    let (metrics, health) = (free_port(), free_port());
+    fixture.config(&format!("script: exec sleep 60\nmetrics_addr: 127.0.0.1:{}\nmetrics_auth:\n  username: scraper\n  password: s3cret\n\
+        health_addr: 127.0.0.1:{}\nhealth_auth:\n  token: t0ken\n", metrics, health));
+    let mut gdep = fixture.spawn(&[]);
+    let status = |port: u16, path: &str, authorization: Option<&str>| {
+        let headers: Vec<_> = authorization.map(|a| ("Authorization", a)).into_iter().collect();
+        send(port, "GET", path, &headers, b"").map(|r| r.0)
+    };
+    assert!(wait_for(TIMEOUT, || status(metrics, "/metrics", None).is_some()), "{}", gdep.log());
+
+    assert_eq!(status(metrics, "/metrics", None), Some(401));
+    assert_eq!(status(metrics, "/metrics", Some("Basic c2NyYXBlcjpndWVzcw==")), Some(401));
+    assert_eq!(status(metrics, "/metrics", Some("Bearer s3cret")), Some(401));
+    // base64 of `scraper:s3cret`, the scheme in any case
+    assert_eq!(status(metrics, "/metrics", Some("Basic c2NyYXBlcjpzM2NyZXQ=")), Some(200));
+    assert_eq!(status(metrics, "/metrics", Some("basic c2NyYXBlcjpzM2NyZXQ=")), Some(200));
+
+    assert_eq!(status(health, "/healthz", None), Some(401));
+    assert_eq!(status(health, "/healthz", Some("Bearer guess")), Some(401));
+    assert!(wait_for(TIMEOUT, || status(health, "/healthz", Some("Bearer t0ken")) == Some(200)), "{}", gdep.log());
+    gdep.stop();
+}
@@ -0,0 +1,149 @@
+//! gdep driven as a library, in the test's own process
+#![cfg(unix)]
+
+mod common;
+
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use gdep::config::Config;
+use gdep::errors::GdepError;
+use gdep::summary::Reason;
+use gdep::{Engine, UpdateRelationState};
+use log::{Level, Log, Metadata, Record};
+use common::{wait_for, Fixture, TIMEOUT};
+
+/// Whatever gdep logs, for the tests to look through
+static RECORDS: Mutex<Vec<(Level, String, String)>> = Mutex::new(Vec::new());
+
+struct Capture;
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        RECORDS.lock().unwrap().push((record.level(), record.target().to_string(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+fn capture_logs() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        log::set_logger(&Capture).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+    });
+}
+
+fn logged(level: Level, text: &str) -> bool {
+    RECORDS.lock().unwrap().iter().any(|(l, _, message)| *l == level && message.contains(text))
+}
+
+/// The config `fixture.config(yaml)` writes, loaded like gdep does
+fn load(fixture: &Fixture, yaml: &str) -> Config {
+    let path = fixture.config(yaml);
+    Config::load_from_files(&[path.to_string_lossy().to_string()]).unwrap()
+}
+
+#[test]
+fn an_update_is_logged_at_info_level() {
+    capture_logs();
+    let fixture = Fixture::new();
+    let engine = Engine::new(load(&fixture, "script: exec sleep 60\npoll_interval: 1\n"), "unused").unwrap();
+    let shutdown = Arc::new(Mutex::new(false));
+    let running = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || engine.run(shutdown))
+    };
+
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2 of the app");
+    let line = format!("Updated to {:.7} \"v2 of the app\" by dev <dev@example.com>", v2.to_string());
+    assert!(wait_for(TIMEOUT, || logged(Level::Info, &line)), "{:?}", RECORDS.lock().unwrap());
+    *shutdown.lock().unwrap() = true;
+    running.join().unwrap();
+}
+
+#[test]
+fn check_and_apply_update_are_one_cycle_in_two_steps() {
+    let fixture = Fixture::new();
+    let engine = Engine::new(load(&fixture, "script: \"true\"\n"), "unused").unwrap();
+    assert_eq!(engine.branch(), "main");
+    assert_eq!(engine.repository().head().unwrap().target(), Some(fixture.tip("main")));
+    assert!(matches!(engine.check().unwrap(), UpdateRelationState::Up2Date));
+
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(matches!(engine.check().unwrap(), UpdateRelationState::Behind(1)));
+    assert_eq!(fixture.read("work/app.txt"), "v1\n");
+
+    engine.apply_update().unwrap();
+    assert_eq!(fixture.head(), v2);
+    assert_eq!(fixture.read("work/app.txt"), "v2\n");
+    assert!(matches!(engine.check().unwrap(), UpdateRelationState::Up2Date));
+}
+
+#[test]
+fn run_returns_the_summary_once_the_script_is_done() {
+    let fixture = Fixture::new();
+    let engine = Engine::new(load(&fixture, "script: echo ran > ../ran.txt\nfinal: true\n"), "unused").unwrap();
+    let summary = engine.run(Arc::new(Mutex::new(false)));
+    assert_eq!(summary.reason, Reason::ScriptExited);
+    assert!(summary.error.is_none(), "{:?}", summary.error);
+    assert_eq!(summary.head, Some(fixture.tip("main").to_string()));
+    assert_eq!(summary.updates, 0);
+    assert_eq!(fixture.read("ran.txt"), "ran\n");
+}
+
+#[test]
+fn a_second_engine_on_the_same_repo_is_refused() {
+    let fixture = Fixture::new();
+    let _first = Engine::new(load(&fixture, "script: \"true\"\n"), "unused").unwrap();
+    let Err(e) = Engine::new(load(&fixture, "script: \"true\"\n"), "unused") else {
+        panic!("both engines locked the repo")
+    };
+    assert!(matches!(e, GdepError::AlreadyRunning(..)), "{:?}", e);
+}
+
+#[test]
+fn the_summary_counts_the_update_the_script_then_exited_on() {
+    let fixture = Fixture::new();
+    let engine = Engine::new(load(&fixture, "script: grep -q v2 app.txt && exit 3; exec sleep 60\npoll_interval: 1\nrestart_update: true\nfinal: true\n"), "unused").unwrap();
+    let running = thread::spawn(move || engine.run(Arc::new(Mutex::new(false))));
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+
+    let summary = running.join().unwrap();
+    assert_eq!(summary.reason, Reason::ScriptExited);
+    assert!(matches!(summary.error, Some(GdepError::ScriptFailed(..))), "{:?}", summary.error);
+    assert_eq!(summary.exit_code(), 3);
+    assert_eq!(summary.head, Some(v2.to_string()));
+    assert_eq!(summary.updates, 1);
+    assert!(summary.uptime.as_secs() < TIMEOUT.as_secs());
+
+    let json = summary.to_json();
+    assert_eq!(json["reason"], "script_exited");
+    assert_eq!(json["exit_code"], 3);
+    assert_eq!(json["updates"], 1);
+    assert_eq!(json["head"], v2.to_string());
+}
+
+#[test]
+fn the_first_cycle_finds_the_remote_tip_even_without_a_tracking_ref() {
+    let fixture = Fixture::new();
+    let engine = Engine::new(load(&fixture, "script: \"true\"\n"), "unused").unwrap();
+    assert!(matches!(engine.check().unwrap(), UpdateRelationState::Up2Date));
+
+    let tracking = |fixture: &Fixture| fixture.checkout().refname_to_id("refs/remotes/origin/main").ok();
+    fixture.checkout().find_reference("refs/remotes/origin/main").unwrap().delete().unwrap();
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(matches!(engine.check().unwrap(), UpdateRelationState::Behind(1)));
+    assert_eq!(tracking(&fixture), Some(v2));
+    drop(engine);
+
+    // A refspec whose source origin lacks falls back to the default one
+    let engine = Engine::new(load(&fixture, "script: \"true\"\nrefspec: +refs/heads/gone:refs/remotes/origin/{branch}\n"), "unused").unwrap();
+    fixture.checkout().find_reference("refs/remotes/origin/main").unwrap().delete().unwrap();
+    let v3 = fixture.commit("main", &[("app.txt", Some("v3\n"))], "v3");
+    assert!(matches!(engine.check().unwrap(), UpdateRelationState::Behind(2)));
+    assert_eq!(tracking(&fixture), Some(v3));
+}
@@ -0,0 +1,66 @@
+//! Notifications gdep posts about updates and errors, sent to a receiver of the test's own
+#![cfg(unix)]
+
+mod common;
+
+use serde_json::json;
+use common::http::Receiver;
+use common::{wait_for, Fixture, TIMEOUT};
+
+#[test]
+fn an_update_is_posted_with_both_commits() {
+    let fixture = Fixture::new();
+    let receiver = Receiver::start();
+    let v1 = fixture.tip("main");
+    fixture.config(&format!("script: exec sleep 60\npoll_interval: 1\nnotify_url: {}\n", receiver.url()));
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.head_id() == Some(v1)), "{}", gdep.log());
+
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || receiver.payloads().iter().any(|p| p["event"] == "update")), "{}", gdep.log());
+    let update = receiver.payloads().into_iter().find(|p| p["event"] == "update").unwrap();
+    assert_eq!(update, json!({"event": "update", "name": "test", "branch": "main", "previous": v1.to_string(), "current": v2.to_string()}));
+    gdep.stop();
+}
+
+#[test]
+fn notify_events_picks_what_is_sent() {
+    let fixture = Fixture::new();
+    let receiver = Receiver::start();
+    fixture.config(&format!("script: exit 4\nfinal: true\nnotify_url: {}\nnotify_events: [error]\n", receiver.url()));
+
+    assert_eq!(fixture.run(&[]).status.code(), Some(4));
+    let payloads = receiver.payloads();
+    assert_eq!(payloads.len(), 1, "{:?}", payloads);
+    assert_eq!(payloads[0]["event"], "error");
+    assert_eq!(payloads[0]["name"], "test");
+    assert_eq!(payloads[0]["branch"], "main");
+    assert_eq!(payloads[0]["error"], "ScriptFailed");
+    assert!(payloads[0]["message"].is_string(), "{:?}", payloads);
+}
+
+#[test]
+fn slack_gets_a_message_for_people() {
+    let fixture = Fixture::new();
+    let receiver = Receiver::start();
+    let v1 = fixture.tip("main");
+    fixture.config(&format!("script: exec sleep 60\npoll_interval: 1\nnotifier:\n  backend: slack\n  url: {}\n", receiver.url()));
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.head_id() == Some(v1)), "{}", gdep.log());
+
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || !receiver.payloads().is_empty()), "{}", gdep.log());
+    let text = format!("\u{1F680} *test* deployed `main` at `{:.7}` (was `{:.7}`)", v2.to_string(), v1.to_string());
+    assert_eq!(receiver.payloads()[0], json!({"text": text}));
+    gdep.stop();
+}
+
+#[test]
+fn an_unreachable_notifier_does_not_break_the_deploy() {
+    let fixture = Fixture::new();
+    let port = common::http::free_port();
+    fixture.config(&format!("script: touch ../ran.txt\nnotifier:\n  backend: discord\n  url: http://127.0.0.1:{}/hook\n", port));
+    let output = fixture.run(&["--once"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(fixture.path("ran.txt").exists());
+}
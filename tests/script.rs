@@ -0,0 +1,512 @@
+//! How gdep runs, restarts and stops the script, driven through the binary against fixture repos
+#![cfg(unix)]
+
+mod common;
+
+use std::time::{Duration, Instant};
+use nix::sys::signal::Signal;
+use common::{alive, wait_for, Fixture, TIMEOUT};
+
+/// Appends the `app.txt` it started with to `runs.txt`, then keeps running
+const LONG_RUNNING: &str = "script: cat app.txt >> ../runs.txt; exec sleep 60\n";
+
+#[test]
+fn restart_update_restarts_the_script_on_the_new_code() {
+    let fixture = Fixture::new();
+    fixture.config(&format!("{}poll_interval: 1\nrestart_update: true\n", LONG_RUNNING));
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "v1\n"), "{}", gdep.log());
+
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "v1\nv2\n"), "{}", gdep.log());
+    assert_eq!(fixture.head(), v2);
+    gdep.stop();
+}
+
+#[test]
+fn without_restart_update_the_script_keeps_running() {
+    let fixture = Fixture::new();
+    fixture.config(&format!("{}poll_interval: 1\n", LONG_RUNNING));
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "v1\n"), "{}", gdep.log());
+
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    gdep.wait_for_log("Updated to");
+    assert_eq!(fixture.head(), v2);
+    assert_eq!(fixture.read("work/app.txt"), "v2\n");
+    assert_eq!(fixture.read("runs.txt"), "v1\n");
+    gdep.stop();
+}
+
+#[test]
+fn stopping_gdep_lets_the_script_handle_sigterm() {
+    let fixture = Fixture::new();
+    fixture.config("script: |\n  trap 'echo trapped > ../trapped.txt; exit 0' TERM\n  touch ../started.txt\n  while true; do sleep 0.1; done\n");
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.path("started.txt").exists()), "{}", gdep.log());
+
+    gdep.stop();
+    assert_eq!(fixture.read("trapped.txt"), "trapped\n", "{}", gdep.log());
+}
+
+#[test]
+fn sigint_stops_gdep_and_the_script() {
+    let fixture = Fixture::new();
+    fixture.config("script: echo $$ > ../script.pid; exec sleep 60\n");
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.read("script.pid").ends_with('\n')), "{}", gdep.log());
+    let script: i32 = fixture.read("script.pid").trim().parse().unwrap();
+
+    gdep.signal(Signal::SIGINT);
+    assert_eq!(gdep.wait_exit(TIMEOUT), Some(Some(130)), "{}", gdep.log());
+    assert!(!alive(script), "the script outlived gdep");
+}
+
+#[test]
+fn a_failing_script_reports_its_last_output_lines() {
+    let fixture = Fixture::new();
+    fixture.config("script: |\n  for i in 1 2 3 4; do echo line $i; done\n  exit 3\nfinal: true\nerror_context_lines: 2\n");
+
+    let output = fixture.run(&[]);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let report = &stderr[stderr.find("Gdep Error =>").expect(&stderr)..];
+    assert!(report.contains("line 3") && report.contains("line 4"), "{}", report);
+    assert!(!report.contains("line 2"), "{}", report);
+}
+
+#[test]
+fn the_script_sees_the_configured_env_and_the_current_commit() {
+    let fixture = Fixture::new();
+    let v1 = fixture.tip("main");
+    fixture.config("script: echo \"$GREETING $GDEP_BRANCH $GDEP_COMMIT\" >> ../env.txt; exec sleep 60\n\
+        env:\n  GREETING: hello\npoll_interval: 1\nrestart_update: true\n");
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.read("env.txt").lines().count() == 1), "{}", gdep.log());
+
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || fixture.read("env.txt").lines().count() == 2), "{}", gdep.log());
+    assert_eq!(fixture.read("env.txt"), format!("hello main {}\nhello main {}\n", v1, v2));
+    gdep.stop();
+}
+
+#[test]
+fn arguments_are_forwarded_exactly() {
+    let fixture = Fixture::new();
+    fixture.config("script: for a in \"$@\"; do echo \"[$a]\"; done > ../args.txt\nargs: [--port, \"8080\", two words]\n");
+
+    assert!(fixture.run(&["--once"]).status.success());
+    assert_eq!(fixture.read("args.txt"), "[--port]\n[8080]\n[two words]\n");
+
+    // Arguments after `--` replace the configured ones
+    assert!(fixture.run(&["--once", "--", "it's", "*", "$HOME"]).status.success());
+    assert_eq!(fixture.read("args.txt"), "[it's]\n[*]\n[$HOME]\n");
+}
+
+#[test]
+fn a_script_past_its_timeout_is_stopped() {
+    let fixture = Fixture::new();
+    fixture.config("script: exec sleep 60\nscript_timeout: 1\nfinal: true\n");
+
+    let started = Instant::now();
+    let output = fixture.run(&[]);
+    assert!(started.elapsed() < Duration::from_secs(10), "took {:?}", started.elapsed());
+    assert_eq!(output.status.code(), Some(232));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Script timed out after 1s"), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn script_output_is_appended_to_the_configured_files() {
+    let fixture = Fixture::new();
+    fixture.write("logs/out.log", "earlier\n");
+    fixture.config("script: |\n  echo to stdout\n  echo to stderr >&2\nstdout_file: logs/out.log\nstderr_file: logs/err.log\n");
+
+    let output = fixture.run(&["--once"]);
+    assert!(output.status.success());
+    assert_eq!(fixture.read("logs/out.log"), "earlier\nto stdout\n");
+    assert_eq!(fixture.read("logs/err.log"), "to stderr\n");
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("to stdout"));
+}
+
+#[test]
+fn gdep_exits_with_the_code_of_a_script_it_does_not_rerun() {
+    let fixture = Fixture::new();
+    fixture.config("script: exit 42\nfinal: true\n");
+    assert_eq!(fixture.run(&[]).status.code(), Some(42));
+
+    fixture.config("script: exit 0\nfinal: true\n");
+    assert_eq!(fixture.run(&[]).status.code(), Some(0));
+}
+
+#[test]
+fn once_runs_the_script_a_single_time_and_returns() {
+    let fixture = Fixture::new();
+    fixture.clone_work();
+    let v2 = fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    fixture.config("script: cat app.txt >> ../runs.txt; exit 5\ncleanup: echo cleaned >> ../runs.txt\npoll_interval: 1\n");
+
+    let started = Instant::now();
+    let output = fixture.run(&["--once"]);
+    assert!(started.elapsed() < Duration::from_secs(10), "took {:?}", started.elapsed());
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(fixture.head(), v2);
+    assert_eq!(fixture.read("runs.txt"), "v2\ncleaned\n");
+}
+
+#[test]
+fn an_always_failing_script_stops_after_max_restarts() {
+    let fixture = Fixture::new();
+    fixture.config("script: echo run >> ../runs.txt; exit 7\nmax_restarts: 2\n");
+
+    let output = fixture.run(&[]);
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(fixture.read("runs.txt"), "run\nrun\nrun\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Giving up after 2 restart(s) in a row"), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn only_changes_to_watch_paths_restart_the_script() {
+    let fixture = Fixture::new();
+    fixture.config("script: echo run >> ../runs.txt; exec sleep 60\npoll_interval: 1\nrestart_update: true\nwatch_paths: [\"service/**\"]\n");
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "run\n"), "{}", gdep.log());
+
+    let docs = fixture.commit("main", &[("docs/readme.md", Some("docs\n"))], "docs");
+    gdep.wait_for_log("No watched path changed, keeping the script running");
+    assert_eq!(fixture.head(), docs);
+    assert_eq!(fixture.read("runs.txt"), "run\n");
+
+    fixture.commit("main", &[("service/main.txt", Some("v2\n"))], "service v2");
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "run\nrun\n"), "{}", gdep.log());
+    gdep.stop();
+}
+
+#[test]
+fn a_script_exiting_is_noticed_without_waiting_for_the_poll_interval() {
+    let fixture = Fixture::new();
+    fixture.config("script: sleep 0.2\npoll_interval: 3600\nfinal: true\n");
+    let started = Instant::now();
+    let mut gdep = fixture.spawn(&[]);
+    assert_eq!(gdep.wait_exit(TIMEOUT), Some(Some(0)), "{}", gdep.log());
+    assert!(started.elapsed() < Duration::from_secs(3), "took {:?}", started.elapsed());
+
+    // Restarts after a crash don't wait for the update thread either
+    fixture.config("script: echo run >> ../runs.txt; exit 1\npoll_interval: 3600\nmax_restarts: 3\n");
+    let started = Instant::now();
+    assert_eq!(fixture.run(&[]).status.code(), Some(1));
+    assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+    assert_eq!(fixture.read("runs.txt"), "run\nrun\nrun\nrun\n");
+}
+
+#[test]
+fn a_script_list_runs_its_steps_in_order_and_supervises_the_last() {
+    let fixture = Fixture::new();
+    fixture.config("script:\n  - echo build >> ../runs.txt\n  - echo migrate >> ../runs.txt\n  - echo serve >> ../runs.txt; exec sleep 60\npoll_interval: 1\nrestart_update: true\n");
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "build\nmigrate\nserve\n"), "{}", gdep.log());
+    // The steps exiting is no reason to restart anything
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(fixture.read("runs.txt"), "build\nmigrate\nserve\n");
+    assert!(gdep.wait_exit(Duration::ZERO).is_none());
+
+    fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "build\nmigrate\nserve\nbuild\nmigrate\nserve\n"), "{}", gdep.log());
+    gdep.stop();
+}
+
+#[test]
+fn a_failing_step_keeps_the_rest_from_running() {
+    let fixture = Fixture::new();
+    fixture.config("script:\n  - echo build >> ../runs.txt\n  - exit 9\n  - echo serve >> ../runs.txt\nfinal: true\n");
+    let output = fixture.run(&[]);
+    assert_eq!(output.status.code(), Some(9));
+    assert_eq!(fixture.read("runs.txt"), "build\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Step 2/2 failed"), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn on_change_runs_the_rules_matching_the_update() {
+    let fixture = Fixture::new();
+    fixture.config("script: echo run >> ../runs.txt; exec sleep 60\npoll_interval: 1\nrestart_update: true\n\
+        on_change:\n  \"migrations/**\": echo migrate >> ../rules.txt\n  \"static/**\": echo rebuild >> ../rules.txt\n");
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "run\n"), "{}", gdep.log());
+
+    // A change only a rule cares about runs that rule, without restarting the script
+    let migration = fixture.commit("main", &[("migrations/1.sql", Some("create\n"))], "migration");
+    assert!(wait_for(TIMEOUT, || fixture.read("rules.txt") == "migrate\n"), "{}", gdep.log());
+    assert!(wait_for(TIMEOUT, || fixture.head_id() == Some(migration)));
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(fixture.read("runs.txt"), "run\n");
+
+    // Both rules match, in the order they are declared, and the app change restarts the script
+    fixture.commit("main", &[("static/site.css", Some("body\n")), ("migrations/2.sql", Some("alter\n")), ("app.txt", Some("v2\n"))], "everything");
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "run\nrun\n"), "{}", gdep.log());
+    assert_eq!(fixture.read("rules.txt"), "migrate\nmigrate\nrebuild\n");
+    gdep.stop();
+}
+
+#[test]
+fn the_script_runs_in_working_dir() {
+    let fixture = Fixture::new();
+    fixture.commit("main", &[("backend/server.txt", Some("backend\n"))], "add the backend");
+    fixture.config("script: pwd > ../../cwd.txt; cat server.txt >> ../../cwd.txt\nworking_dir: backend\n");
+    assert!(fixture.run(&["--once"]).status.success());
+    let backend = fixture.path("work/backend").canonicalize().unwrap();
+    assert_eq!(fixture.read("cwd.txt"), format!("{}\nbackend\n", backend.display()));
+
+    // An absolute one is taken as it is
+    let elsewhere = fixture.path("elsewhere");
+    std::fs::create_dir(&elsewhere).unwrap();
+    fixture.config(&format!("script: pwd > ../cwd.txt\nworking_dir: {}\n", elsewhere.display()));
+    assert!(fixture.run(&["--once"]).status.success());
+    assert_eq!(fixture.read("cwd.txt"), format!("{}\n", elsewhere.canonicalize().unwrap().display()));
+
+    fixture.config("script: echo ran > ../ran.txt\nworking_dir: frontend\n");
+    let output = fixture.run(&["--once"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("working_dir"), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("frontend` is not a directory"), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!fixture.path("ran.txt").exists());
+}
+
+#[test]
+fn the_script_runs_in_the_configured_shell_or_runner() {
+    let fixture = Fixture::new();
+    let script = "script: |\n  printf '%s\\n' \"hello $1\" >> ../out.txt\n  [ -n \"$BASH_VERSION\" ] && echo bash >> ../shells.txt || echo posix >> ../shells.txt\nargs: [world]\n";
+    for shell in ["dash", "bash"] {
+        fixture.config(&format!("{}shell: {}\n", script, shell));
+        let output = fixture.run(&["--once"]);
+        assert!(output.status.success(), "{}: {}", shell, String::from_utf8_lossy(&output.stderr));
+    }
+    assert_eq!(fixture.read("out.txt"), "hello world\nhello world\n");
+    assert_eq!(fixture.read("shells.txt"), "posix\nbash\n");
+
+    fixture.config("script: |\n  import sys\n  open('../out.txt', 'a').write('hello ' + sys.argv[1] + '\\n')\nargs: [python]\nrunner: python3 -u\n");
+    let output = fixture.run(&["--once"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fixture.read("out.txt"), "hello world\nhello world\nhello python\n");
+}
+
+#[test]
+fn a_missing_interpreter_is_reported_before_the_script_runs() {
+    let fixture = Fixture::new();
+    fixture.config("script: touch ../ran.txt\nrunner: no-such-interpreter\n");
+    let output = fixture.run(&["--once"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid value for `runner`: `no-such-interpreter` is not installed or not on the PATH"),
+        "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!fixture.path("ran.txt").exists());
+}
+
+#[test]
+fn a_script_that_cannot_start_fails_instead_of_crashing_gdep() {
+    let fixture = Fixture::new();
+    // On the PATH check it passes, but it isn't executable
+    fixture.write("runner", "#!/bin/sh\n");
+    fixture.config(&format!("script: touch ../ran.txt\ncleanup: touch ../cleaned.txt\nrunner: {}\n", fixture.path("runner").display()));
+    let output = fixture.run(&["--once"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(127), "{}", stderr);
+    assert!(stderr.contains("Could not start the script: Permission denied"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert!(!fixture.path("ran.txt").exists());
+}
+
+#[test]
+fn pushes_within_the_debounce_window_cause_one_restart() {
+    let fixture = Fixture::new();
+    fixture.config(&format!("{}poll_interval: 1\nrestart_update: true\ndebounce: 3\n", LONG_RUNNING));
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "v1\n"), "{}", gdep.log());
+
+    fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "v1\nv2\n"), "{}", gdep.log());
+    for version in ["v3", "v4", "v5"] {
+        fixture.commit("main", &[("app.txt", Some(&format!("{}\n", version)))], version);
+        std::thread::sleep(Duration::from_millis(300));
+    }
+    assert!(wait_for(TIMEOUT, || fixture.read("runs.txt") == "v1\nv2\nv5\n"), "{}", gdep.log());
+    std::thread::sleep(Duration::from_secs(2));
+    assert_eq!(fixture.read("runs.txt"), "v1\nv2\nv5\n");
+    gdep.stop();
+}
+
+#[test]
+fn bootstrap_runs_after_the_first_clone_only() {
+    let fixture = Fixture::new();
+    fixture.config("script: echo run >> ../runs.txt\nbootstrap: echo bootstrap >> ../runs.txt\n");
+    assert!(fixture.run(&["--once"]).status.success());
+    assert_eq!(fixture.read("runs.txt"), "bootstrap\nrun\n");
+
+    assert!(fixture.run(&["--once"]).status.success());
+    assert_eq!(fixture.read("runs.txt"), "bootstrap\nrun\nrun\n");
+}
+
+#[test]
+fn a_failing_bootstrap_stops_gdep_unless_script_errors_are_ignored() {
+    let fixture = Fixture::new();
+    fixture.config("script: echo run >> ../runs.txt\nbootstrap: exit 6\n");
+    let output = fixture.run(&["--once"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("bootstrap"), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!fixture.path("runs.txt").exists());
+
+    std::fs::remove_dir_all(fixture.work()).unwrap();
+    fixture.config("script: echo run >> ../runs.txt\nbootstrap: exit 6\nscript_err_ignore: true\n");
+    assert!(fixture.run(&["--once"]).status.success());
+    assert_eq!(fixture.read("runs.txt"), "run\n");
+}
+
+#[test]
+fn run_as_starts_the_script_as_that_user() {
+    if !nix::unistd::Uid::effective().is_root() {
+        eprintln!("skipped, only root can run scripts as another user");
+        return
+    }
+    use std::os::unix::fs::PermissionsExt;
+    let fixture = Fixture::new();
+    // The script has to get into its working directory as nobody
+    std::fs::set_permissions(fixture.dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+    fixture.config("script: echo \"$(id -u):$(id -g)\"\nrun_as: nobody\n");
+    let output = fixture.run(&["--once"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("65534:65534"), "{}", String::from_utf8_lossy(&output.stdout));
+    // gdep itself stays root, the checkout is still its own
+    assert_eq!(std::os::unix::fs::MetadataExt::uid(&std::fs::metadata(fixture.work().join(".git")).unwrap()), 0);
+}
+
+#[test]
+fn ready_check_waits_for_the_script_and_rolls_back_what_never_gets_ready() {
+    let fixture = Fixture::new();
+    let v1 = fixture.commit("main", &[("ready.txt", Some("yes\n"))], "ready");
+    // Ready a second after it started, as long as the commit has `ready.txt`
+    fixture.config("script: sleep 1; [ -f ready.txt ] && git rev-parse HEAD > ../up; exec sleep 60\n\
+        ready_check: '[ \"$(cat ../up)\" = \"$(git rev-parse HEAD)\" ]'\nready_timeout: 3\npoll_interval: 1\nrestart_update: true\n");
+    let mut gdep = fixture.spawn(&[]);
+    gdep.wait_for_log("Waiting for the script to become ready...");
+    let waiting = Instant::now();
+    gdep.wait_for_log("Script is ready");
+    assert!(waiting.elapsed() >= Duration::from_millis(500), "ready after {:?}", waiting.elapsed());
+
+    let v2 = fixture.commit("main", &[("ready.txt", None)], "never ready");
+    gdep.wait_for_log(&format!("Commit {} did not become ready: `[ \"$(cat ../up)\" = \"$(git rev-parse HEAD)\" ]` did not pass within 3s", v2));
+    gdep.wait_for_log(&format!("Rolled back to {}", v1));
+    assert!(wait_for(TIMEOUT, || fixture.head_id() == Some(v1)), "{}", gdep.log());
+    gdep.stop();
+}
+
+#[test]
+fn graceful_restart_keeps_the_old_script_until_the_new_one_is_ready() {
+    let fixture = Fixture::new();
+    let pid = |version: &str| fixture.read(&format!("pid-{}", version)).trim().parse::<i32>().ok();
+    // Each version writes down its pid and gets ready once the test creates its `go-` file
+    fixture.config("script: echo $$ > ../pid-$(cat app.txt); exec sleep 60\nready_check: test -f ../go-$(cat app.txt)\n\
+        ready_timeout: 4\npoll_interval: 1\nrestart_update: true\ngraceful_restart: true\n");
+    fixture.write("go-v1", "");
+    let mut gdep = fixture.spawn(&[]);
+    gdep.wait_for_log("Script is ready");
+    let v1 = pid("v1").unwrap();
+
+    fixture.commit("main", &[("app.txt", Some("v2\n"))], "v2");
+    assert!(wait_for(TIMEOUT, || pid("v2").is_some()), "{}", gdep.log());
+    let v2 = pid("v2").unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(alive(v1) && alive(v2), "{}", gdep.log());
+
+    fixture.write("go-v2", "");
+    gdep.wait_for_log("The updated script is ready, stopping the previous one...");
+    assert!(wait_for(TIMEOUT, || !alive(v1)), "{}", gdep.log());
+    assert!(alive(v2));
+
+    // One that never gets ready is stopped, the running one is kept
+    fixture.commit("main", &[("app.txt", Some("v3\n"))], "v3");
+    assert!(wait_for(TIMEOUT, || pid("v3").is_some()), "{}", gdep.log());
+    let v3 = pid("v3").unwrap();
+    gdep.wait_for_log("the previous version keeps running");
+    assert!(wait_for(TIMEOUT, || !alive(v3)), "{}", gdep.log());
+    assert!(alive(v2));
+    assert_eq!(fixture.read("work/app.txt"), "v2\n");
+    gdep.stop();
+}
+
+#[test]
+fn restarts_wait_restart_delay_and_a_stop_cuts_the_wait_short() {
+    let fixture = Fixture::new();
+    fixture.config("script: echo started >> ../starts.txt\npoll_interval: 1\nrestart_delay: 2\n");
+    let starts = || fixture.read("starts.txt").lines().count();
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || starts() == 1), "{}", gdep.log());
+    let first = Instant::now();
+    assert!(wait_for(TIMEOUT, || starts() == 2), "{}", gdep.log());
+    assert!(first.elapsed() >= Duration::from_millis(1900), "restarted after {:?}", first.elapsed());
+    gdep.wait_for_log("Restarting in 2s...");
+
+    // Stopped while it waits to restart, gdep does so right away
+    assert!(wait_for(TIMEOUT, || gdep.log().matches("Restarting in 2s...").count() == 2), "{}", gdep.log());
+    let stopping = Instant::now();
+    gdep.signal(Signal::SIGTERM);
+    assert!(gdep.wait_exit(TIMEOUT).is_some());
+    assert!(stopping.elapsed() < Duration::from_secs(1), "stopped after {:?}", stopping.elapsed());
+    assert_eq!(starts(), 2);
+}
+
+#[test]
+fn cleanup_is_told_why_the_script_stopped() {
+    let fixture = Fixture::new();
+    let cleanup = "cleanup: echo \"$GDEP_EXIT_REASON|${GDEP_ERROR-none}|${GDEP_SCRIPT_EXIT_CODE-none}\" >> ../cleanup.txt\n";
+    fixture.config(&format!("script: exit 3\nfinal: true\n{}", cleanup));
+    assert_eq!(fixture.run(&[]).status.code(), Some(3));
+    assert_eq!(fixture.read("cleanup.txt"), "script_exited|Script failed (exit status: 3)|3\n");
+
+    fixture.config(&format!("script: \"true\"\nfinal: true\n{}", cleanup));
+    assert!(fixture.run(&[]).status.success());
+    assert_eq!(fixture.read("cleanup.txt").lines().last(), Some("script_exited|none|0"));
+
+    fixture.config(&format!("script: touch ../started; exec sleep 60\npoll_interval: 1\n{}", cleanup));
+    let mut gdep = fixture.spawn(&[]);
+    assert!(wait_for(TIMEOUT, || fixture.path("started").exists()), "{}", gdep.log());
+    gdep.stop();
+    assert_eq!(fixture.read("cleanup.txt").lines().last(), Some("signal|Interrupted by signal 15|none"));
+}
+
+#[test]
+fn prefix_output_tags_each_line_with_its_stream() {
+    let fixture = Fixture::new();
+    fixture.config("script: echo one; sleep 0.2; echo two >&2; sleep 0.2; echo three\nprefix_output: true\n");
+    let output = fixture.run(&["--once"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+    // Through gdep's logger, which puts the timestamp first
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<_> = stderr.lines().filter(|l| l.contains(" script] ")).collect();
+    assert_eq!(lines.iter().map(|l| l.split_once(" script] ").unwrap().1).collect::<Vec<_>>(), ["[stdout] one", "[stderr] two", "[stdout] three"], "{}", stderr);
+    assert!(lines.iter().all(|l| l.starts_with("[20")), "{}", stderr);
+
+    // Lines going to a file get the timestamp from gdep
+    fixture.config("script: echo one; echo two >&2\nprefix_output: true\nstdout_file: out.log\nstderr_file: out.log\n");
+    assert!(fixture.run(&["--once"]).status.success());
+    let log = fixture.read("out.log");
+    let mut tagged: Vec<_> = log.lines().map(|l| {
+        let (timestamp, rest) = l.split_once(' ').unwrap();
+        assert!(timestamp.ends_with('Z') && timestamp.contains('T'), "{}", log);
+        rest
+    }).collect();
+    tagged.sort();
+    assert_eq!(tagged, ["[stderr] two", "[stdout] one"], "{}", log);
+}
+
+#[test]
+fn max_output_rate_drops_and_counts_the_lines_over_it() {
+    let fixture = Fixture::new();
+    fixture.config("script: for i in $(seq 1 500); do echo line $i; done\nmax_output_rate: 10\n");
+    let output = fixture.run(&["--once"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let forwarded = stdout.lines().count();
+    assert!(stdout.starts_with("line 1\nline 2\n") && (10..=30).contains(&forwarded), "{}", stdout);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let suppressed: usize = stderr.lines().filter_map(|l| l.split("Suppressed ").nth(1)?.strip_suffix(" lines of script output")?.parse::<usize>().ok()).sum();
+    assert_eq!(forwarded + suppressed, 500, "{}", stderr);
+}
@@ -0,0 +1,47 @@
+//! The readiness, watchdog and stopping notifications gdep sends when systemd runs it as a `Type=notify` service
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use common::Fixture;
+
+/// The next datagram gdep sent to the socket, None if nothing came within the read timeout
+fn receive(socket: &UnixDatagram) -> Option<String> {
+    let mut buffer = [0; 256];
+    let len = socket.recv(&mut buffer).ok()?;
+    Some(String::from_utf8_lossy(&buffer[..len]).to_string())
+}
+
+#[test]
+fn systemd_hears_ready_keepalives_and_stopping() {
+    let fixture = Fixture::new();
+    let socket = UnixDatagram::bind(fixture.path("notify.sock")).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    fixture.config("script: exec sleep 60\n");
+    let notify_socket = fixture.path("notify.sock").to_string_lossy().to_string();
+    let mut gdep = fixture.spawn_with_env(&[], &[("NOTIFY_SOCKET", &notify_socket), ("WATCHDOG_USEC", "400000")]);
+
+    let mut received = vec![receive(&socket).unwrap_or_else(|| panic!("{}", gdep.log()))];
+    while received.iter().filter(|m| *m == "WATCHDOG=1").count() < 3 {
+        received.push(receive(&socket).unwrap_or_else(|| panic!("{:?}\n{}", received, gdep.log())));
+    }
+    assert_eq!(received[0], "READY=1");
+    assert!(received[1..].iter().all(|m| m == "WATCHDOG=1"), "{:?}", received);
+
+    gdep.stop();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let rest: Vec<_> = std::iter::from_fn(|| receive(&socket)).collect();
+    assert_eq!(rest.iter().filter(|m| *m == "STOPPING=1").count(), 1, "{:?}", rest);
+    assert!(!rest.contains(&"READY=1".to_string()), "{:?}", rest);
+}
+
+#[test]
+fn nothing_is_sent_without_notify_socket() {
+    let fixture = Fixture::new();
+    fixture.config("script: \"true\"\n");
+    let output = fixture.gdep().args(["-s", "gdep.yml", "--once", "-v"]).env("WATCHDOG_USEC", "400000").output().unwrap();
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Notified systemd"), "{}", String::from_utf8_lossy(&output.stderr));
+}